@@ -0,0 +1,366 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use x86_64::PhysAddr;
+
+use crate::sync::Once;
+use crate::{memory, serial_println};
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+// ------------------------------------------------------------------------------------------------------┐
+// ACPI tables, how the firmware describes the hardware it booted us on.                                  |
+//                                                                                                       |
+//   RSDP (found by scanning the BIOS area) -> RSDT / XSDT (list of physical table addresses)             |
+//                                                -> "APIC" MADT - interrupt controllers and CPUs         |
+//                                                -> "FACP" FADT - power management, points to the DSDT   |
+//                                                -> "HPET" HPET - high precision event timer             |
+//                                                -> "MCFG" MCFG - PCIe configuration space               |
+//                                                                                                       |
+// Every table except the RSDP starts with the same 36 byte `SdtHeader`, its signature says which table  |
+// it is and its length covers the whole table. All bytes of a table (and of the RSDP) add up to 0 mod    |
+// 256, tables that fail this check are ignored.                                                         |
+//                                                                                                       |
+// Tables live in normal RAM (ACPI reclaimable or reserved), which the bootloader maps at the physical    |
+// memory offset, so we read them in place. The table directory is built once, on first use.             |
+// ------------------------------------------------------------------------------------------------------┙
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// Size of the ACPI 1.0 part of the RSDP, which the first checksum covers.
+const RSDP_V1_LENGTH: usize = 20;
+
+static TABLES: Once<Result<AcpiTables, AcpiError>> = Once::new();
+
+/// Why the ACPI tables could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP in the BIOS areas (not a BIOS machine, or no ACPI at all).
+    NoRsdp,
+    /// A checksum did not add up, `signature` is `RSDP`, `RSDT` or `XSDT`.
+    BadChecksum([u8; 4]),
+    /// The table does not exist, or its checksum is wrong.
+    TableNotFound([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP found"),
+            AcpiError::BadChecksum(signature) => write!(f, "bad checksum in {}", Signature(signature)),
+            AcpiError::TableNotFound(signature) => write!(f, "no valid {} table", Signature(signature)),
+        }
+    }
+}
+
+// Prints a table signature as text.
+struct Signature<'a>(&'a [u8]);
+
+impl fmt::Display for Signature<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in self.0 {
+            let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure, a register in memory or I/O space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 = system memory, 1 = system I/O, 2 = PCI configuration space.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 = byte, 2 = word, 3 = dword, 4 = qword, 0 = undefined.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    /// An all zero structure means the register does not exist.
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// Width of an access in bytes, from `access_size` or else `bit_width`.
+    fn access_bytes(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => (self.bit_width / 8).clamp(1, 8),
+        }
+    }
+
+    /// **Write** `value` to the register, returns `false` for address spaces we can not access.
+    ///
+    /// # Safety
+    /// Writing to hardware registers can do anything, the address must come from the firmware.
+    pub unsafe fn write(&self, value: u64) -> bool {
+        use x86_64::instructions::port::Port;
+
+        let address = self.address;
+        match self.address_space {
+            GenericAddress::SYSTEM_IO => {
+                let port = address as u16;
+                match self.access_bytes() {
+                    1 => Port::<u8>::new(port).write(value as u8),
+                    2 => Port::<u16>::new(port).write(value as u16),
+                    _ => Port::<u32>::new(port).write(value as u32),
+                }
+                true
+            }
+            GenericAddress::SYSTEM_MEMORY => {
+                let virt = match memory::kernel_memory().try_lock() {
+                    Some(memory) => memory.phys_to_virt(PhysAddr::new(address)),
+                    None => return false,
+                };
+                match self.access_bytes() {
+                    1 => ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+                    2 => ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+                    4 => ptr::write_volatile(virt.as_mut_ptr::<u32>(), value as u32),
+                    _ => ptr::write_volatile(virt.as_mut_ptr::<u64>(), value),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Root System Description Pointer, the ACPI 2.0 fields are only valid if `revision >= 2`.
+// The layout mirrors the spec, not every field is used.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Address and header of one table listed in the RSDT/XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct TableEntry {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+    pub checksum_ok: bool,
+}
+
+/// The table directory, see [`tables`].
+#[derive(Debug, Clone)]
+pub struct AcpiTables {
+    pub rsdp: PhysAddr,
+    /// 0 for ACPI 1.0, 2 and up for ACPI 2.0+.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// `true` if the 64 bit XSDT is used, `false` for the RSDT.
+    pub extended: bool,
+    pub root: TableEntry,
+    pub entries: Vec<TableEntry>,
+}
+
+impl AcpiTables {
+    /// Physical address of the first valid table with `signature`.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<PhysAddr> {
+        self.entries
+            .iter()
+            .find(|entry| entry.checksum_ok && entry.header.signature == *signature)
+            .map(|entry| entry.address)
+    }
+
+    fn discover() -> Result<AcpiTables, AcpiError> {
+        let rsdp_address = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+        let rsdp = unsafe { read_phys::<Rsdp>(rsdp_address) };
+        if !unsafe { checksum_ok(rsdp_address, RSDP_V1_LENGTH) } {
+            return Err(AcpiError::BadChecksum(*b"RSDP"));
+        }
+
+        // The XSDT holds 64 bit pointers and supersedes the RSDT if it exists.
+        let extended = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+        let (root_address, entry_size) = if extended {
+            if !unsafe { checksum_ok(rsdp_address, rsdp.length as usize) } {
+                return Err(AcpiError::BadChecksum(*b"RSDP"));
+            }
+            (PhysAddr::new(rsdp.xsdt_address), 8)
+        } else {
+            (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+        };
+
+        let root = unsafe { TableEntry::read(root_address) };
+        if !root.checksum_ok {
+            return Err(AcpiError::BadChecksum(root.header.signature));
+        }
+        let count = (root.header.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+        let entries = (0..count)
+            .map(|i| {
+                let entry = root_address + size_of::<SdtHeader>() + i * entry_size;
+                let address = unsafe {
+                    if entry_size == 8 {
+                        read_phys::<u64>(entry)
+                    } else {
+                        read_phys::<u32>(entry) as u64
+                    }
+                };
+                unsafe { TableEntry::read(PhysAddr::new(address)) }
+            })
+            .collect();
+
+        Ok(AcpiTables { rsdp: rsdp_address, revision: rsdp.revision, oem_id: rsdp.oem_id, extended, root, entries })
+    }
+}
+
+impl TableEntry {
+    /// Read field `T` at `offset` from the start of the table, `None` if the table is too short
+    /// to have it (older revisions).
+    ///
+    /// # Safety
+    /// The table must hold a `T` at `offset`, if it is long enough.
+    pub unsafe fn field<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + size_of::<T>() <= self.header.length as usize {
+            Some(read_phys(self.address + offset))
+        } else {
+            None
+        }
+    }
+
+    unsafe fn read(address: PhysAddr) -> TableEntry {
+        let header = read_phys::<SdtHeader>(address);
+        TableEntry { address, header, checksum_ok: checksum_ok(address, header.length as usize) }
+    }
+}
+
+/// Read a `T` from physical memory.
+///
+/// # Safety
+/// `addr` must point to readable memory holding a valid `T`.
+pub(crate) unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    let virt = memory::kernel_memory().lock().phys_to_virt(addr);
+    ptr::read_unaligned(virt.as_ptr::<T>())
+}
+
+/// `length` bytes of physical memory at `addr`.
+///
+/// # Safety
+/// The memory must be readable and must not change while the slice is used.
+pub(crate) unsafe fn phys_slice(addr: PhysAddr, length: usize) -> &'static [u8] {
+    let virt = memory::kernel_memory().lock().phys_to_virt(addr);
+    core::slice::from_raw_parts(virt.as_ptr::<u8>(), length)
+}
+
+// The bytes of every ACPI structure add up to 0 (mod 256).
+unsafe fn checksum_ok(addr: PhysAddr, length: usize) -> bool {
+    (0..length).fold(0u8, |sum, i| sum.wrapping_add(read_phys::<u8>(addr + i))) == 0
+}
+
+/// Physical address of the RSDP, searched in the first KiB of the EBDA and in the BIOS ROM area.
+fn find_rsdp() -> Option<PhysAddr> {
+    // The real mode segment of the Extended BIOS Data Area is stored at 0x40E.
+    let ebda = unsafe { read_phys::<u16>(PhysAddr::new(0x40E)) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for &(start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+        // The RSDP is always 16 byte aligned.
+        for addr in (start..end).step_by(16) {
+            let signature = unsafe { read_phys::<[u8; 8]>(PhysAddr::new(addr)) };
+            if &signature == RSDP_SIGNATURE {
+                return Some(PhysAddr::new(addr));
+            }
+        }
+    }
+    None
+}
+
+/// **The ACPI table directory**, discovered on the first call. Needs [`memory::init`] and the heap.
+pub fn tables() -> Result<&'static AcpiTables, AcpiError> {
+    TABLES.call_once(AcpiTables::discover).as_ref().map_err(|error| *error)
+}
+
+/// Physical address of the first valid table with the given `signature`, e.g. `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    tables().ok()?.find(signature)
+}
+
+/// Directory entry of the first valid table with the given `signature`.
+pub fn find_entry(signature: &[u8; 4]) -> Option<TableEntry> {
+    let address = find_table(signature)?;
+    Some(unsafe { TableEntry::read(address) })
+}
+
+/// **Dump all ACPI tables we know** to the serial port.
+pub fn dump() {
+    let tables = match tables() {
+        Ok(tables) => tables,
+        Err(error) => {
+            serial_println!("ACPI: {}", error);
+            return;
+        }
+    };
+    serial_println!(
+        "ACPI: RSDP at {:#x}, revision {}, OEM \"{}\", root {}",
+        tables.rsdp.as_u64(),
+        tables.revision,
+        Signature(&tables.oem_id),
+        if tables.extended { "XSDT" } else { "RSDT" }
+    );
+    for entry in core::iter::once(&tables.root).chain(tables.entries.iter()) {
+        let header = entry.header;
+        serial_println!(
+            "  {} at {:#010x}, {:5} bytes, revision {}, OEM \"{}\" \"{}\"{}",
+            Signature(&header.signature),
+            entry.address.as_u64(),
+            { header.length },
+            header.revision,
+            Signature(&header.oem_id),
+            Signature(&header.oem_table_id),
+            if entry.checksum_ok { "" } else { "  BAD CHECKSUM" }
+        );
+    }
+
+    if let Some(madt) = Madt::get() {
+        serial_println!("{:#x?}", madt);
+    }
+    if let Some(fadt) = Fadt::get() {
+        serial_println!("{:#x?}", fadt);
+    }
+    if let Some(hpet) = Hpet::get() {
+        serial_println!("{:#x?}", hpet);
+    }
+    if let Some(mcfg) = Mcfg::get() {
+        serial_println!("{:#x?}", mcfg);
+    }
+}
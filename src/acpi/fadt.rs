@@ -0,0 +1,126 @@
+use x86_64::PhysAddr;
+
+use super::{find_entry, GenericAddress, TableEntry};
+
+// ---------------------------------------------------------------------------------------------------┐
+// Fixed ACPI Description Table (signature "FACP").                                                    |
+//                                                                                                    |
+// Describes the fixed power management hardware: the PM1 event/control register blocks (sleep and   |
+// power off), the PM timer, the reset register, and where the DSDT (the AML code of the machine) is.  |
+// The table grew with every ACPI revision, newer fields are only read when the table is long enough. |
+// The 64 bit X_ fields replace the 32 bit ones when they are set.                                    |
+// ---------------------------------------------------------------------------------------------------┙
+
+const OFFSET_DSDT: usize = 40;
+const OFFSET_SCI_INTERRUPT: usize = 46;
+const OFFSET_SMI_COMMAND: usize = 48;
+const OFFSET_ACPI_ENABLE: usize = 52;
+const OFFSET_ACPI_DISABLE: usize = 53;
+const OFFSET_PM1A_EVENT_BLOCK: usize = 56;
+const OFFSET_PM1B_EVENT_BLOCK: usize = 60;
+const OFFSET_PM1A_CONTROL_BLOCK: usize = 64;
+const OFFSET_PM1B_CONTROL_BLOCK: usize = 68;
+const OFFSET_PM_TIMER_BLOCK: usize = 76;
+const OFFSET_PM1_EVENT_LENGTH: usize = 88;
+const OFFSET_PM1_CONTROL_LENGTH: usize = 89;
+const OFFSET_CENTURY: usize = 108;
+const OFFSET_BOOT_ARCHITECTURE_FLAGS: usize = 109;
+const OFFSET_FLAGS: usize = 112;
+const OFFSET_RESET_REGISTER: usize = 116;
+const OFFSET_RESET_VALUE: usize = 128;
+const OFFSET_X_DSDT: usize = 140;
+const OFFSET_X_PM1A_CONTROL_BLOCK: usize = 172;
+const OFFSET_X_PM1B_CONTROL_BLOCK: usize = 184;
+
+/// `flags`: the reset register is supported.
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+/// `boot_architecture_flags`: there is an 8042 keyboard controller.
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// Parsed FADT, only the fields the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    /// Differentiated System Description Table, holds the AML of the machine.
+    pub dsdt: PhysAddr,
+    /// ISA IRQ of the System Control Interrupt.
+    pub sci_interrupt: u16,
+    /// I/O port to write `acpi_enable` to, 0 if the machine always runs in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: GenericAddress,
+    pub pm1b_control_block: GenericAddress,
+    pub pm_timer_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    /// CMOS register of the RTC century, 0 if there is none.
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    /// Writing `reset_value` to it resets the machine, if `flags` has [`FLAG_RESET_REG_SUP`].
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Find and parse the FADT, `None` if the firmware does not provide a valid one.
+    pub fn get() -> Option<Fadt> {
+        find_entry(b"FACP").map(|entry| unsafe { Fadt::parse(&entry) })
+    }
+
+    /// # Safety
+    /// `entry` must be a FADT.
+    pub unsafe fn parse(entry: &TableEntry) -> Fadt {
+        let u8_at = |offset| entry.field::<u8>(offset).unwrap_or(0);
+        let u32_at = |offset| entry.field::<u32>(offset).unwrap_or(0);
+        // A 32 bit I/O port block, as a generic address.
+        let io_block = |offset| GenericAddress {
+            address_space: GenericAddress::SYSTEM_IO,
+            bit_width: 0,
+            bit_offset: 0,
+            access_size: 0,
+            address: u32_at(offset) as u64,
+        };
+        let extended = |offset, legacy: GenericAddress| match entry.field::<GenericAddress>(offset) {
+            Some(address) if address.is_present() => address,
+            _ => legacy,
+        };
+
+        let dsdt = match entry.field::<u64>(OFFSET_X_DSDT) {
+            Some(address) if address != 0 => address,
+            _ => u32_at(OFFSET_DSDT) as u64,
+        };
+        let flags = u32_at(OFFSET_FLAGS);
+
+        Fadt {
+            revision: entry.header.revision,
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: entry.field(OFFSET_SCI_INTERRUPT).unwrap_or(0),
+            smi_command_port: u32_at(OFFSET_SMI_COMMAND),
+            acpi_enable: u8_at(OFFSET_ACPI_ENABLE),
+            acpi_disable: u8_at(OFFSET_ACPI_DISABLE),
+            pm1a_event_block: u32_at(OFFSET_PM1A_EVENT_BLOCK),
+            pm1b_event_block: u32_at(OFFSET_PM1B_EVENT_BLOCK),
+            pm1a_control_block: extended(OFFSET_X_PM1A_CONTROL_BLOCK, io_block(OFFSET_PM1A_CONTROL_BLOCK)),
+            pm1b_control_block: extended(OFFSET_X_PM1B_CONTROL_BLOCK, io_block(OFFSET_PM1B_CONTROL_BLOCK)),
+            pm_timer_block: u32_at(OFFSET_PM_TIMER_BLOCK),
+            pm1_event_length: u8_at(OFFSET_PM1_EVENT_LENGTH),
+            pm1_control_length: u8_at(OFFSET_PM1_CONTROL_LENGTH),
+            century: u8_at(OFFSET_CENTURY),
+            boot_architecture_flags: entry.field(OFFSET_BOOT_ARCHITECTURE_FLAGS).unwrap_or(0),
+            flags,
+            reset_register: entry
+                .field::<GenericAddress>(OFFSET_RESET_REGISTER)
+                .filter(|register| flags & FLAG_RESET_REG_SUP != 0 && register.is_present()),
+            reset_value: u8_at(OFFSET_RESET_VALUE),
+        }
+    }
+
+    /// ACPI 2.0+ machines without the flag have no 8042, before that it is always assumed.
+    pub fn has_8042(&self) -> bool {
+        self.revision < 2 || self.boot_architecture_flags & BOOT_ARCH_8042 != 0
+    }
+}
@@ -0,0 +1,51 @@
+use super::{find_entry, GenericAddress, TableEntry};
+
+// -----------------------------------------------------------------------------------------------┐
+// High Precision Event Timer table (signature "HPET").                                            |
+//                                                                                                |
+//   36 u32 event timer block id: bits 0-7 hardware revision, 8-12 comparators - 1, 13 64 bit      |
+//          counter, 15 legacy replacement capable, 16-31 PCI vendor id                            |
+//   40 GAS base address of the memory mapped registers                                           |
+//   52 u8  HPET sequence number, 53 u16 minimum clock tick in periodic mode, 55 u8 page protection |
+// -----------------------------------------------------------------------------------------------┙
+
+const OFFSET_EVENT_TIMER_BLOCK_ID: usize = 36;
+const OFFSET_BASE_ADDRESS: usize = 40;
+const OFFSET_NUMBER: usize = 52;
+const OFFSET_MINIMUM_TICK: usize = 53;
+
+/// Parsed HPET description.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64_bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Find and parse the HPET table, `None` if the machine has no (valid) one.
+    pub fn get() -> Option<Hpet> {
+        find_entry(b"HPET").and_then(|entry| unsafe { Hpet::parse(&entry) })
+    }
+
+    /// # Safety
+    /// `entry` must be a HPET table.
+    pub unsafe fn parse(entry: &TableEntry) -> Option<Hpet> {
+        let id = entry.field::<u32>(OFFSET_EVENT_TIMER_BLOCK_ID)?;
+        Some(Hpet {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            counter_64_bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: entry.field(OFFSET_BASE_ADDRESS)?,
+            number: entry.field(OFFSET_NUMBER)?,
+            minimum_tick: entry.field(OFFSET_MINIMUM_TICK)?,
+        })
+    }
+}
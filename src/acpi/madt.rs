@@ -0,0 +1,199 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+use super::{find_table, read_phys, SdtHeader};
+
+// ---------------------------------------------------------------------------------------------------┐
+// Multiple APIC Description Table (signature "APIC").                                                 |
+//                                                                                                    |
+// After the header: u32 local APIC address, u32 flags (bit 0 = also has 8259 PICs), then a list of    |
+// variable length entries, each starting with a type byte and a length byte:                          |
+//   0 - Processor Local APIC      (ACPI processor id, APIC id, flags: bit 0 enabled, bit 1 can enable) |
+//   1 - I/O APIC                  (id, MMIO address, first global system interrupt it handles)        |
+//   2 - Interrupt Source Override (ISA IRQ is wired to a different GSI and/or polarity/trigger mode)   |
+//   4 - Local APIC NMI            (which LINT pin of which CPU the NMI is connected to)                |
+//   5 - Local APIC Address Override (64 bit address that replaces the 32 bit one)                     |
+// ---------------------------------------------------------------------------------------------------┙
+
+const ENTRY_PROCESSOR_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A CPU core, as seen by its local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// `false` for cores that are present but disabled, and can not be started.
+    pub usable: bool,
+}
+
+/// An I/O APIC handling `gsi_base..gsi_base + number of redirection entries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// Pin polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// ISA `irq` is connected to global system interrupt `gsi`, instead of GSI `irq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// The NMI is wired to local APIC pin `lint` (0 or 1) of the processor `processor_id`, 0xFF = all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// Parsed MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The legacy 8259 PICs are present too (and have to be disabled when the APIC is used).
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    /// Find and parse the MADT, `None` if the firmware does not provide one.
+    pub fn get() -> Option<Madt> {
+        find_table(b"APIC").map(|addr| unsafe { Madt::parse(addr) })
+    }
+
+    /// # Safety
+    /// `addr` must point to a MADT.
+    pub unsafe fn parse(addr: PhysAddr) -> Madt {
+        let header = read_phys::<SdtHeader>(addr);
+        let body = addr + size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(read_phys::<u32>(body) as u64),
+            has_8259: read_phys::<u32>(body + 4u64) & FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let end = addr + header.length as u64;
+        let mut entry = body + 8u64;
+        while entry + 2u64 <= end {
+            let kind = read_phys::<u8>(entry);
+            let length = read_phys::<u8>(entry + 1u64);
+            if length < 2 {
+                break; // Broken table, stop instead of looping forever.
+            }
+            madt.parse_entry(kind, entry);
+            entry += length as u64;
+        }
+        madt
+    }
+
+    // `entry` points to the type byte, the fields start at `entry + 2`.
+    unsafe fn parse_entry(&mut self, kind: u8, entry: PhysAddr) {
+        let field = |offset: u64| entry + offset;
+        match kind {
+            ENTRY_PROCESSOR_LOCAL_APIC => {
+                let flags = read_phys::<u32>(field(4));
+                self.processors.push(Processor {
+                    processor_id: read_phys(field(2)),
+                    apic_id: read_phys(field(3)),
+                    usable: flags & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0,
+                });
+            }
+            ENTRY_IO_APIC => self.io_apics.push(IoApic {
+                id: read_phys(field(2)),
+                address: PhysAddr::new(read_phys::<u32>(field(4)) as u64),
+                gsi_base: read_phys(field(8)),
+            }),
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                let flags = read_phys::<u16>(field(8));
+                self.overrides.push(InterruptOverride {
+                    irq: read_phys(field(3)),
+                    gsi: read_phys(field(4)),
+                    // ISA interrupts default to active high, edge triggered.
+                    polarity: polarity(flags, Polarity::ActiveHigh),
+                    trigger_mode: trigger_mode(flags, TriggerMode::Edge),
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let flags = read_phys::<u16>(field(3));
+                self.local_apic_nmis.push(LocalApicNmi {
+                    processor_id: read_phys(field(2)),
+                    lint: read_phys(field(5)),
+                    polarity: polarity(flags, Polarity::ActiveHigh),
+                    trigger_mode: trigger_mode(flags, TriggerMode::Edge),
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                self.local_apic_address = PhysAddr::new(read_phys::<u64>(field(4)));
+            }
+            _ => {} // x2APIC and other entries we do not use.
+        }
+    }
+
+    /// GSI, polarity and trigger mode of the legacy ISA `irq`.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger_mode),
+            None => (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+        }
+    }
+}
+
+// MPS INTI flags: bits 0-1 polarity, bits 2-3 trigger mode, 0 = conforms to the bus default.
+fn polarity(flags: u16, default: Polarity) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => default,
+    }
+}
+
+fn trigger_mode(flags: u16, default: TriggerMode) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => default,
+    }
+}
+
+#[test_case]
+fn test_inti_flags() {
+    assert_eq!(polarity(0, Polarity::ActiveHigh), Polarity::ActiveHigh);
+    assert_eq!(polarity(0b11, Polarity::ActiveHigh), Polarity::ActiveLow);
+    assert_eq!(trigger_mode(0b1100, TriggerMode::Edge), TriggerMode::Level);
+    assert_eq!(trigger_mode(0b0111, TriggerMode::Level), TriggerMode::Edge);
+}
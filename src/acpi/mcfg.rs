@@ -0,0 +1,69 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+use super::{find_entry, TableEntry};
+
+// -----------------------------------------------------------------------------------------------┐
+// PCI Express memory mapped configuration table (signature "MCFG").                               |
+//                                                                                                |
+// After the header and 8 reserved bytes follows one 16 byte entry per PCI segment group:          |
+//   u64 base address | u16 segment group | u8 first bus | u8 last bus | u32 reserved              |
+// The configuration space of bus/device/function lives at                                        |
+//   base + ((bus - first bus) << 20 | device << 15 | function << 12)                             |
+// -----------------------------------------------------------------------------------------------┙
+
+const OFFSET_ENTRIES: usize = 44;
+
+/// Memory mapped configuration space of one PCI segment group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    reserved: u32,
+}
+
+impl McfgEntry {
+    /// Physical address of the 4 KiB configuration space of `bus:device.function`.
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(PhysAddr::new(self.base_address + offset))
+    }
+}
+
+/// Parsed MCFG.
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    /// Find and parse the MCFG, `None` without PCI Express (or a valid table).
+    pub fn get() -> Option<Mcfg> {
+        find_entry(b"MCFG").map(|entry| unsafe { Mcfg::parse(&entry) })
+    }
+
+    /// # Safety
+    /// `entry` must be a MCFG.
+    pub unsafe fn parse(entry: &TableEntry) -> Mcfg {
+        let count = (entry.header.length as usize).saturating_sub(OFFSET_ENTRIES) / size_of::<McfgEntry>();
+        let entries = (0..count)
+            .filter_map(|i| entry.field(OFFSET_ENTRIES + i * size_of::<McfgEntry>()))
+            .collect();
+        Mcfg { entries }
+    }
+}
+
+#[test_case]
+fn test_config_address() {
+    let entry = McfgEntry { base_address: 0xB000_0000, segment_group: 0, start_bus: 0, end_bus: 255, reserved: 0 };
+    assert_eq!(entry.config_address(0, 0, 0), Some(PhysAddr::new(0xB000_0000)));
+    assert_eq!(entry.config_address(1, 2, 3), Some(PhysAddr::new(0xB000_0000 + (1 << 20) + (2 << 15) + (3 << 12))));
+    assert_eq!(entry.config_address(0, 32, 0), None);
+}
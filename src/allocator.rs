@@ -0,0 +1,86 @@
+use core::alloc::Layout;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::memory::{self, MemoryError};
+use crate::sync::{SpinLock, SpinLockGuard};
+
+pub mod fixed_size_block;
+pub mod linked_list;
+
+/// Start of the kernel heap, see the address space layout in [`memory`](crate::memory).
+pub const HEAP_START: u64 = 0xFFFF_C000_0000_0000;
+/// Size of the kernel heap (2 MiB), all of it is mapped up front by [`init_heap`].
+pub const HEAP_SIZE: u64 = 2 * 1024 * 1024;
+
+// ----------------------------------------------------------------------------------------------┐
+// Global allocator is selected at build time:                                                    |
+//   default                                -> FixedSizeBlockAllocator (linked list as fallback)  |
+//   --features linked-list-allocator       -> LinkedListAllocator only                           |
+// Fixed size blocks make small allocations O(1), linked list is slower but wastes less memory.   |
+// ----------------------------------------------------------------------------------------------┙
+#[cfg(not(feature = "linked-list-allocator"))]
+#[global_allocator]
+static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
+
+#[cfg(feature = "linked-list-allocator")]
+#[global_allocator]
+static ALLOCATOR: Locked<linked_list::LinkedListAllocator> =
+    Locked::new(linked_list::LinkedListAllocator::new());
+
+/// **Initialize the kernel heap**, map `HEAP_START..HEAP_START + HEAP_SIZE` to fresh frames
+/// and hand that region to the global allocator.
+///
+/// Must be called once, after [`memory::init`].
+pub fn init_heap() -> Result<(), MemoryError> {
+    let heap_start = Page::containing_address(VirtAddr::new(HEAP_START));
+    let heap_end = Page::containing_address(VirtAddr::new(HEAP_START + HEAP_SIZE - 1));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    memory::kernel_memory().lock().map_range(Page::range_inclusive(heap_start, heap_end), flags)?;
+
+    unsafe { ALLOCATOR.lock().init(HEAP_START as usize, HEAP_SIZE as usize) };
+    Ok(())
+}
+
+/// Wrapper around `SpinLock` so we can implement `GlobalAlloc` for our allocators
+/// (trait implementations for foreign types like `SpinLock<A>` are not allowed).
+pub struct Locked<A> {
+    inner: SpinLock<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked { inner: SpinLock::new(inner) }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`, `align` must be a power of two.
+///
+/// `align - 1` has all bits below the alignment set, so `addr + align - 1` reaches into the next
+/// aligned block (unless `addr` is already aligned) and `& !(align - 1)` clears the low bits again.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Called by the `alloc` crate when an allocation fails, instead of the default abort.
+// The layout goes to both outputs, so it is visible on screen and in the test logs.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    crate::println!("ALLOCATION ERROR: {:?}", layout);
+    crate::serial_println!("ALLOCATION ERROR: {:?}", layout);
+    panic!("kernel heap allocation failed")
+}
+
+#[test_case]
+fn test_align_up() {
+    assert_eq!(align_up(0, 8), 0);
+    assert_eq!(align_up(1, 8), 8);
+    assert_eq!(align_up(8, 8), 8);
+    assert_eq!(align_up(4097, 4096), 8192);
+}
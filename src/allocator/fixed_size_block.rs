@@ -0,0 +1,128 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::NonNull};
+
+use super::linked_list::LinkedListAllocator;
+use super::Locked;
+
+/// Block sizes to use, each one is also used as the block alignment so they must be powers of 2.
+///
+/// Blocks smaller than 8 bytes can't hold a `ListNode` (the next pointer), allocations bigger
+/// than the biggest block size (2 KiB) go straight to the fallback allocator.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// ----------------------------------------------------------------------------------------------┐
+// One free list per block size. Allocation rounds the layout up to the next block size and pops  |
+// the head of that list, deallocation pushes the block back. Both are O(1).                      |
+// Empty lists are refilled from the fallback linked list allocator, blocks are never merged     |
+// back into it, so memory used for e.g. 16 byte blocks stays reserved for 16 byte blocks.        |
+//                                                                                               |
+//   list_heads[0] (8)   -> [ ] -> [ ] -> None                                                   |
+//   list_heads[1] (16)  -> [   ] -> None                                                        |
+//   ...                                                                                         |
+//   list_heads[8] (2048)-> None                                                                 |
+// ----------------------------------------------------------------------------------------------┙
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Allocator that serves fixed size blocks from per-size free lists.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Create an empty allocator, it can't allocate anything until [`init`][Self::init].
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /// Hand the region `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// # Safety
+    /// The region must be mapped, unused, and this must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Allocate with the fallback allocator, returns a null pointer when out of memory.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator.allocate(layout)
+    }
+
+    /// Allocate a block for `layout`, returns a null pointer when out of memory.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block left in the list, allocate a new one from the fallback allocator.
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    self.fallback_alloc(layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    /// Give the block at `ptr` back to the allocator.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`allocate`][Self::allocate] with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // Block size is always >= size and align of ListNode (smallest block is 8 bytes).
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+                let new_node = ListNode { next: self.list_heads[index].take() };
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr.as_ptr(), layout);
+            }
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Choose the smallest block size that fits the size and alignment of `layout`.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
+
+#[test_case]
+fn test_list_index() {
+    assert_eq!(list_index(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+    assert_eq!(list_index(&Layout::from_size_align(9, 8).unwrap()), Some(1));
+    assert_eq!(list_index(&Layout::from_size_align(8, 64).unwrap()), Some(3));
+    assert_eq!(list_index(&Layout::from_size_align(4096, 8).unwrap()), None);
+}
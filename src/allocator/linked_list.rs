@@ -0,0 +1,155 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+use super::{align_up, Locked};
+
+// ----------------------------------------------------------------------------------------------┐
+// Free memory regions form a singly linked list, each node is stored inside the free region     |
+// it describes, so the allocator needs no memory of its own.                                    |
+//                                                                                               |
+//  head -> [size|next] ---------> [size|next] ---------> [size|next] -> None                    |
+//          |___free____|  used    |_____free_____| used  |__free__|                             |
+// ----------------------------------------------------------------------------------------------┙
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// Allocator that keeps a list of free regions, first fit.
+///
+/// Freed regions are pushed back to the list (without merging neighbours),
+/// so heavy fragmentation is possible over time.
+pub struct LinkedListAllocator {
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    /// Create an empty allocator, it can't allocate anything until [`init`][Self::init].
+    pub const fn new() -> Self {
+        LinkedListAllocator { head: ListNode::new(0) }
+    }
+
+    /// Hand the region `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// # Safety
+    /// The region must be mapped, unused, and this must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    /// Push the region `addr..addr + size` to the front of the list.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // Region must be able to hold a ListNode.
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let mut node = ListNode::new(size);
+        node.next = self.head.next.take();
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        self.head.next = Some(&mut *node_ptr);
+    }
+
+    /// Find a free region big enough for `size` bytes aligned to `align` and remove it from the list.
+    ///
+    /// Returns the region and the start address of the allocation inside it.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let found = (current.next.take().unwrap(), alloc_start);
+                current.next = next;
+                return Some(found);
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+
+        None
+    }
+
+    /// Try to fit the allocation into `region`, returns the allocation start address on success.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        // The rest of the region has to fit a ListNode, otherwise it would be lost.
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Adjust the layout so the allocated region is also capable of storing a `ListNode` when freed.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /// Allocate a region for `layout`, returns a null pointer when out of memory.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let alloc_end = alloc_start + size;
+            let excess_size = region.end_addr() - alloc_end;
+            if excess_size > 0 {
+                unsafe { self.add_free_region(alloc_end, excess_size) };
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Give the region at `ptr` back to the allocator.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`allocate`][Self::allocate] with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
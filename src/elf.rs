@@ -0,0 +1,375 @@
+use core::fmt;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::{MemoryError, USER_END, USER_START};
+
+pub mod loader;
+pub mod programs;
+
+pub use loader::{load, spawn, LoadedProgram};
+
+// ---------------------------------------------------------------------------------------------------┐
+// User programs are statically linked ELF64 executables for x86_64. The loader only needs the file    |
+// header and the program headers, sections are for linkers and debuggers:                            |
+//                                                                                                    |
+//   offset 0        ELF header: magic, class, machine, entry point, where the program headers are    |
+//   e_phoff         program headers, one per segment. `PT_LOAD` segments say which bytes of the       |
+//                   file go where in memory, the rest up to `p_memsz` is zeroed (.bss)                |
+//   p_offset ...    the segment contents                                                             |
+//                                                                                                    |
+// The file comes from somewhere user controlled (eventually), so `Elf::parse` checks every offset    |
+// and size before anything is read or mapped. A bad file is an `ElfError`, never a fault.            |
+// ---------------------------------------------------------------------------------------------------┙
+
+/// Size of the ELF64 file header.
+pub const HEADER_SIZE: usize = 64;
+/// Size of an ELF64 program header.
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+/// `e_type` of a (not position independent) executable.
+pub const TYPE_EXECUTABLE: u16 = 2;
+/// `e_machine` of x86_64.
+pub const MACHINE_X86_64: u16 = 62;
+
+/// `p_type` of a segment that gets loaded into memory.
+pub const PT_LOAD: u32 = 1;
+/// `p_type` of the segment naming a dynamic linker, which we don't have.
+pub const PT_INTERP: u32 = 3;
+
+/// Segment flag bits (`p_flags`).
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// Why an ELF file was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends inside the ELF header.
+    Truncated,
+    /// The file does not start with `\x7FELF`.
+    BadMagic,
+    /// Not a 64 bit ELF file.
+    Not64Bit,
+    /// Not little endian.
+    NotLittleEndian,
+    /// Unknown ELF version.
+    UnsupportedVersion,
+    /// Built for another architecture, contains `e_machine`.
+    WrongMachine(u16),
+    /// Not a static executable (shared object, relocatable, core dump), contains `e_type`.
+    UnsupportedType(u16),
+    /// Needs a dynamic linker.
+    NeedsInterpreter,
+    /// `e_phentsize` is too small for an ELF64 program header.
+    BadProgramHeaderSize(u16),
+    /// The program header table does not fit into the file.
+    ProgramHeadersOutOfBounds,
+    /// The file contents of a segment do not fit into the file.
+    SegmentOutOfBounds,
+    /// A segment has more bytes in the file than in memory.
+    SegmentFileSizeTooLarge,
+    /// A segment would be loaded outside of user space.
+    SegmentNotInUserSpace,
+    /// `p_align` is not a power of two, or `p_vaddr` and `p_offset` disagree modulo `p_align`.
+    MisalignedSegment,
+    /// There is nothing to load.
+    NoLoadableSegments,
+    /// The entry point is not inside an executable segment.
+    EntryNotExecutable,
+    /// The arguments and environment do not fit into the initial stack.
+    ArgumentsTooLarge,
+    /// Mapping the program failed.
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for ElfError {
+    fn from(error: MemoryError) -> Self {
+        ElfError::Memory(error)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is shorter than the ELF header"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::Not64Bit => write!(f, "not a 64 bit ELF file"),
+            ElfError::NotLittleEndian => write!(f, "not little endian"),
+            ElfError::UnsupportedVersion => write!(f, "unsupported ELF version"),
+            ElfError::WrongMachine(machine) => write!(f, "built for machine {}, not x86_64", machine),
+            ElfError::UnsupportedType(kind) => write!(f, "ELF type {} is not a static executable", kind),
+            ElfError::NeedsInterpreter => write!(f, "dynamically linked programs are not supported"),
+            ElfError::BadProgramHeaderSize(size) => write!(f, "program header size {} is too small", size),
+            ElfError::ProgramHeadersOutOfBounds => write!(f, "program headers are outside of the file"),
+            ElfError::SegmentOutOfBounds => write!(f, "segment contents are outside of the file"),
+            ElfError::SegmentFileSizeTooLarge => write!(f, "segment is larger in the file than in memory"),
+            ElfError::SegmentNotInUserSpace => write!(f, "segment is outside of user space"),
+            ElfError::MisalignedSegment => write!(f, "segment is misaligned"),
+            ElfError::NoLoadableSegments => write!(f, "nothing to load"),
+            ElfError::EntryNotExecutable => write!(f, "entry point is not in an executable segment"),
+            ElfError::ArgumentsTooLarge => write!(f, "arguments and environment are too large"),
+            ElfError::Memory(error) => write!(f, "memory error: {:?}", error),
+        }
+    }
+}
+
+/// One entry of the program header table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    /// `p_type`, e.g. [`PT_LOAD`].
+    pub kind: u32,
+    /// `p_flags`, [`PF_R`], [`PF_W`] and [`PF_X`].
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn parse(data: &[u8]) -> Self {
+        ProgramHeader {
+            kind: read_u32(data, 0),
+            flags: read_u32(data, 4),
+            offset: read_u64(data, 8),
+            virtual_address: read_u64(data, 16),
+            file_size: read_u64(data, 32),
+            memory_size: read_u64(data, 40),
+            align: read_u64(data, 48),
+        }
+    }
+
+    /// **Page flags for the segment**, user accessible and `PRESENT`. Every mapped page is readable, so
+    /// `PF_R` makes no difference.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    /// `true` if `addr` is part of the segment in memory.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.virtual_address && addr - self.virtual_address < self.memory_size
+    }
+
+    fn validate(&self, file_size: usize) -> Result<(), ElfError> {
+        match self.offset.checked_add(self.file_size) {
+            Some(end) if end <= file_size as u64 => {}
+            _ => return Err(ElfError::SegmentOutOfBounds),
+        }
+        if self.file_size > self.memory_size {
+            return Err(ElfError::SegmentFileSizeTooLarge);
+        }
+        match self.virtual_address.checked_add(self.memory_size) {
+            Some(end) if self.virtual_address >= USER_START && end <= USER_END => {}
+            _ => return Err(ElfError::SegmentNotInUserSpace),
+        }
+        if self.align > 1 && (!self.align.is_power_of_two() || self.virtual_address % self.align != self.offset % self.align) {
+            return Err(ElfError::MisalignedSegment);
+        }
+        Ok(())
+    }
+}
+
+/// A validated ELF64 executable, borrowing the file contents.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_header_offset: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// **Parse and validate** the ELF file in `data`.
+    ///
+    /// On success every program header and every `PT_LOAD` segment lies inside `data`, every segment
+    /// inside user space, and the entry point in an executable segment.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(ElfError::Not64Bit);
+        }
+        if data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != VERSION_CURRENT || read_u32(data, 20) != u32::from(VERSION_CURRENT) {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        let machine = read_u16(data, 18);
+        if machine != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        let kind = read_u16(data, 16);
+        if kind != TYPE_EXECUTABLE {
+            return Err(ElfError::UnsupportedType(kind));
+        }
+
+        let entry_size = read_u16(data, 54);
+        if usize::from(entry_size) < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize(entry_size));
+        }
+        let offset = read_u64(data, 32);
+        let count = usize::from(read_u16(data, 56));
+        let table_size = (count * usize::from(entry_size)) as u64;
+        match offset.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::ProgramHeadersOutOfBounds),
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            program_header_offset: offset as usize,
+            program_header_size: usize::from(entry_size),
+            program_header_count: count,
+        };
+        elf.validate()?;
+        Ok(elf)
+    }
+
+    fn validate(&self) -> Result<(), ElfError> {
+        if self.program_headers().any(|header| header.kind == PT_INTERP) {
+            return Err(ElfError::NeedsInterpreter);
+        }
+        for segment in self.segments() {
+            segment.validate(self.data.len())?;
+        }
+        if self.segments().next().is_none() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !self.segments().any(|segment| segment.flags & PF_X != 0 && segment.contains(self.entry)) {
+            return Err(ElfError::EntryNotExecutable);
+        }
+        Ok(())
+    }
+
+    /// Address of the first instruction.
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    /// Every entry of the program header table.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let Elf { data, program_header_offset, program_header_size, program_header_count, .. } = *self;
+        (0..program_header_count).map(move |index| {
+            let start = program_header_offset + index * program_header_size;
+            ProgramHeader::parse(&data[start..start + PROGRAM_HEADER_SIZE])
+        })
+    }
+
+    /// The `PT_LOAD` segments.
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers().filter(|header| header.kind == PT_LOAD)
+    }
+
+    /// The bytes of `segment` in the file, the first `file_size` bytes of it in memory.
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+
+    /// Size of one program header entry (`e_phentsize`).
+    pub fn program_header_size(&self) -> usize {
+        self.program_header_size
+    }
+
+    /// Number of program headers (`e_phnum`).
+    pub fn program_header_count(&self) -> usize {
+        self.program_header_count
+    }
+
+    /// Where the program header table ends up in memory, `None` if no segment loads it.
+    pub fn program_header_address(&self) -> Option<VirtAddr> {
+        let start = self.program_header_offset as u64;
+        let end = start + (self.program_header_count * self.program_header_size) as u64;
+        self.segments()
+            .find(|segment| segment.offset <= start && end <= segment.offset + segment.file_size)
+            .map(|segment| VirtAddr::new(segment.virtual_address + (start - segment.offset)))
+    }
+}
+
+// Callers checked the bounds.
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+fn patched(offset: usize, bytes: &[u8]) -> alloc::vec::Vec<u8> {
+    let mut image = programs::HELLO.to_vec();
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    image
+}
+
+#[test_case]
+fn test_parse_hello() {
+    let elf = Elf::parse(programs::HELLO).unwrap();
+    assert!(elf.segments().count() >= 2);
+    assert!(elf.segments().any(|segment| segment.page_flags().contains(PageTableFlags::WRITABLE)));
+    assert_eq!(elf.program_header_size(), PROGRAM_HEADER_SIZE);
+    let headers = elf.program_header_address().expect("program headers are not loaded");
+    assert!(elf.segments().any(|segment| segment.contains(headers.as_u64())));
+}
+
+#[test_case]
+fn test_malformed_headers_are_rejected() {
+    let hello = programs::HELLO;
+    assert_eq!(Elf::parse(&hello[..HEADER_SIZE - 1]).unwrap_err(), ElfError::Truncated);
+    assert_eq!(Elf::parse(&patched(0, b"\x7FELG")).unwrap_err(), ElfError::BadMagic);
+    assert_eq!(Elf::parse(&patched(4, &[1])).unwrap_err(), ElfError::Not64Bit);
+    assert_eq!(Elf::parse(&patched(5, &[2])).unwrap_err(), ElfError::NotLittleEndian);
+    assert_eq!(Elf::parse(&patched(6, &[2])).unwrap_err(), ElfError::UnsupportedVersion);
+    assert_eq!(Elf::parse(&patched(16, &3u16.to_le_bytes())).unwrap_err(), ElfError::UnsupportedType(3));
+    assert_eq!(Elf::parse(&patched(18, &40u16.to_le_bytes())).unwrap_err(), ElfError::WrongMachine(40));
+    assert_eq!(Elf::parse(&patched(54, &32u16.to_le_bytes())).unwrap_err(), ElfError::BadProgramHeaderSize(32));
+    assert_eq!(Elf::parse(&patched(56, &u16::MAX.to_le_bytes())).unwrap_err(), ElfError::ProgramHeadersOutOfBounds);
+    // Entry point in the data segment.
+    let data = Elf::parse(hello).unwrap().segments().find(|segment| segment.flags & PF_X == 0).unwrap();
+    let entry = patched(24, &data.virtual_address.to_le_bytes());
+    assert_eq!(Elf::parse(&entry).unwrap_err(), ElfError::EntryNotExecutable);
+}
+
+#[test_case]
+fn test_malformed_segments_are_rejected() {
+    let elf = Elf::parse(programs::HELLO).unwrap();
+    let index = elf.program_headers().position(|header| header.kind == PT_LOAD).unwrap();
+    let header = elf.program_header_offset + index * elf.program_header_size;
+    let segment = |field: usize, value: u64| Elf::parse(&patched(header + field, &value.to_le_bytes())).unwrap_err();
+
+    assert_eq!(segment(8, u64::MAX), ElfError::SegmentOutOfBounds);
+    assert_eq!(segment(32, programs::HELLO.len() as u64), ElfError::SegmentFileSizeTooLarge);
+    assert_eq!(segment(16, 0x1000), ElfError::SegmentNotInUserSpace);
+    assert_eq!(segment(16, USER_END - 0x100), ElfError::SegmentNotInUserSpace);
+    assert_eq!(segment(48, 3), ElfError::MisalignedSegment);
+    let interpreter = patched(header, &PT_INTERP.to_le_bytes());
+    assert_eq!(Elf::parse(&interpreter).unwrap_err(), ElfError::NeedsInterpreter);
+}
@@ -0,0 +1,154 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{Elf, ElfError};
+use crate::memory::{AddressSpace, FRAME_SIZE, USER_END};
+use crate::scheduler::{self, ThreadId};
+use crate::usermode;
+
+// -------------------------------------------------------------------------------------------------┐
+// Loading a program into a fresh address space:                                                    |
+//                                                                                                  |
+//  1. every `PT_LOAD` segment gets zeroed frames with the permissions of the segment, then the     |
+//     file bytes are copied in through the physical memory mapping (the address space does not    |
+//     have to be active, and read-only pages stay read-only),                                      |
+//  2. the stack gets what the System V ABI promises a program at its entry point:                  |
+//                                                                                                  |
+//     USER_STACK_TOP -> | argument and environment strings |                                      |
+//                       | padding to 16 bytes               |                                      |
+//                       | auxv: (type, value) pairs ... AT_NULL                                    |
+//                       | envp[0] ... 0                      |                                      |
+//                       | argv[0] ... 0                      |                                      |
+//     stack pointer  -> | argc                               |  16 byte aligned                    |
+//                                                                                                  |
+//  3. a thread in the address space enters user mode at the entry point.                           |
+//                                                                                                  |
+// Pages shared by two segments get the permissions of both. The stack grows on demand below the     |
+// pages holding the arguments, up to `USER_STACK_SIZE`.                                            |
+// -------------------------------------------------------------------------------------------------┙
+
+/// Initial stack pointer of user programs (before the arguments are pushed), the page above stays unmapped.
+pub const USER_STACK_TOP: u64 = USER_END - FRAME_SIZE;
+/// How far the user stack can grow, guard page included.
+pub const USER_STACK_SIZE: u64 = 8 * 1024 * 1024;
+/// Most bytes the arguments, environment and auxiliary vector may take on the stack.
+pub const MAX_ARGUMENTS_SIZE: u64 = 8 * FRAME_SIZE;
+
+/// Auxiliary vector types passed on the stack.
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+/// Where a loaded program starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    /// Points at `argc`.
+    pub stack_pointer: VirtAddr,
+}
+
+/// **Load `elf` into `address_space`** and prepare a stack with `argv` and `envp`.
+///
+/// `address_space` should be fresh, the segments must not overlap anything mapped already. On error
+/// it is left half loaded, dropping it cleans up.
+pub fn load(address_space: &AddressSpace, elf: &Elf, argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, ElfError> {
+    for segment in elf.segments().filter(|segment| segment.memory_size > 0) {
+        let first = Page::containing_address(VirtAddr::new(segment.virtual_address));
+        let last = Page::containing_address(VirtAddr::new(segment.virtual_address + segment.memory_size - 1));
+        map_pages(address_space, Page::range_inclusive(first, last), segment.page_flags())?;
+        address_space.write(VirtAddr::new(segment.virtual_address), elf.segment_data(&segment))?;
+    }
+
+    let stack_pointer = push_arguments(address_space, elf, argv, envp)?;
+    Ok(LoadedProgram { entry: elf.entry(), stack_pointer })
+}
+
+/// **Run the ELF executable in `image`** in a new address space, on a new thread.
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<ThreadId, ElfError> {
+    let elf = Elf::parse(image)?;
+    let address_space = Arc::new(AddressSpace::new()?);
+    let program = load(&address_space, &elf, argv, envp)?;
+    let thread = scheduler::spawn_in(address_space, move || unsafe {
+        usermode::enter(program.entry, program.stack_pointer)
+    })?;
+    Ok(thread)
+}
+
+// Map `pages` to zeroed frames, pages that are mapped already get the permissions of `flags` on top.
+fn map_pages(
+    address_space: &AddressSpace,
+    pages: impl Iterator<Item = Page>,
+    flags: PageTableFlags,
+) -> Result<(), ElfError> {
+    let mut memory = address_space.memory().lock();
+    for page in pages {
+        match memory.translate_page(page) {
+            Some((_, existing)) => {
+                let mut combined = existing | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    combined.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe { memory.protect(page, combined)? };
+            }
+            None => memory.map_zeroed(page, flags)?,
+        }
+    }
+    Ok(())
+}
+
+// Lay out argc, argv, envp and the auxiliary vector below `USER_STACK_TOP`, returns the stack pointer.
+fn push_arguments(address_space: &AddressSpace, elf: &Elf, argv: &[&str], envp: &[&str]) -> Result<VirtAddr, ElfError> {
+    let mut auxv = vec![
+        (AT_PHENT, elf.program_header_size() as u64),
+        (AT_PHNUM, elf.program_header_count() as u64),
+        (AT_PAGESZ, FRAME_SIZE),
+        (AT_ENTRY, elf.entry().as_u64()),
+    ];
+    if let Some(headers) = elf.program_header_address() {
+        auxv.push((AT_PHDR, headers.as_u64()));
+    }
+    auxv.push((AT_NULL, 0));
+
+    let strings: u64 = argv.iter().chain(envp).map(|string| string.len() as u64 + 1).sum();
+    let words = 1 + (argv.len() + 1 + envp.len() + 1 + 2 * auxv.len()) as u64;
+    let size = strings.checked_add(words * 8).filter(|&size| size <= MAX_ARGUMENTS_SIZE);
+    let size = size.ok_or(ElfError::ArgumentsTooLarge)?;
+    let stack_pointer = (USER_STACK_TOP - size) & !0xF;
+
+    // Build the whole block in the kernel, then copy it over at once.
+    let mut block = vec![0u8; (USER_STACK_TOP - stack_pointer) as usize];
+    let mut words_out: Vec<u64> = Vec::with_capacity(words as usize);
+    let mut string_at = USER_STACK_TOP - strings;
+    let mut pointers = |list: &[&str], words_out: &mut Vec<u64>| {
+        for string in list {
+            let offset = (string_at - stack_pointer) as usize;
+            block[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+            words_out.push(string_at);
+            string_at += string.len() as u64 + 1;
+        }
+        words_out.push(0);
+    };
+    words_out.push(argv.len() as u64);
+    pointers(argv, &mut words_out);
+    pointers(envp, &mut words_out);
+    for (kind, value) in auxv {
+        words_out.extend_from_slice(&[kind, value]);
+    }
+    for (index, word) in words_out.iter().enumerate() {
+        block[index * 8..index * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let top = VirtAddr::new(USER_STACK_TOP);
+    let first = Page::containing_address(VirtAddr::new(stack_pointer));
+    map_pages(address_space, Page::range(first, Page::containing_address(top)), flags | PageTableFlags::PRESENT)?;
+    address_space.register_stack_region(top, USER_STACK_SIZE, top - first.start_address(), flags)?;
+    address_space.write(VirtAddr::new(stack_pointer), &block)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
@@ -0,0 +1,21 @@
+// --------------------------------------------------------------------------------------------┐
+// User programs built into the kernel image, until there is a filesystem to load them from.    |
+// The sources and the linker script are in user/ at the top of the repository.                |
+// --------------------------------------------------------------------------------------------┙
+
+/// Prints its arguments and environment, one per line, and exits. See user/hello.S.
+pub static HELLO: &[u8] = include_bytes!("../../user/hello.elf");
+
+/// Exercises fork, exec, wait and exit, `argv[1]` picks the test. See user/proctest.S.
+pub static PROCTEST: &[u8] = include_bytes!("../../user/proctest.elf");
+
+/// Producer and consumer talking through a pipe or a channel, `argv[1]` picks which. See user/ipctest.S.
+pub static IPCTEST: &[u8] = include_bytes!("../../user/ipctest.elf");
+
+/// Every built in program by name, what `exec` looks them up with.
+pub static PROGRAMS: [(&str, &[u8]); 3] = [("hello", HELLO), ("proctest", PROCTEST), ("ipctest", IPCTEST)];
+
+/// **The program called `name`**, `None` if there is none.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.iter().find(|(program, _)| *program == name).map(|(_, image)| *image)
+}
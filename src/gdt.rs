@@ -1,55 +1,125 @@
-use lazy_static::lazy_static;
-use x86_64::registers::segmentation::{CS, Segment};
+use alloc::boxed::Box;
+use x86_64::registers::model_specific::{Efer, EferFlags, Star};
+use x86_64::registers::segmentation::{CS, SS, Segment};
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::instructions::tables::load_tss;
+use x86_64::structures::paging::{Page, Size4KiB};
 use x86_64::VirtAddr;
 
+use crate::memory::{self, MemoryError, FRAME_SIZE};
+use crate::smp::percpu;
+use crate::sync::Lazy;
+
 // Interrupt stack table to be used for double fault exceptions at index 0.
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-
-lazy_static! {
-    // --------------------------------------------------------------------------------------------------------------┑
-    // Task State Segment contains interrupt_stack_table that holds safe stack frames to prevent overflow, these     |
-    // these stacks are used for interrupts.                                                                         |
-    // CPU will invoke page fault exception and will push the exception stack frame on to the                        |
-    // already overflowed stack, actually it will check that stack pointer is still pointing                         |
-    // to the non-existing guard page and will throw page fault again which causes double fault.                     | 
-    // So the CPU tries to invoke double fault handler now and will again have to push the exception_stack           |
-    // on to the stack, except it will again see that the stack pointer is pointing to the non-existing guard page   |
-    // and will triple-fault, thus shutting down the CPU (powering off the machine).                                 |
-    // Task State Segment allows for stack switching via interrupt_stack_table, thus preventing prior from happening.|
-    // --------------------------------------------------------------------------------------------------------------┙
-    // TSS also holds privilege_stack_table, that is used when exception occurs in user-made programs to swap stacks.
-    // Privilege stack table holds 4 stack tables, each representing privilege level (0, 1, 2, 3).
-    // Modern OS only uses 0-kernel and 3-user.
-    // In user-space privilege level is 3, if exception occurs then CPU will change privilege level to 0 and it
-    // will switch stacks to the 0th stack in the privilege_stack_table since 0 is the target privilege level.
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-
-    // Global Descriptor Table is legacy structure that was used mostly before memory paging was a thing and when segmentation was a thing.
-    // Now it is mostly used for two things: Switching between kernel space and user space, and loading a TSS structure.
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+pub const DOUBLE_FAULT_IST_INDEX: u16 = Ist::DoubleFault as u16;
+
+/// The interrupt stacks of a CPU, the value is the index into `interrupt_stack_table`.
+///
+/// Exceptions that can hit at any time, even while the current stack is unusable, get their own stack:
+/// the CPU switches to it no matter what `rsp` was. The page fault stack is only used by the IDT with the
+/// `page-fault-ist` feature, see `interrupts::IDT` for why that is not the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Ist {
+    DoubleFault = 0,
+    Nmi = 1,
+    MachineCheck = 2,
+    PageFault = 3,
+}
+
+impl Ist {
+    pub const ALL: [Ist; 4] = [Ist::DoubleFault, Ist::Nmi, Ist::MachineCheck, Ist::PageFault];
+
+    /// Index into `interrupt_stack_table`, for `set_stack_index`.
+    pub fn index(self) -> u16 {
+        self as u16
+    }
+
+    /// Size of the stack in pages, not counting the guard page below it.
+    pub fn pages(self) -> u64 {
+        match self {
+            // The double fault handler dumps every register and formats a panic message.
+            Ist::DoubleFault => 5,
+            Ist::Nmi | Ist::MachineCheck | Ist::PageFault => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ist::DoubleFault => "double fault",
+            Ist::Nmi => "NMI",
+            Ist::MachineCheck => "machine check",
+            Ist::PageFault => "page fault",
+        }
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+// Size of each interrupt stack of the boot tables, before there is a frame allocator.
+const BOOT_IST_STACK_SIZE: usize = 4096 * 5;
+
+// --------------------------------------------------------------------------------------------------------------┑
+// Task State Segment contains interrupt_stack_table that holds safe stack frames to prevent overflow, these     |
+// these stacks are used for interrupts.                                                                         |
+// CPU will invoke page fault exception and will push the exception stack frame on to the                        |
+// already overflowed stack, actually it will check that stack pointer is still pointing                         |
+// to the non-existing guard page and will throw page fault again which causes double fault.                     | 
+// So the CPU tries to invoke double fault handler now and will again have to push the exception_stack           |
+// on to the stack, except it will again see that the stack pointer is pointing to the non-existing guard page   |
+// and will triple-fault, thus shutting down the CPU (powering off the machine).                                 |
+// Task State Segment allows for stack switching via interrupt_stack_table, thus preventing prior from happening.|
+// --------------------------------------------------------------------------------------------------------------┙
+// TSS also holds privilege_stack_table: when an interrupt arrives while the CPU runs user code (ring 3), it
+// switches to privilege_stack_table[0] (the ring 0 stack) before pushing the interrupt stack frame.
+// The boot TSS leaves it empty, user mode needs the per CPU TSS from `init_cpu`, where the scheduler points
+// it at the kernel stack of whichever thread it switches to (see `set_kernel_stack`).
+// The boot stacks are plain statics without guard pages, `init_cpu` replaces them once memory management is up.
+static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    static mut STACKS: [[u8; BOOT_IST_STACK_SIZE]; Ist::ALL.len()] = [[0; BOOT_IST_STACK_SIZE]; Ist::ALL.len()];
+
+    let mut tss = TaskStateSegment::new();
+    for ist in Ist::ALL.iter() {
+        let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACKS[ist.index() as usize]) });
+        tss.interrupt_stack_table[ist.index() as usize] = stack_start + BOOT_IST_STACK_SIZE;
+    }
+    tss
+});
+
+// Global Descriptor Table is legacy structure that was used mostly before memory paging was a thing and when segmentation was a thing.
+// Now it is mostly used for two things: Switching between kernel space and user space, and loading a TSS structure.
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| build_gdt(&TSS));
+
+// ------------------------------------------------------------------------------------------------┐
+// Every GDT we build has the same layout, so the selectors are the same on every CPU:             |
+//                                                                                                 |
+//   0x00  null                                                                                    |
+//   0x08  kernel code        ring 0                                                               |
+//   0x10  kernel data        ring 0                                                               |
+//   0x18  user data          ring 3, selector 0x1B                                                |
+//   0x20  user code          ring 3, selector 0x23                                                |
+//   0x28  TSS                (16 bytes, a system descriptor takes two entries)                    |
+//                                                                                                 |
+// The order is dictated by `syscall`/`sysret`, they don't read the GDT but compute the selectors  |
+// from the STAR MSR: syscall loads CS = STAR[47:32] and SS = that + 8, sysret loads               |
+// SS = STAR[63:48] + 8 and CS = that + 16. So kernel data has to follow kernel code, and user code |
+// has to follow user data.                                                                        |
+// ------------------------------------------------------------------------------------------------┙
+
+/// The segment selectors of the GDT, the same for every CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    /// Requested privilege level 3, ready to be used in an `iretq` frame.
+    pub user_data: SegmentSelector,
+    /// Requested privilege level 3, ready to be used in an `iretq` frame.
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// The segment selectors, see [`Selectors`].
+pub fn selectors() -> Selectors {
+    GDT.1
 }
 
 /// **Initialize [`GDT`][x86_64::structures::gdt::GlobalDescriptorTable]**, aka load the gdt into
@@ -67,7 +137,7 @@ struct Selectors {
 ///    }
 ///}
 ///```
-/// We also set the `CS` register and load the [`TSS`], assembly for TSS:
+/// We also set the `CS` and `SS` registers, the STAR MSR for `syscall`/`sysret` and load the [`TSS`], assembly for TSS:
 ///```no_run
 /// #[inline]
 ///pub unsafe fn load_tss(sel: SegmentSelector) {
@@ -77,9 +147,132 @@ struct Selectors {
 ///}
 ///```
 pub fn init_gdt() {
-    GDT.0.load();
+    load(&GDT);
+}
+
+// ----------------------------------------------------------------------------------------------------┐
+// The tables above are good enough for booting, but they can only be used by one CPU:                 |
+//  - `ltr` marks the TSS descriptor as busy, a second CPU loading the same one gets a #GP.             |
+//  - Two CPUs taking an NMI at the same time would share (and corrupt) the same static stack.          |
+//  - The static stacks have no guard page, overflowing one silently overwrites whatever is below it.  |
+//                                                                                                     |
+// So once memory management is up, every CPU builds its own GDT + TSS with `init_cpu`. Every          |
+// interrupt stack comes from `memory::allocate_stack`, with an unmapped guard page below it:          |
+//                                                                                                     |
+//   | guard | double fault | guard | NMI | guard | machine check | guard | page fault |               |
+//                                                                                                     |
+// An overflowing interrupt stack page faults on its guard page. The page fault can't be pushed onto   |
+// the full stack either, so the CPU raises a double fault, which switches to the (fresh) top of the    |
+// double fault stack. Cr2 still holds the guard page address, `overflowed_ist` tells whose it is.     |
+// The tables are leaked, a CPU never gives them back.                                                 |
+// ----------------------------------------------------------------------------------------------------┙
+
+/// **Build and load a GDT and TSS for the executing CPU**, with its own interrupt stacks.
+///
+/// The TSS is stored in the per CPU data of the CPU, it reads it on every privilege level change and
+/// IST switch so it must only be modified by the CPU it belongs to. Needs the kernel heap and memory
+/// management.
+pub fn init_cpu() -> Result<*mut TaskStateSegment, MemoryError> {
+    use x86_64::instructions::interrupts;
+
+    let mut tss = TaskStateSegment::new();
+    for ist in Ist::ALL.iter() {
+        let stack = memory::allocate_stack(&mut memory::kernel_memory().lock(), ist.pages())?;
+        tss.interrupt_stack_table[ist.index() as usize] = stack.top();
+    }
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+    let tss = tss as *mut TaskStateSegment;
+
+    let gdt = Box::leak(Box::new(build_gdt(unsafe { &*tss })));
+    interrupts::without_interrupts(move || {
+        load(gdt);
+        percpu::current().set_tss(tss);
+    });
+    Ok(tss)
+}
+
+/// **Set the stack the executing CPU switches to** when an interrupt or system call arrives in user mode.
+///
+/// Called by the scheduler on every context switch with the top of the kernel stack of the next thread, so
+/// every thread enters the kernel on its own stack. Interrupts find it in the TSS, `syscall` in the per CPU
+/// data. The TSS is left alone before [`init_cpu`], the boot TSS is shared.
+/// Call it with interrupts disabled, otherwise the thread may already be on another CPU.
+pub fn set_kernel_stack(top: VirtAddr) {
+    let per_cpu = percpu::current();
+    per_cpu.set_kernel_stack(top);
+    let tss = per_cpu.tss();
+    if !tss.is_null() {
+        unsafe { (*tss).privilege_stack_table[0] = top };
+    }
+}
+
+/// The stack set by [`set_kernel_stack`] on the executing CPU, `None` before [`init_cpu`].
+pub fn kernel_stack() -> Option<VirtAddr> {
+    let tss = percpu::current().tss();
+    if tss.is_null() {
+        return None;
+    }
+    Some(unsafe { (*tss).privilege_stack_table[0] })
+}
+
+/// **Which interrupt stack of the executing CPU has its guard page at `addr`**, if any.
+///
+/// Used by the double fault and page fault handlers with the faulting address from Cr2. Always `None`
+/// before [`init_cpu`], the boot stacks have no guard pages.
+pub fn overflowed_ist(addr: VirtAddr) -> Option<Ist> {
+    let tss = percpu::current().tss();
+    if tss.is_null() {
+        return None;
+    }
+    let page = Page::<Size4KiB>::containing_address(addr);
+    Ist::ALL.iter().copied().find(|ist| {
+        let top = unsafe { (*tss).interrupt_stack_table[ist.index() as usize] };
+        // See `memory::allocate_stack`, the guard page is right below the lowest stack page.
+        page == Page::containing_address(top - (ist.pages() + 1) * FRAME_SIZE)
+    })
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // The order matters, see the layout above.
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors { kernel_code, kernel_data, user_data, user_code, tss })
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    let selectors = &gdt.1;
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+        // Without SCE `sysret` (and later `syscall`) raise #UD.
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
     }
+    Star::write(selectors.user_code, selectors.user_data, selectors.kernel_code, selectors.kernel_data)
+        .expect("GDT layout does not fit syscall/sysret");
+}
+
+#[test_case]
+fn test_interrupt_stacks_have_guard_pages() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let tss = percpu::current().tss();
+        assert!(!tss.is_null(), "init_cpu was not called");
+        let memory = memory::kernel_memory().lock();
+        for ist in Ist::ALL.iter().copied() {
+            let top = unsafe { (*tss).interrupt_stack_table[ist.index() as usize] };
+            let guard = top - (ist.pages() + 1) * FRAME_SIZE;
+            assert!(memory.translate(top - 1u64).is_some());
+            assert!(memory.translate(guard + FRAME_SIZE).is_some());
+            assert!(memory.translate(guard).is_none());
+            assert_eq!(overflowed_ist(guard + 8u64), Some(ist));
+            assert_eq!(overflowed_ist(top - 8u64), None);
+        }
+    });
 }
\ No newline at end of file
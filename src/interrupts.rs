@@ -1,34 +1,81 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::instructions::port::Port;
 use pic8259::ChainedPics;
-use lazy_static::lazy_static;
-use crate::{println, gdt, print, htl_loop};
-
-lazy_static!{
-    // ---------------------------------------------------------------------------------------------------------------------------------┐
-    // Interrupt Descriptor Table is a structure that is responsible for handling hardware and software interrupts.                     |
-    // It holds entries and each entry represents the interrupt handler aka a function that is invoked on each interrupt.               |
-    // IDT holds up to 256 interrupt handlers.                                                                                          |
-    // First 32 entries are reserved by the CPU, and those are called exceptions (these are automatically thrown by hardware aka CPU).  |
-    // Rest of the interrupts are customizable and can be used as system calls or other kinds of interrupts.                            |                                                           |
-    // ---------------------------------------------------------------------------------------------------------------------------------┚
-    pub static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            // Here we are able to modify and set options and handler function for each entry inside our IDT.
-            idt.double_fault
-                // Method for setting our handler function, for our selected entry.
-                .set_handler_fn(double_fault_handler)
-                // We can of course set which stack to use from interrupt_stack_table in GDT. (0-7)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+use crate::gdt;
+use crate::sync::{Lazy, SpinLock};
+
+pub mod apic;
+mod exceptions;
+pub mod trap;
+
+// ---------------------------------------------------------------------------------------------------------------------------------┐
+// Interrupt Descriptor Table is a structure that is responsible for handling hardware and software interrupts.                     |
+// It holds entries and each entry represents the interrupt handler aka a function that is invoked on each interrupt.               |
+// IDT holds up to 256 interrupt handlers.                                                                                          |
+// First 32 entries are reserved by the CPU, and those are called exceptions (these are automatically thrown by hardware aka CPU).  |
+// Rest of the interrupts are customizable and can be used as system calls or other kinds of interrupts.                            |                                                           |
+// ---------------------------------------------------------------------------------------------------------------------------------┚
+pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    // Every architecturally defined exception enters through a trap stub that saves all registers
+    // (see interrupts/trap.rs), so the handlers can dump them before deciding whether to panic.
+    unsafe {
+        use trap::*;
+
+        idt.divide_error.set_handler_addr(stub_addr(trap_stub_0));
+        idt.debug.set_handler_addr(stub_addr(trap_stub_1));
+        // An NMI can arrive at any instruction, also right when the stack pointer is bad.
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub_addr(trap_stub_2))
+            .set_stack_index(gdt::Ist::Nmi.index());
+        idt.breakpoint.set_handler_addr(stub_addr(trap_stub_3));
+        idt.overflow.set_handler_addr(stub_addr(trap_stub_4));
+        idt.bound_range_exceeded.set_handler_addr(stub_addr(trap_stub_5));
+        idt.invalid_opcode.set_handler_addr(stub_addr(trap_stub_6));
+        idt.device_not_available.set_handler_addr(stub_addr(trap_stub_7));
+        idt.double_fault
+            // Method for setting our handler, for our selected entry.
+            .set_handler_addr(stub_addr(trap_stub_8))
+            // We can of course set which stack to use from interrupt_stack_table in GDT. (0-7)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub_addr(trap_stub_10));
+        idt.segment_not_present.set_handler_addr(stub_addr(trap_stub_11));
+        idt.stack_segment_fault.set_handler_addr(stub_addr(trap_stub_12));
+        idt.general_protection_fault.set_handler_addr(stub_addr(trap_stub_13));
+        // A kernel stack overflow page faults, on its own stack the handler can report it instead of
+        // double faulting. Opt-in, because a thread that blocks in the handler would leave its frame
+        // on the IST stack of the CPU, and the next page fault there overwrites it.
+        let page_fault = idt.page_fault.set_handler_addr(stub_addr(trap_stub_14));
+        if cfg!(feature = "page-fault-ist") {
+            page_fault.set_stack_index(gdt::Ist::PageFault.index());
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt
-    };
-}
+        idt.x87_floating_point.set_handler_addr(stub_addr(trap_stub_16));
+        idt.alignment_check.set_handler_addr(stub_addr(trap_stub_17));
+        idt.machine_check
+            .set_handler_addr(stub_addr(trap_stub_18))
+            .set_stack_index(gdt::Ist::MachineCheck.index());
+        idt.simd_floating_point.set_handler_addr(stub_addr(trap_stub_19));
+        idt.virtualization.set_handler_addr(stub_addr(trap_stub_20));
+        idt.cp_protection_exception.set_handler_addr(stub_addr(trap_stub_21));
+        idt.hv_injection_exception.set_handler_addr(stub_addr(trap_stub_28));
+        idt.vmm_communication_exception.set_handler_addr(stub_addr(trap_stub_29));
+        idt.security_exception.set_handler_addr(stub_addr(trap_stub_30));
+    }
+    unsafe {
+        idt[InterruptIndex::Timer.as_usize()].set_handler_addr(trap::stub_addr(trap::trap_stub_timer));
+        // Device interrupts can arrive in user mode too, the stubs switch to the kernel GS base.
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_addr(trap::stub_addr(trap::trap_stub_keyboard));
+        idt[InterruptIndex::Serial1.as_usize()].set_handler_addr(trap::stub_addr(trap::trap_stub_serial1));
+        idt[InterruptIndex::RealTimeClock.as_usize()].set_handler_addr(trap::stub_addr(trap::trap_stub_rtc));
+        idt[YIELD_VECTOR as usize].set_handler_addr(trap::stub_addr(trap::trap_stub_yield));
+        // DPL 3, so `int 0x80` in user mode does not raise a #GP.
+        idt[SYSCALL_VECTOR as usize]
+            .set_handler_addr(trap::stub_addr(trap::trap_stub_syscall))
+            .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+    }
+    idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+    idt
+});
 
 /// **Initialize [`IDT`][x86_64::structures::idt::InterruptDescriptorTable]**, aka load the idt into
 /// the *interrupt descriptor table register* (`IDTR`).
@@ -55,60 +102,77 @@ pub fn init_idt() {
 // This is different from `CDECL` calling convention (C standard cc), also when we return from these exceptions we use iret instead of ret.     |
 // x86-interrupt calling convention basically knows to search for the register values on the stack instead of looking for them in registers.    |
 // ---------------------------------------------------------------------------------------------------------------------------------------------┚
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
+// Only the spurious interrupt handler still uses it. Those handlers don't swap GS base, coming from user mode it still
+// belongs to the user program, so they must not touch per CPU data (locks do, for lockdep). Device interrupts go
+// through the trap stubs instead (interrupts/trap.rs) and are dispatched to the functions below.
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+// Only reads the scancode and queues it, decoding happens in the `task::keyboard::print_keypresses` task.
+fn keyboard_interrupt() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+// Reading the receive buffer acknowledges the UART, the byte goes to `task::serial`.
+fn serial_interrupt() {
+    let mut data: Port<u8> = Port::new(0x3F8);
+    let byte: u8 = unsafe { data.read() };
+    crate::task::serial::add_byte(byte);
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
-    }
+    end_of_interrupt(InterruptIndex::Serial1);
 }
 
-use x86_64::structures::idt::PageFaultErrorCode;
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
+// Only fires after `time::rtc::enable_periodic_interrupt`.
+fn rtc_interrupt() {
+    crate::time::rtc::on_interrupt();
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    htl_loop();
+    end_of_interrupt(InterruptIndex::RealTimeClock);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{Keyboard, layouts, ScancodeSet1, HandleControl, DecodedKey};
-    use spin::Mutex;
+// The local APIC raises this when an interrupt went away before the CPU accepted it, it must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
 
-    lazy_static! {
-        pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+/// **Acknowledge** the hardware interrupt `index`, with whichever controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    match apic::local_apic() {
+        Some(local_apic) if apic::is_enabled() => local_apic.end_of_interrupt(),
+        _ => unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) },
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(char) => print!("{}", char),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+/// **Unmask the IRQ line** behind `index`, so the interrupt controller starts delivering it.
+///
+/// With the PICs, IRQs on the secondary PIC also need the cascade line (IRQ 2) of the primary one.
+pub fn enable_irq(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::enable_irq(index);
+        return;
     }
-
+    let irq = index.as_u8() - PIC_1_OFFSET;
+    let mut pics = PICS.lock();
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
+        let [mut primary, mut secondary] = pics.read_masks();
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            primary &= !(1 << 2);
+            secondary &= !(1 << (irq - 8));
+        }
+        pics.write_masks(primary, secondary);
     }
 }
 
+/// Software interrupt for system calls from user mode, see [`crate::syscall`].
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Software interrupt used by `scheduler::yield_now` to enter the scheduler.
+pub const YIELD_VECTOR: u8 = 0x81;
+
+/// Vector of spurious interrupts from the local APIC, the low 4 bits have to be set on old CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
@@ -124,14 +188,17 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 // Secondary ATA ----> |____________|   Parallel Port 1----> |____________|                 |
 //                                                                                          |
 // -----------------------------------------------------------------------------------------┚
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> = SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // IRQ 4, COM1.
+    Serial1 = PIC_1_OFFSET + 4,
+    // IRQ 8, first line of the secondary PIC.
+    RealTimeClock = PIC_2_OFFSET,
 }
 
 impl InterruptIndex {
@@ -0,0 +1,409 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{InterruptIndex, PICS, SPURIOUS_VECTOR};
+use crate::acpi::madt::{Madt, Polarity, TriggerMode};
+use crate::memory::{self, MemoryError};
+use crate::sync::{Once, SpinLock};
+use crate::time;
+
+// ---------------------------------------------------------------------------------------------------------┐
+// Advanced Programmable Interrupt Controller.                                                             |
+//                                                                                                         |
+// Every CPU core has a Local APIC (LAPIC): it receives interrupts, has its own timer and is where          |
+// interrupts are acknowledged (EOI). Device IRQs are collected by one or more I/O APICs, which turn them   |
+// into messages for a LAPIC according to their redirection table, one entry per input pin:                |
+//                                                                                                         |
+//   keyboard (ISA IRQ 1) --> IOAPIC pin (GSI, from the MADT overrides) --redirection entry--> LAPIC --> CPU |
+//                                                                                                         |
+// The 8259 PICs only know 15 lines and one CPU, so once the APIC is up they are masked completely.        |
+// Vectors stay the same as with the PICs (see `InterruptIndex`), so the handlers do not care which         |
+// controller delivered the interrupt, only `end_of_interrupt` does.                                       |
+//                                                                                                         |
+// The LAPIC timer replaces the PIT as the scheduler tick. Its input clock differs between machines, so it |
+// is calibrated against the PIT first: count how far the LAPIC timer gets during a few PIT ticks, then    |
+// program it to fire once per PIT period. The monotonic clock in `time` keeps working unchanged.           |
+// ---------------------------------------------------------------------------------------------------------┙
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, offsets from its MMIO base (every register is 32 bit, 16 byte aligned).
+const LAPIC_ID: u32 = 0x020;
+const LAPIC_TPR: u32 = 0x080;
+const LAPIC_EOI: u32 = 0x0B0;
+const LAPIC_SVR: u32 = 0x0F0;
+const LAPIC_ICR_LOW: u32 = 0x300;
+const LAPIC_ICR_HIGH: u32 = 0x310;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_LVT_LINT0: u32 = 0x350;
+const LAPIC_LVT_LINT1: u32 = 0x360;
+const LAPIC_TIMER_INITIAL_COUNT: u32 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Interrupt command register: delivery mode (bits 8-10), level assert (bit 14), delivery status (bit 12).
+const ICR_INIT: u32 = 0b101 << 8 | 1 << 14;
+const ICR_STARTUP: u32 = 0b110 << 8 | 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// I/O APIC, only two registers are memory mapped: a register select and a data window.
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// How many PIT ticks the LAPIC timer calibration runs for.
+const CALIBRATION_TICKS: u64 = 5;
+
+/// Legacy ISA IRQs routed through the I/O APIC, and whether they start unmasked.
+const ISA_ROUTES: [(u8, InterruptIndex, bool); 3] = [
+    (1, InterruptIndex::Keyboard, true),
+    (4, InterruptIndex::Serial1, false),
+    (8, InterruptIndex::RealTimeClock, false),
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: SpinLock<Vec<IoApic>> = SpinLock::new(Vec::new());
+static MADT: Once<Madt> = Once::new();
+
+/// Why the APIC could not be brought up, the PICs stay in charge in that case.
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID says there is no local APIC.
+    Unsupported,
+    /// The firmware has no MADT, so we do not know where the I/O APICs are.
+    NoMadt,
+    NoIoApic,
+    /// Timer interrupts have to be enabled to calibrate the LAPIC timer.
+    InterruptsDisabled,
+    AlreadyInitialized,
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for ApicError {
+    fn from(error: MemoryError) -> Self {
+        ApicError::Memory(error)
+    }
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApicError::Unsupported => write!(f, "CPU has no local APIC"),
+            ApicError::NoMadt => write!(f, "no ACPI MADT found"),
+            ApicError::NoIoApic => write!(f, "MADT lists no I/O APIC"),
+            ApicError::InterruptsDisabled => write!(f, "interrupts must be enabled for timer calibration"),
+            ApicError::AlreadyInitialized => write!(f, "APIC is already initialized"),
+            ApicError::Memory(error) => write!(f, "mapping APIC registers failed: {:?}", error),
+        }
+    }
+}
+
+/// Memory mapped registers of the local APIC of the current CPU (every core sees its own at the same address).
+pub struct LocalApic {
+    base: VirtAddr,
+    // LAPIC timer counts (divided by 16) per timer tick.
+    timer_count: u32,
+}
+
+impl LocalApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + register as u64).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + register as u64).as_mut_ptr::<u32>(), value) }
+    }
+
+    /// APIC id of the CPU executing this.
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    // Software enable and accept all priorities.
+    fn enable(&self) {
+        self.write(LAPIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        self.write(LAPIC_TPR, 0);
+    }
+
+    // LINT0 carries the PIC output in virtual wire mode, only mask it once the PICs are no longer
+    // used. The NMI pins come from the MADT.
+    fn configure_lints(&self, madt: &Madt) {
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_MASKED);
+        let apic_id = self.id();
+        let processor_id = madt.processors.iter().find(|p| p.apic_id == apic_id).map(|p| p.processor_id);
+        // Processor id 0xFF means every CPU.
+        let nmis = madt.local_apic_nmis.iter().filter(|nmi| nmi.processor_id == 0xFF || Some(nmi.processor_id) == processor_id);
+        for nmi in nmis {
+            let mut lvt = LVT_DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if nmi.trigger_mode == TriggerMode::Level {
+                lvt |= LVT_LEVEL_TRIGGERED;
+            }
+            match nmi.lint {
+                0 => self.write(LAPIC_LVT_LINT0, lvt),
+                1 => self.write(LAPIC_LVT_LINT1, lvt),
+                _ => {}
+            }
+        }
+    }
+
+    // Measure how far the LAPIC timer counts during one PIT tick.
+    fn calibrate_timer(&self) -> u32 {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+        // Start right at the beginning of a tick.
+        let start = time::ticks();
+        while time::ticks() == start {
+            x86_64::instructions::hlt();
+        }
+        self.write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+        let start = time::ticks();
+        while time::ticks() < start + CALIBRATION_TICKS {
+            x86_64::instructions::hlt();
+        }
+        let elapsed = u32::MAX - self.read(LAPIC_TIMER_CURRENT_COUNT);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+        (elapsed / CALIBRATION_TICKS as u32).max(1)
+    }
+
+    // Send an inter-processor interrupt to the CPU `apic_id` and wait until the LAPIC accepted it.
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+        // Writing the low half sends it.
+        self.write(LAPIC_ICR_LOW, command);
+        while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Put the CPU `apic_id` into its wait-for-SIPI state, see [`smp`](crate::smp).
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_INIT);
+    }
+
+    /// Start the CPU `apic_id` in real mode at physical address `page << 12`.
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | page as u32);
+    }
+
+    /// Start the periodic timer interrupt on this CPU, one per [`time::tick_period`].
+    pub fn start_timer(&self) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, InterruptIndex::Timer.as_u8() as u32 | LVT_TIMER_PERIODIC);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, self.timer_count);
+    }
+}
+
+/// One I/O APIC and the global system interrupts it is responsible for.
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>(), register);
+            core::ptr::read_volatile((self.base + IOAPIC_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>(), register);
+            core::ptr::write_volatile((self.base + IOAPIC_WINDOW).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn read_entry(&mut self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+    }
+
+    fn write_entry(&mut self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // Mask first, so the line is never live with a half written entry.
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// `true` once [`init`] switched interrupt delivery from the PICs to the APIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// The local APIC, `None` while the PICs are in charge.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// The MADT the APIC was set up from.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+/// **Switch from the 8259 PICs to the local APIC and the I/O APICs.**
+///
+/// Needs the kernel heap and running timer interrupts (for the calibration). When this fails
+/// nothing was changed and the PICs keep delivering interrupts.
+pub fn init() -> Result<(), ApicError> {
+    if !has_local_apic() {
+        return Err(ApicError::Unsupported);
+    }
+    if !interrupts::are_enabled() {
+        return Err(ApicError::InterruptsDisabled);
+    }
+    if LOCAL_APIC.get().is_some() {
+        return Err(ApicError::AlreadyInitialized);
+    }
+    let madt = Madt::get().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut io_apics = Vec::new();
+    let local_apic_base = {
+        let mut memory = memory::kernel_memory().lock();
+        for io_apic in madt.io_apics.iter() {
+            let base = unsafe { memory.map_mmio(io_apic.address, 0x20, PageTableFlags::empty())? };
+            io_apics.push(IoApic { base, gsi_base: io_apic.gsi_base, entries: 0 });
+        }
+        unsafe { memory.map_mmio(madt.local_apic_address, 0x400, PageTableFlags::empty())? }
+    };
+
+    hardware_enable();
+    let mut local_apic = LocalApic { base: local_apic_base, timer_count: 0 };
+    local_apic.enable();
+    local_apic.timer_count = local_apic.calibrate_timer();
+    let apic_id = local_apic.id();
+
+    for io_apic in io_apics.iter_mut() {
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        // Nothing is routed until we say so.
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.write_entry(gsi, REDIRECTION_MASKED);
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        // Mask every line of both PICs, they are still remapped to 32..48, so a spurious
+        // interrupt they may raise anyway does not look like an exception.
+        unsafe { PICS.lock().disable() };
+
+        *IO_APICS.lock() = io_apics;
+        let local_apic = LOCAL_APIC.call_once(|| local_apic);
+        let madt = MADT.call_once(|| madt);
+        local_apic.configure_lints(madt);
+        for &(irq, index, enabled) in ISA_ROUTES.iter() {
+            route(madt, irq, index, apic_id, enabled);
+        }
+        local_apic.start_timer();
+        ENABLED.store(true, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// **Set up the local APIC of an application processor** like [`init`] did on the boot CPU.
+///
+/// Every LAPIC has the same registers at the same address, but each CPU only sees its own. The
+/// timer runs with the calibration of the boot CPU, all cores share the same bus clock.
+pub(crate) fn init_application_processor() {
+    let local_apic = LOCAL_APIC.get().expect("APIC is not initialized");
+    hardware_enable();
+    local_apic.enable();
+    local_apic.configure_lints(MADT.get().expect("APIC is not initialized"));
+    local_apic.start_timer();
+}
+
+// Set the enable bit of the APIC base MSR, firmware normally did that already.
+fn hardware_enable() {
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+}
+
+// Program the redirection entry of ISA `irq` to deliver `index` to the CPU `apic_id`.
+fn route(madt: &Madt, irq: u8, index: InterruptIndex, apic_id: u8, enabled: bool) {
+    let (gsi, polarity, trigger_mode) = madt.isa_irq(irq);
+    let mut entry = index.as_u8() as u64 | (apic_id as u64) << 56;
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    if !enabled {
+        entry |= REDIRECTION_MASKED;
+    }
+    if let Some(io_apic) = IO_APICS.lock().iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        io_apic.write_entry(gsi, entry);
+    }
+}
+
+/// Unmask the I/O APIC line that delivers `index`, returns `false` if it is not routed.
+pub(super) fn enable_irq(index: InterruptIndex) -> bool {
+    let madt = match MADT.get() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    let irq = match ISA_ROUTES.iter().find(|(_, routed, _)| routed.as_u8() == index.as_u8()) {
+        Some(&(irq, _, _)) => irq,
+        None => return false,
+    };
+    let (gsi, _, _) = madt.isa_irq(irq);
+    let mut io_apics = IO_APICS.lock();
+    match io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => {
+            let entry = io_apic.read_entry(gsi);
+            io_apic.write_entry(gsi, entry & !REDIRECTION_MASKED);
+            true
+        }
+        None => false,
+    }
+}
+
+// CPUID leaf 1, EDX bit 9.
+fn has_local_apic() -> bool {
+    // `__cpuid` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
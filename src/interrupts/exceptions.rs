@@ -0,0 +1,255 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::{PageFaultErrorCode, SelectorErrorCode};
+
+use super::trap::TrapFrame;
+use crate::{println, serial_println};
+
+// ----------------------------------------------------------------------------------------------------┐
+// Vector | Name                          | Type       | Error code                                       |
+// -------|-------------------------------|------------|------------------------------------------------- |
+//   0    | Divide Error (#DE)            | fault      | -                                                |
+//   1    | Debug (#DB)                   | fault/trap | -                                                |
+//   2    | Non Maskable Interrupt        | interrupt  | -                                                |
+//   3    | Breakpoint (#BP)              | trap       | -                                                |
+//   4    | Overflow (#OF)                | trap       | -                                                |
+//   5    | Bound Range Exceeded (#BR)    | fault      | -                                                |
+//   6    | Invalid Opcode (#UD)          | fault      | -                                                |
+//   7    | Device Not Available (#NM)    | fault      | -                                                |
+//   8    | Double Fault (#DF)            | abort      | always 0                                         |
+//  10    | Invalid TSS (#TS)             | fault      | selector                                         |
+//  11    | Segment Not Present (#NP)     | fault      | selector                                         |
+//  12    | Stack-Segment Fault (#SS)     | fault      | selector                                         |
+//  13    | General Protection (#GP)      | fault      | selector (0 if not segment related)              |
+//  14    | Page Fault (#PF)              | fault      | page fault flags, address in Cr2                 |
+//  16    | x87 Floating Point (#MF)      | fault      | -                                                |
+//  17    | Alignment Check (#AC)         | fault      | always 0                                         |
+//  18    | Machine Check (#MC)           | abort      | -                                                |
+//  19    | SIMD Floating Point (#XM)     | fault      | -                                                |
+//  20    | Virtualization (#VE)          | fault      | -                                                |
+//  21    | Control Protection (#CP)      | fault      | control protection cause                         |
+//  28    | Hypervisor Injection (#HV)    | fault      | -                                                |
+//  29    | VMM Communication (#VC)       | fault      | VMEXIT code                                      |
+//  30    | Security Exception (#SX)      | fault      | security exception cause                         |
+//                                                                                                      |
+// 9 (coprocessor segment overrun), 15 and 22-27, 31 are reserved and never raised on x86_64.          |
+// ----------------------------------------------------------------------------------------------------┙
+
+// Non maskable interrupts so far, and how many of them `report_nmis` has reported. An NMI can hit while
+// any lock is held, even the serial port one, so the handler only counts it.
+static NMIS: AtomicU64 = AtomicU64::new(0);
+static REPORTED_NMIS: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the exception with the given `vector`.
+pub fn name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NON MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        21 => "CONTROL PROTECTION",
+        28 => "HYPERVISOR INJECTION",
+        29 => "VMM COMMUNICATION",
+        30 => "SECURITY EXCEPTION",
+        _ => "RESERVED",
+    }
+}
+
+/// Human readable decoding of the error code of an exception.
+pub struct ErrorCode {
+    pub vector: u64,
+    pub code: u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.vector {
+            10..=13 if self.code == 0 => write!(f, "not segment related"),
+            10..=13 => {
+                let selector = SelectorErrorCode::new_truncate(self.code);
+                write!(
+                    f,
+                    "selector index {} in {:?}{}",
+                    selector.index(),
+                    selector.descriptor_table(),
+                    if selector.external() { ", caused by external event" } else { "" }
+                )
+            }
+            14 => {
+                use x86_64::registers::control::Cr2;
+
+                let flags = PageFaultErrorCode::from_bits_truncate(self.code);
+                write!(f, "{}, accessed address {:?}", crate::memory::fault::FaultReason(flags), Cr2::read())
+            }
+            21 => write!(
+                f,
+                "{}",
+                match self.code & 0x7fff {
+                    1 => "near ret",
+                    2 => "far ret/iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown cause",
+                }
+            ),
+            8 | 17 | 29 | 30 => write!(f, "{:#x}", self.code),
+            _ => write!(f, "none"),
+        }
+    }
+}
+
+/// Dump the exception and every saved register to serial.
+fn dump(frame: &TrapFrame) {
+    serial_println!(
+        "EXCEPTION: {} ({})\nError code: {}\n{:?}",
+        name(frame.vector),
+        frame.vector,
+        ErrorCode { vector: frame.vector, code: frame.error_code },
+        frame
+    );
+}
+
+// Called through the trap stubs for vectors 0-31, returns the frame to resume: the interrupted code, or
+// another thread if the interrupted one was user code that got killed.
+pub(super) fn handle(frame: &mut TrapFrame) -> *mut TrapFrame {
+    if frame.vector == 8 || frame.vector == 14 {
+        check_interrupt_stack_overflow(frame);
+    }
+
+    match frame.vector {
+        // Traps, the instruction is done. Nothing to fix, just report and continue.
+        3 => {
+            println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame);
+            return frame;
+        }
+        1 => {
+            dump(frame);
+            return frame;
+        }
+        2 => {
+            NMIS.fetch_add(1, Ordering::Relaxed);
+            return frame;
+        }
+        14 => {
+            use x86_64::registers::control::Cr2;
+
+            let flags = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            match crate::memory::fault::resolve(Cr2::read(), flags) {
+                Ok(()) => return frame,
+                Err(_) if from_user(frame) => return kill_user_thread(frame),
+                Err(error) => {
+                    dump(frame);
+                    panic!(
+                        "EXCEPTION: PAGE FAULT\n{}\nUnresolved: {:?}\n{:#?}",
+                        ErrorCode { vector: 14, code: frame.error_code },
+                        error,
+                        frame.stack_frame
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+
+    // Everything else is either an abort, or a fault that would just happen again on return.
+    if from_user(frame) && !matches!(frame.vector, 8 | 18) {
+        return kill_user_thread(frame);
+    }
+    dump(frame);
+    panic!(
+        "EXCEPTION: {}\nError code: {}\n{:#?}",
+        name(frame.vector),
+        ErrorCode { vector: frame.vector, code: frame.error_code },
+        frame.stack_frame
+    );
+}
+
+// Non maskable interrupts received so far, on all CPUs.
+fn nmi_count() -> u64 {
+    NMIS.load(Ordering::Relaxed)
+}
+
+// Report the non maskable interrupts that came in since the last call, from a context that may lock.
+pub(super) fn report_nmis() {
+    let count = nmi_count();
+    let reported = REPORTED_NMIS.swap(count, Ordering::Relaxed);
+    if count != reported {
+        serial_println!("NMI: {} non maskable interrupts received ({} so far)", count - reported, count);
+    }
+}
+
+// Was the CPU running user code (ring 3) when the exception hit?
+fn from_user(frame: &TrapFrame) -> bool {
+    frame.stack_frame.code_segment & 3 == 3
+}
+
+// A fault in user code says nothing about the state of the kernel, only the process that caused it is
+// terminated (or just the thread, outside of any process).
+fn kill_user_thread(frame: &mut TrapFrame) -> *mut TrapFrame {
+    serial_println!(
+        "user thread {} killed by {} ({}), error code: {}, at {:?}",
+        crate::scheduler::current().as_u64(),
+        name(frame.vector),
+        frame.vector,
+        ErrorCode { vector: frame.vector, code: frame.error_code },
+        frame.stack_frame.instruction_pointer
+    );
+    if crate::process::kill_from_exception(frame) {
+        return frame;
+    }
+    crate::scheduler::exit_from_interrupt(frame)
+}
+
+// A fault on the guard page of an interrupt stack, see gdt.rs. Nothing on that stack can be trusted
+// anymore, so this does not even try to resolve the fault.
+fn check_interrupt_stack_overflow(frame: &TrapFrame) {
+    use x86_64::registers::control::Cr2;
+
+    if let Some(ist) = crate::gdt::overflowed_ist(Cr2::read()) {
+        dump(frame);
+        panic!(
+            "EXCEPTION: STACK OVERFLOW\n{} interrupt stack of CPU {} overflowed (accessed address {:?})",
+            ist.name(),
+            crate::smp::cpu_index(),
+            Cr2::read()
+        );
+    }
+}
+
+#[test_case]
+fn test_decode_selector_error_code() {
+    use alloc::string::ToString;
+
+    // index 3, IDT (0b01 << 1), not external
+    let code = ErrorCode { vector: 13, code: (3 << 3) | 0b010 };
+    assert_eq!(code.to_string(), "selector index 3 in Idt");
+    let code = ErrorCode { vector: 13, code: 0 };
+    assert_eq!(code.to_string(), "not segment related");
+}
+
+#[test_case]
+fn test_nmi_is_only_counted() {
+    let before = nmi_count();
+    // `int 2` goes through the NMI handler too, with the serial port locked it would deadlock if that printed.
+    let serial = crate::serial::SERIAL1.lock();
+    unsafe { core::arch::asm!("int 2") };
+    drop(serial);
+    assert_eq!(nmi_count(), before + 1);
+    report_nmis();
+}
@@ -0,0 +1,241 @@
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+use super::{end_of_interrupt, exceptions, InterruptIndex, SYSCALL_VECTOR, YIELD_VECTOR};
+use crate::smp::percpu::InterruptContext;
+use crate::{process, scheduler, smp, syscall, time};
+
+// -----------------------------------------------------------------------------------------------------------┐
+// "x86-interrupt" handlers only get the interrupt stack frame, the general purpose registers of the           |
+// interrupted code are saved somewhere by the compiler but we can't look at them. So exceptions (and the      |
+// device interrupts) enter through small assembly stubs instead, that save every register into a `TrapFrame`  |
+// on the stack:                                                                                               |
+//                                                                                                             |
+//   trap_stub_N:  push 0            (only if the CPU doesn't push an error code for vector N)                 |
+//                 push N                                                                                      |
+//                 jmp trap_common                                                                             |
+//                                                                                                             |
+//   trap_common:  push rax ... r15  (TrapFrame is now complete, rsp points at it)                             |
+//                 call trap_dispatch(rsp) -> rsp to resume                                                    |
+//                 pop r15 ... rax, drop vector and error code, iretq                                          |
+//                                                                                                             |
+// Stack while dispatching, lowest address first (= field order of `TrapFrame`):                               |
+//   r15 r14 r13 r12 r11 r10 r9 r8 rbp rdi rsi rdx rcx rbx rax | vector error_code | rip cs rflags rsp ss      |
+//   \_______________ pushed by trap_common _________________/   \__ trap_stub __/    \___ pushed by CPU ___/  |
+//                                                                                                             |
+// That's 22 * 8 = 176 bytes. The CPU aligns rsp to 16 bytes before pushing its frame, and 176 is a multiple   |
+// of 16, so the stack is correctly aligned for the call into Rust.                                            |
+//                                                                                                             |
+// Frames built by the `syscall` entry (see syscall.rs) are returned to with `sysretq` instead, that clobbers  |
+// rcx and r11, which `syscall` did as well.                                                                  |
+//                                                                                                             |
+// Coming from user mode (the saved CS has RPL 3) GS base belongs to the user program, `swapgs` exchanges it   |
+// with the kernel one (per CPU data, see smp/percpu.rs) that waits in the KernelGsBase MSR. Going back to    |
+// user mode swaps them again. An NMI between that last `swapgs` and `iretq` sees a kernel CS with the user  |
+// GS base, the NMI path must not use per CPU data.                                                           |
+// -----------------------------------------------------------------------------------------------------------┙
+global_asm!(
+    ".global trap_common",
+    "trap_common:",
+    // rsp + 24 = CS pushed by the CPU
+    "test qword ptr [rsp + 24], 3",
+    "jz 1f",
+    "swapgs",
+    "1:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call trap_dispatch",
+    "mov rsp, rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "cmp qword ptr [rsp], {syscall_frame}",
+    "je 3f",
+    "add rsp, 16",
+    // rsp + 8 = CS of the frame we are about to return to
+    "test qword ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "iretq",
+    // Built by the `syscall` entry, return the way it came in: rip from rcx, rflags from r11.
+    // rsp -> vector error_code rip cs rflags rsp ss
+    "3:",
+    "mov rcx, [rsp + 16]",
+    "mov r11, [rsp + 32]",
+    "mov rsp, [rsp + 40]",
+    "swapgs",
+    "sysretq",
+    syscall_frame = const syscall::SYSCALL_FRAME,
+);
+
+// Defines the entry stub `$name` for `$vector`, `error_code` marks vectors where the CPU pushes one itself.
+macro_rules! trap_stub {
+    ($name:ident, $vector:literal) => {
+        global_asm!(concat!(
+            ".global ", stringify!($name), "\n",
+            stringify!($name), ":\n",
+            "push 0\n",
+            "push ", stringify!($vector), "\n",
+            "jmp trap_common\n",
+        ));
+        extern "C" {
+            pub fn $name();
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        global_asm!(concat!(
+            ".global ", stringify!($name), "\n",
+            stringify!($name), ":\n",
+            "push ", stringify!($vector), "\n",
+            "jmp trap_common\n",
+        ));
+        extern "C" {
+            pub fn $name();
+        }
+    };
+}
+
+trap_stub!(trap_stub_0, 0);
+trap_stub!(trap_stub_1, 1);
+trap_stub!(trap_stub_2, 2);
+trap_stub!(trap_stub_3, 3);
+trap_stub!(trap_stub_4, 4);
+trap_stub!(trap_stub_5, 5);
+trap_stub!(trap_stub_6, 6);
+trap_stub!(trap_stub_7, 7);
+trap_stub!(trap_stub_8, 8, error_code);
+trap_stub!(trap_stub_10, 10, error_code);
+trap_stub!(trap_stub_11, 11, error_code);
+trap_stub!(trap_stub_12, 12, error_code);
+trap_stub!(trap_stub_13, 13, error_code);
+trap_stub!(trap_stub_14, 14, error_code);
+trap_stub!(trap_stub_16, 16);
+trap_stub!(trap_stub_17, 17, error_code);
+trap_stub!(trap_stub_18, 18);
+trap_stub!(trap_stub_19, 19);
+trap_stub!(trap_stub_20, 20);
+trap_stub!(trap_stub_21, 21, error_code);
+trap_stub!(trap_stub_28, 28);
+trap_stub!(trap_stub_29, 29, error_code);
+trap_stub!(trap_stub_30, 30, error_code);
+// Interrupts that may switch threads, see scheduler.rs
+trap_stub!(trap_stub_timer, 32); // InterruptIndex::Timer
+trap_stub!(trap_stub_yield, 0x81); // YIELD_VECTOR
+// Device interrupts, they can interrupt user mode as well
+trap_stub!(trap_stub_keyboard, 33); // InterruptIndex::Keyboard
+trap_stub!(trap_stub_serial1, 36); // InterruptIndex::Serial1
+trap_stub!(trap_stub_rtc, 40); // InterruptIndex::RealTimeClock
+// System calls, see syscall.rs
+trap_stub!(trap_stub_syscall, 0x80); // SYSCALL_VECTOR
+
+/// Address of an entry stub, as expected by `Entry::set_handler_addr`.
+pub fn stub_addr(stub: unsafe extern "C" fn()) -> x86_64::VirtAddr {
+    x86_64::VirtAddr::new(stub as usize as u64)
+}
+
+/// Everything the interrupted code had in its registers, as saved by the trap entry stubs.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Error code pushed by the CPU, `0` for vectors without one.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "rsi={:#018x} rdi={:#018x} rbp={:#018x} r8 ={:#018x}", self.rsi, self.rdi, self.rbp, self.r8)?;
+        writeln!(f, "r9 ={:#018x} r10={:#018x} r11={:#018x} r12={:#018x}", self.r9, self.r10, self.r11, self.r12)?;
+        writeln!(f, "r13={:#018x} r14={:#018x} r15={:#018x}", self.r13, self.r14, self.r15)?;
+        writeln!(f, "vector={} error_code={:#x}", self.vector, self.error_code)?;
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+// Called by trap_common with interrupts disabled, returns the stack pointer (aka frame) to resume with.
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) -> *mut TrapFrame {
+    const TIMER: u64 = InterruptIndex::Timer as u64;
+    const KEYBOARD: u64 = InterruptIndex::Keyboard as u64;
+    const SERIAL1: u64 = InterruptIndex::Serial1 as u64;
+    const RTC: u64 = InterruptIndex::RealTimeClock as u64;
+    const YIELD: u64 = YIELD_VECTOR as u64;
+    const SYSCALL: u64 = SYSCALL_VECTOR as u64;
+
+    match frame.vector {
+        0..=31 => exceptions::handle(frame),
+        TIMER => {
+            let _context = InterruptContext::enter();
+            // EOI first, the next thread may run for a while before we get back here.
+            end_of_interrupt(InterruptIndex::Timer);
+            // Every CPU gets timer interrupts for preemption, but the clock only advances on one of them.
+            if smp::cpu_index() == 0 {
+                time::tick();
+                exceptions::report_nmis();
+            }
+            // User code of an exiting process does not get the CPU back.
+            if frame.stack_frame.code_segment & 3 == 3 {
+                process::exit_on_return(frame);
+            }
+            scheduler::schedule(frame)
+        }
+        KEYBOARD | SERIAL1 | RTC => {
+            let _context = InterruptContext::enter();
+            match frame.vector {
+                KEYBOARD => super::keyboard_interrupt(),
+                SERIAL1 => super::serial_interrupt(),
+                _ => super::rtc_interrupt(),
+            }
+            frame
+        }
+        YIELD => scheduler::schedule(frame),
+        SYSCALL | syscall::SYSCALL_FRAME => syscall::dispatch(frame),
+        vector => panic!("trap stub for unexpected vector {}\n{:?}", vector, frame),
+    }
+}
@@ -0,0 +1,97 @@
+use core::fmt;
+use core::task::{Context, Poll};
+
+use crate::sync::{SpinLock, WaitQueue};
+use crate::{process, scheduler};
+
+pub mod channel;
+pub mod pipe;
+
+pub use channel::{channel, Receiver, SendError, Sender};
+pub use pipe::{pipe, PipeReader, PipeWriter};
+
+// -------------------------------------------------------------------------------------------------┐
+// Inter-process communication, two kinds of queues between a producer and a consumer:              |
+//                                                                                                  |
+//   pipe      a byte stream. Reads block until there is data, writes while the buffer is full.     |
+//             Once every write end is gone, reads return 0 (end of file) after the buffer drained. |
+//   channel   a bounded queue of typed messages (`Sender<T>`, `Receiver<T>`), `recv` blocks while  |
+//             it is empty, `send` while it is full, `try_recv` never blocks.                       |
+//                                                                                                  |
+// Both sides can be used from kernel threads (blocking calls) and from async tasks (the `_async`   |
+// variants), even at the same time. Whoever waits goes into a `WaitQueue`: threads are blocked in  |
+// the scheduler until `wake`, tasks leave their `Waker`. User code gets both through file          |
+// descriptors (see process/file.rs), a channel between processes carries byte messages.            |
+//                                                                                                  |
+// The state of a queue is behind a `SpinLock`, taken before the scheduler lock. Blocked threads of |
+// an exiting process are woken up and give up with `Interrupted`.                                  |
+// -------------------------------------------------------------------------------------------------┙
+
+/// Why an operation on a pipe or channel did not complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// The other side is gone: no reader left for a write, no sender for a receive on an empty channel.
+    Closed,
+    /// Nothing to receive right now.
+    Empty,
+    /// No room for the message right now.
+    Full,
+    /// The process of the blocked thread is exiting.
+    Interrupted,
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcError::Closed => write!(f, "the other side is closed"),
+            IpcError::Empty => write!(f, "nothing to receive"),
+            IpcError::Full => write!(f, "no room to send"),
+            IpcError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+// Block the current thread until `ready` returns something. `ready` runs with `state` locked, `None` means
+// not yet: the thread waits in the queue `queue` picks and checks again once woken up.
+fn wait_until<S, R>(
+    state: &SpinLock<S>,
+    mut ready: impl FnMut(&mut S) -> Option<Result<R, IpcError>>,
+    queue: fn(&mut S) -> &mut WaitQueue,
+) -> Result<R, IpcError> {
+    loop {
+        let result = {
+            let mut state = state.lock();
+            let result = ready(&mut state);
+            if result.is_none() {
+                queue(&mut state).prepare_to_wait();
+            }
+            result
+        };
+        if let Some(result) = result {
+            return result;
+        }
+        // Checked after blocking, a kill in between wakes the thread again and isn't missed.
+        if process::pending_exit().is_some() {
+            scheduler::wake(scheduler::current());
+            return Err(IpcError::Interrupted);
+        }
+        scheduler::yield_now();
+    }
+}
+
+// `wait_until` for async tasks: instead of blocking, leave the waker of `cx` in the queue.
+fn poll_until<S, R>(
+    state: &SpinLock<S>,
+    cx: &mut Context<'_>,
+    ready: impl FnOnce(&mut S) -> Option<Result<R, IpcError>>,
+    queue: fn(&mut S) -> &mut WaitQueue,
+) -> Poll<Result<R, IpcError>> {
+    let mut state = state.lock();
+    match ready(&mut state) {
+        Some(result) => Poll::Ready(result),
+        None => {
+            queue(&mut state).register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
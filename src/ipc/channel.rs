@@ -0,0 +1,215 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future;
+
+use super::{poll_until, wait_until, IpcError};
+use crate::process::File;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::SyscallError;
+
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    // Receivers waiting for a message, senders waiting for room.
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+}
+
+impl<T> ChannelState<T> {
+    // Queue `message` if there is room, `None` if there is not. Takes it out of the option only on success.
+    fn send(&mut self, message: &mut Option<T>) -> Option<Result<(), IpcError>> {
+        if self.receivers == 0 {
+            return Some(Err(IpcError::Closed));
+        }
+        if self.queue.len() == self.capacity {
+            return None;
+        }
+        self.queue.push_back(message.take().expect("message was sent already"));
+        self.not_empty.wake_all();
+        Some(Ok(()))
+    }
+
+    // The oldest message, `None` if there is none yet but still a sender.
+    fn recv(&mut self) -> Option<Result<T, IpcError>> {
+        match self.queue.pop_front() {
+            Some(message) => {
+                self.not_full.wake_all();
+                Some(Ok(message))
+            }
+            None if self.senders == 0 => Some(Err(IpcError::Closed)),
+            None => None,
+        }
+    }
+
+    fn not_empty(&mut self) -> &mut WaitQueue {
+        &mut self.not_empty
+    }
+
+    fn not_full(&mut self) -> &mut WaitQueue {
+        &mut self.not_full
+    }
+}
+
+/// **Create a channel** that holds up to `capacity` messages, panics if that is zero.
+///
+/// Both ends can be cloned, the channel stays open as long as there is one of each. Messages are received
+/// in the order they were sent, each by one receiver.
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel without capacity");
+    let state = Arc::new(SpinLock::new(ChannelState {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receivers: 1,
+        not_empty: WaitQueue::new(),
+        not_full: WaitQueue::new(),
+    }));
+    (Sender(state.clone()), Receiver(state))
+}
+
+/// A message [`Sender::send`] could not deliver, and why.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T> {
+    pub error: IpcError,
+    pub message: T,
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendError").field("error", &self.error).finish_non_exhaustive()
+    }
+}
+
+/// The end of a channel that sends messages.
+pub struct Sender<T>(Arc<SpinLock<ChannelState<T>>>);
+
+impl<T: Send> Sender<T> {
+    /// **Send `message`**, blocks while the channel is full. Fails with [`IpcError::Closed`] if there is no
+    /// receiver left, the message is handed back then.
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        wait_until(&self.0, |state| state.send(&mut message), ChannelState::not_full)
+            .map_err(|error| SendError { error, message: message.take().expect("message was sent") })
+    }
+
+    /// **Send `message` if there is room**, fails with [`IpcError::Full`] instead of blocking.
+    pub fn try_send(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        self.0.lock().send(&mut message).unwrap_or(Err(IpcError::Full))
+            .map_err(|error| SendError { error, message: message.take().expect("message was sent") })
+    }
+
+    /// [`send`](Self::send) for async tasks.
+    pub async fn send_async(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        future::poll_fn(|cx| poll_until(&self.0, cx, |state| state.send(&mut message), ChannelState::not_full))
+            .await
+            .map_err(|error| SendError { error, message: message.take().expect("message was sent") })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    // The last sender gone closes the channel once it is empty.
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.not_empty.wake_all();
+        }
+    }
+}
+
+/// The end of a channel that receives messages.
+pub struct Receiver<T>(Arc<SpinLock<ChannelState<T>>>);
+
+impl<T: Send> Receiver<T> {
+    /// **Receive the next message**, blocks while the channel is empty. Fails with [`IpcError::Closed`] once it
+    /// is empty and every sender is gone.
+    pub fn recv(&self) -> Result<T, IpcError> {
+        wait_until(&self.0, ChannelState::recv, ChannelState::not_empty)
+    }
+
+    /// **Receive the next message if there is one**, fails with [`IpcError::Empty`] instead of blocking.
+    pub fn try_recv(&self) -> Result<T, IpcError> {
+        self.0.lock().recv().unwrap_or(Err(IpcError::Empty))
+    }
+
+    /// [`recv`](Self::recv) for async tasks.
+    pub async fn recv_async(&self) -> Result<T, IpcError> {
+        future::poll_fn(|cx| poll_until(&self.0, cx, ChannelState::recv, ChannelState::not_empty)).await
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().receivers += 1;
+        Receiver(self.0.clone())
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    // The last receiver gone fails the senders.
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            state.not_full.wake_all();
+        }
+    }
+}
+
+// Channels between processes carry byte messages, see `Syscall::Send`.
+
+impl File for Sender<Vec<u8>> {
+    fn send(&self, message: Vec<u8>) -> Result<(), SyscallError> {
+        Sender::send(self, message).map_err(|error| error.error.into())
+    }
+}
+
+impl File for Receiver<Vec<u8>> {
+    // The part of the message that does not fit into `buffer` is lost, `0` is the end of the channel (empty
+    // messages can't be sent).
+    fn recv(&self, buffer: &mut [u8], wait: bool) -> Result<usize, SyscallError> {
+        let received = if wait { Receiver::recv(self) } else { self.try_recv() };
+        let message = match received {
+            Ok(message) => message,
+            Err(IpcError::Closed) => return Ok(0),
+            Err(error) => return Err(error.into()),
+        };
+        let count = message.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&message[..count]);
+        Ok(count)
+    }
+}
+
+#[test_case]
+fn test_channel_delivers_in_order_until_closed() {
+    let (sender, receiver) = channel(2);
+    assert_eq!(receiver.try_recv(), Err(IpcError::Empty));
+    sender.send(1).unwrap();
+    sender.clone().send(2).unwrap();
+    assert_eq!(sender.try_send(3), Err(SendError { error: IpcError::Full, message: 3 }));
+
+    drop(sender);
+    assert_eq!(receiver.recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.recv(), Err(IpcError::Closed));
+}
+
+#[test_case]
+fn test_send_without_receiver_returns_the_message() {
+    let (sender, receiver) = channel::<&str>(1);
+    drop(receiver);
+    assert_eq!(sender.send("lost"), Err(SendError { error: IpcError::Closed, message: "lost" }));
+}
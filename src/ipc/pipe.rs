@@ -0,0 +1,197 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future;
+use core::task::{Context, Poll};
+
+use super::{poll_until, wait_until, IpcError};
+use crate::process::File;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::SyscallError;
+
+/// Bytes a pipe buffers, writers block once it is full.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    // Readers waiting for data, writers waiting for room.
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+impl PipeState {
+    // Take what is there, `None` if there is nothing yet but still a writer.
+    fn read(&mut self, buffer: &mut [u8]) -> Option<Result<usize, IpcError>> {
+        if self.buffer.is_empty() && !buffer.is_empty() {
+            return if self.writers == 0 { Some(Ok(0)) } else { None };
+        }
+        let count = self.buffer.len().min(buffer.len());
+        for (byte, data) in buffer.iter_mut().zip(self.buffer.drain(..count)) {
+            *byte = data;
+        }
+        self.writable.wake_all();
+        Some(Ok(count))
+    }
+
+    // Put as much of `data` into the buffer as fits, `None` if nothing does.
+    fn write(&mut self, data: &[u8]) -> Option<Result<usize, IpcError>> {
+        if self.readers == 0 {
+            return Some(Err(IpcError::Closed));
+        }
+        let count = (PIPE_CAPACITY - self.buffer.len()).min(data.len());
+        if count == 0 {
+            return None;
+        }
+        self.buffer.extend(&data[..count]);
+        self.readable.wake_all();
+        Some(Ok(count))
+    }
+
+    fn readable(&mut self) -> &mut WaitQueue {
+        &mut self.readable
+    }
+
+    fn writable(&mut self) -> &mut WaitQueue {
+        &mut self.writable
+    }
+}
+
+/// **Create a pipe**, bytes written to the [`PipeWriter`] come out of the [`PipeReader`] in order.
+///
+/// Both ends can be cloned, the pipe stays open as long as there is one of each.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let state = Arc::new(SpinLock::new(PipeState {
+        buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    }));
+    (PipeReader(state.clone()), PipeWriter(state))
+}
+
+/// The end of a pipe that is read from.
+pub struct PipeReader(Arc<SpinLock<PipeState>>);
+
+impl PipeReader {
+    /// **Read into `buffer`**, blocks until at least one byte is available. Returns how many bytes were read,
+    /// `0` at the end of the file: every writer is gone and the buffer is empty.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, IpcError> {
+        wait_until(&self.0, |state| state.read(buffer), PipeState::readable)
+    }
+
+    /// [`read`](Self::read) for async tasks.
+    pub async fn read_async(&self, buffer: &mut [u8]) -> Result<usize, IpcError> {
+        future::poll_fn(|cx| self.poll_read(cx, buffer)).await
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<Result<usize, IpcError>> {
+        poll_until(&self.0, cx, |state| state.read(buffer), PipeState::readable)
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.0.lock().readers += 1;
+        PipeReader(self.0.clone())
+    }
+}
+
+impl Drop for PipeReader {
+    // The last reader gone fails the writers.
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.writable.wake_all();
+        }
+    }
+}
+
+/// The end of a pipe that is written to.
+pub struct PipeWriter(Arc<SpinLock<PipeState>>);
+
+impl PipeWriter {
+    /// **Write all of `data`**, blocks while the pipe is full. Fails with [`IpcError::Closed`] if there is no
+    /// reader. Returns less than `data.len()` only if that or [`IpcError::Interrupted`] happened halfway.
+    pub fn write(&self, data: &[u8]) -> Result<usize, IpcError> {
+        let mut written = 0;
+        while written < data.len() {
+            match wait_until(&self.0, |state| state.write(&data[written..]), PipeState::writable) {
+                Ok(count) => written += count,
+                Err(error) if written == 0 => return Err(error),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    }
+
+    /// [`write`](Self::write) for async tasks.
+    pub async fn write_async(&self, data: &[u8]) -> Result<usize, IpcError> {
+        let mut written = 0;
+        while written < data.len() {
+            let rest = &data[written..];
+            match future::poll_fn(|cx| poll_until(&self.0, cx, |state| state.write(rest), PipeState::writable)).await {
+                Ok(count) => written += count,
+                Err(error) if written == 0 => return Err(error),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.0.lock().writers += 1;
+        PipeWriter(self.0.clone())
+    }
+}
+
+impl Drop for PipeWriter {
+    // The last writer gone is the end of the file for the readers.
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.writers -= 1;
+        if state.writers == 0 {
+            state.readable.wake_all();
+        }
+    }
+}
+
+impl File for PipeReader {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        Ok(PipeReader::read(self, buffer)?)
+    }
+}
+
+impl File for PipeWriter {
+    fn write(&self, data: &[u8]) -> Result<usize, SyscallError> {
+        Ok(PipeWriter::write(self, data)?)
+    }
+}
+
+#[test_case]
+fn test_pipe_reads_what_was_written_until_the_end() {
+    let (reader, writer) = pipe();
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    let mut buffer = [0; 3];
+    assert_eq!(reader.read(&mut buffer), Ok(3));
+    assert_eq!(&buffer, b"hel");
+
+    drop(writer);
+    assert_eq!(reader.read(&mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], b"lo");
+    assert_eq!(reader.read(&mut buffer), Ok(0));
+}
+
+#[test_case]
+fn test_pipe_without_reader_is_closed() {
+    let (reader, writer) = pipe();
+    let second = reader.clone();
+    drop(reader);
+    assert_eq!(writer.write(b"x"), Ok(1));
+    drop(second);
+    assert_eq!(writer.write(b"x"), Err(IpcError::Closed));
+}
@@ -4,11 +4,28 @@
 #![test_runner(test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)] // Experimental calling convention for interrupt/exception handler functions
+#![feature(alloc_error_handler)] // Custom handler for failed heap allocations
 
+extern crate alloc;
+
+pub mod acpi;
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
 pub mod gdt;
+pub mod memory;
+pub mod allocator;
+pub mod elf;
+pub mod ipc;
+pub mod power;
+pub mod process;
+pub mod scheduler;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod usermode;
 
 use nostd_color::colors::{BRIGHT_RED, BRIGHT_GREEN, YELLOW, RED};
 use nostd_color::colorize::Colored;
@@ -18,8 +35,12 @@ use x86_64::instructions::port::Port;
 /// 
 pub fn init() {
     gdt::init_gdt();
+    smp::percpu::init_bsp();
     interrupts::init_idt();
+    syscall::init_cpu();
     unsafe { interrupts::PICS.lock().initialize() }
+    time::init(time::DEFAULT_FREQUENCY);
+    time::rtc::init();
 
     // The interrupts::enable function of the x86_64 crate executes the special sti ("assembly") instruction
     // (“set interrupts”) to enable external interrupts.
@@ -85,9 +106,17 @@ pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
 //       ENTRY AND PANIC HANDLER        //
 // ------------------------------------ //
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+use bootloader::{entry_point, BootInfo};
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
     test_main();
     htl_loop()
 }
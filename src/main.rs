@@ -4,26 +4,57 @@
 #![test_runner(rust_os::test_runner)] // Test runner function = test_runner
 #![reexport_test_harness_main = "test_main"] // Renaming/ReExporting test function name to test_main because of no_main attribute
 
-use rust_os::println;
+use rust_os::{println, gdt, memory, allocator, scheduler, smp, time, interrupts, power, vga_buffer};
+use rust_os::task::{executor::Executor, keyboard, serial, Task};
 use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
 
-#[no_mangle] // Don't mangle the name of this function
-// This fn is the entry point, since the linker looks for function named '_start' by default,
-// this is the reason for no_mangle attribute.
-pub extern "C" fn _start() -> ! {
+// The linker looks for a function named '_start' by default, entry_point macro defines it for us
+// and also type checks that our entry point takes the `BootInfo` the bootloader passes to the kernel.
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("El. Psy. Kongroo.");
 
     rust_os::init();
+    println!("wall clock: {} UTC", time::wall_clock());
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    let scrollback = vga_buffer::WRITER.lock().set_scrollback_capacity(vga_buffer::SCROLLBACK_LINES);
+    if let Err(error) = scrollback {
+        println!("vga: keeping the boot scrollback ({})", error);
+    }
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    match interrupts::apic::init() {
+        Ok(()) => println!("interrupts: local APIC and I/O APIC"),
+        Err(error) => println!("interrupts: 8259 PIC ({})", error),
+    }
+    power::init();
+    scheduler::init().expect("scheduler initialization failed");
+    match smp::init() {
+        Ok(cpus) => println!("smp: {} CPUs online", cpus),
+        Err(error) => println!("smp: running on the boot CPU only ({})", error),
+    }
 
-    use x86_64::registers::control::Cr3;
-
-    let (lvl_4_table, _) = Cr3::read();
-    println!("level 4 table is at address {:?}", lvl_4_table.start_address());
+    let (free_frames, total_frames) = {
+        let allocator = memory::FRAME_ALLOCATOR.lock();
+        (allocator.free_frames() as u64, allocator.total_frames() as u64)
+    };
+    println!(
+        "physical memory: {} KiB free of {} KiB",
+        free_frames * memory::FRAME_SIZE / 1024,
+        total_frames * memory::FRAME_SIZE / 1024
+    );
     
     #[cfg(test)]
     test_main();
 
-    rust_os::htl_loop()
+    // The boot thread becomes the executor, it halts whenever no task is ready.
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(serial::run_commands()));
+    interrupts::enable_irq(interrupts::InterruptIndex::Serial1);
+    executor.run()
 }
 
 /// This function is called on panic.
@@ -0,0 +1,76 @@
+use bootloader::BootInfo;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use crate::sync::{Once, SpinLock};
+
+pub mod address_space;
+pub mod fault;
+pub mod frame;
+pub mod paging;
+pub mod stack;
+
+pub use address_space::{with_active_memory, AddressSpace};
+pub use frame::{BitmapFrameAllocator, BootInfoFrameAllocator, GlobalFrameAllocator, FRAME_ALLOCATOR};
+pub use paging::{MemoryError, VirtualMemory};
+pub use stack::{allocate_stack, Stack};
+
+/// Size of a single physical frame (and virtual page) in bytes.
+pub const FRAME_SIZE: u64 = 4096;
+
+// ------------------------------------------------------------------------------------------------┐
+// Kernel virtual address space layout, each level 4 entry covers 512 GiB.                          |
+//                                                                                                  |
+// 0x0000_0000_0000_0000  P4[0]        kernel image and identity mapped VGA buffer (bootloader)     |
+// 0x0000_0080_0000_0000  P4[1..256]   user space (`USER_START..USER_END`), private per address space |
+// 0xFFFF_8000_0000_0000  P4[256]      all of physical memory (`physical-memory-offset`)            |
+// 0xFFFF_C000_0000_0000  P4[384]      kernel heap (`allocator::HEAP_START`)                        |
+// 0xFFFF_C800_0000_0000  P4[400]      kernel stacks with guard pages                               |
+// 0xFFFF_D000_0000_0000  P4[416]      MMIO window                                                  |
+// 0xFFFF_FE80_0000_0000  P4[509]      boot info (`boot-info-address`)                              |
+// 0xFFFF_FF00_0000_0000  P4[510]      boot stack (`kernel-stack-address`)                          |
+//                                                                                                  |
+// The bootloader addresses are pinned in Cargo.toml under [package.metadata.bootloader].           |
+// Everything outside of user space is shared by all address spaces, see memory/address_space.rs.   |
+// ------------------------------------------------------------------------------------------------┙
+
+/// Lowest address user programs may use, everything below belongs to the kernel image.
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+/// End (exclusive) of user space, the end of the lower canonical half.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Start of the region kernel stacks are allocated from by [`allocate_stack`].
+pub const KERNEL_STACKS_START: u64 = 0xFFFF_C800_0000_0000;
+/// End (exclusive) of the kernel stack region.
+pub const KERNEL_STACKS_END: u64 = KERNEL_STACKS_START + 512 * 1024 * 1024 * 1024;
+
+/// Start of the window that device memory gets mapped into by [`VirtualMemory::map_mmio`].
+pub const MMIO_START: u64 = 0xFFFF_D000_0000_0000;
+/// End (exclusive) of the MMIO window.
+pub const MMIO_END: u64 = MMIO_START + 512 * 1024 * 1024 * 1024;
+
+static KERNEL_MEMORY: Once<SpinLock<VirtualMemory>> = Once::new();
+// What `Cr3` holds while no user address space is active.
+static KERNEL_LEVEL_4: Once<PhysFrame> = Once::new();
+
+/// **Initialize memory management**, hand the bootloader memory map over to the global
+/// [`FRAME_ALLOCATOR`] and set up the kernel [`VirtualMemory`] for the active page table.
+///
+/// Must be called exactly once, before anything tries to allocate frames or map pages.
+pub fn init(boot_info: &'static BootInfo) {
+    FRAME_ALLOCATOR.lock().init(&boot_info.memory_map);
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let memory = unsafe { VirtualMemory::active(physical_memory_offset) };
+    address_space::share_kernel_half(&memory);
+    KERNEL_LEVEL_4.call_once(|| memory.level_4_frame());
+    KERNEL_MEMORY.call_once(|| SpinLock::new(memory));
+}
+
+/// The kernel [`VirtualMemory`] manager.
+///
+/// Its lock disables interrupts while it is held, since interrupt handlers may need it.
+/// Panics when called before [`init`].
+pub fn kernel_memory() -> &'static SpinLock<VirtualMemory> {
+    KERNEL_MEMORY.get().expect("memory::init was not called")
+}
@@ -0,0 +1,362 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::fault::{self, RegionKind};
+use super::frame::GlobalFrameAllocator;
+use super::{MemoryError, VirtualMemory, FRAME_SIZE, KERNEL_LEVEL_4, KERNEL_MEMORY, USER_END, USER_START};
+use crate::smp::percpu;
+use crate::sync::SpinLock;
+
+// ---------------------------------------------------------------------------------------------------┐
+// Every user program gets its own level 4 table. Only the user half is private, all other entries    |
+// are copied from the kernel table when the address space is created, so they point to the same     |
+// level 3 tables:                                                                                    |
+//                                                                                                    |
+//   kernel P4   [0] [1 ........ 255] [256] .......... [511]                                          |
+//                |                     |                |     shared level 3 tables                  |
+//   process P4  [0] [1 ........ 255] [256] .......... [511]                                          |
+//                    |                                                                               |
+//                    private tables and frames, freed with the address space                         |
+//                                                                                                    |
+// Kernel mappings made later only change the shared tables below level 4, so they show up in every   |
+// address space. That only holds as long as the kernel never adds a level 4 entry, `memory::init`    |
+// creates all of them up front (`share_kernel_half`).                                                |
+//                                                                                                    |
+// The scheduler activates the address space of the next thread on every switch. Besides `Cr3` the   |
+// per CPU data points at it, so the page fault handler and system calls know which `VirtualMemory`   |
+// the user half of the active table belongs to. Kernel threads run in the kernel table.              |
+//                                                                                                    |
+// `fork` gives the copy the same frames: writable pages become read-only copy-on-write pages in both,|
+// read-only ones are just shared. Every mapping counts as a user of its frame (see memory/fault.rs),  |
+// so whichever address space goes away last frees it. Other CPUs are not told about the pages that   |
+// became read-only, so `process::fork` refuses to copy a process with more than one thread: a thread |
+// running elsewhere could still write through a stale TLB entry.                                     |
+// ---------------------------------------------------------------------------------------------------┙
+
+/// Level 4 entries of user space, see the layout in [`crate::memory`].
+const USER_ENTRIES: Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Where `mmap` places memory when the caller does not ask for an address.
+pub const MMAP_START: u64 = 0x0000_6000_0000_0000;
+
+/// A level 4 page table with a private user half and the kernel half shared with every other one.
+pub struct AddressSpace {
+    memory: SpinLock<VirtualMemory>,
+    level_4_frame: PhysFrame,
+    // Start of the next area `mmap` hands out, it only moves up.
+    next_mmap: AtomicU64,
+}
+
+impl AddressSpace {
+    /// **Create an address space** with an empty user half.
+    pub fn new() -> Result<Self, MemoryError> {
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or(MemoryError::OutOfFrames)?;
+
+        let memory = {
+            let kernel = super::kernel_memory().lock();
+            unsafe {
+                let kernel_table: &PageTable = &*kernel.phys_to_virt(kernel.level_4_frame().start_address()).as_ptr();
+                let table: &mut PageTable = &mut *kernel.phys_to_virt(frame.start_address()).as_mut_ptr();
+                table.zero();
+                for (index, entry) in kernel_table.iter().enumerate() {
+                    if !USER_ENTRIES.contains(&index) {
+                        table[index] = entry.clone();
+                    }
+                }
+                VirtualMemory::new(frame, kernel.phys_to_virt(PhysAddr::zero()))
+            }
+        };
+        Ok(AddressSpace { memory: SpinLock::new(memory), level_4_frame: frame, next_mmap: AtomicU64::new(MMAP_START) })
+    }
+
+    /// Frame of the level 4 table, what `Cr3` holds while the address space is active.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// The memory manager of the address space.
+    ///
+    /// Only use it for user addresses, the kernel half belongs to [`kernel_memory`](super::kernel_memory).
+    pub fn memory(&self) -> &SpinLock<VirtualMemory> {
+        &self.memory
+    }
+
+    /// `true` if this address space is the one in `Cr3` of the executing CPU.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// **Switch the executing CPU to this address space**, nothing happens if it is active already.
+    ///
+    /// Call it with interrupts disabled, so the thread does not move to another CPU halfway through.
+    ///
+    /// # Safety
+    /// The address space must stay alive until the CPU switched to another one. Code and data the caller
+    /// keeps using must be in the kernel half.
+    pub unsafe fn activate(&self) {
+        percpu::current().set_address_space(self);
+        if !self.is_active() {
+            let (_, flags) = Cr3::read();
+            Cr3::write(self.level_4_frame, flags);
+        }
+    }
+
+    /// **Register a demand zero region** in this address space, see [`fault::register_lazy_region`].
+    pub fn register_lazy_region(&self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MemoryError> {
+        fault::register(Some(self.level_4_frame), start, size, flags, |_| RegionKind::DemandZero)
+    }
+
+    /// **Register a stack region** in this address space, see [`fault::register_stack_region`].
+    /// The top `mapped` bytes of it are mapped already.
+    pub fn register_stack_region(
+        &self,
+        top: VirtAddr,
+        max_size: u64,
+        mapped: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        let mapped_pages = mapped.div_ceil(FRAME_SIZE);
+        fault::register(Some(self.level_4_frame), top - max_size, max_size, flags, |end| RegionKind::Stack {
+            bottom: end - mapped_pages,
+        })
+    }
+
+    /// **Pick `size` bytes for `mmap`** above every area picked before, starting at [`MMAP_START`].
+    /// `None` once the rest of user space is too small.
+    pub fn next_mmap_area(&self, size: u64) -> Option<VirtAddr> {
+        let end = |start: u64| start.checked_add(size).filter(|&end| end <= USER_END);
+        let start = self.next_mmap.fetch_update(Ordering::Relaxed, Ordering::Relaxed, end).ok()?;
+        Some(VirtAddr::new(start))
+    }
+
+    /// **Create a copy-on-write copy** of this address space: same user pages, same regions.
+    ///
+    /// Writable pages turn read-only with [`fault::COPY_ON_WRITE`] set on both sides, the first write
+    /// (of either one) gets a private copy of the frame.
+    pub fn fork(&self) -> Result<AddressSpace, MemoryError> {
+        let copy = AddressSpace::new()?;
+        {
+            let mut memory = self.memory.lock();
+            let mut copy_memory = copy.memory.lock();
+            let pages = unsafe { user_pages(&memory) };
+            for (page, frame, flags) in pages {
+                let flags = if flags.intersects(PageTableFlags::WRITABLE | fault::COPY_ON_WRITE) {
+                    fault::share_copy_on_write(&mut memory, page)?;
+                    (flags - PageTableFlags::WRITABLE) | fault::COPY_ON_WRITE
+                } else {
+                    fault::share_frame(frame);
+                    flags
+                };
+                if let Err(error) = unsafe { copy_memory.map(page, frame, flags) } {
+                    // Not mapped after all, drop the user counted above again.
+                    unsafe { fault::release_frame(frame) };
+                    return Err(error);
+                }
+            }
+        }
+        fault::copy_regions(self.level_4_frame, copy.level_4_frame);
+        copy.next_mmap.store(self.next_mmap.load(Ordering::Relaxed), Ordering::Relaxed);
+        Ok(copy)
+    }
+
+    /// **Copy `buffer.len()` bytes at `addr` out of the address space**, whether it is active or not.
+    pub fn read(&self, addr: VirtAddr, buffer: &mut [u8]) -> Result<(), MemoryError> {
+        self.for_each_chunk(addr, buffer.len(), |ptr, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(ptr as *const u8, buffer[offset..].as_mut_ptr(), length);
+        })
+    }
+
+    /// **Copy `data` into the address space** at `addr`, whether it is active or not.
+    ///
+    /// Page permissions are ignored, so this is how read-only pages get their content. The pages must not be
+    /// copy-on-write, the write would show up in every address space sharing the frame.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), MemoryError> {
+        self.for_each_chunk(addr, data.len(), |ptr, offset, length| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), ptr, length);
+        })
+    }
+
+    // Calls `f(pointer, offset, length)` for each piece of `addr..addr + length` that lies in a single page,
+    // `pointer` is where the piece is in the physical memory mapping.
+    fn for_each_chunk(
+        &self,
+        addr: VirtAddr,
+        length: usize,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), MemoryError> {
+        let memory = self.memory.lock();
+        let mut offset = 0;
+        while offset < length {
+            let current = addr + offset as u64;
+            let page: Page = Page::containing_address(current);
+            let (frame, _) = memory.translate_page(page).ok_or(MemoryError::NotMapped)?;
+            let in_page = current - page.start_address();
+            let chunk = ((FRAME_SIZE - in_page) as usize).min(length - offset);
+            f(memory.phys_to_virt(frame.start_address() + in_page).as_mut_ptr(), offset, chunk);
+            offset += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // The scheduler switches away from a thread before its address space can go away.
+        assert!(!self.is_active(), "dropping the active address space");
+        fault::unregister_address_space(self.level_4_frame);
+
+        let memory = self.memory.get_mut();
+        unsafe {
+            let table: &mut PageTable = &mut *memory.phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr();
+            for entry in table.iter_mut().skip(USER_ENTRIES.start).take(USER_ENTRIES.len()) {
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    free_table(memory, entry.addr(), 3);
+                }
+            }
+            GlobalFrameAllocator.deallocate_frame(self.level_4_frame);
+        }
+    }
+}
+
+// Free the level `level` table at `addr`, the tables below it and every frame mapped through it.
+// Frames can be shared copy-on-write with other address spaces, `fault::release_frame` keeps track of that.
+unsafe fn free_table(memory: &VirtualMemory, addr: PhysAddr, level: u8) {
+    let table: &PageTable = &*memory.phys_to_virt(addr).as_ptr();
+    for entry in table.iter().filter(|entry| entry.flags().contains(PageTableFlags::PRESENT)) {
+        assert!(!entry.flags().contains(PageTableFlags::HUGE_PAGE), "huge page in user space");
+        if level == 1 {
+            fault::release_frame(PhysFrame::containing_address(entry.addr()));
+        } else {
+            free_table(memory, entry.addr(), level - 1);
+        }
+    }
+    GlobalFrameAllocator.deallocate_frame(PhysFrame::containing_address(addr));
+}
+
+// Every page mapped in the user half of `memory`, with its frame and flags.
+unsafe fn user_pages(memory: &VirtualMemory) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+    let mut pages = Vec::new();
+    collect_pages(memory, memory.level_4_frame().start_address(), 4, 0, &mut pages);
+    pages
+}
+
+// Add the pages mapped through the level `level` table at `addr` to `pages`, `start` is the first
+// address the table covers.
+unsafe fn collect_pages(
+    memory: &VirtualMemory,
+    addr: PhysAddr,
+    level: u8,
+    start: u64,
+    pages: &mut Vec<(Page, PhysFrame, PageTableFlags)>,
+) {
+    let table: &PageTable = &*memory.phys_to_virt(addr).as_ptr();
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) || (level == 4 && !USER_ENTRIES.contains(&index)) {
+            continue;
+        }
+        let entry_start = start + ((index as u64) << (12 + 9 * (level as u64 - 1)));
+        if level == 1 {
+            let page = Page::containing_address(VirtAddr::new(entry_start));
+            pages.push((page, PhysFrame::containing_address(entry.addr()), entry.flags()));
+        } else {
+            assert!(!entry.flags().contains(PageTableFlags::HUGE_PAGE), "huge page in user space");
+            collect_pages(memory, entry.addr(), level - 1, entry_start, pages);
+        }
+    }
+}
+
+/// **Switch the executing CPU back to the kernel page table**, see [`AddressSpace::activate`].
+pub fn activate_kernel() {
+    percpu::current().set_address_space(core::ptr::null());
+    if let Some(&frame) = KERNEL_LEVEL_4.get() {
+        let (active, flags) = Cr3::read();
+        if active != frame {
+            unsafe { Cr3::write(frame, flags) };
+        }
+    }
+}
+
+/// **Run `f` with the memory manager for the user half of the active address space**: the one of
+/// the running user program, or the kernel one.
+pub fn with_active_memory<R>(f: impl FnOnce(&SpinLock<VirtualMemory>) -> R) -> R {
+    // The active address space can only change with a switch, not while interrupts are disabled.
+    interrupts::without_interrupts(|| f(unsafe { active_memory() }.expect("memory::init was not called")))
+}
+
+/// Memory manager for the user half of the active address space, `None` before `memory::init`.
+///
+/// # Safety
+/// Only valid until the next switch, interrupts have to stay disabled while it is used.
+pub(super) unsafe fn active_memory<'a>() -> Option<&'a SpinLock<VirtualMemory>> {
+    match percpu::current().address_space().as_ref() {
+        Some(space) => Some(&space.memory),
+        None => KERNEL_MEMORY.get(),
+    }
+}
+
+/// **Create every level 4 entry of the kernel half**, so that address spaces created from now on
+/// see all kernel mappings. Called once by [`memory::init`](super::init).
+pub(super) fn share_kernel_half(memory: &VirtualMemory) {
+    // Nothing else uses the page tables yet.
+    let table: &mut PageTable = unsafe { &mut *memory.phys_to_virt(memory.level_4_frame().start_address()).as_mut_ptr() };
+    for (index, entry) in table.iter_mut().enumerate() {
+        if USER_ENTRIES.contains(&index) || !entry.is_unused() {
+            continue;
+        }
+        let frame = GlobalFrameAllocator.allocate_frame().expect("no frames for the kernel page tables");
+        unsafe { (*memory.phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()).zero() };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+#[test_case]
+fn test_kernel_half_is_shared() {
+    let space = AddressSpace::new().unwrap();
+    {
+        let kernel = super::kernel_memory().lock();
+        let memory = space.memory().lock();
+        // Kernel code, heap and the VGA buffer in P4[0].
+        for addr in [crate::init as *const () as u64, crate::allocator::HEAP_START, 0xb8000] {
+            let addr = VirtAddr::new(addr);
+            assert_eq!(memory.translate(addr), kernel.translate(addr));
+        }
+    }
+
+    // User pages only show up in their own address space.
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    space.memory().lock().map_range(Page::range_inclusive(page, page), flags).unwrap();
+    space.write(page.start_address() + 4090u64, b"across pages").unwrap_err();
+    space.write(page.start_address() + 8u64, b"user").unwrap();
+    let mut buffer = [0; 4];
+    space.read(page.start_address() + 8u64, &mut buffer).unwrap();
+    assert_eq!(&buffer, b"user");
+    assert_eq!(super::kernel_memory().lock().translate_page(page), None);
+}
+
+#[test_case]
+fn test_fork_shares_pages_copy_on_write() {
+    let space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    space.memory().lock().map_zeroed(page, flags).unwrap();
+    space.write(page.start_address(), b"parent").unwrap();
+
+    let copy = space.fork().unwrap();
+    let translate = |space: &AddressSpace| space.memory().lock().translate_page(page);
+    let (frame, flags) = translate(&space).unwrap();
+    assert_eq!(translate(&copy), Some((frame, flags)));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(fault::COPY_ON_WRITE));
+
+    // The frame stays around as long as one of them maps it.
+    drop(space);
+    let mut buffer = [0; 6];
+    copy.read(page.start_address(), &mut buffer).unwrap();
+    assert_eq!(&buffer, b"parent");
+}
@@ -0,0 +1,362 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::frame::GlobalFrameAllocator;
+use super::{address_space, MemoryError, VirtualMemory, FRAME_SIZE, KERNEL_MEMORY, USER_END, USER_START};
+use crate::sync::SpinLock;
+
+// ------------------------------------------------------------------------------------------------------┐
+// Not every page fault is a bug. The page fault handler asks `resolve` to fix the fault first:           |
+//                                                                                                        |
+//  * Demand zero:   registered lazy regions have no frames until they are touched, the first access    |
+//                   maps a zeroed frame with the region flags.                                           |
+//  * Copy-on-write: shared pages are mapped read-only with the `COPY_ON_WRITE` bit set, the first write |
+//                   gets its own copy of the frame (or just the write permission if it is the last user).|
+//  * Stack growth:  stack regions map pages downwards as the stack grows, the lowest page of a region  |
+//                   stays unmapped forever and acts as guard page.                                       |
+//                                                                                                        |
+// Only when none of these apply the fault is unrecoverable and the kernel panics.                        |
+//                                                                                                        |
+// Regions in user space belong to the address space that was active when they were registered (see      |
+// memory/address_space.rs), two programs can use the same addresses. Kernel regions are visible in all. |
+// ------------------------------------------------------------------------------------------------------┙
+
+/// Available (OS defined) page table bit that marks a page as copy-on-write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Most regions one address space can have, the kernel counts as one address space too.
+pub const MAX_REGIONS: usize = 256;
+
+/// How many pages below the mapped bottom of a stack region an access may land and still count as
+/// stack growth (a big stack frame can skip a few pages). Anything further away is a wild access.
+pub const STACK_GROWTH_WINDOW: u64 = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum RegionKind {
+    DemandZero,
+    // Lowest mapped page of the stack, everything from here up to the region end is mapped.
+    Stack { bottom: Page },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: Page,
+    // Exclusive.
+    end: Page,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    // Level 4 table of the address space, `None` for kernel regions.
+    space: Option<PhysFrame>,
+}
+
+impl Region {
+    fn contains(&self, page: Page) -> bool {
+        self.start <= page && page < self.end
+    }
+
+    // Part of the address space whose level 4 table is `space`?
+    fn visible_in(&self, space: PhysFrame) -> bool {
+        self.space.is_none() || self.space == Some(space)
+    }
+}
+
+static REGIONS: SpinLock<Vec<Region>> = SpinLock::new(Vec::new());
+
+// Number of page table entries referencing a copy-on-write frame. Frames that are not in the map
+// have a single owner.
+static SHARED_FRAMES: SpinLock<BTreeMap<PhysFrame, usize>> = SpinLock::new(BTreeMap::new());
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not part of any lazy or stack region.
+    NotInRegion,
+    /// The access hit the guard page at the bottom of a stack region.
+    StackOverflow,
+    /// The access is below a stack region bottom, but too far away to be stack growth.
+    BelowStack,
+    /// The page is present and the access is not allowed (and it is not a copy-on-write page).
+    ProtectionViolation,
+    /// The page tables are malformed (reserved bit set).
+    MalformedTable,
+    /// The fault happened while the memory manager was locked, probably by the faulting code itself.
+    MemoryManagerBusy,
+    /// Resolving the fault failed, e.g. because there are no free frames left.
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for FaultError {
+    fn from(error: MemoryError) -> Self {
+        FaultError::Memory(error)
+    }
+}
+
+/// Human readable decoding of a [`PageFaultErrorCode`].
+pub struct FaultReason(pub PageFaultErrorCode);
+
+impl fmt::Display for FaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            write!(f, "protection violation")?;
+        } else {
+            write!(f, "page not present")?;
+        }
+
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            write!(f, ", instruction fetch")?;
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            write!(f, ", write access")?;
+        } else {
+            write!(f, ", read access")?;
+        }
+
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            write!(f, ", user mode")?;
+        } else {
+            write!(f, ", kernel mode")?;
+        }
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in page table")?;
+        }
+        Ok(())
+    }
+}
+
+/// **Register a demand zero region** of `size` bytes at `start`, the pages are mapped with `flags`
+/// (plus `PRESENT`) to zeroed frames the first time they are accessed.
+pub fn register_lazy_region(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MemoryError> {
+    register(active_space(start), start, size, flags, |_| RegionKind::DemandZero)
+}
+
+/// **Register a stack region** of at most `max_size` bytes that ends (exclusive) at `top`.
+///
+/// Pages are mapped on demand while the stack grows downwards, the lowest page of the region is
+/// never mapped and turns an overflow into a clean [`FaultError::StackOverflow`].
+pub fn register_stack_region(top: VirtAddr, max_size: u64, flags: PageTableFlags) -> Result<(), MemoryError> {
+    register(active_space(top - 1u64), top - max_size, max_size, flags, |end| RegionKind::Stack { bottom: end })
+}
+
+/// Register a region in the address space with the level 4 table `space`, `None` for kernel regions.
+/// Fails if it overlaps a region of that address space, or there are [`MAX_REGIONS`] already.
+pub(super) fn register(
+    space: Option<PhysFrame>,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    kind: impl FnOnce(Page) -> RegionKind,
+) -> Result<(), MemoryError> {
+    let start = Page::containing_address(start);
+    let end = Page::containing_address(start.start_address() + size + FRAME_SIZE - 1u64);
+    let region = Region { start, end, flags: flags | PageTableFlags::PRESENT, kind: kind(end), space };
+
+    let mut regions = REGIONS.lock();
+    // Kernel and user regions can't overlap, they are in different halves.
+    let mut count = 0;
+    for other in regions.iter().filter(|other| other.space == space) {
+        if other.start < region.end && region.start < other.end {
+            return Err(MemoryError::RegionOverlaps);
+        }
+        count += 1;
+    }
+    if count >= MAX_REGIONS {
+        return Err(MemoryError::TooManyRegions);
+    }
+    regions.push(region);
+    Ok(())
+}
+
+/// **Remove the region** that starts at `start`. Pages it already mapped stay mapped.
+pub fn unregister_region(start: VirtAddr) {
+    let space = Cr3::read().0;
+    let start = Page::containing_address(start);
+    REGIONS.lock().retain(|region| region.start != start || !region.visible_in(space));
+}
+
+/// Remove every region of the address space with the level 4 table `space`.
+pub(super) fn unregister_address_space(space: PhysFrame) {
+    REGIONS.lock().retain(|region| region.space != Some(space));
+}
+
+/// Give the address space with the level 4 table `to` a copy of every region of the one with `from`.
+pub(super) fn copy_regions(from: PhysFrame, to: PhysFrame) {
+    let mut regions = REGIONS.lock();
+    let copies: Vec<Region> = regions
+        .iter()
+        .filter(|region| region.space == Some(from))
+        .map(|region| Region { space: Some(to), ..*region })
+        .collect();
+    regions.extend(copies);
+}
+
+// Address space a region at `start` registered now belongs to.
+fn active_space(start: VirtAddr) -> Option<PhysFrame> {
+    let user = (USER_START..USER_END).contains(&start.as_u64());
+    user.then(|| Cr3::read().0)
+}
+
+/// **Flags a fault at `page` would be resolved with**: the flags of the region containing it (`PRESENT` included),
+/// `None` outside of every region and for the guard page of a stack region.
+pub fn region_flags(page: Page) -> Option<PageTableFlags> {
+    let space = Cr3::read().0;
+    let regions = REGIONS.lock();
+    let region = regions.iter().find(|region| region.contains(page) && region.visible_in(space))?;
+    match region.kind {
+        RegionKind::Stack { .. } if page == region.start => None,
+        _ => Some(region.flags),
+    }
+}
+
+/// `true` if any page of the `size` bytes at `start` belongs to a registered region.
+pub fn overlaps_region(start: VirtAddr, size: u64) -> bool {
+    let space = Cr3::read().0;
+    let first = Page::containing_address(start);
+    let end = Page::containing_address(start + size + FRAME_SIZE - 1u64);
+    let regions = REGIONS.lock();
+    regions.iter().any(|region| region.start < end && first < region.end && region.visible_in(space))
+}
+
+/// **Share `page` copy-on-write**: make it read-only, set [`COPY_ON_WRITE`] and count one more user
+/// of its frame. The caller is expected to map the same frame (also read-only + COW) somewhere else.
+pub fn share_copy_on_write(memory: &mut VirtualMemory, page: Page) -> Result<PhysFrame, MemoryError> {
+    let (frame, flags) = memory.translate_page(page).ok_or(MemoryError::NotMapped)?;
+    let cow_flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+    unsafe { memory.protect(page, cow_flags)? };
+    share_frame(frame);
+    Ok(frame)
+}
+
+/// **Count one more user of `frame`**, without changing any mapping. That's how read-only pages are
+/// shared: nobody writes to them, but the frame must only be freed with its last mapping.
+pub fn share_frame(frame: PhysFrame) {
+    let mut shared = SHARED_FRAMES.lock();
+    // First time it is shared there are two users, the existing mapping and the new one.
+    *shared.entry(frame).or_insert(1) += 1;
+}
+
+/// **Drop one user** of a (possibly) copy-on-write `frame` whose mapping was removed,
+/// the frame is freed once nobody references it anymore.
+///
+/// # Safety
+/// The caller must have removed one mapping of `frame`.
+pub unsafe fn release_frame(frame: PhysFrame) {
+    let last_user = {
+        let mut shared = SHARED_FRAMES.lock();
+        match shared.get_mut(&frame) {
+            Some(count) if *count > 2 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                // One user left, it owns the frame alone from now on.
+                shared.remove(&frame);
+                false
+            }
+            None => true,
+        }
+    };
+
+    if last_user {
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+/// **Try to resolve the page fault** at `addr`. On `Ok` the faulting instruction can be retried.
+///
+/// Called from the page fault handler with interrupts disabled, so it only `try_lock`s:
+/// a fault while the memory manager is locked can't be resolved without deadlocking.
+pub fn resolve(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        return Err(FaultError::MalformedTable);
+    }
+
+    let page = Page::containing_address(addr);
+    // Faults before `memory::init` can't be anything we know how to resolve.
+    let memory = match active_space(addr) {
+        // Interrupts stay disabled until the fault is resolved.
+        Some(_) => unsafe { address_space::active_memory() },
+        None => KERNEL_MEMORY.get(),
+    };
+    let mut memory = memory.ok_or(FaultError::NotInRegion)?.try_lock().ok_or(FaultError::MemoryManagerBusy)?;
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return resolve_copy_on_write(&mut memory, page, error_code);
+    }
+
+    let mut regions = REGIONS.try_lock().ok_or(FaultError::MemoryManagerBusy)?;
+    let space = Cr3::read().0;
+    let region = regions
+        .iter_mut()
+        .find(|region| region.contains(page) && region.visible_in(space))
+        .ok_or(FaultError::NotInRegion)?;
+
+    match region.kind {
+        RegionKind::DemandZero => map_zeroed(&mut memory, page, region.flags),
+        RegionKind::Stack { bottom } => {
+            if page == region.start {
+                return Err(FaultError::StackOverflow);
+            }
+            if page >= bottom {
+                // Unmapped page above the bottom, probably unmapped by hand. Treat as demand zero.
+                return map_zeroed(&mut memory, page, region.flags);
+            }
+            if bottom - page > STACK_GROWTH_WINDOW {
+                return Err(FaultError::BelowStack);
+            }
+
+            for new_page in Page::range(page, bottom) {
+                map_zeroed(&mut memory, new_page, region.flags)?;
+            }
+            region.kind = RegionKind::Stack { bottom: page };
+            Ok(())
+        }
+    }
+}
+
+fn resolve_copy_on_write(
+    memory: &mut VirtualMemory,
+    page: Page,
+    error_code: PageFaultErrorCode,
+) -> Result<(), FaultError> {
+    let (frame, flags) = memory.translate_page(page).ok_or(FaultError::ProtectionViolation)?;
+    if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) || !flags.contains(COPY_ON_WRITE) {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    let new_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let mut shared = SHARED_FRAMES.try_lock().ok_or(FaultError::MemoryManagerBusy)?;
+
+    match shared.get_mut(&frame) {
+        Some(count) => {
+            // Someone else still uses the frame, write to a private copy.
+            let copy = GlobalFrameAllocator.allocate_frame().ok_or(MemoryError::OutOfFrames)?;
+            unsafe {
+                let src: *const u8 = memory.phys_to_virt(frame.start_address()).as_ptr();
+                let dst: *mut u8 = memory.phys_to_virt(copy.start_address()).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(src, dst, FRAME_SIZE as usize);
+
+                memory.unmap(page)?;
+                memory.map(page, copy, new_flags)?;
+            }
+
+            *count -= 1;
+            if *count == 1 {
+                shared.remove(&frame);
+            }
+        }
+        // Last user of the frame, it can just be made writable again.
+        None => unsafe { memory.protect(page, new_flags)? },
+    }
+    Ok(())
+}
+
+fn map_zeroed(memory: &mut VirtualMemory, page: Page, flags: PageTableFlags) -> Result<(), FaultError> {
+    Ok(memory.map_zeroed(page, flags)?)
+}
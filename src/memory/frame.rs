@@ -0,0 +1,210 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::FRAME_SIZE;
+use crate::sync::SpinLock;
+
+// -----------------------------------------------------------------------------------------------┐
+// The bootloader hands us a memory map (built from the BIOS E820 call) that describes which       |
+// physical ranges are RAM we are free to use and which are taken by the kernel image, page        |
+// tables, boot info, ACPI tables, MMIO holes and so on. Only `MemoryRegionType::Usable` regions   |
+// may ever be handed out as frames, everything else must be left alone.                           |
+// -----------------------------------------------------------------------------------------------┙
+
+/// Simple [`FrameAllocator`] that walks the usable regions of the bootloader memory map.
+///
+/// It is a bump allocator: frames are handed out in order and can never be returned.
+/// Useful for early boot code (or tests) that just needs a handful of frames.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a [`BootInfoFrameAllocator`] from the passed memory map.
+    ///
+    /// # Safety
+    /// Caller must guarantee that the memory map is valid, all frames marked as `Usable`
+    /// must really be unused, and that no other allocator hands out the same frames.
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator { memory_map, next: 0 }
+    }
+
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| region.range.start_addr()..region.range.end_addr())
+            .flat_map(|range| range.step_by(FRAME_SIZE as usize))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+/// Highest physical address the [`BitmapFrameAllocator`] keeps track of (4 GiB).
+/// Usable memory above this address is ignored.
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+
+const FRAME_COUNT: usize = (MAX_PHYSICAL_MEMORY / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = FRAME_COUNT / 64;
+
+/// Global physical frame allocator, initialized by [`memory::init`][super::init].
+pub static FRAME_ALLOCATOR: SpinLock<BitmapFrameAllocator> = SpinLock::new(BitmapFrameAllocator::new());
+
+/// [`FrameAllocator`] that tracks every physical frame with a single bit, so frames can be freed.
+///
+/// A set bit means the frame is **free**. This way the whole bitmap starts out zeroed (all frames
+/// in use) and lives in `.bss`, instead of adding 128 KiB of `0xff` bytes to the kernel image.
+pub struct BitmapFrameAllocator {
+    bitmap: [u64; BITMAP_WORDS],
+    // Word index where the next search starts, everything before it is known to be full.
+    next_word: usize,
+    free_frames: usize,
+    total_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create an empty allocator, every frame is marked as used until [`init`][Self::init].
+    pub const fn new() -> Self {
+        BitmapFrameAllocator {
+            bitmap: [0; BITMAP_WORDS],
+            next_word: 0,
+            free_frames: 0,
+            total_frames: 0,
+        }
+    }
+
+    /// Mark every frame of every `Usable` region in the memory map as free.
+    pub fn init(&mut self, memory_map: &MemoryMap) {
+        for region in memory_map.iter() {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+
+            let start = region.range.start_frame_number as usize;
+            let end = (region.range.end_frame_number as usize).min(FRAME_COUNT);
+            for index in start..end {
+                if !self.is_free(index) {
+                    self.set_free(index, true);
+                    self.free_frames += 1;
+                    self.total_frames += 1;
+                }
+            }
+        }
+        self.next_word = 0;
+    }
+
+    /// Number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames this allocator manages (free + allocated).
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Allocate a free frame that ends below physical address `limit`.
+    ///
+    /// For hardware that can only address low memory, like the real mode AP trampoline (below 1 MiB).
+    /// Frame 0 is never handed out, it holds the real mode interrupt vector table.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(FRAME_COUNT);
+        let index = (1..end).find(|&index| self.is_free(index))?;
+
+        self.set_free(index, false);
+        self.free_frames -= 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_free(&mut self, index: usize, free: bool) {
+        if free {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+        }
+    }
+}
+
+impl Default for BitmapFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let word = (self.next_word..BITMAP_WORDS).find(|&word| self.bitmap[word] != 0)?;
+        let index = word * 64 + self.bitmap[word].trailing_zeros() as usize;
+
+        self.set_free(index, false);
+        self.free_frames -= 1;
+        self.next_word = word;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Return the `frame` back to the allocator.
+    ///
+    /// Panics on double free, or when the frame was never managed by this allocator.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(index < FRAME_COUNT, "deallocating untracked frame {:?}", frame);
+        assert!(!self.is_free(index), "double free of frame {:?}", frame);
+
+        self.set_free(index, true);
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(index / 64);
+    }
+}
+
+/// Zero-sized handle to the global [`FRAME_ALLOCATOR`], so it can be passed wherever
+/// the `x86_64` crate expects a `&mut impl FrameAllocator`.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        FRAME_ALLOCATOR.lock().deallocate_frame(frame)
+    }
+}
+
+#[test_case]
+fn test_allocate_and_free_frame() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let free = allocator.free_frames();
+
+    let first = allocator.allocate_frame().expect("out of physical memory");
+    let second = allocator.allocate_frame().expect("out of physical memory");
+    assert_ne!(first, second);
+    assert_eq!(allocator.free_frames(), free - 2);
+
+    unsafe { allocator.deallocate_frame(first) };
+    assert_eq!(allocator.allocate_frame(), Some(first));
+
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
@@ -0,0 +1,307 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frame::GlobalFrameAllocator;
+use super::{FRAME_SIZE, MMIO_END, MMIO_START};
+
+// ----------------------------------------------------------------------------------------------------┐
+// With the bootloader `map_physical_memory` feature the whole physical address space is mapped at      |
+// `physical_memory_offset`. So every physical address `p` is reachable at virtual `offset + p`, which  |
+// includes page table frames themselves. `OffsetPageTable` uses exactly this to walk and edit tables.  |
+//                                                                                                      |
+//   virtual:  offset ________________ offset + p ______________________                                |
+//                    |               |          |                                                      |
+//   physical: 0 _____|_______________ p ________|______________________                                |
+// ----------------------------------------------------------------------------------------------------┙
+
+/// Error returned by [`VirtualMemory`] operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The frame allocator has no free frames left.
+    OutOfFrames,
+    /// The page is already mapped to the contained frame.
+    AlreadyMapped(PhysFrame),
+    /// The page is not mapped.
+    NotMapped,
+    /// One of the parent entries maps a huge page, so the 4 KiB entry does not exist.
+    HugePage,
+    /// A page table entry points to a physical address that is not a valid frame.
+    InvalidFrameAddress(PhysAddr),
+    /// The kernel virtual region used for the request is exhausted.
+    OutOfVirtualMemory,
+    /// The region overlaps one that is registered already (see [`fault`](super::fault)).
+    RegionOverlaps,
+    /// The address space has [`MAX_REGIONS`](super::fault::MAX_REGIONS) regions already.
+    TooManyRegions,
+}
+
+impl From<MapToError<Size4KiB>> for MemoryError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => MemoryError::OutOfFrames,
+            MapToError::ParentEntryHugePage => MemoryError::HugePage,
+            MapToError::PageAlreadyMapped(frame) => MemoryError::AlreadyMapped(frame),
+        }
+    }
+}
+
+impl From<UnmapError> for MemoryError {
+    fn from(error: UnmapError) -> Self {
+        match error {
+            UnmapError::ParentEntryHugePage => MemoryError::HugePage,
+            UnmapError::PageNotMapped => MemoryError::NotMapped,
+            UnmapError::InvalidFrameAddress(addr) => MemoryError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for MemoryError {
+    fn from(error: FlagUpdateError) -> Self {
+        match error {
+            FlagUpdateError::PageNotMapped => MemoryError::NotMapped,
+            FlagUpdateError::ParentEntryHugePage => MemoryError::HugePage,
+        }
+    }
+}
+
+// Next free address inside the MMIO window, MMIO mappings are never given back.
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Virtual memory manager for one level 4 page table.
+///
+/// Every method that changes a mapping also flushes the TLB entry of the affected page,
+/// frames for new mappings and intermediate page tables come from the global frame allocator.
+pub struct VirtualMemory {
+    page_table: OffsetPageTable<'static>,
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl VirtualMemory {
+    /// Create a manager for the level 4 table stored in `level_4_frame`.
+    ///
+    /// # Safety
+    /// All physical memory must be mapped at `physical_memory_offset`, and there must be no other
+    /// manager (or `&mut` reference) for the same page table.
+    pub unsafe fn new(level_4_frame: PhysFrame, physical_memory_offset: VirtAddr) -> Self {
+        let table_ptr = physical_memory_offset + level_4_frame.start_address().as_u64();
+        let level_4_table: &'static mut PageTable = &mut *table_ptr.as_mut_ptr();
+
+        VirtualMemory {
+            page_table: OffsetPageTable::new(level_4_table, physical_memory_offset),
+            level_4_frame,
+            physical_memory_offset,
+        }
+    }
+
+    /// Create a manager for the currently active level 4 table (the one in `Cr3`).
+    ///
+    /// # Safety
+    /// Same as [`VirtualMemory::new`].
+    pub unsafe fn active(physical_memory_offset: VirtAddr) -> Self {
+        use x86_64::registers::control::Cr3;
+
+        let (level_4_frame, _) = Cr3::read();
+        Self::new(level_4_frame, physical_memory_offset)
+    }
+
+    /// Frame of the level 4 table, what `Cr3` holds while the table is active.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Virtual address through which the physical address `addr` can be accessed.
+    pub fn phys_to_virt(&self, addr: PhysAddr) -> VirtAddr {
+        self.physical_memory_offset + addr.as_u64()
+    }
+
+    /// Access the underlying `OffsetPageTable`.
+    pub fn page_table(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.page_table
+    }
+
+    /// **Map** `page` to `frame` with the given `flags`.
+    ///
+    /// # Safety
+    /// Mapping a frame that is already used elsewhere creates aliasing, the caller must make sure
+    /// that this is intended (shared memory, MMIO) and does not break memory safety.
+    pub unsafe fn map(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        // Intermediate tables are always writable (and user accessible if the page is),
+        // the final entry decides the real permissions. Otherwise mapping a read-only page first
+        // would make every later page in the same 2 MiB/1 GiB/512 GiB range read-only too.
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+        self.page_table
+            .map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)?
+            .flush();
+        Ok(())
+    }
+
+    /// **Map** every page in `pages` to a freshly allocated frame.
+    ///
+    /// Frames are not zeroed. If mapping fails halfway, the pages mapped so far stay mapped.
+    pub fn map_range(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), MemoryError> {
+        for page in pages {
+            let frame = GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(MemoryError::OutOfFrames)?;
+            unsafe {
+                if let Err(error) = self.map(page, frame, flags) {
+                    GlobalFrameAllocator.deallocate_frame(frame);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// **Map** `page` to a freshly allocated, zeroed frame.
+    pub fn map_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MemoryError> {
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or(MemoryError::OutOfFrames)?;
+        unsafe {
+            let ptr: *mut u8 = self.phys_to_virt(frame.start_address()).as_mut_ptr();
+            core::ptr::write_bytes(ptr, 0, FRAME_SIZE as usize);
+
+            if let Err(error) = self.map(page, frame, flags) {
+                GlobalFrameAllocator.deallocate_frame(frame);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// **Unmap** `page` and return the frame it was mapped to, the frame is **not** freed.
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, MemoryError> {
+        let (frame, flush) = self.page_table.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    }
+
+    /// **Unmap** every page in `pages` and give their frames back to the frame allocator.
+    ///
+    /// # Safety
+    /// The frames must not be mapped anywhere else.
+    pub unsafe fn unmap_range_and_free(&mut self, pages: PageRangeInclusive) -> Result<(), MemoryError> {
+        for page in pages {
+            let frame = self.unmap(page)?;
+            GlobalFrameAllocator.deallocate_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// **Remap** an already mapped `page` with new `flags`, keeping the same frame.
+    ///
+    /// # Safety
+    /// Changing flags can make memory that is still referenced inaccessible (or executable).
+    pub unsafe fn protect(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MemoryError> {
+        self.page_table.update_flags(page, flags)?.flush();
+        Ok(())
+    }
+
+    /// **Translate** a virtual address into the physical address it is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.page_table.translate_addr(addr)
+    }
+
+    /// Frame and flags of the final page table entry for `page`,
+    /// `None` if the page is not mapped or mapped with a huge page.
+    pub fn translate_page(&self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        match self.page_table.translate(page.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => {
+                Some((frame, flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// **Map** `size` bytes of device memory starting at physical address `addr` into the kernel
+    /// MMIO window, and return the virtual address that corresponds to `addr`.
+    ///
+    /// Mappings are uncached, non-executable and writable, `extra_flags` are added on top.
+    ///
+    /// # Safety
+    /// `addr..addr + size` must be device memory (or memory nothing else uses).
+    pub unsafe fn map_mmio(
+        &mut self,
+        addr: PhysAddr,
+        size: u64,
+        extra_flags: PageTableFlags,
+    ) -> Result<VirtAddr, MemoryError> {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(addr);
+        let last_frame = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+        let frame_count = (last_frame - first_frame) + 1;
+
+        let start = NEXT_MMIO.fetch_add(frame_count * FRAME_SIZE, Ordering::Relaxed);
+        if start + frame_count * FRAME_SIZE > MMIO_END {
+            return Err(MemoryError::OutOfVirtualMemory);
+        }
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE
+            | extra_flags;
+
+        let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+        for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+            self.map(first_page + i as u64, frame, flags)?;
+        }
+
+        Ok(VirtAddr::new(start) + (addr - first_frame.start_address()))
+    }
+}
+
+#[test_case]
+fn test_translate_vga_buffer() {
+    let memory = super::kernel_memory().lock();
+    // VGA text buffer is identity mapped by the bootloader.
+    let phys = memory.translate(VirtAddr::new(0xb8000));
+    assert_eq!(phys, Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn test_map_unmap_page() {
+    let mut memory = super::kernel_memory().lock();
+    let page = Page::containing_address(VirtAddr::new(MMIO_END - FRAME_SIZE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+
+    unsafe { memory.map(page, frame, flags).unwrap() };
+    assert_eq!(memory.translate_page(page), Some((frame, flags)));
+    assert_eq!(unsafe { memory.map(page, frame, flags) }, Err(MemoryError::AlreadyMapped(frame)));
+
+    // Write through the new mapping, read back through the physical memory mapping.
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(0xdead_beef) };
+    let alias: *const u64 = memory.phys_to_virt(frame.start_address()).as_ptr();
+    assert_eq!(unsafe { alias.read_volatile() }, 0xdead_beef);
+
+    unsafe { memory.protect(page, PageTableFlags::PRESENT).unwrap() };
+    assert_eq!(memory.translate_page(page), Some((frame, PageTableFlags::PRESENT)));
+
+    assert_eq!(memory.unmap(page), Ok(frame));
+    assert_eq!(memory.translate(page.start_address()), None);
+    assert_eq!(memory.unmap(page), Err(MemoryError::NotMapped));
+
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
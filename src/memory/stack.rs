@@ -0,0 +1,81 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{MemoryError, VirtualMemory, FRAME_SIZE, KERNEL_STACKS_END, KERNEL_STACKS_START};
+
+// -----------------------------------------------------------------------------------------------┐
+// Kernel stacks are carved out of their own virtual region, each one sits on top of an unmapped   |
+// guard page. Overflowing a stack then page faults on the guard page instead of silently          |
+// overwriting whatever is below it.                                                               |
+//                                                                                                 |
+//   lower addresses                                                        higher addresses       |
+//   | guard | stack 0 ...... | guard | stack 1 .......... | guard | stack 2 ... | -> next free      |
+// -----------------------------------------------------------------------------------------------┙
+
+// Next free virtual address in the kernel stack region. Virtual ranges of freed stacks are not reused.
+static NEXT_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+/// A mapped kernel stack with an unmapped guard page below it.
+#[derive(Debug)]
+pub struct Stack {
+    guard: Page,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// Highest address of the stack (exclusive), the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Lowest mapped address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        (self.guard + 1).start_address()
+    }
+
+    /// The unmapped page below the stack.
+    pub fn guard_page(&self) -> Page {
+        self.guard
+    }
+
+    /// **Unmap the stack** and give its frames back to the frame allocator.
+    ///
+    /// # Safety
+    /// Nothing may be running on, or referencing, the stack anymore.
+    pub unsafe fn free(self, memory: &mut VirtualMemory) -> Result<(), MemoryError> {
+        let first = self.guard + 1;
+        let last = Page::containing_address(self.top - 1u64);
+        memory.unmap_range_and_free(Page::range_inclusive(first, last))
+    }
+}
+
+/// **Allocate a kernel stack** of `pages` pages (plus the guard page) and map it into `memory`.
+pub fn allocate_stack(memory: &mut VirtualMemory, pages: u64) -> Result<Stack, MemoryError> {
+    let size = (pages + 1) * FRAME_SIZE;
+    let start = NEXT_STACK.fetch_add(size, Ordering::Relaxed);
+    if start + size > KERNEL_STACKS_END {
+        return Err(MemoryError::OutOfVirtualMemory);
+    }
+
+    let guard = Page::containing_address(VirtAddr::new(start));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory.map_range(Page::range_inclusive(guard + 1, guard + pages), flags)?;
+
+    Ok(Stack { guard, top: VirtAddr::new(start + size) })
+}
+
+#[test_case]
+fn test_stack_has_guard_page() {
+    let mut memory = super::kernel_memory().lock();
+    let stack = allocate_stack(&mut memory, 4).unwrap();
+
+    assert_eq!(stack.top() - stack.bottom(), 4 * FRAME_SIZE);
+    assert!(memory.translate(stack.bottom()).is_some());
+    assert!(memory.translate(stack.top() - 1u64).is_some());
+    assert!(memory.translate(stack.guard_page().start_address()).is_none());
+
+    let bottom = stack.bottom();
+    unsafe { stack.free(&mut memory).unwrap() };
+    assert!(memory.translate(bottom).is_none());
+}
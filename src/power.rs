@@ -0,0 +1,253 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, Fadt, GenericAddress, SdtHeader};
+use crate::serial_println;
+use crate::sync::Once;
+
+// ---------------------------------------------------------------------------------------------------------┐
+// Powering the machine off and restarting it, each tries the proper way first and falls back to cruder     |
+// ones when that does not work:                                                                           |
+//                                                                                                         |
+//   shutdown: ACPI S5 soft off -> emulator specific ports -> halt forever                                 |
+//   reboot:   ACPI reset register -> 8042 keyboard controller reset line -> triple fault                  |
+//                                                                                                         |
+// ACPI soft off: write SLP_TYPx | SLP_EN to the PM1a (and PM1b) control register. SLP_TYP is not in a     |
+// table, it is defined by the `\_S5` package in the AML of the DSDT, which we find by scanning for it:     |
+//                                                                                                         |
+//   NameOp "_S5_" PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...                                   |
+//   0x08   5F 53 35 5F  0x12  1-4 bytes  u8  (0x0A byte | 0x00 | 0x01 | byte)                              |
+//                                                                                                         |
+// The firmware may hand us the machine in legacy mode, then ACPI has to be enabled first by writing        |
+// ACPI_ENABLE to the SMI command port and waiting for SCI_EN in PM1a control.                              |
+//                                                                                                         |
+// Everything needed is looked up in `init`, so shutdown and reboot also work from a panic handler without |
+// touching the heap or the memory manager.                                                                |
+// ---------------------------------------------------------------------------------------------------------┙
+
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+
+const PACKAGE_OP: u8 = 0x12;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+
+// 8042 keyboard controller, writing 0xFE pulses the CPU reset line.
+const KBC_STATUS_COMMAND: u16 = 0x64;
+const KBC_INPUT_BUFFER_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+// Shutdown ports of emulators without (working) ACPI: (port, value).
+const EMULATOR_SHUTDOWN: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // QEMU (q35, newer i440fx)
+    (0xB004, 0x2000), // Bochs, older QEMU
+    (0x4004, 0x3400), // VirtualBox
+];
+
+static POWER: Once<Option<AcpiPower>> = Once::new();
+
+// What ACPI shutdown and reset need, collected from the FADT and the DSDT.
+#[derive(Debug, Clone, Copy)]
+struct AcpiPower {
+    fadt: Fadt,
+    // SLP_TYPa, SLP_TYPb of `\_S5`
+    s5: Option<(u8, u8)>,
+}
+
+/// **Look up the ACPI power management registers**, needs the heap and the memory manager.
+///
+/// Without it shutdown and reboot skip straight to the fallbacks.
+pub fn init() {
+    POWER.call_once(|| {
+        let fadt = Fadt::get()?;
+        let s5 = unsafe { dsdt_aml(fadt.dsdt) }.and_then(find_s5);
+        Some(AcpiPower { fadt, s5 })
+    });
+}
+
+/// **Turn the machine off.**
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some(power) = acpi_power() {
+        match power.s5 {
+            Some(s5) => unsafe { acpi_sleep(&power.fadt, s5) },
+            None => {
+                serial_println!("power: no \\_S5 object in the DSDT");
+            }
+        }
+        delay();
+    }
+
+    for &(port, value) in EMULATOR_SHUTDOWN.iter() {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    delay();
+
+    serial_println!("power: shutdown failed, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// **Restart the machine.**
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(power) = acpi_power() {
+        if let Some(register) = power.fadt.reset_register {
+            unsafe { register.write(power.fadt.reset_value as u64) };
+            delay();
+        }
+    }
+
+    // Only machines without ACPI or with the flag have an 8042, but trying does not hurt.
+    unsafe { pulse_8042_reset() };
+    delay();
+
+    triple_fault()
+}
+
+fn acpi_power() -> Option<&'static AcpiPower> {
+    POWER.get()?.as_ref()
+}
+
+// Enter S5 through PM1a/PM1b control.
+unsafe fn acpi_sleep(fadt: &Fadt, (slp_typ_a, slp_typ_b): (u8, u8)) {
+    enable_acpi_mode(fadt);
+
+    let pm1a = fadt.pm1a_control_block;
+    pm1a.write(((slp_typ_a as u16) << SLP_TYP_SHIFT | SLP_EN) as u64);
+    let pm1b = fadt.pm1b_control_block;
+    if pm1b.is_present() {
+        pm1b.write(((slp_typ_b as u16) << SLP_TYP_SHIFT | SLP_EN) as u64);
+    }
+}
+
+unsafe fn enable_acpi_mode(fadt: &Fadt) {
+    let pm1a = fadt.pm1a_control_block;
+    // Only I/O PM1 blocks can be polled here, which is what every PC has.
+    if pm1a.address_space != GenericAddress::SYSTEM_IO || fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    let mut control = Port::<u16>::new(pm1a.address as u16);
+    if control.read() & SCI_EN != 0 {
+        return;
+    }
+    Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+    for _ in 0..1000 {
+        if control.read() & SCI_EN != 0 {
+            return;
+        }
+        delay_ms(1);
+    }
+}
+
+unsafe fn pulse_8042_reset() {
+    let mut command = Port::<u8>::new(KBC_STATUS_COMMAND);
+    // Wait until the controller can take a command.
+    for _ in 0..100_000 {
+        if command.read() & KBC_INPUT_BUFFER_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    command.write(KBC_PULSE_RESET);
+}
+
+// Load an empty IDT and raise an exception, without a handler the CPU double and then triple faults,
+// which resets it.
+fn triple_fault() -> ! {
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    unreachable!("CPU survived a triple fault");
+}
+
+// AML body of the DSDT (without the header).
+unsafe fn dsdt_aml(dsdt: PhysAddr) -> Option<&'static [u8]> {
+    if dsdt.as_u64() == 0 {
+        return None;
+    }
+    let header = acpi::read_phys::<SdtHeader>(dsdt);
+    if &header.signature != b"DSDT" {
+        return None;
+    }
+    let header_length = core::mem::size_of::<SdtHeader>();
+    let length = (header.length as usize).checked_sub(header_length)?;
+    Some(acpi::phys_slice(dsdt + header_length, length))
+}
+
+/// SLP_TYPa and SLP_TYPb of the `\_S5` package in `aml`.
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // Must be a name definition, `Name(_S5, ...)` or `Name(\_S5, ...)`.
+    let is_name = match position {
+        0 => false,
+        _ if aml[position - 1] == NAME_OP => true,
+        _ => position >= 2 && aml[position - 1] == b'\\' && aml[position - 2] == NAME_OP,
+    };
+    if !is_name {
+        return None;
+    }
+
+    let mut bytes = aml[position + 4..].iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: bits 6-7 of the lead byte say how many more bytes follow.
+    let lead = bytes.next()?;
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    let _elements = bytes.next()?;
+
+    let mut integer = || match bytes.next()? {
+        BYTE_PREFIX => bytes.next(),
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        value => Some(value),
+    };
+    let slp_typ_a = integer()?;
+    let slp_typ_b = integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+// Wait roughly `ms` milliseconds without interrupts, every access to port 0x80 takes about 1µs.
+fn delay_ms(ms: u32) {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..ms * 1000 {
+        unsafe { port.write(0) };
+    }
+}
+
+// Give the hardware time to act before trying the next method.
+fn delay() {
+    delay_ms(100);
+}
+
+#[test_case]
+fn test_find_s5() {
+    // Name(_S5, Package(0x04) { 0x05, 0x05, Zero, Zero }) as QEMU generates it.
+    let qemu = [0x10, 0x08, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, BYTE_PREFIX, 0x05, BYTE_PREFIX, 0x05, 0x00, 0x00];
+    assert_eq!(find_s5(&qemu), Some((5, 5)));
+
+    // Root path prefix, two byte PkgLength and Zero/One opcodes.
+    let rooted = [NAME_OP, b'\\', b'_', b'S', b'5', b'_', PACKAGE_OP, 0x40, 0x00, 0x02, ZERO_OP, ONE_OP];
+    assert_eq!(find_s5(&rooted), Some((0, 1)));
+
+    // A method called _S5_ is not the sleep package.
+    let method = [0x14, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x04, 0x02, 0x07, 0x07];
+    assert_eq!(find_s5(&method), None);
+    assert_eq!(find_s5(&[]), None);
+}
@@ -0,0 +1,608 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::VirtAddr;
+
+use crate::elf::{self, Elf, ElfError, LoadedProgram};
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::{AddressSpace, MemoryError};
+use crate::scheduler::{self, ThreadId};
+use crate::sync::{Once, SpinLock};
+use crate::{gdt, usermode};
+
+pub mod file;
+
+pub use file::{File, FileTable};
+
+// -----------------------------------------------------------------------------------------------------┐
+// A process is a running program: an address space (see memory/address_space.rs), a table of open      |
+// files and the threads executing in it. The scheduler only knows threads, but every thread of a       |
+// process holds its address space and a switch to it loads `Cr3`. So switching between processes       |
+// switches page tables, switching between threads of the same process leaves them alone.               |
+//                                                                                                      |
+//  spawn / fork --> running --exit, kill, fault--> exiting --last thread gone--> zombie --wait--> gone |
+//                                                                                                      |
+//  * The first `exit` sets the exit status, the other threads follow the next time they enter the      |
+//    kernel: at the end of a system call, or on a timer interrupt while they run user code.            |
+//  * Each thread leaves the address space before it terminates, so the last one frees it (and the      |
+//    files) in thread context, not the scheduler in interrupt context.                                 |
+//  * A zombie is just the pid and the exit status, kept until the parent collects them with `wait`.    |
+//    Processes spawned by the kernel have no parent, any kernel thread can wait for them.              |
+//  * Children of a process that is gone are orphans. Nobody waits for them, they are reaped as soon as |
+//    they exit (zombies right away).                                                                   |
+//                                                                                                      |
+// Everything is in one table behind one lock. It is taken with interrupts disabled, never while        |
+// blocking, and before the scheduler lock when both are needed.                                        |
+// -----------------------------------------------------------------------------------------------------┙
+
+/// Signals a process can be killed with, numbered like on Linux.
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGSEGV: u8 = 11;
+
+static PROCESSES: SpinLock<ProcessTable> = SpinLock::new(ProcessTable {
+    processes: BTreeMap::new(),
+    owners: BTreeMap::new(),
+    waiting: Vec::new(),
+});
+
+// Files of threads that run user code outside of any process.
+static CONSOLE: Once<FileTable> = Once::new();
+
+/// Unique identifier of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The pid with the number `pid`, as user code passes it to system calls.
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It called `exit` with this status.
+    Exited(u8),
+    /// It was terminated by the kernel with this signal, e.g. [`SIGSEGV`] after a page fault.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// **The status as `wait` reports it to user code**, encoded like on Linux: `status << 8` for
+    /// [`Exited`](ExitStatus::Exited), the signal number for [`Killed`](ExitStatus::Killed).
+    pub fn to_wait_status(self) -> u32 {
+        match self {
+            ExitStatus::Exited(status) => u32::from(status) << 8,
+            ExitStatus::Killed(signal) => u32::from(signal & 0x7F),
+        }
+    }
+
+    /// Decode a status encoded by [`to_wait_status`](ExitStatus::to_wait_status).
+    pub fn from_wait_status(status: u32) -> Self {
+        match status & 0x7F {
+            0 => ExitStatus::Exited((status >> 8) as u8),
+            signal => ExitStatus::Killed(signal as u8),
+        }
+    }
+
+    /// How the exception `vector` in user code ends the process.
+    pub fn from_exception(vector: u64) -> Self {
+        ExitStatus::Killed(match vector {
+            // #DE, #MF, #XM
+            0 | 16 | 19 => SIGFPE,
+            // #DB, #BP
+            1 | 3 => SIGTRAP,
+            // #UD
+            6 => SIGILL,
+            // #AC
+            17 => SIGBUS,
+            _ => SIGSEGV,
+        })
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(status) => write!(f, "exited with status {}", status),
+            ExitStatus::Killed(signal) => write!(f, "killed by signal {}", signal),
+        }
+    }
+}
+
+/// Why a process operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The calling thread does not belong to a process, or that process is exiting.
+    NotAProcess,
+    /// There is no such process (anymore), or it is not a child of the caller.
+    NoSuchProcess,
+    /// The caller has no children to wait for.
+    NoChildren,
+    /// `exec` or `fork` in a process with more than one thread: `exec` would pull the address space away
+    /// under the others, `fork` would make their pages read-only behind the back of their TLBs.
+    Busy,
+    /// The process has [`file::MAX_FILES`] files open already.
+    TooManyFiles,
+    /// The program could not be loaded.
+    Elf(ElfError),
+    Memory(MemoryError),
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(error: ElfError) -> Self {
+        ProcessError::Elf(error)
+    }
+}
+
+impl From<MemoryError> for ProcessError {
+    fn from(error: MemoryError) -> Self {
+        ProcessError::Memory(error)
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::NotAProcess => write!(f, "not called by a running process"),
+            ProcessError::NoSuchProcess => write!(f, "no such process"),
+            ProcessError::NoChildren => write!(f, "no children to wait for"),
+            ProcessError::Busy => write!(f, "the process has more than one thread"),
+            ProcessError::TooManyFiles => write!(f, "too many open files"),
+            ProcessError::Elf(error) => write!(f, "{}", error),
+            ProcessError::Memory(error) => write!(f, "{:?}", error),
+        }
+    }
+}
+
+/// Where a process is in its life, see the comment at the top.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// It has an exit status, some of its threads are still around.
+    Exiting,
+    /// All threads are gone, only the exit status is left for `wait`.
+    Zombie,
+}
+
+/// A running program, see the comment at the top.
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    // Nobody waits for it, it is removed as soon as it is a zombie.
+    orphan: bool,
+    // `None` once the last thread exited.
+    address_space: Option<Arc<AddressSpace>>,
+    files: FileTable,
+    threads: Vec<ThreadId>,
+    children: Vec<Pid>,
+    exit_status: Option<ExitStatus>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// The process that created it, `None` for processes spawned by the kernel and for orphans.
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    /// The address space, `None` once the process exited.
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>> {
+        self.address_space.as_ref()
+    }
+
+    pub fn files(&self) -> &FileTable {
+        &self.files
+    }
+
+    /// Threads that did not exit yet.
+    pub fn threads(&self) -> &[ThreadId] {
+        &self.threads
+    }
+
+    /// Children that were not reaped yet.
+    pub fn children(&self) -> &[Pid] {
+        &self.children
+    }
+
+    /// `Some` once the process exits.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status
+    }
+
+    pub fn state(&self) -> ProcessState {
+        match self.exit_status {
+            None => ProcessState::Running,
+            Some(_) if self.threads.is_empty() => ProcessState::Zombie,
+            Some(_) => ProcessState::Exiting,
+        }
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    // Process of every thread that runs user code in one.
+    owners: BTreeMap<ThreadId, Pid>,
+    // Threads blocked in `wait`, with the process whose children they wait for (`None` for kernel threads).
+    waiting: Vec<(Option<Pid>, ThreadId)>,
+}
+
+impl ProcessTable {
+    // Set the exit status of `pid` (unless it has one already) and wake its threads, so that those blocked
+    // in the kernel notice. `false` if there is no such process, or it is a zombie.
+    fn begin_exit(&mut self, pid: Pid, status: ExitStatus) -> bool {
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) if process.state() != ProcessState::Zombie => process,
+            _ => return false,
+        };
+        process.exit_status.get_or_insert(status);
+        for thread in &process.threads {
+            scheduler::wake(*thread);
+        }
+        true
+    }
+
+    // The last thread of `pid` is gone: turn it into a zombie, or reap it if nobody waits for it.
+    // Returns what it owned, the caller drops it outside of the lock.
+    fn finish(&mut self, pid: Pid) -> (Option<Arc<AddressSpace>>, FileTable) {
+        let process = self.processes.get_mut(&pid).expect("exiting process is missing");
+        let owned = (process.address_space.take(), core::mem::take(&mut process.files));
+        let children = core::mem::take(&mut process.children);
+        let (parent, orphan) = (process.parent, process.orphan);
+
+        for child in children {
+            let child_process = self.processes.get_mut(&child).expect("child process is missing");
+            child_process.parent = None;
+            child_process.orphan = true;
+            if child_process.state() == ProcessState::Zombie {
+                self.processes.remove(&child);
+            }
+        }
+
+        if orphan {
+            self.processes.remove(&pid);
+        } else {
+            self.waiting.retain(|&(waiting_for, thread)| {
+                if waiting_for == parent {
+                    scheduler::wake(thread);
+                }
+                waiting_for != parent
+            });
+        }
+        owned
+    }
+
+    // Remove the zombie `pid`, returns its exit status.
+    fn reap(&mut self, pid: Pid) -> ExitStatus {
+        let process = self.processes.remove(&pid).expect("zombie is missing");
+        if let Some(parent) = process.parent.and_then(|parent| self.processes.get_mut(&parent)) {
+            parent.children.retain(|child| *child != pid);
+        }
+        process.exit_status.expect("zombie without exit status")
+    }
+}
+
+fn with_table<R>(f: impl FnOnce(&mut ProcessTable) -> R) -> R {
+    f(&mut PROCESSES.lock())
+}
+
+/// **Run the ELF executable in `image`** as a new process.
+///
+/// Called by a process, the new one is its child and gets a copy of its file table. Processes spawned by
+/// kernel threads have no parent and start with the console (see [`file`]).
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let (address_space, program) = load(image, argv, envp)?;
+    let parent = current();
+    let files = match parent {
+        Some(parent) => with_process(parent, |process| process.files.clone()).ok_or(ProcessError::NotAProcess)?,
+        None => FileTable::console(),
+    };
+    let LoadedProgram { entry, stack_pointer } = program;
+    create(parent, address_space, files, move |address_space| {
+        scheduler::spawn_in(address_space, move || unsafe { usermode::enter(entry, stack_pointer) })
+    })
+}
+
+/// **Fork the calling process**: the child gets a copy-on-write copy of the address space, a copy of the
+/// file table and one thread, which continues the user code interrupted with `frame` (with `rax` = 0, what
+/// `fork` returns in the child). Returns the pid of the child.
+///
+/// Fails with [`ProcessError::Busy`] if the process has more than one thread, like [`exec`].
+pub fn fork(frame: &TrapFrame) -> Result<Pid, ProcessError> {
+    let parent = current().ok_or(ProcessError::NotAProcess)?;
+    let (address_space, files) = with_process(parent, |process| {
+        // Only the calling thread could add another one, so this holds until the copy is done.
+        match process.threads.len() {
+            1 => Ok((process.address_space.clone(), process.files.clone())),
+            _ => Err(ProcessError::Busy),
+        }
+    })
+    .ok_or(ProcessError::NotAProcess)??;
+    let copy = Arc::new(address_space.ok_or(ProcessError::NotAProcess)?.fork()?);
+
+    let mut frame = *frame;
+    frame.rax = 0;
+    create(Some(parent), copy, files, move |address_space| scheduler::spawn_resuming(address_space, frame))
+}
+
+/// **Replace the program of the calling process** with the ELF executable in `image`. Pid, parent and files
+/// stay, the address space is a new one.
+///
+/// Returns where the new program starts. The caller enters it with [`usermode::enter`] once it dropped
+/// everything it owns, its kernel stack is abandoned there. On error the process is left as it was.
+pub fn exec(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, ProcessError> {
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    let (address_space, program) = load(image, argv, envp)?;
+    let old = with_table(|table| {
+        let process = table.processes.get_mut(&pid).ok_or(ProcessError::NotAProcess)?;
+        if process.threads.len() > 1 {
+            return Err(ProcessError::Busy);
+        }
+        process.address_space = Some(address_space.clone());
+        Ok(scheduler::replace_address_space(Some(address_space.clone())))
+    })?;
+    // Not active anymore, the thread was its last user.
+    drop(old);
+    Ok(program)
+}
+
+/// **Wait for a child of the caller to exit** and reap it: `Some(pid)` waits for that child, `None` for any.
+/// Returns the pid of the child and how it ended.
+///
+/// The children of kernel threads are the processes spawned by the kernel.
+pub fn wait(child: Option<Pid>) -> Result<(Pid, ExitStatus), ProcessError> {
+    let parent = current();
+    loop {
+        let result = with_table(|table| {
+            if parent.and_then(|parent| table.processes.get(&parent)?.exit_status).is_some() {
+                // The caller is exiting, it has to get out of the kernel instead of waiting.
+                return Some(Err(ProcessError::NotAProcess));
+            }
+
+            let is_child = |process: &&Process| {
+                process.parent == parent && !process.orphan && (child.is_none() || child == Some(process.pid))
+            };
+            let mut children = table.processes.values().filter(is_child).peekable();
+            if children.peek().is_none() {
+                return Some(Err(if child.is_some() { ProcessError::NoSuchProcess } else { ProcessError::NoChildren }));
+            }
+            match children.find(|process| process.state() == ProcessState::Zombie).map(Process::pid) {
+                Some(zombie) => Some(Ok((zombie, table.reap(zombie)))),
+                None => {
+                    // `finish` of a child wakes the thread up again.
+                    table.waiting.push((parent, scheduler::current()));
+                    scheduler::prepare_to_block();
+                    None
+                }
+            }
+        });
+        if let Some(result) = result {
+            return result;
+        }
+        scheduler::yield_now();
+    }
+}
+
+/// **Terminate the calling process** with `status`. If it is exiting already just the calling thread
+/// terminates, the first status wins. The other threads follow the next time they enter the kernel.
+///
+/// Threads outside of any process just exit, like [`scheduler::exit`].
+pub fn exit(status: ExitStatus) -> ! {
+    let thread = scheduler::current();
+    // Leave the address space, so that it is freed here and not by the scheduler.
+    let address_space = scheduler::replace_address_space(None);
+    let owned = with_table(|table| {
+        let pid = table.owners.remove(&thread)?;
+        table.begin_exit(pid, status);
+        let process = table.processes.get_mut(&pid).expect("process of a thread is missing");
+        process.threads.retain(|other| *other != thread);
+        process.threads.is_empty().then(|| table.finish(pid))
+    });
+    drop(address_space);
+    drop(owned);
+    scheduler::exit()
+}
+
+/// **Terminate process `pid`** with `status`. Its threads exit the next time they enter the kernel, for
+/// threads running user code that's the next timer interrupt.
+pub fn kill(pid: Pid, status: ExitStatus) -> Result<(), ProcessError> {
+    with_table(|table| match table.begin_exit(pid, status) {
+        true => Ok(()),
+        false => Err(ProcessError::NoSuchProcess),
+    })
+}
+
+/// **Start another thread in process `pid`**, it enters user mode at `entry` with the stack pointer `stack`.
+pub fn spawn_thread(pid: Pid, entry: VirtAddr, stack: VirtAddr) -> Result<ThreadId, ProcessError> {
+    with_table(|table| {
+        let process = table.processes.get_mut(&pid).filter(|process| process.exit_status.is_none());
+        let process = process.ok_or(ProcessError::NoSuchProcess)?;
+        let address_space = process.address_space.clone().ok_or(ProcessError::NoSuchProcess)?;
+        let thread = scheduler::spawn_in(address_space, move || unsafe { usermode::enter(entry, stack) })?;
+        process.threads.push(thread);
+        table.owners.insert(thread, pid);
+        Ok(thread)
+    })
+}
+
+/// Process of the calling thread, `None` for kernel threads.
+pub fn current() -> Option<Pid> {
+    if !scheduler::is_initialized() {
+        return None;
+    }
+    let thread = scheduler::current();
+    with_table(|table| table.owners.get(&thread).copied())
+}
+
+/// **Run `f` with process `pid`**, `None` if there is no such process (anymore). Interrupts are
+/// disabled while `f` runs.
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&Process) -> R) -> Option<R> {
+    with_table(|table| table.processes.get(&pid).map(f))
+}
+
+/// Number of processes, zombies included.
+pub fn count() -> usize {
+    with_table(|table| table.processes.len())
+}
+
+/// **The file behind `fd`** in the file table of the calling process. Threads running user code outside
+/// of any process see the console.
+pub fn file(fd: u64) -> Option<Arc<dyn File>> {
+    match current() {
+        Some(pid) => with_process(pid, |process| process.files.get(fd)).flatten(),
+        None => CONSOLE.call_once(FileTable::console).get(fd),
+    }
+}
+
+/// **Open `file` in the calling process** on the lowest free file descriptor and return that.
+pub fn open(file: Arc<dyn File>) -> Result<u64, ProcessError> {
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    with_table(|table| table.processes.get_mut(&pid).map(|process| process.files.insert(file)))
+        .ok_or(ProcessError::NoSuchProcess)?
+        .ok_or(ProcessError::TooManyFiles)
+}
+
+/// **Close `fd`** in the calling process, returns the file it referred to. The caller drops that, the file
+/// may do some work when the last reference goes away.
+pub fn close(fd: u64) -> Option<Arc<dyn File>> {
+    let pid = current()?;
+    with_table(|table| table.processes.get_mut(&pid)?.files.remove(fd))
+}
+
+/// **Exit status of the process of the calling thread if that is exiting**. The thread should call
+/// [`exit`] instead of returning to user mode then.
+pub fn pending_exit() -> Option<ExitStatus> {
+    if !scheduler::is_initialized() {
+        return None;
+    }
+    let thread = scheduler::current();
+    with_table(|table| table.processes.get(table.owners.get(&thread)?)?.exit_status)
+}
+
+/// **Send the current thread into [`exit`]** instead of back to user mode if its process is exiting.
+/// Called on timer interrupts from user mode, with interrupts disabled.
+pub(crate) fn exit_on_return(frame: &mut TrapFrame) {
+    if let Some(status) = pending_exit() {
+        leave_through_exit(frame, status);
+    }
+}
+
+/// **Terminate the process of the current thread after an exception in user mode**, the interrupt returns
+/// into [`exit`]. `false` if the thread does not belong to a process.
+pub(crate) fn kill_from_exception(frame: &mut TrapFrame) -> bool {
+    let thread = scheduler::current();
+    let status = ExitStatus::from_exception(frame.vector);
+    let killed = with_table(|table| match table.owners.get(&thread) {
+        Some(&pid) => table.begin_exit(pid, status),
+        None => false,
+    });
+    if killed {
+        leave_through_exit(frame, status);
+    }
+    killed
+}
+
+// Parse `image` and load it into a new address space.
+fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<(Arc<AddressSpace>, LoadedProgram), ProcessError> {
+    let elf = Elf::parse(image)?;
+    let address_space = Arc::new(AddressSpace::new()?);
+    let program = elf::load(&address_space, &elf, argv, envp)?;
+    Ok((address_space, program))
+}
+
+// Add a process whose first thread is started by `spawn_thread`.
+fn create(
+    parent: Option<Pid>,
+    address_space: Arc<AddressSpace>,
+    files: FileTable,
+    spawn_thread: impl FnOnce(Arc<AddressSpace>) -> Result<ThreadId, MemoryError>,
+) -> Result<Pid, ProcessError> {
+    let pid = Pid::new();
+    with_table(|table| {
+        if let Some(parent) = parent {
+            let running = table.processes.get(&parent).map(Process::state) == Some(ProcessState::Running);
+            if !running {
+                return Err(ProcessError::NotAProcess);
+            }
+        }
+        // The thread may start on another CPU right away, the lock keeps it from looking for its process
+        // before the process is in the table.
+        let thread = spawn_thread(address_space.clone())?;
+        table.owners.insert(thread, pid);
+        table.processes.insert(
+            pid,
+            Process {
+                pid,
+                parent,
+                orphan: false,
+                address_space: Some(address_space.clone()),
+                files,
+                threads: alloc::vec![thread],
+                children: Vec::new(),
+                exit_status: None,
+            },
+        );
+        if let Some(parent) = parent.and_then(|parent| table.processes.get_mut(&parent)) {
+            parent.children.push(pid);
+        }
+        Ok(pid)
+    })
+}
+
+// Make the interrupt return into `exit_entry` in kernel mode, on the kernel stack of the thread. The user
+// registers are lost, the thread never goes back.
+fn leave_through_exit(frame: &mut TrapFrame, status: ExitStatus) {
+    let stack = scheduler::current_kernel_stack().expect("user thread without a kernel stack");
+    frame.rdi = u64::from(status.to_wait_status());
+    frame.stack_frame = InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(exit_entry as *const () as u64),
+        code_segment: u64::from(gdt::selectors().kernel_code.0),
+        cpu_flags: RFlags::INTERRUPT_FLAG.bits(),
+        // Looks like `exit_entry` was called: rsp + 8 is 16 byte aligned.
+        stack_pointer: stack - 8u64,
+        stack_segment: 0,
+    };
+}
+
+extern "C" fn exit_entry(status: u64) -> ! {
+    exit(ExitStatus::from_wait_status(status as u32))
+}
+
+#[test_case]
+fn test_wait_status_round_trip() {
+    for status in [ExitStatus::Exited(0), ExitStatus::Exited(255), ExitStatus::Killed(SIGSEGV)] {
+        assert_eq!(ExitStatus::from_wait_status(status.to_wait_status()), status);
+    }
+    assert_eq!(ExitStatus::Exited(42).to_wait_status(), 42 << 8);
+    assert_eq!(ExitStatus::from_exception(14), ExitStatus::Killed(SIGSEGV));
+    assert_eq!(ExitStatus::from_exception(6), ExitStatus::Killed(SIGILL));
+}
+
+#[test_case]
+fn test_kernel_threads_have_no_process() {
+    assert_eq!(current(), None);
+    assert_eq!(pending_exit(), None);
+    assert!(file(file::STDOUT).is_some());
+    assert_eq!(wait(None).map(|_| ()), Err(ProcessError::NoChildren));
+}
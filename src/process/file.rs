@@ -0,0 +1,186 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::sync::SpinLock;
+use crate::syscall::SyscallError;
+use crate::{print, scheduler, serial_print};
+
+// -----------------------------------------------------------------------------------------------┐
+// File descriptors are indices into the file table of a process, every entry points to an open   |
+// `File`. `fork` copies the table, so parent and child share the files behind it, like on Unix.  |
+// A file is closed once no table refers to it anymore, e.g. a pipe (see ipc.rs) reaches the end  |
+// when the write end is closed in every process. New processes start with the console:           |
+//                                                                                                |
+//   0  stdin    characters typed on the keyboard (see `push_input`)                              |
+//   1  stdout   the VGA text buffer                                                              |
+//   2  stderr   the serial port                                                                  |
+// -----------------------------------------------------------------------------------------------┙
+
+/// Standard file descriptors of a new process.
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Most files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+// Characters typed but not read yet, older ones are dropped beyond this.
+const STDIN_CAPACITY: usize = 256;
+
+static STDIN_QUEUE: SpinLock<VecDeque<u8>> = SpinLock::new(VecDeque::new());
+
+/// Something a file descriptor refers to. Every operation fails with `BadFileDescriptor` unless the
+/// file supports it: byte streams read and write, the ends of a channel send and receive messages.
+pub trait File: Send + Sync {
+    /// **Read into `buffer`**, blocks until at least one byte is available. Returns how many bytes
+    /// were read, `0` only at the end of the file.
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    /// **Write `data`**, returns how many bytes were written.
+    fn write(&self, _data: &[u8]) -> Result<usize, SyscallError> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    /// **Send `message`** as a whole, blocks while there is no room for it.
+    fn send(&self, _message: Vec<u8>) -> Result<(), SyscallError> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    /// **Receive the next message into `buffer`**, blocks until there is one unless `wait` is `false`
+    /// (fails with `WouldBlock` then). Returns its length, `0` only at the end.
+    fn recv(&self, _buffer: &mut [u8], _wait: bool) -> Result<usize, SyscallError> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+}
+
+/// The open files of a process, indexed by file descriptor.
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    /// A table with stdin, stdout and stderr connected to the console.
+    pub fn console() -> Self {
+        FileTable { files: alloc::vec![Some(Arc::new(Keyboard)), Some(Arc::new(Screen)), Some(Arc::new(Serial))] }
+    }
+
+    /// The file behind `fd`, `None` if it is not open.
+    pub fn get(&self, fd: u64) -> Option<Arc<dyn File>> {
+        self.files.get(usize::try_from(fd).ok()?)?.clone()
+    }
+
+    /// **Open `file`** on the lowest free file descriptor and return that, `None` if [`MAX_FILES`] are
+    /// open already.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Option<u64> {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Some(fd as u64)
+            }
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(Some(file));
+                Some(self.files.len() as u64 - 1)
+            }
+            None => None,
+        }
+    }
+
+    /// **Close `fd`** and return the file it referred to.
+    pub fn remove(&mut self, fd: u64) -> Option<Arc<dyn File>> {
+        self.files.get_mut(usize::try_from(fd).ok()?)?.take()
+    }
+}
+
+/// **Make `byte` available to reads from the keyboard** ([`STDIN`]), the keyboard task feeds typed
+/// characters in here.
+pub fn push_input(byte: u8) {
+    let mut queue = STDIN_QUEUE.lock();
+    if queue.len() == STDIN_CAPACITY {
+        queue.pop_front();
+    }
+    queue.push_back(byte);
+}
+
+/// Characters typed on the keyboard, every reader takes them from the same queue.
+pub struct Keyboard;
+
+impl File for Keyboard {
+    // Returns whatever is there once something is, never the end of the file. Gives up when the process
+    // of the reader is exiting.
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let count = {
+                let mut queue = STDIN_QUEUE.lock();
+                let count = queue.len().min(buffer.len());
+                for (byte, input) in buffer.iter_mut().zip(queue.drain(..count)) {
+                    *byte = input;
+                }
+                count
+            };
+            if count > 0 {
+                return Ok(count);
+            }
+            if crate::process::pending_exit().is_some() {
+                return Err(SyscallError::Interrupted);
+            }
+            scheduler::sleep(1);
+        }
+    }
+}
+
+/// The VGA text console, invalid UTF-8 shows up as U+FFFD.
+pub struct Screen;
+
+impl File for Screen {
+    fn write(&self, data: &[u8]) -> Result<usize, SyscallError> {
+        for part in data.utf8_chunks() {
+            let invalid = if part.invalid().is_empty() { "" } else { "\u{FFFD}" };
+            print!("{}{}", part.valid(), invalid);
+        }
+        Ok(data.len())
+    }
+}
+
+/// The serial port, invalid UTF-8 shows up as U+FFFD.
+pub struct Serial;
+
+impl File for Serial {
+    fn write(&self, data: &[u8]) -> Result<usize, SyscallError> {
+        for part in data.utf8_chunks() {
+            let invalid = if part.invalid().is_empty() { "" } else { "\u{FFFD}" };
+            serial_print!("{}{}", part.valid(), invalid);
+        }
+        Ok(data.len())
+    }
+}
+
+#[test_case]
+fn test_lowest_free_descriptor_is_reused() {
+    let mut table = FileTable::console();
+    assert!(table.get(STDERR).is_some());
+    assert!(table.get(3).is_none());
+    assert_eq!(table.insert(Arc::new(Serial)), Some(3));
+    assert!(table.remove(STDOUT).is_some());
+    assert!(table.remove(STDOUT).is_none());
+    assert_eq!(table.insert(Arc::new(Screen)), Some(STDOUT));
+    assert_eq!(table.insert(Arc::new(Screen)), Some(4));
+}
+
+#[test_case]
+fn test_descriptor_limit() {
+    let mut table = FileTable::console();
+    for fd in 3..MAX_FILES as u64 {
+        assert_eq!(table.insert(Arc::new(Serial)), Some(fd));
+    }
+    assert_eq!(table.insert(Arc::new(Serial)), None);
+    assert!(table.remove(5).is_some());
+    assert_eq!(table.insert(Arc::new(Serial)), Some(5));
+}
@@ -0,0 +1,360 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::{self, AddressSpace, MemoryError};
+use crate::sync::SpinLock;
+use crate::{gdt, smp, time};
+
+pub mod thread;
+
+pub use thread::{Thread, ThreadId, ThreadState};
+
+// ------------------------------------------------------------------------------------------------------┐
+// Round robin scheduler, driven by the timer interrupt.                                                 |
+//                                                                                                       |
+// Every thread has its own kernel stack. When a thread is interrupted (timer tick or `yield_now`) the    |
+// trap stub saves all of its registers into a `TrapFrame` on that stack, so the stack pointer alone is   |
+// enough to resume it later. A context switch is just returning a different stack pointer from           |
+// `trap_dispatch`, trap_common then pops the registers of the next thread and `iretq`s into it.          |
+//                                                                                                       |
+//   thread A --timer--> [trap stub saves A] -> schedule() -> rsp = B.context -> [pop B, iretq] -> thread B|
+//                                                                                                       |
+// The idle thread runs (`hlt` loop) whenever no other thread is ready, it is never in the ready queue.   |
+//                                                                                                       |
+// With several CPUs there is still one ready queue, but every CPU has its own current and idle thread.  |
+// A thread that was just switched out is still in use: the CPU is running on its stack until the        |
+// `iretq` into the next thread. So it becomes the `previous` thread of that CPU and is only put back     |
+// into the ready queue (or freed, if it exited) on the next switch of the same CPU. Otherwise another    |
+// CPU could pick it up and resume it while its registers are still being popped off that stack.         |
+//                                                                                                       |
+// A thread running user code (see usermode.rs) has no kernel stack pointer the CPU could fall back on,  |
+// interrupts from ring 3 switch to the stack in the TSS. So every switch points the TSS of the CPU at   |
+// the kernel stack of the next thread, its `TrapFrame` then ends up on top of that stack as usual.      |
+//                                                                                                       |
+// Threads of a user program also take their address space along: the switch loads `Cr3` with its level |
+// 4 table, kernel threads get the kernel one back (see memory/address_space.rs). The thread holds a     |
+// reference to the address space, so it can't go away while a CPU still runs in it.                    |
+// ------------------------------------------------------------------------------------------------------┙
+
+static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Thread>,
+    ready: VecDeque<ThreadId>,
+    // Indexed by `smp::cpu_index`, `None` for CPUs that do not run threads (yet).
+    cpus: Vec<Option<Cpu>>,
+    // Exited threads whose stacks still have to be freed.
+    dead: Vec<Thread>,
+}
+
+// Scheduling state of one CPU.
+#[derive(Clone, Copy)]
+struct Cpu {
+    current: ThreadId,
+    idle: ThreadId,
+    // Switched out by the last switch of this CPU, see the comment at the top.
+    previous: Option<ThreadId>,
+}
+
+impl Scheduler {
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        let id = thread.id;
+        if thread.state == ThreadState::Ready {
+            self.ready.push_back(id);
+        }
+        self.threads.insert(id, thread);
+        id
+    }
+
+    fn cpu(&self) -> Option<&Cpu> {
+        self.cpus.get(smp::cpu_index())?.as_ref()
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        let current = self.cpu().expect("CPU does not run threads").current;
+        self.threads.get_mut(&current).expect("current thread is missing")
+    }
+
+    // Save `context` as the current thread context and pick the next thread to run.
+    fn switch(&mut self, context: VirtAddr) -> VirtAddr {
+        let index = smp::cpu_index();
+        let mut cpu = match self.cpus.get(index) {
+            Some(Some(cpu)) => *cpu,
+            // An AP that did not register yet, keep running the boot code.
+            _ => return context,
+        };
+
+        self.reap();
+        self.wake_sleepers();
+        if let Some(previous) = cpu.previous.take() {
+            self.release(previous, cpu.idle);
+        }
+
+        let current = cpu.current;
+        let thread = self.threads.get_mut(&current).expect("current thread is missing");
+        thread.context = context;
+        if thread.state == ThreadState::Running {
+            thread.state = ThreadState::Ready;
+        }
+        let runnable = thread.state == ThreadState::Ready && current != cpu.idle;
+
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            // Nothing else to do, no need to go through the ready queue.
+            None if runnable => current,
+            None => cpu.idle,
+        };
+        if next != current {
+            cpu.previous = Some(current);
+        }
+        cpu.current = next;
+        self.cpus[index] = Some(cpu);
+
+        let thread = self.threads.get_mut(&next).expect("next thread is missing");
+        thread.state = ThreadState::Running;
+        if let Some(stack) = &thread.stack {
+            gdt::set_kernel_stack(stack.top());
+        }
+        match &thread.address_space {
+            Some(address_space) => unsafe { address_space.activate() },
+            None => memory::address_space::activate_kernel(),
+        }
+        thread.context
+    }
+
+    // `id` was switched out by the previous switch of its CPU, and that CPU no longer uses its stack.
+    fn release(&mut self, id: ThreadId, idle: ThreadId) {
+        let state = match self.threads.get(&id) {
+            Some(thread) => thread.state,
+            None => return,
+        };
+        match state {
+            ThreadState::Ready if id != idle => self.ready.push_back(id),
+            ThreadState::Exited => {
+                let thread = self.threads.remove(&id).unwrap();
+                self.dead.push(thread);
+            }
+            _ => {}
+        }
+    }
+
+    // Is `id` running, or was it just switched out, on some CPU? Then it must not enter the ready queue yet.
+    fn in_use(&self, id: ThreadId) -> bool {
+        self.cpus.iter().flatten().any(|cpu| cpu.current == id || cpu.previous == Some(id))
+    }
+
+    fn wake_sleepers(&mut self) {
+        let now = time::ticks();
+        let mut woken = Vec::new();
+        for thread in self.threads.values_mut() {
+            if let ThreadState::Sleeping { until } = thread.state {
+                if until <= now {
+                    thread.state = ThreadState::Ready;
+                    woken.push(thread.id);
+                }
+            }
+        }
+        for id in woken {
+            // Threads still in use are queued by `switch` or `release`.
+            if !self.in_use(id) {
+                self.ready.push_back(id);
+            }
+        }
+    }
+
+    // Runs in interrupt context, so it must not wait for the memory manager.
+    fn reap(&mut self) {
+        if self.dead.is_empty() {
+            return;
+        }
+        if let Some(mut memory) = memory::kernel_memory().try_lock() {
+            for thread in self.dead.drain(..) {
+                if let Some(stack) = thread.stack {
+                    unsafe { stack.free(&mut memory).expect("freeing thread stack failed") };
+                }
+            }
+        }
+    }
+}
+
+/// **Initialize the scheduler**, the code calling this becomes the first thread.
+///
+/// Needs the kernel heap. Preemption starts with the next timer interrupt.
+pub fn init() -> Result<(), MemoryError> {
+    let boot = Thread::boot();
+    let idle = Thread::new(Box::new(|| crate::htl_loop()))?;
+
+    let mut cpus = Vec::new();
+    cpus.resize(smp::cpu_index() + 1, None);
+    cpus[smp::cpu_index()] = Some(Cpu { current: boot.id, idle: idle.id, previous: None });
+    let mut scheduler = Scheduler { threads: BTreeMap::new(), ready: VecDeque::new(), cpus, dead: Vec::new() };
+    let mut idle = idle;
+    // The idle thread only runs when nothing else can, so it never enters the ready queue.
+    idle.state = ThreadState::Blocked;
+    scheduler.add(idle);
+    scheduler.add(boot);
+
+    *SCHEDULER.lock() = Some(scheduler);
+    Ok(())
+}
+
+/// **Spawn a kernel thread** running `f`, it gets scheduled after the threads already waiting.
+pub fn spawn<F>(f: F) -> Result<ThreadId, MemoryError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new(Box::new(f))?;
+    Ok(SCHEDULER.lock().as_mut().expect("scheduler is not initialized").add(thread))
+}
+
+/// **Spawn a thread in the user `address_space`**, like [`spawn`]. `f` runs in the kernel, but with the
+/// user half of `address_space` mapped, usually it enters user mode (see [`crate::usermode`]).
+pub fn spawn_in<F>(address_space: Arc<AddressSpace>, f: F) -> Result<ThreadId, MemoryError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = Thread::new(Box::new(f))?;
+    thread.address_space = Some(address_space);
+    Ok(SCHEDULER.lock().as_mut().expect("scheduler is not initialized").add(thread))
+}
+
+/// **Spawn a thread that continues the user code interrupted with `frame`**, in `address_space`.
+/// That's how the child of a `fork` starts, see [`crate::process`].
+pub fn spawn_resuming(address_space: Arc<AddressSpace>, frame: TrapFrame) -> Result<ThreadId, MemoryError> {
+    let mut thread = Thread::resuming(frame)?;
+    thread.address_space = Some(address_space);
+    Ok(SCHEDULER.lock().as_mut().expect("scheduler is not initialized").add(thread))
+}
+
+/// **Move the current thread into `address_space`** (the kernel one for `None`) and activate it right away,
+/// returns the address space the thread was in.
+///
+/// Dropping the old address space is up to the caller, outside of interrupt context.
+pub fn replace_address_space(address_space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+    let mut scheduler = SCHEDULER.lock();
+    let thread = scheduler.as_mut().expect("scheduler is not initialized").current_mut();
+    match &address_space {
+        Some(address_space) => unsafe { address_space.activate() },
+        None => memory::address_space::activate_kernel(),
+    }
+    core::mem::replace(&mut thread.address_space, address_space)
+}
+
+/// Give the CPU to the next ready thread, returns once this thread gets scheduled again.
+pub fn yield_now() {
+    crate::sync::might_sleep();
+    // int 0x81 = YIELD_VECTOR
+    unsafe { core::arch::asm!("int 0x81", options(nomem, nostack)) };
+}
+
+/// Block the current thread for at least `ticks` timer ticks.
+///
+/// See [`time::sleep`] to sleep for a [`Duration`](time::Duration).
+pub fn sleep(ticks: u64) {
+    sleep_until(time::ticks() + ticks);
+}
+
+/// Block the current thread until [`time::ticks`] reaches `tick`.
+pub fn sleep_until(tick: u64) {
+    set_current_state(ThreadState::Sleeping { until: tick });
+    yield_now();
+}
+
+/// **Mark the current thread as blocked**, it stops running at the next [`yield_now`] until [`wake`]
+/// makes it ready again.
+///
+/// Call it once the thread decided to wait, still holding the lock that protects what it waits for (with
+/// interrupts disabled), then release the lock and yield. Whoever changes the condition needs the same lock,
+/// so its `wake` can't come too early. A `wake` before the yield is not lost, the yield just returns right away.
+pub fn prepare_to_block() {
+    set_current_state(ThreadState::Blocked);
+}
+
+/// **Make the blocked thread `id` ready again**, nothing happens if it is not blocked.
+pub fn wake(id: ThreadId) {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("scheduler is not initialized");
+    let in_use = scheduler.in_use(id);
+    match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state == ThreadState::Blocked => {
+            thread.state = ThreadState::Ready;
+            // Threads still in use are queued by `switch` or `release`.
+            if !in_use {
+                scheduler.ready.push_back(id);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Terminate the current thread, its stack is freed after the next context switch.
+pub fn exit() -> ! {
+    set_current_state(ThreadState::Exited);
+    yield_now();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Id of the thread that is currently running.
+pub fn current() -> ThreadId {
+    SCHEDULER.lock().as_ref().expect("scheduler is not initialized").cpu().expect("CPU does not run threads").current
+}
+
+/// Top of the kernel stack of the current thread, `None` for threads running on a stack the scheduler
+/// did not allocate (the boot thread, the idle threads of the application processors).
+pub fn current_kernel_stack() -> Option<VirtAddr> {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("scheduler is not initialized");
+    scheduler.current_mut().stack.as_ref().map(|stack| stack.top())
+}
+
+/// State of thread `id`, `None` if the thread does not exist (anymore).
+pub fn state(id: ThreadId) -> Option<ThreadState> {
+    SCHEDULER.lock().as_ref()?.threads.get(&id).map(Thread::state)
+}
+
+/// **Run threads on this application processor**, called by [`smp`] once the CPU is set up.
+///
+/// The code calling this becomes the idle thread of the CPU, so it just halts until the first
+/// timer interrupt switches to a ready thread.
+pub(crate) fn run_application_processor() -> ! {
+    let idle = Thread::boot();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler is not initialized");
+        let index = smp::cpu_index();
+        if scheduler.cpus.len() <= index {
+            scheduler.cpus.resize(index + 1, None);
+        }
+        scheduler.cpus[index] = Some(Cpu { current: idle.id, idle: idle.id, previous: None });
+        scheduler.threads.insert(idle.id, idle);
+    }
+    x86_64::instructions::interrupts::enable();
+    crate::htl_loop()
+}
+
+/// `true` once [`init`] was called, from then on threads can block.
+pub fn is_initialized() -> bool {
+    SCHEDULER.lock().is_some()
+}
+
+fn set_current_state(state: ThreadState) {
+    SCHEDULER.lock().as_mut().expect("scheduler is not initialized").current_mut().state = state;
+}
+
+/// Terminate the current thread from an interrupt handler, returns the context to resume instead of `frame`.
+pub(crate) fn exit_from_interrupt(frame: &mut TrapFrame) -> *mut TrapFrame {
+    set_current_state(ThreadState::Exited);
+    schedule(frame)
+}
+
+/// Switch to the next thread, returns the context to resume. Called with interrupts disabled.
+pub(crate) fn schedule(frame: &mut TrapFrame) -> *mut TrapFrame {
+    match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.switch(VirtAddr::from_ptr(frame)).as_mut_ptr(),
+        None => frame,
+    }
+}
@@ -0,0 +1,151 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::{self, AddressSpace, MemoryError, Stack};
+
+/// Size of a kernel thread stack in pages (64 KiB).
+pub const THREAD_STACK_PAGES: u64 = 16;
+
+/// Unique identifier of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in the ready queue.
+    Ready,
+    /// Currently executing.
+    Running,
+    /// Not runnable until the tick counter reaches `until`.
+    Sleeping { until: u64 },
+    /// Not runnable until someone wakes it up.
+    Blocked,
+    /// Finished, its stack is freed on one of the next context switches.
+    Exited,
+}
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+/// A kernel thread: its own stack plus the registers it was interrupted with.
+pub struct Thread {
+    pub(super) id: ThreadId,
+    pub(super) state: ThreadState,
+    // Stack pointer (aka address of the saved `TrapFrame`) to resume the thread with.
+    pub(super) context: VirtAddr,
+    // `None` for the boot thread, that runs on the stack the bootloader gave us.
+    pub(super) stack: Option<Stack>,
+    // User address space the thread runs in, `None` for kernel threads.
+    pub(super) address_space: Option<Arc<AddressSpace>>,
+}
+
+impl Thread {
+    /// The thread that is already running when the scheduler starts, its context is saved on the first switch.
+    pub(super) fn boot() -> Self {
+        Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Running,
+            context: VirtAddr::zero(),
+            stack: None,
+            address_space: None,
+        }
+    }
+
+    /// Create a thread that runs `entry` on a new stack.
+    ///
+    /// The stack is prepared as if the thread got interrupted right before its first instruction,
+    /// a context switch to it "returns" from that interrupt into [`thread_entry`].
+    pub(super) fn new(entry: Entry) -> Result<Self, MemoryError> {
+        use x86_64::instructions::segmentation::{Segment, CS};
+
+        // Double boxed, so a thin pointer fits into a register.
+        let entry = Box::into_raw(Box::new(entry));
+        let thread = Self::with_frame(|stack_top| TrapFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            // First argument of thread_entry.
+            rdi: entry as u64,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vector: 0,
+            error_code: 0,
+            stack_frame: InterruptStackFrameValue {
+                instruction_pointer: VirtAddr::new(thread_entry as *const () as u64),
+                code_segment: CS::get_reg().0 as u64,
+                cpu_flags: RFlags::INTERRUPT_FLAG.bits(),
+                // Looks like `thread_entry` was called: rsp + 8 is 16 byte aligned.
+                stack_pointer: stack_top - 8u64,
+                stack_segment: 0,
+            },
+        });
+        if thread.is_err() {
+            drop(unsafe { Box::from_raw(entry) });
+        }
+        thread
+    }
+
+    /// Create a thread that continues user code interrupted with `frame`, e.g. the child of a `fork`.
+    ///
+    /// The frame ends up on top of the new kernel stack, right where an interrupt from user mode puts it,
+    /// so the first switch to the thread returns straight into user mode.
+    pub(super) fn resuming(frame: TrapFrame) -> Result<Self, MemoryError> {
+        Self::with_frame(|_| frame)
+    }
+
+    // A ready thread on a new stack, with the frame `make_frame(stack top)` on top to resume it with.
+    fn with_frame(make_frame: impl FnOnce(VirtAddr) -> TrapFrame) -> Result<Self, MemoryError> {
+        let stack = memory::allocate_stack(&mut memory::kernel_memory().lock(), THREAD_STACK_PAGES)?;
+
+        let frame_addr = stack.top() - mem::size_of::<TrapFrame>();
+        unsafe { frame_addr.as_mut_ptr::<TrapFrame>().write(make_frame(stack.top())) };
+
+        Ok(Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Ready,
+            context: frame_addr,
+            stack: Some(stack),
+            address_space: None,
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+}
+
+// First code every spawned thread runs, with interrupts enabled.
+extern "C" fn thread_entry(entry: *mut Entry) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    super::exit()
+}
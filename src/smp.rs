@@ -0,0 +1,168 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::FrameDeallocator;
+use x86_64::PhysAddr;
+
+use crate::interrupts::apic;
+use crate::memory::{self, MemoryError, FRAME_ALLOCATOR};
+use crate::{gdt, scheduler, serial_println, time};
+
+pub mod percpu;
+mod trampoline;
+
+pub use percpu::PerCpu;
+use trampoline::{Trampoline, LOW_MEMORY_END};
+
+// ---------------------------------------------------------------------------------------------------------┐
+// Symmetric multiprocessing: bringing up the other CPU cores (application processors, APs).                |
+//                                                                                                         |
+// After reset only the boot CPU (BSP) runs, every AP waits for a startup IPI from a local APIC:            |
+//                                                                                                         |
+//   BSP                                        AP                                                         |
+//   INIT IPI           -------------------->   reset, wait for SIPI                                        |
+//   wait 10 ms                                                                                             |
+//   startup IPI (vector = trampoline page) ->  real mode at vector << 12 -> ... -> long mode (trampoline.rs)|
+//   wait 200 µs, startup IPI again             (the second one is ignored if the first one worked)         |
+//                                              `ap_entry`: GS base, GDT/TSS, IDT, syscall, local APIC     |
+//   wait for `AP_READY`  <-----------------    AP_READY = true                                            |
+//                                              `scheduler::run_application_processor`, never returns       |
+//                                                                                                         |
+// APs are started one at a time, they all boot through the same trampoline data block. Which CPUs exist   |
+// comes from the MADT, its processor entries list every core by local APIC id.                          |
+// ---------------------------------------------------------------------------------------------------------┙
+
+/// Size of the boot stack of an AP in pages, it later becomes the stack of its idle thread.
+const AP_STACK_PAGES: u64 = scheduler::thread::THREAD_STACK_PAGES;
+/// How long an AP gets to report in before it is given up on.
+const AP_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+
+// Number of CPUs that are up, the boot CPU included.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+// Set by the AP that is currently booting, once it is done with the trampoline.
+static AP_READY: AtomicBool = AtomicBool::new(false);
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Why the application processors could not be started.
+#[derive(Debug)]
+pub enum SmpError {
+    /// Startup IPIs are sent through the local APIC, see [`apic::init`].
+    ApicDisabled,
+    /// The APs run threads from the start, see [`scheduler::init`].
+    SchedulerNotInitialized,
+    /// Timer interrupts are needed for the delays between the IPIs.
+    InterruptsDisabled,
+    AlreadyStarted,
+    /// No free frame below 1 MiB for the trampoline.
+    NoLowMemory,
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for SmpError {
+    fn from(error: MemoryError) -> Self {
+        SmpError::Memory(error)
+    }
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::ApicDisabled => write!(f, "local APIC is not enabled"),
+            SmpError::SchedulerNotInitialized => write!(f, "scheduler is not initialized"),
+            SmpError::InterruptsDisabled => write!(f, "interrupts must be enabled"),
+            SmpError::AlreadyStarted => write!(f, "application processors were already started"),
+            SmpError::NoLowMemory => write!(f, "no free frame below 1 MiB for the trampoline"),
+            SmpError::Memory(error) => write!(f, "memory error: {:?}", error),
+        }
+    }
+}
+
+/// **Start every application processor** listed in the MADT, returns the number of CPUs online.
+///
+/// The boot CPU switches to its own per CPU GDT and TSS as well, if it did not yet. Cores that do not come up within
+/// a timeout are reported on serial and skipped.
+pub fn init() -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::ApicDisabled);
+    }
+    if !scheduler::is_initialized() {
+        return Err(SmpError::SchedulerNotInitialized);
+    }
+    if !interrupts::are_enabled() {
+        return Err(SmpError::InterruptsDisabled);
+    }
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return Err(SmpError::AlreadyStarted);
+    }
+
+    if percpu::current().tss().is_null() {
+        gdt::init_cpu()?;
+    }
+
+    let local_apic = apic::local_apic().ok_or(SmpError::ApicDisabled)?;
+    let madt = apic::madt().ok_or(SmpError::ApicDisabled)?;
+    let bsp_apic_id = local_apic.id();
+
+    let frame = FRAME_ALLOCATOR.lock().allocate_frame_below(PhysAddr::new(LOW_MEMORY_END)).ok_or(SmpError::NoLowMemory)?;
+    let trampoline = unsafe { Trampoline::install(&mut memory::kernel_memory().lock(), frame)? };
+
+    for processor in madt.processors.iter().filter(|p| p.usable && p.apic_id != bsp_apic_id) {
+        let index = ONLINE.load(Ordering::Acquire);
+        let stack = memory::allocate_stack(&mut memory::kernel_memory().lock(), AP_STACK_PAGES)?;
+        let per_cpu = PerCpu::leak(index, processor.apic_id);
+
+        trampoline.prepare(&memory::kernel_memory().lock(), stack.top(), ap_entry, per_cpu as *const PerCpu as u64);
+        AP_READY.store(false, Ordering::Release);
+
+        local_apic.send_init(processor.apic_id);
+        time::spin_wait(time::Duration::from_millis(10));
+        for _ in 0..2 {
+            local_apic.send_startup(processor.apic_id, trampoline.vector());
+            time::spin_wait(time::Duration::from_micros(200));
+            if AP_READY.load(Ordering::Acquire) {
+                break;
+            }
+        }
+
+        let deadline = time::Instant::now() + AP_TIMEOUT;
+        while !AP_READY.load(Ordering::Acquire) && time::Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if AP_READY.load(Ordering::Acquire) {
+            ONLINE.fetch_add(1, Ordering::AcqRel);
+        } else {
+            // Park it again, its stack and per CPU data are leaked.
+            local_apic.send_init(processor.apic_id);
+            serial_println!("smp: CPU with APIC id {} did not start", processor.apic_id);
+        }
+    }
+
+    let frame = trampoline.remove(&mut memory::kernel_memory().lock())?;
+    unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+    Ok(cpu_count())
+}
+
+/// Number of CPUs that are running, 1 until [`init`] started the others.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// [`PerCpu::index`] of the executing CPU, see [`percpu::current`] for when that is stable.
+pub fn cpu_index() -> usize {
+    percpu::index()
+}
+
+// First Rust code of an AP, called by the trampoline on the boot stack with interrupts disabled.
+extern "C" fn ap_entry(per_cpu: u64) -> ! {
+    let per_cpu = unsafe { &*(per_cpu as *const PerCpu) };
+    percpu::install(per_cpu);
+
+    // The trampoline GDT is in the frame that is freed once all APs are up.
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    crate::interrupts::init_idt();
+    crate::syscall::init_cpu();
+    apic::init_application_processor();
+
+    AP_READY.store(true, Ordering::Release);
+    scheduler::run_application_processor()
+}
@@ -0,0 +1,197 @@
+use core::ptr;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::memory::AddressSpace;
+
+// ---------------------------------------------------------------------------------------------------┐
+// Every CPU points its GS base MSR at its own `PerCpu`. The first field is a pointer to the struct    |
+// itself, so `mov reg, gs:[0]` gives a normal reference without reading the (slow) MSR:             |
+//                                                                                                    |
+//   CPU 0: GS base ---> PerCpu { self_ptr: ---+, index: 0, apic_id: 0, ... }                         |
+//                          ^------------------+                                                      |
+//   CPU 1: GS base ---> PerCpu { self_ptr, index: 1, apic_id: 1, ... }       (heap, leaked)          |
+//                                                                                                    |
+// The boot CPU uses a static, so per CPU data works from `crate::init` on, before there is a heap.   |
+// Application processors get theirs from `smp::init`, before they run any other Rust code.           |
+//                                                                                                    |
+// A thread can be moved to another CPU on every interrupt, so whatever is read from `current()` is   |
+// only guaranteed to be about the CPU the thread is still running on while interrupts are disabled.  |
+//                                                                                                    |
+// While a CPU runs user code its GS base belongs to the user program, the pointer to `PerCpu` waits  |
+// in the KernelGsBase MSR until `swapgs` on the next kernel entry (see interrupts/trap.rs).           |
+// ---------------------------------------------------------------------------------------------------┙
+
+static BSP: PerCpu = PerCpu::new(&BSP, 0, 0);
+// Set once the boot CPU has its GS base, before that `current` falls back to `BSP`.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Data that every CPU has its own copy of.
+#[repr(C)]
+pub struct PerCpu {
+    // Must stay the first field, see `current`.
+    self_ptr: *const PerCpu,
+    index: usize,
+    apic_id: AtomicU8,
+    tss: AtomicPtr<TaskStateSegment>,
+    // Read by the `syscall` entry, see `KERNEL_STACK_OFFSET`.
+    kernel_stack: AtomicU64,
+    user_stack: AtomicU64,
+    address_space: AtomicPtr<AddressSpace>,
+    // Hardware interrupt handlers currently running, see `InterruptContext`.
+    interrupt_depth: AtomicU32,
+    // Spin locks the CPU holds, only touched with interrupts disabled (see sync/lockdep.rs).
+    #[cfg(debug_assertions)]
+    held_locks: core::cell::UnsafeCell<crate::sync::lockdep::HeldLocks>,
+}
+
+/// Offset of the kernel stack top of the running thread, `syscall` switches to it with `mov rsp, gs:[offset]`.
+pub(crate) const KERNEL_STACK_OFFSET: usize = mem::offset_of!(PerCpu, kernel_stack);
+/// Offset of a scratch slot for the user stack pointer, while `syscall` switches stacks.
+pub(crate) const USER_STACK_OFFSET: usize = mem::offset_of!(PerCpu, user_stack);
+
+// Only the owning CPU writes to its `PerCpu` (through atomics), `self_ptr` never changes once shared.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new(self_ptr: *const PerCpu, index: usize, apic_id: u8) -> Self {
+        PerCpu {
+            self_ptr,
+            index,
+            apic_id: AtomicU8::new(apic_id),
+            tss: AtomicPtr::new(ptr::null_mut()),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            address_space: AtomicPtr::new(ptr::null_mut()),
+            interrupt_depth: AtomicU32::new(0),
+            #[cfg(debug_assertions)]
+            held_locks: core::cell::UnsafeCell::new(crate::sync::lockdep::HeldLocks::new()),
+        }
+    }
+
+    /// Per CPU data for the application processor `apic_id`, it is never freed.
+    pub(crate) fn leak(index: usize, apic_id: u8) -> &'static PerCpu {
+        use alloc::boxed::Box;
+
+        let per_cpu: &'static mut PerCpu = Box::leak(Box::new(PerCpu::new(ptr::null(), index, apic_id)));
+        per_cpu.self_ptr = per_cpu;
+        per_cpu
+    }
+
+    /// Number of the CPU, the boot CPU is 0 and the others are numbered in the order they came up.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Id of the local APIC of the CPU, this is how interrupts and IPIs address it.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// The TSS of the CPU, null until [`gdt::init_cpu`](crate::gdt::init_cpu) ran on it.
+    pub fn tss(&self) -> *mut TaskStateSegment {
+        self.tss.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Release);
+    }
+
+    /// Top of the kernel stack of the thread running on the CPU, see [`gdt::set_kernel_stack`](crate::gdt::set_kernel_stack).
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_kernel_stack(&self, top: VirtAddr) {
+        self.kernel_stack.store(top.as_u64(), Ordering::Relaxed);
+    }
+
+    /// The user address space the CPU runs in, null for the kernel page table. See [`AddressSpace::activate`].
+    pub fn address_space(&self) -> *const AddressSpace {
+        self.address_space.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_address_space(&self, address_space: *const AddressSpace) {
+        self.address_space.store(address_space as *mut AddressSpace, Ordering::Relaxed);
+    }
+
+    /// `true` while the CPU handles a hardware interrupt, code that may block must not run then.
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.load(Ordering::Relaxed) > 0
+    }
+
+    /// The spin locks the CPU holds, see [`lockdep`](crate::sync::lockdep).
+    ///
+    /// # Safety
+    /// Interrupts have to stay disabled while the reference is used, and it must be the only one.
+    #[cfg(debug_assertions)]
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn held_locks(&self) -> &mut crate::sync::lockdep::HeldLocks {
+        &mut *self.held_locks.get()
+    }
+}
+
+/// **Marks the executing CPU as handling a hardware interrupt** until dropped, see [`PerCpu::in_interrupt`].
+/// Interrupt handlers create one first thing.
+pub(crate) struct InterruptContext(&'static PerCpu);
+
+impl InterruptContext {
+    pub(crate) fn enter() -> Self {
+        let per_cpu = current();
+        per_cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        InterruptContext(per_cpu)
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        self.0.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// **Point GS base of the boot CPU at its per CPU data**, called by [`crate::init`].
+pub fn init_bsp() {
+    // The LAPIC is not mapped this early, but CPUID knows the initial APIC id too (leaf 1, EBX 31:24).
+    #[allow(unused_unsafe)]
+    let apic_id = (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as u8;
+    BSP.apic_id.store(apic_id, Ordering::Relaxed);
+
+    install(&BSP);
+    INSTALLED.store(true, Ordering::Release);
+}
+
+/// Make `per_cpu` the per CPU data of the executing CPU.
+pub(crate) fn install(per_cpu: &'static PerCpu) {
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+}
+
+/// **Per CPU data of the executing CPU.**
+pub fn current() -> &'static PerCpu {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return &BSP;
+    }
+    let per_cpu: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) per_cpu, options(nostack, readonly, preserves_flags));
+        &*per_cpu
+    }
+}
+
+/// [`PerCpu::index`] of the executing CPU.
+pub fn index() -> usize {
+    current().index()
+}
+
+#[test_case]
+fn test_boot_cpu_per_cpu_data() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let per_cpu = current();
+        // The test kernel never starts the other CPUs.
+        assert_eq!(per_cpu.index(), 0);
+        assert!(ptr::eq(per_cpu, &BSP));
+        assert_eq!(GsBase::read(), VirtAddr::from_ptr(&BSP));
+    });
+}
@@ -0,0 +1,213 @@
+use core::arch::global_asm;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{MemoryError, VirtualMemory, FRAME_SIZE};
+
+// -----------------------------------------------------------------------------------------------------------┐
+// An application processor (AP) starts the same way the whole PC did: in 16 bit real mode, at the physical    |
+// address `vector << 12` given by the startup IPI, so that code has to live in the first MiB. The trampoline |
+// is assembled into the kernel image and copied to a free low frame before the APs are started, it then does |
+// what the bootloader did for the boot CPU:                                                                  |
+//                                                                                                            |
+//   16 bit real mode     cli, load the trampoline GDT, set CR0.PE, far jump into 32 bit code                 |
+//   32 bit protected     CR4 (PAE), CR3 (kernel page table), EFER (LME, NXE), CR0 (PG) = long mode           |
+//                        far jump into 64 bit code                                                           |
+//   64 bit long mode     load the stack pointer and call `entry(argument)`, a normal Rust function            |
+//                                                                                                            |
+// Nothing in it may depend on where it was copied to. Real mode code is addressed relative to CS (= the       |
+// frame), after that `ebx` holds the physical base. Whatever depends on the base (the far jump targets and    |
+// the GDT address) is patched into the data block at offset 8 by `Trampoline::prepare`, together with the    |
+// values the boot CPU has in its control registers.                                                          |
+//                                                                                                            |
+// The trampoline frame is identity mapped while the APs boot: right after paging is enabled the CPU still    |
+// fetches instructions from the low physical address, now through the kernel page table.                     |
+// -----------------------------------------------------------------------------------------------------------┙
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "jmp 2f",
+    // Data block at offset 8, see `TrampolineData`. The numbers below are offsets into it.
+    ".balign 8",
+    ".global ap_trampoline_data",
+    "ap_trampoline_data:",
+    ".fill 10, 8, 0",
+    ".global ap_trampoline_gdt",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF", // 0x08: 32 bit code, base 0, limit 4 GiB
+    ".quad 0x00CF92000000FFFF", // 0x10: 32 bit data, base 0, limit 4 GiB
+    ".quad 0x00AF9A000000FFFF", // 0x18: 64 bit code
+    "2:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lgdt [24]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // jmp far dword [8], with the operand size prefix for a 32 bit offset
+    ".byte 0x66, 0xFF, 0x2E",
+    ".word 8",
+    ".code32",
+    ".global ap_trampoline_32",
+    "ap_trampoline_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov eax, [ebx + 40]",
+    "mov cr4, eax",
+    "mov eax, [ebx + 32]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, [ebx + 48]",
+    "xor edx, edx",
+    "wrmsr",
+    "mov eax, [ebx + 56]",
+    "mov cr0, eax",
+    "jmp fword ptr [ebx + 16]",
+    ".code64",
+    ".global ap_trampoline_64",
+    "ap_trampoline_64:",
+    // The upper halves of the registers are undefined after the mode switch.
+    "mov ebx, ebx",
+    "xor eax, eax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, qword ptr [rbx + 64]",
+    "mov rdi, qword ptr [rbx + 80]",
+    "call qword ptr [rbx + 72]",
+    "3:",
+    "hlt",
+    "jmp 3b",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_32: u8;
+    static ap_trampoline_64: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+const CODE_32_SELECTOR: u64 = 0x08;
+const CODE_64_SELECTOR: u64 = 0x18;
+const GDT_ENTRIES: u64 = 4;
+
+/// Highest physical address the trampoline may end at, real mode can't reach further.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+// Offset of the data block from the start of the trampoline.
+const DATA_OFFSET: u64 = 8;
+
+// The data block at `ap_trampoline_data`, offsets are hardcoded in the assembly above.
+#[repr(C)]
+struct TrampolineData {
+    // m16:32 far pointers: 32 bit offset, then the 16 bit selector.
+    far_jump_32: u64,
+    far_jump_64: u64,
+    // lgdt operand: 16 bit limit, then the 32 bit base.
+    gdtr: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    cr0: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+// Offset of `symbol` from the start of the trampoline.
+fn offset_of(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 }
+}
+
+/// The AP trampoline, copied to a frame below 1 MiB.
+pub struct Trampoline {
+    frame: PhysFrame,
+    // `true` if the identity mapping was created by us, and has to be removed again.
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// **Copy the trampoline into `frame`** and identity map it.
+    ///
+    /// # Safety
+    /// `frame` must be an unused frame below [`LOW_MEMORY_END`].
+    pub unsafe fn install(memory: &mut VirtualMemory, frame: PhysFrame) -> Result<Self, MemoryError> {
+        let size = offset_of(&ap_trampoline_end);
+        assert!(size <= FRAME_SIZE, "AP trampoline does not fit into one frame");
+        assert_eq!(offset_of(&ap_trampoline_data), DATA_OFFSET, "AP trampoline data block moved");
+        assert!(frame.start_address().as_u64() + FRAME_SIZE <= LOW_MEMORY_END);
+
+        let destination = memory.phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(&ap_trampoline_start as *const u8, destination, size as usize);
+
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        let mapped = match memory.translate_page(page) {
+            Some((mapped, _)) if mapped == frame => false,
+            Some(_) => return Err(MemoryError::AlreadyMapped(frame)),
+            None => {
+                memory.map(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?;
+                true
+            }
+        };
+        Ok(Trampoline { frame, mapped })
+    }
+
+    /// Startup IPI vector that makes a CPU execute the trampoline.
+    pub fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// **Fill in the data block** for the next AP: it runs `entry(argument)` on `stack_top`.
+    ///
+    /// Control registers are copied from the executing CPU, so the AP ends up with the same paging setup.
+    pub fn prepare(&self, memory: &VirtualMemory, stack_top: VirtAddr, entry: extern "C" fn(u64) -> !, argument: u64) {
+        let base = self.frame.start_address().as_u64();
+        let (level_4_frame, cr3_flags) = Cr3::read();
+        let cr3 = level_4_frame.start_address().as_u64() | cr3_flags.bits();
+        assert!(cr3 <= u32::MAX as u64, "kernel page table is above 4 GiB, 32 bit code can't load it");
+
+        let gdt_limit = GDT_ENTRIES * 8 - 1;
+        let data = TrampolineData {
+            far_jump_32: (base + offset_of(unsafe { &ap_trampoline_32 })) | CODE_32_SELECTOR << 32,
+            far_jump_64: (base + offset_of(unsafe { &ap_trampoline_64 })) | CODE_64_SELECTOR << 32,
+            gdtr: gdt_limit | (base + offset_of(unsafe { &ap_trampoline_gdt })) << 16,
+            cr3,
+            // PCIDs can only be turned on once long mode is active.
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+            // LMA is set by the CPU when paging gets enabled.
+            efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+            cr0: Cr0::read().bits(),
+            stack_top: stack_top.as_u64(),
+            entry: entry as usize as u64,
+            argument,
+        };
+        let address = memory.phys_to_virt(PhysAddr::new(base + DATA_OFFSET));
+        unsafe { address.as_mut_ptr::<TrampolineData>().write_volatile(data) };
+    }
+
+    /// **Remove the identity mapping** and give the frame back, once every AP is past the trampoline.
+    pub fn remove(self, memory: &mut VirtualMemory) -> Result<PhysFrame, MemoryError> {
+        if self.mapped {
+            let page = Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+            memory.unmap(page)?;
+        }
+        Ok(self.frame)
+    }
+}
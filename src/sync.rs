@@ -0,0 +1,44 @@
+pub mod condvar;
+#[cfg(debug_assertions)]
+pub mod lockdep;
+pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use once::{Lazy, Once};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use wait_queue::WaitQueue;
+
+// ---------------------------------------------------------------------------------------------------------┐
+// Locks for the kernel, two families:                                                                      |
+//                                                                                                          |
+//   SpinLock          busy waits, and disables interrupts while it is held (restoring RFLAGS.IF after).    |
+//                     An interrupt handler on the same CPU can't deadlock on it, so it is the lock for     |
+//                     everything interrupt handlers touch: the VGA writer, the serial port, the PICs,      |
+//                     the scheduler. Hold it briefly and never block with it.                              |
+//   Mutex, RwLock,    sleep: a thread that has to wait is blocked in the scheduler and woken up when the   |
+//   Semaphore,        lock is released, so they can be held for long and across blocking operations. They  |
+//   Condvar           need a thread to block, never use them from interrupt handlers.                      |
+//                                                                                                          |
+// Once and Lazy initialize a value on first use, they replace lazy_static.                                 |
+//                                                                                                          |
+// Debug builds check how the locks are used (see lockdep.rs): taking a spin lock the CPU already holds     |
+// (e.g. in an interrupt handler that interrupted its holder), taking two spin locks in both orders, and    |
+// sleeping in an interrupt handler or while holding a spin lock all panic right away, instead of           |
+// deadlocking some day.                                                                                    |
+// ---------------------------------------------------------------------------------------------------------┙
+
+/// **Check that the current thread may block**: not in an interrupt handler, no spin lock held. Only checks
+/// in debug builds.
+#[inline]
+pub fn might_sleep() {
+    #[cfg(debug_assertions)]
+    lockdep::might_sleep();
+}
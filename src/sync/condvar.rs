@@ -0,0 +1,62 @@
+use x86_64::instructions::interrupts;
+
+use super::{might_sleep, MutexGuard, SpinLock, WaitQueue};
+use crate::scheduler;
+
+/// **Lets threads wait for a condition on data behind a [`Mutex`](super::Mutex)**. Whoever changes the
+/// data does so holding the mutex and calls [`notify_one`](Self::notify_one) or
+/// [`notify_all`](Self::notify_all) after.
+pub struct Condvar {
+    waiters: SpinLock<WaitQueue>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { waiters: SpinLock::new(WaitQueue::new()) }
+    }
+
+    /// **Release the mutex of `guard` and block until notified**, returns with the mutex taken again.
+    ///
+    /// Wakeups can be spurious, check the condition in a loop or use [`wait_while`](Self::wait_while).
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        might_sleep();
+        let mutex = MutexGuard::mutex(&guard);
+        // Queued before the mutex is released, a notification right after is not lost. And not preempted in
+        // between, the thread must not stop running while it still holds the mutex.
+        interrupts::without_interrupts(|| {
+            self.waiters.lock().prepare_to_wait();
+            drop(guard);
+        });
+        scheduler::yield_now();
+        self.waiters.lock().cancel();
+        mutex.lock()
+    }
+
+    /// **Wait until `condition` is `false`**, it is checked with the mutex held.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// **Wake one waiting thread**, nothing happens if there is none.
+    pub fn notify_one(&self) {
+        self.waiters.lock().wake_one();
+    }
+
+    /// **Wake every waiting thread.**
+    pub fn notify_all(&self) {
+        self.waiters.lock().wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
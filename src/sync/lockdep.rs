@@ -0,0 +1,175 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::smp::percpu;
+
+// ------------------------------------------------------------------------------------------------------┐
+// Lock checking of debug builds, a small version of Linux' lockdep. Every CPU keeps a stack of the      |
+// spin locks it holds (in its `PerCpu`). Locks are grouped into classes, one per type of the protected  |
+// value: all pipes share the class of `PipeState`, the scheduler has its own.                           |
+//                                                                                                       |
+//  * Taking a lock the CPU already holds is reported before it spins forever. With interrupts disabled  |
+//    by `SpinLock` this happens when code recurses into a lock, or an NMI or exception handler runs     |
+//    into a lock the interrupted code held.                                                             |
+//  * Taking lock B while holding lock A records "A before B". Once both orders were seen, two CPUs can  |
+//    deadlock taking them at the same time, even if it did not happen yet. That is reported the first   |
+//    time the second order shows up.                                                                    |
+//  * Releasing a lock that restores interrupts while other locks are still held is reported: an         |
+//    interrupt handler could then spin on them (release spin locks in reverse order).                   |
+//  * Blocking in an interrupt handler or while holding a spin lock is reported (`might_sleep`).         |
+//                                                                                                       |
+// A report is a panic, after the first one the checks are off so the panic can still print.             |
+// ------------------------------------------------------------------------------------------------------┙
+
+// Locks a CPU holds at once, and orderings remembered.
+const MAX_HELD: usize = 16;
+const MAX_ORDERS: usize = 256;
+
+static REPORTED: AtomicBool = AtomicBool::new(false);
+// The lock of the recorded orders is a `spin::Mutex`, it can't check itself.
+static ORDERS: spin::Mutex<Orders> = spin::Mutex::new(Orders { pairs: [("", ""); MAX_ORDERS], len: 0 });
+
+/// The spin locks held by a CPU, innermost last.
+pub struct HeldLocks {
+    locks: [(usize, &'static str); MAX_HELD],
+    len: usize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        HeldLocks { locks: [(0, ""); MAX_HELD], len: 0 }
+    }
+
+    fn iter(&self) -> core::slice::Iter<'_, (usize, &'static str)> {
+        self.locks[..self.len].iter()
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// "first before second" pairs of lock classes.
+struct Orders {
+    pairs: [(&'static str, &'static str); MAX_ORDERS],
+    len: usize,
+}
+
+impl Orders {
+    fn contains(&self, first: &str, second: &str) -> bool {
+        self.pairs[..self.len].iter().any(|&(a, b)| a == first && b == second)
+    }
+
+    // Remember the order, once there is no more room new ones are not checked anymore.
+    fn insert(&mut self, first: &'static str, second: &'static str) {
+        if self.len < MAX_ORDERS && !self.contains(first, second) {
+            self.pairs[self.len] = (first, second);
+            self.len += 1;
+        }
+    }
+}
+
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        REPORTED.store(true, Ordering::Relaxed);
+        panic!("lockdep: {}", format_args!($($arg)*))
+    }};
+}
+
+fn checking() -> bool {
+    !REPORTED.load(Ordering::Relaxed)
+}
+
+fn context() -> &'static str {
+    if percpu::current().in_interrupt() {
+        " in an interrupt handler"
+    } else {
+        ""
+    }
+}
+
+/// **Check that the lock at `address` can be taken** before spinning on it. Called with interrupts disabled.
+pub(super) fn acquire(address: usize, class: &'static str) {
+    if !checking() {
+        return;
+    }
+    let held = unsafe { percpu::current().held_locks() };
+    if held.iter().any(|&(held, _)| held == address) {
+        report!("re-entrant locking of {}{}", class, context());
+    }
+
+    let mut inversion = None;
+    {
+        let mut orders = ORDERS.lock();
+        for &(_, outer) in held.iter().filter(|&&(_, outer)| outer != class) {
+            if orders.contains(class, outer) {
+                inversion = Some(outer);
+                break;
+            }
+            orders.insert(outer, class);
+        }
+    }
+    if let Some(outer) = inversion {
+        report!("lock order inversion: {} taken while holding {}, elsewhere it is the other way around", class, outer);
+    }
+}
+
+/// **The lock at `address` was taken.** Called with interrupts disabled.
+pub(super) fn acquired(address: usize, class: &'static str) {
+    if !checking() {
+        return;
+    }
+    let held = unsafe { percpu::current().held_locks() };
+    if held.len == MAX_HELD {
+        report!("more than {} spin locks held, taking {}", MAX_HELD, class);
+    }
+    held.locks[held.len] = (address, class);
+    held.len += 1;
+}
+
+/// **The lock at `address` is released**, `enables_interrupts` if the release turns interrupts back on. Called
+/// with interrupts disabled.
+pub(super) fn release(address: usize, enables_interrupts: bool) {
+    if !checking() {
+        return;
+    }
+    let held = unsafe { percpu::current().held_locks() };
+    // Not necessarily the innermost one, guards can be dropped in any order.
+    if let Some(index) = held.iter().rposition(|&(held, _)| held == address) {
+        held.locks.copy_within(index + 1..held.len, index);
+        held.len -= 1;
+    }
+    if enables_interrupts && held.len > 0 {
+        let still_held = held.locks[held.len - 1].1;
+        report!("interrupts enabled by a released spin lock while {} is still held", still_held);
+    }
+}
+
+/// **Report blocking where it is not allowed**, see [`super::might_sleep`].
+pub fn might_sleep() {
+    if !checking() {
+        return;
+    }
+    let holding = interrupts::without_interrupts(|| {
+        let per_cpu = percpu::current();
+        let held = unsafe { per_cpu.held_locks() };
+        (per_cpu.in_interrupt(), held.iter().last().map(|&(_, class)| class))
+    });
+    match holding {
+        (true, _) => report!("blocking in an interrupt handler"),
+        (false, Some(class)) => report!("blocking while holding the spin lock {}", class),
+        (false, None) => {}
+    }
+}
+
+#[test_case]
+fn test_orders_are_recorded_once() {
+    let mut orders = Orders { pairs: [("", ""); MAX_ORDERS], len: 0 };
+    orders.insert("a", "b");
+    orders.insert("a", "b");
+    assert_eq!(orders.len, 1);
+    assert!(orders.contains("a", "b"));
+    assert!(!orders.contains("b", "a"));
+}
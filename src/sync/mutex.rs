@@ -0,0 +1,136 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use super::{might_sleep, SpinLock, WaitQueue};
+use crate::scheduler;
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+/// **A mutex that puts waiting threads to sleep** instead of spinning. Interrupts stay enabled while it is
+/// held, and the holder may block, e.g. on I/O. Needs the scheduler, and must not be used in interrupt handlers.
+pub struct Mutex<T: ?Sized> {
+    state: SpinLock<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { state: SpinLock::new(MutexState { locked: false, waiters: WaitQueue::new() }), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// **Take the mutex**, blocking the current thread until it is free.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        might_sleep();
+        loop {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                state.waiters.cancel();
+                return MutexGuard { mutex: self };
+            }
+            state.waiters.prepare_to_wait();
+            drop(state);
+            scheduler::yield_now();
+        }
+    }
+
+    /// **Take the mutex if it is free**, `None` without waiting otherwise.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// `true` while someone holds the mutex, only a hint by the time it returns.
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
+
+    /// The value, no locking needed with a `&mut`.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        state.locked = false;
+        state.waiters.wake_one();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+/// Access to the value of a [`Mutex`], releases it when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex the guard belongs to, [`Condvar`](super::Condvar) takes it again after waiting.
+    pub(super) fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[test_case]
+fn test_try_lock_fails_while_locked() {
+    let mutex = Mutex::new(0);
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock().is_none());
+    }
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+}
@@ -0,0 +1,132 @@
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// **A value that is initialized once**, by the first [`call_once`](Self::call_once). Others that come while
+/// that runs spin until it is done, so the initialization must not wait for anything that needs the value.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Shared once complete, and the value may be dropped on another thread.
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once { state: AtomicU8::new(INCOMPLETE), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// **The value, initialized by `f` if nobody did yet.**
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        match self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// The value, `None` if it is not initialized (yet).
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("Once").field(value).finish(),
+            None => f.write_str("Once(<uninitialized>)"),
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// **A value that is computed on first access** by `F`, usually for statics that can't be built at compile
+/// time:
+///
+/// ```ignore
+/// static TABLE: Lazy<Table> = Lazy::new(|| Table::build());
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    // Taken by the one call that initializes `once`.
+    init: Cell<Option<F>>,
+}
+
+// `init` is only touched by the call that won the race in `Once`.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy { once: Once::new(), init: Cell::new(Some(init)) }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// **The value, computed now if this is the first access.**
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => unreachable!("Lazy initialized twice"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[test_case]
+fn test_once_runs_the_first_initializer() {
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert!(once.is_completed());
+
+    static LAZY: Lazy<u64> = Lazy::new(|| 40 + 2);
+    assert_eq!(*LAZY, 42);
+}
@@ -0,0 +1,204 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::{might_sleep, SpinLock, WaitQueue};
+use crate::scheduler;
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    // Writers blocked in `write`, new readers wait behind them so they don't starve.
+    writers_waiting: usize,
+    readers_queue: WaitQueue,
+    writers_queue: WaitQueue,
+}
+
+impl RwLockState {
+    fn can_read(&self) -> bool {
+        !self.writer && self.writers_waiting == 0
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+}
+
+/// **A reader-writer lock that puts waiting threads to sleep**: any number of readers, or one writer. A
+/// waiting writer goes before readers that come after it. Must not be used in interrupt handlers.
+pub struct RwLock<T: ?Sized> {
+    state: SpinLock<RwLockState>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: SpinLock::new(RwLockState {
+                readers: 0,
+                writer: false,
+                writers_waiting: 0,
+                readers_queue: WaitQueue::new(),
+                writers_queue: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// **Take the lock for reading**, blocks while there is a writer or one waiting.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        might_sleep();
+        loop {
+            let mut state = self.state.lock();
+            if state.can_read() {
+                state.readers += 1;
+                state.readers_queue.cancel();
+                return RwLockReadGuard { lock: self };
+            }
+            state.readers_queue.prepare_to_wait();
+            drop(state);
+            scheduler::yield_now();
+        }
+    }
+
+    /// **Take the lock for writing**, blocks while there are readers or another writer.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        might_sleep();
+        let mut waiting = false;
+        loop {
+            let mut state = self.state.lock();
+            if state.can_write() {
+                state.writer = true;
+                if waiting {
+                    state.writers_waiting -= 1;
+                    state.writers_queue.cancel();
+                }
+                return RwLockWriteGuard { lock: self };
+            }
+            if !waiting {
+                state.writers_waiting += 1;
+                waiting = true;
+            }
+            state.writers_queue.prepare_to_wait();
+            drop(state);
+            scheduler::yield_now();
+        }
+    }
+
+    /// **Take the lock for reading if that is possible right away.**
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if !state.can_read() {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// **Take the lock for writing if that is possible right away.**
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if !state.can_write() {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// The value, no locking needed with a `&mut`.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock_read(&self) {
+        let mut state = self.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.writers_queue.wake_one();
+        }
+    }
+
+    // The next writer if there is one, otherwise all readers.
+    fn unlock_write(&self) {
+        let mut state = self.state.lock();
+        state.writer = false;
+        if state.writers_waiting == 0 || !state.writers_queue.wake_one() {
+            state.readers_queue.wake_all();
+        }
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Shared access to the value of a [`RwLock`], releases it when dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+/// Exclusive access to the value of a [`RwLock`], releases it when dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
+
+#[test_case]
+fn test_readers_share_writers_exclude() {
+    let lock = RwLock::new(0);
+    {
+        let first = lock.read();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 0);
+        assert!(lock.try_write().is_none());
+    }
+    {
+        let mut writer = lock.write();
+        *writer = 1;
+        assert!(lock.try_read().is_none());
+    }
+    assert_eq!(*lock.read(), 1);
+}
@@ -0,0 +1,68 @@
+use super::{might_sleep, SpinLock, WaitQueue};
+use crate::scheduler;
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitQueue,
+}
+
+/// **A counting semaphore**: [`acquire`](Self::acquire) takes one of the permits, blocking the current thread
+/// while there is none, [`release`](Self::release) gives one back. Any thread can release, not just the one
+/// that acquired, so it also works for signalling between threads.
+pub struct Semaphore {
+    state: SpinLock<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { state: SpinLock::new(SemaphoreState { permits, waiters: WaitQueue::new() }) }
+    }
+
+    /// **Take a permit**, blocks until one is available.
+    pub fn acquire(&self) {
+        might_sleep();
+        loop {
+            let mut state = self.state.lock();
+            if state.permits > 0 {
+                state.permits -= 1;
+                state.waiters.cancel();
+                return;
+            }
+            state.waiters.prepare_to_wait();
+            drop(state);
+            scheduler::yield_now();
+        }
+    }
+
+    /// **Take a permit if one is available**, `false` without waiting otherwise.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.permits == 0 {
+            return false;
+        }
+        state.permits -= 1;
+        true
+    }
+
+    /// **Give a permit back**, wakes a waiting thread. Can be called from interrupt handlers.
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        state.permits += 1;
+        state.waiters.wake_one();
+    }
+
+    /// Permits available right now.
+    pub fn available(&self) -> usize {
+        self.state.lock().permits
+    }
+}
+
+#[test_case]
+fn test_permits_are_counted() {
+    let semaphore = Semaphore::new(2);
+    assert!(semaphore.try_acquire());
+    semaphore.acquire();
+    assert!(!semaphore.try_acquire());
+    semaphore.release();
+    assert_eq!(semaphore.available(), 1);
+}
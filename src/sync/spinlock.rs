@@ -0,0 +1,165 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use super::lockdep;
+
+/// **A spin lock that disables interrupts while it is held**, and restores `RFLAGS.IF` when released.
+///
+/// Interrupt handlers can take it without deadlocking on the code they interrupted, which replaces wrapping
+/// every `lock()` into `without_interrupts`. Guards that restore interrupts have to be dropped last, so release
+/// nested locks in reverse order.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    // Name of the lock class, see lockdep.rs.
+    #[cfg(debug_assertions)]
+    class: fn() -> &'static str,
+    value: UnsafeCell<T>,
+}
+
+// Like `spin::Mutex`: the value only moves between threads, one at a time.
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            class: core::any::type_name::<T>,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// **Disable interrupts and take the lock**, spinning until it is free.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.address(), (self.class)());
+
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            // Only read while it is taken, the cache line stays shared until the holder releases it.
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.guard(enabled)
+    }
+
+    /// **Take the lock if it is free**, `None` without waiting otherwise.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(self.guard(enabled))
+        } else {
+            if enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    /// `true` while someone holds the lock, only a hint by the time it returns.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// The value, no locking needed with a `&mut`.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn guard(&self, interrupts_enabled: bool) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        lockdep::acquired(self.address(), (self.class)());
+        SpinLockGuard { lock: self, interrupts_enabled, _not_send: PhantomData }
+    }
+
+    #[cfg(debug_assertions)]
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("SpinLock").field("value", &&*guard).finish(),
+            None => f.write_str("SpinLock { <locked> }"),
+        }
+    }
+}
+
+/// Access to the value of a [`SpinLock`], releases it when dropped.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    // Whether interrupts were enabled before `lock`, dropping turns them back on then.
+    interrupts_enabled: bool,
+    // The flags belong to the CPU the lock was taken on.
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinLockGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.lock.address(), self.interrupts_enabled);
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_spin_lock_restores_interrupts() {
+    let lock = SpinLock::new(1);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 2);
+
+    // Taken with interrupts disabled, they stay that way.
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+}
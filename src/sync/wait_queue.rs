@@ -0,0 +1,73 @@
+use alloc::vec::Vec;
+use core::task::Waker;
+
+use crate::scheduler::{self, ThreadId};
+
+/// Threads and async tasks waiting for the same condition, e.g. for a pipe to become readable or a
+/// [`Mutex`](super::Mutex) to be released. The queue belongs inside the lock that protects the condition.
+#[derive(Default)]
+pub struct WaitQueue {
+    threads: Vec<ThreadId>,
+    tasks: Vec<Waker>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { threads: Vec::new(), tasks: Vec::new() }
+    }
+
+    /// **Block the current thread until it is woken up** through the queue. Call it holding the lock that
+    /// protects the condition, then release the lock and yield (see [`scheduler::prepare_to_block`]).
+    pub fn prepare_to_wait(&mut self) {
+        let thread = scheduler::current();
+        if !self.threads.contains(&thread) {
+            self.threads.push(thread);
+        }
+        scheduler::prepare_to_block();
+    }
+
+    /// **Take the current thread out of the queue**, once it stops waiting without being woken through the
+    /// queue. Otherwise a [`wake_one`](Self::wake_one) could pick it and the wakeup would be lost.
+    pub fn cancel(&mut self) {
+        if !self.threads.is_empty() {
+            let thread = scheduler::current();
+            self.threads.retain(|waiting| *waiting != thread);
+        }
+    }
+
+    /// **Wake `waker` on the next [`wake_all`](Self::wake_all)**, or a [`wake_one`](Self::wake_one) once the
+    /// threads are through.
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.tasks.iter().any(|task| task.will_wake(waker)) {
+            self.tasks.push(waker.clone());
+        }
+    }
+
+    /// **Wake the thread or task that waits the longest**, threads first. `false` if nobody waits.
+    pub fn wake_one(&mut self) -> bool {
+        if !self.threads.is_empty() {
+            scheduler::wake(self.threads.remove(0));
+            true
+        } else if !self.tasks.is_empty() {
+            self.tasks.remove(0).wake();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// **Wake everyone waiting**, they check their condition again.
+    pub fn wake_all(&mut self) {
+        for thread in self.threads.drain(..) {
+            scheduler::wake(thread);
+        }
+        for task in self.tasks.drain(..) {
+            task.wake();
+        }
+    }
+
+    /// `true` if no thread or task waits.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty() && self.tasks.is_empty()
+    }
+}
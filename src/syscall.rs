@@ -0,0 +1,592 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{LStar, SFMask};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::interrupts::SYSCALL_VECTOR;
+use crate::elf::{loader::MAX_ARGUMENTS_SIZE, programs, ElfError};
+use crate::memory::{self, MemoryError, FRAME_SIZE};
+use crate::ipc::{self, IpcError};
+use crate::process::{self, ExitStatus, File, Pid, ProcessError};
+use crate::smp::percpu;
+use crate::{gdt, scheduler, time, usermode};
+
+pub mod user_memory;
+
+pub use crate::process::file::{push_input, STDERR, STDIN, STDOUT};
+
+// ---------------------------------------------------------------------------------------------------------┐
+// System calls: how user code (ring 3) asks the kernel for something. There are two ways in:               |
+//                                                                                                          |
+//   syscall      the fast one. The CPU saves rip in rcx and rflags in r11, loads CS/SS from STAR, clears    |
+//                the rflags bits in SFMASK and jumps to LSTAR (`syscall_entry`). It does not switch        |
+//                stacks, so the entry does: swapgs, park the user rsp in the per CPU data, load the kernel |
+//                stack of the thread from there. It then pushes what an interrupt would have pushed and   |
+//                continues like every other trap (`trap_common`, see interrupts/trap.rs), the vector of    |
+//                the frame is `SYSCALL_FRAME`. trap_common returns to such frames with `sysretq`.          |
+//   int 0x80     an interrupt gate with DPL 3, works with every CPU and debugger, just slower.             |
+//                                                                                                          |
+// Both use the same registers, the ones of the Linux x86_64 `syscall` ABI:                                 |
+//                                                                                                          |
+//   rax          number (see `Syscall`), replaced by the result                                            |
+//   rdi rsi rdx r10 r8 r9   arguments 0-5                                                                  |
+//   rcx r11      clobbered by `syscall` (return address and flags), preserved by `int 0x80`                 |
+//                                                                                                          |
+// The result is a `u64`, errors are returned as the negated error number (see `SyscallError`), so         |
+// values from -4095 to -1 mean failure.                                                                    |
+//                                                                                                          |
+// Handlers run with interrupts enabled on the kernel stack of the calling thread, they can block and be   |
+// preempted like any kernel thread. Pointers from user code are never dereferenced directly, they are     |
+// checked and copied through `user_memory`. If the process of the caller started exiting meanwhile, the  |
+// thread exits instead of returning (see process.rs).                                                     |
+// ---------------------------------------------------------------------------------------------------------┙
+
+/// `TrapFrame::vector` of frames built by the `syscall` instruction entry. Not an IDT vector, it tells
+/// trap_common to return with `sysretq`.
+pub const SYSCALL_FRAME: u64 = 0x100;
+
+/// The system calls, the value is the number user code puts into `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    /// `read(fd, buffer, length) -> bytes read`, blocks until at least one byte is available.
+    Read = 0,
+    /// `write(fd, buffer, length) -> bytes written`.
+    Write = 1,
+    /// `exit(status) -> !`, terminates the calling process with the low 8 bits of `status`.
+    Exit = 2,
+    /// `yield() -> 0`, gives the CPU to the next ready thread.
+    Yield = 3,
+    /// `sleep(milliseconds) -> 0`.
+    Sleep = 4,
+    /// `getpid() -> id` of the calling process, the thread id for threads outside of any process.
+    GetPid = 5,
+    /// `mmap(address, length, protection) -> address` of new zeroed memory, see [`PROT_READ`].
+    Mmap = 6,
+    /// `fork() -> pid` of the child in the parent, `0` in the child.
+    Fork = 7,
+    /// `exec(path, argv, envp) -> !` replaces the program, `argv` and `envp` are NULL terminated arrays of C
+    /// strings (`envp` may be NULL). Programs are looked up by name in [`programs`].
+    Exec = 8,
+    /// `wait(pid, status) -> pid` of the child that exited, `pid` = -1 waits for any child. Writes the exit
+    /// status as a 32 bit integer to `status` unless it is NULL, see [`ExitStatus::to_wait_status`].
+    Wait = 9,
+    /// `getppid() -> id` of the parent process, `0` if there is none.
+    GetPpid = 10,
+    /// `pipe(fds) -> 0` creates a pipe, writes the file descriptors of the read and the write end as two 32 bit
+    /// integers to `fds`. Reads return 0 once the write end is closed everywhere, see [`ipc`].
+    Pipe = 11,
+    /// `close(fd) -> 0`.
+    Close = 12,
+    /// `channel(fds, capacity) -> 0` creates a channel for up to `capacity` messages (at most
+    /// [`MAX_CHANNEL_CAPACITY`]), writes the file descriptors of the receiving and the sending end like `pipe`.
+    Channel = 13,
+    /// `send(fd, message, length) -> 0` sends a message of 1 to [`MAX_MESSAGE_SIZE`] bytes, blocks while the
+    /// channel is full.
+    Send = 14,
+    /// `recv(fd, buffer, length) -> length` of the next message, blocks while the channel is empty. What does
+    /// not fit into `buffer` is lost, `0` means all sending ends are closed.
+    Recv = 15,
+    /// `try_recv(fd, buffer, length) -> length` like `recv`, fails with [`SyscallError::WouldBlock`] instead of
+    /// blocking.
+    TryRecv = 16,
+}
+
+/// `mmap` protection bits. Pages are always readable, `PROT_READ` is accepted for compatibility.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+// Bytes copied from or to user memory at once.
+const CHUNK_SIZE: usize = 256;
+// Longest program name `exec` accepts, and most arguments plus environment variables.
+const MAX_PATH: usize = 256;
+const MAX_ARGUMENTS: usize = 256;
+
+/// Largest message `send` accepts, and most messages a channel between processes can hold.
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+pub const MAX_CHANNEL_CAPACITY: u64 = 64;
+
+/// Why a system call failed, the value is the error number (as on Linux).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// No such file or program (`ENOENT`).
+    NoSuchFile = 2,
+    /// Interrupted, the process is exiting (`EINTR`).
+    Interrupted = 4,
+    /// Arguments and environment of `exec` are too large (`E2BIG`).
+    ArgumentListTooLong = 7,
+    /// Not a valid executable (`ENOEXEC`).
+    ExecFormat = 8,
+    /// Bad file descriptor (`EBADF`).
+    BadFileDescriptor = 9,
+    /// No child process to wait for (`ECHILD`).
+    NoChild = 10,
+    /// The operation would block, e.g. `try_recv` on an empty channel (`EAGAIN`).
+    WouldBlock = 11,
+    /// Out of memory (`ENOMEM`).
+    OutOfMemory = 12,
+    /// A pointer argument is not accessible user memory (`EFAULT`).
+    BadAddress = 14,
+    /// The resource is in use, e.g. `exec` with several threads (`EBUSY`).
+    Busy = 16,
+    /// An argument is out of range (`EINVAL`).
+    InvalidArgument = 22,
+    /// The process has too many files open (`EMFILE`).
+    TooManyFiles = 24,
+    /// Nobody is left to read or receive (`EPIPE`).
+    BrokenPipe = 32,
+    /// There is no system call with that number (`ENOSYS`).
+    NoSuchSyscall = 38,
+}
+
+impl SyscallError {
+    const ALL: [SyscallError; 14] = [
+        SyscallError::NoSuchFile,
+        SyscallError::Interrupted,
+        SyscallError::ArgumentListTooLong,
+        SyscallError::ExecFormat,
+        SyscallError::BadFileDescriptor,
+        SyscallError::NoChild,
+        SyscallError::WouldBlock,
+        SyscallError::OutOfMemory,
+        SyscallError::BadAddress,
+        SyscallError::Busy,
+        SyscallError::InvalidArgument,
+        SyscallError::TooManyFiles,
+        SyscallError::BrokenPipe,
+        SyscallError::NoSuchSyscall,
+    ];
+
+    pub fn errno(self) -> u64 {
+        self as u64
+    }
+
+    /// **Decode the value a system call returned** in `rax`.
+    pub fn from_return(value: u64) -> Result<u64, SyscallError> {
+        let errno = (value as i64).checked_neg().filter(|errno| (1..4096).contains(errno));
+        match errno {
+            Some(errno) => {
+                let known = Self::ALL.iter().copied().find(|error| error.errno() == errno as u64);
+                // Numbers this kernel never returns can only come from a handler that does not exist.
+                Err(known.unwrap_or(SyscallError::NoSuchSyscall))
+            }
+            None => Ok(value),
+        }
+    }
+
+    fn to_return(self) -> u64 {
+        (self.errno() as i64).wrapping_neg() as u64
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallError::NoSuchFile => write!(f, "no such file"),
+            SyscallError::Interrupted => write!(f, "interrupted"),
+            SyscallError::ArgumentListTooLong => write!(f, "argument list too long"),
+            SyscallError::ExecFormat => write!(f, "not an executable"),
+            SyscallError::BadFileDescriptor => write!(f, "bad file descriptor"),
+            SyscallError::NoChild => write!(f, "no child processes"),
+            SyscallError::WouldBlock => write!(f, "operation would block"),
+            SyscallError::OutOfMemory => write!(f, "out of memory"),
+            SyscallError::BadAddress => write!(f, "bad address"),
+            SyscallError::Busy => write!(f, "resource busy"),
+            SyscallError::InvalidArgument => write!(f, "invalid argument"),
+            SyscallError::TooManyFiles => write!(f, "too many open files"),
+            SyscallError::BrokenPipe => write!(f, "broken pipe"),
+            SyscallError::NoSuchSyscall => write!(f, "no such system call"),
+        }
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(error: ProcessError) -> Self {
+        match error {
+            ProcessError::NotAProcess => SyscallError::InvalidArgument,
+            ProcessError::NoSuchProcess | ProcessError::NoChildren => SyscallError::NoChild,
+            ProcessError::Busy => SyscallError::Busy,
+            ProcessError::TooManyFiles => SyscallError::TooManyFiles,
+            ProcessError::Elf(ElfError::ArgumentsTooLarge) => SyscallError::ArgumentListTooLong,
+            ProcessError::Elf(ElfError::Memory(_)) | ProcessError::Memory(_) => SyscallError::OutOfMemory,
+            ProcessError::Elf(_) => SyscallError::ExecFormat,
+        }
+    }
+}
+
+impl From<IpcError> for SyscallError {
+    fn from(error: IpcError) -> Self {
+        match error {
+            IpcError::Closed => SyscallError::BrokenPipe,
+            IpcError::Empty | IpcError::Full => SyscallError::WouldBlock,
+            IpcError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, SyscallError>;
+
+// Indexed by `Syscall`, the order has to match the numbers.
+const HANDLERS: [Handler; 17] = [
+    sys_read, sys_write, sys_exit, sys_yield, sys_sleep, sys_getpid, sys_mmap, sys_fork, sys_exec, sys_wait,
+    sys_getppid, sys_pipe, sys_close, sys_channel, sys_send, sys_recv, sys_try_recv,
+];
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    // What an interrupt from ring 3 would have pushed: ss, rsp, rflags, cs, rip.
+    "push {user_data}",
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push {user_code}",
+    "push rcx",
+    // trap_common swaps GS base again, it sees a frame from ring 3.
+    "swapgs",
+    "push 0",
+    "push {frame}",
+    "jmp trap_common",
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    user_data = const 0x1B,
+    user_code = const 0x23,
+    frame = const SYSCALL_FRAME,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// **Enable the `syscall` instruction** on the executing CPU, called once per CPU after the GDT is loaded.
+///
+/// `EFER.SCE` and the selectors in STAR are set up by [`gdt`], this points LSTAR at the entry and masks the
+/// flags that must not leak into the kernel.
+pub fn init_cpu() {
+    let selectors = gdt::selectors();
+    // `syscall_entry` pushes the selectors as constants.
+    assert_eq!((selectors.user_data.0, selectors.user_code.0), (0x1B, 0x23));
+
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Interrupts stay off until the entry is on the kernel stack, the direction flag is what Rust expects.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+}
+
+/// Called through the trap stubs for `int 0x80` and by the `syscall` entry, returns the frame to resume.
+pub(crate) fn dispatch(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let arguments = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let handler = HANDLERS.get(frame.rax as usize);
+
+    interrupts::enable();
+    let result = match handler {
+        Some(handler) => handler(&arguments),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    if let Some(status) = process::pending_exit() {
+        process::exit(status);
+    }
+    interrupts::disable();
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.to_return(),
+    };
+    // `sysretq` to a non canonical address faults in ring 0 with the user stack, return with `iretq` instead.
+    if frame.vector == SYSCALL_FRAME && !usermode::is_user_address(frame.stack_frame.instruction_pointer) {
+        frame.vector = SYSCALL_VECTOR as u64;
+    }
+    frame
+}
+
+fn sys_read(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [fd, buffer, length, ..] = *arguments;
+    let file = process::file(fd).ok_or(SyscallError::BadFileDescriptor)?;
+    user_memory::check(buffer, length, user_memory::Access::Write)?;
+    if length == 0 {
+        return Ok(0);
+    }
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let count = file.read(&mut chunk[..(length as usize).min(CHUNK_SIZE)])?;
+    user_memory::copy_to_user(buffer, &chunk[..count])?;
+    Ok(count as u64)
+}
+
+fn sys_write(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [fd, buffer, length, ..] = *arguments;
+    let file = process::file(fd).ok_or(SyscallError::BadFileDescriptor)?;
+    user_memory::check(buffer, length, user_memory::Access::Read)?;
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut written = 0;
+    while written < length {
+        let count = (length - written).min(CHUNK_SIZE as u64) as usize;
+        user_memory::copy_from_user(&mut chunk[..count], buffer + written)?;
+        // What made it is reported, the error again on the next write.
+        let count = match file.write(&chunk[..count]) {
+            Ok(count) => count,
+            Err(_) if written > 0 => break,
+            Err(error) => return Err(error),
+        };
+        written += count as u64;
+        if count == 0 {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+fn sys_exit(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    process::exit(ExitStatus::Exited(arguments[0] as u8))
+}
+
+fn sys_yield(_arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    scheduler::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    time::sleep(time::Duration::from_millis(arguments[0]));
+    Ok(0)
+}
+
+fn sys_getpid(_arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    match process::current() {
+        Some(pid) => Ok(pid.as_u64()),
+        None => Ok(scheduler::current().as_u64()),
+    }
+}
+
+fn sys_getppid(_arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let parent = process::current().and_then(|pid| process::with_process(pid, |process| process.parent()).flatten());
+    Ok(parent.map_or(0, Pid::as_u64))
+}
+
+fn sys_fork(_arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    Ok(process::fork(&user_frame())?.as_u64())
+}
+
+fn sys_exec(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [path, argv, envp, ..] = *arguments;
+    // Everything the kernel allocates here has to be gone before entering the new program, the stack
+    // of this handler is never unwound.
+    let program = {
+        let name = user_memory::copy_string_from_user(path, MAX_PATH)?;
+        let image = programs::find(&name).ok_or(SyscallError::NoSuchFile)?;
+        // Both share the limit of the loader, checked while copying so a huge list never reaches the heap.
+        let mut size = 0;
+        let argv = copy_string_array(argv, &mut size)?;
+        let envp = copy_string_array(envp, &mut size)?;
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+        process::exec(image, &argv, &envp)?
+    };
+    unsafe { usermode::enter(program.entry, program.stack_pointer) }
+}
+
+fn sys_wait(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [pid, status, ..] = *arguments;
+    let child = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    if status != 0 {
+        user_memory::check(status, 4, user_memory::Access::Write)?;
+    }
+    let (pid, exit_status) = process::wait(child)?;
+    if status != 0 {
+        user_memory::copy_to_user(status, &exit_status.to_wait_status().to_le_bytes())?;
+    }
+    Ok(pid.as_u64())
+}
+
+fn sys_pipe(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let (reader, writer) = ipc::pipe();
+    open_pair(arguments[0], Arc::new(reader), Arc::new(writer))
+}
+
+fn sys_close(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let file = process::close(arguments[0]).ok_or(SyscallError::BadFileDescriptor)?;
+    // Outside of the process table lock, closing the last end of a pipe wakes up the other side.
+    drop(file);
+    Ok(0)
+}
+
+fn sys_channel(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [fds, capacity, ..] = *arguments;
+    if !(1..=MAX_CHANNEL_CAPACITY).contains(&capacity) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let (sender, receiver) = ipc::channel::<Vec<u8>>(capacity as usize);
+    open_pair(fds, Arc::new(receiver), Arc::new(sender))
+}
+
+fn sys_send(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [fd, message, length, ..] = *arguments;
+    let file = process::file(fd).ok_or(SyscallError::BadFileDescriptor)?;
+    if length == 0 || length > MAX_MESSAGE_SIZE as u64 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut data = zeroed(length as usize)?;
+    user_memory::copy_from_user(&mut data, message)?;
+    file.send(data)?;
+    Ok(0)
+}
+
+fn sys_recv(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    receive(arguments, true)
+}
+
+fn sys_try_recv(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    receive(arguments, false)
+}
+
+fn receive(arguments: &[u64; 6], wait: bool) -> Result<u64, SyscallError> {
+    let [fd, buffer, length, ..] = *arguments;
+    let file = process::file(fd).ok_or(SyscallError::BadFileDescriptor)?;
+    user_memory::check(buffer, length, user_memory::Access::Write)?;
+
+    let mut message = zeroed((length as usize).min(MAX_MESSAGE_SIZE))?;
+    let count = file.recv(&mut message, wait)?;
+    user_memory::copy_to_user(buffer, &message[..count])?;
+    Ok(count as u64)
+}
+
+// A buffer of `length` zero bytes, `OutOfMemory` instead of a panic when the heap can't hold it.
+fn zeroed(length: usize) -> Result<Vec<u8>, SyscallError> {
+    let mut buffer = Vec::new();
+    buffer.try_reserve_exact(length).map_err(|_| SyscallError::OutOfMemory)?;
+    buffer.resize(length, 0);
+    Ok(buffer)
+}
+
+// Open both ends of a pipe or channel in the calling process and write their descriptors to `fds`.
+fn open_pair(fds: u64, first: Arc<dyn File>, second: Arc<dyn File>) -> Result<u64, SyscallError> {
+    user_memory::check(fds, 8, user_memory::Access::Write)?;
+    let first = process::open(first)?;
+    let second = match process::open(second) {
+        Ok(second) => second,
+        Err(error) => {
+            drop(process::close(first));
+            return Err(error.into());
+        }
+    };
+    let mut descriptors = [0; 8];
+    descriptors[..4].copy_from_slice(&(first as u32).to_le_bytes());
+    descriptors[4..].copy_from_slice(&(second as u32).to_le_bytes());
+    if let Err(error) = user_memory::copy_to_user(fds, &descriptors) {
+        drop((process::close(first), process::close(second)));
+        return Err(error);
+    }
+    Ok(0)
+}
+
+// Memory is demand zero: nothing is allocated until the pages are touched, see `memory::fault`.
+fn sys_mmap(arguments: &[u64; 6]) -> Result<u64, SyscallError> {
+    let [address, length, protection, ..] = *arguments;
+    if length == 0 || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let size = length.checked_add(FRAME_SIZE - 1).ok_or(SyscallError::InvalidArgument)? & !(FRAME_SIZE - 1);
+
+    let pid = process::current().ok_or(SyscallError::InvalidArgument)?;
+    let address_space = process::with_process(pid, |process| process.address_space().cloned())
+        .flatten()
+        .ok_or(SyscallError::InvalidArgument)?;
+    let start = if address == 0 {
+        address_space.next_mmap_area(size).ok_or(SyscallError::OutOfMemory)?
+    } else if address % FRAME_SIZE == 0 && user_memory::in_user_space(address, size) {
+        VirtAddr::new(address)
+    } else {
+        return Err(SyscallError::InvalidArgument);
+    };
+    if is_mapped(start, size) {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let mut flags = PageTableFlags::USER_ACCESSIBLE;
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    address_space.register_lazy_region(start, size, flags).map_err(|error| match error {
+        MemoryError::TooManyRegions => SyscallError::OutOfMemory,
+        _ => SyscallError::InvalidArgument,
+    })?;
+    Ok(start.as_u64())
+}
+
+// Registers the calling user code entered the kernel with. System calls only come from user mode, so the
+// frame is on top of the kernel stack of the thread (see interrupts/trap.rs and the `syscall_entry` above).
+fn user_frame() -> TrapFrame {
+    let top = scheduler::current_kernel_stack().expect("system call from a thread without kernel stack");
+    unsafe { (top - core::mem::size_of::<TrapFrame>()).as_ptr::<TrapFrame>().read() }
+}
+
+// Copy the NULL terminated array of C strings at `address` into the kernel, an empty one for NULL.
+// `size` counts the bytes they take on the user stack, at most `MAX_ARGUMENTS_SIZE`.
+fn copy_string_array(address: u64, size: &mut u64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Ok(strings);
+    }
+    for index in 0.. {
+        if index == MAX_ARGUMENTS {
+            return Err(SyscallError::ArgumentListTooLong);
+        }
+        let mut pointer = [0; 8];
+        user_memory::copy_from_user(&mut pointer, address.wrapping_add(index as u64 * 8))?;
+        match u64::from_le_bytes(pointer) {
+            0 => break,
+            pointer => {
+                let string = user_memory::copy_string_from_user(pointer, FRAME_SIZE as usize)?;
+                // The string with its NUL, and the pointer to it.
+                *size += string.len() as u64 + 1 + 8;
+                if *size > MAX_ARGUMENTS_SIZE {
+                    return Err(SyscallError::ArgumentListTooLong);
+                }
+                strings.push(string);
+            }
+        }
+    }
+    Ok(strings)
+}
+
+fn is_mapped(start: VirtAddr, size: u64) -> bool {
+    let pages = Page::range(Page::containing_address(start), Page::containing_address(start + size));
+    memory::with_active_memory(|memory| {
+        let memory = memory.lock();
+        pages.into_iter().any(|page| memory.translate_page(page).is_some())
+    })
+}
+
+#[test_case]
+fn test_errors_round_trip() {
+    use crate::memory::USER_END;
+
+    for error in SyscallError::ALL.iter().copied() {
+        assert_eq!(SyscallError::from_return(error.to_return()), Err(error));
+    }
+    assert_eq!(SyscallError::from_return(0), Ok(0));
+    assert_eq!(SyscallError::from_return(USER_END), Ok(USER_END));
+}
@@ -0,0 +1,114 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::SyscallError;
+use crate::memory::{self, fault, FRAME_SIZE, USER_END, USER_START};
+
+// ------------------------------------------------------------------------------------------------┐
+// Every pointer a system call gets is a claim by user code, the kernel checks it before touching   |
+// the memory behind it:                                                                            |
+//                                                                                                  |
+//  * the whole range lies in user space, without wrapping around,                                 |
+//  * every page is mapped `USER_ACCESSIBLE` (and writable, or copy-on-write, for writes), or it     |
+//    belongs to a lazy region that would map it that way on first access. Both are looked up in    |
+//    the active address space, the one of the calling program.                                     |
+//                                                                                                  |
+// Otherwise a system call could read or overwrite kernel memory on behalf of the caller. The data  |
+// is copied instead of handing out references, user code on another CPU may change it any time.   |
+// ------------------------------------------------------------------------------------------------┙
+
+/// What the kernel is about to do with user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// `true` if the `length` bytes at `address` are inside user space.
+pub fn in_user_space(address: u64, length: u64) -> bool {
+    match address.checked_add(length) {
+        Some(end) => address >= USER_START && end <= USER_END,
+        None => false,
+    }
+}
+
+/// **Check that user code may `access` the `length` bytes at `address`**.
+pub fn check(address: u64, length: u64, access: Access) -> Result<(), SyscallError> {
+    if !in_user_space(address, length) {
+        return Err(SyscallError::BadAddress);
+    }
+    if length == 0 {
+        return Ok(());
+    }
+
+    let first = Page::containing_address(VirtAddr::new(address));
+    let last = Page::containing_address(VirtAddr::new(address + length - 1));
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if access == Access::Write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    for page in Page::range_inclusive(first, last) {
+        let mapped = memory::with_active_memory(|memory| memory.lock().translate_page(page));
+        let flags = match mapped {
+            // The first write gets a private writable copy, see `memory::fault`.
+            Some((_, flags)) if flags.contains(fault::COPY_ON_WRITE) => flags | PageTableFlags::WRITABLE,
+            Some((_, flags)) => flags,
+            None => fault::region_flags(page).ok_or(SyscallError::BadAddress)?,
+        };
+        if !flags.contains(required) {
+            return Err(SyscallError::BadAddress);
+        }
+    }
+    Ok(())
+}
+
+/// **Copy `destination.len()` bytes from user memory** at `source` into the kernel.
+pub fn copy_from_user(destination: &mut [u8], source: u64) -> Result<(), SyscallError> {
+    check(source, destination.len() as u64, Access::Read)?;
+    unsafe { core::ptr::copy_nonoverlapping(source as *const u8, destination.as_mut_ptr(), destination.len()) };
+    Ok(())
+}
+
+/// **Copy `source` into user memory** at `destination`.
+pub fn copy_to_user(destination: u64, source: &[u8]) -> Result<(), SyscallError> {
+    check(destination, source.len() as u64, Access::Write)?;
+    unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len()) };
+    Ok(())
+}
+
+/// **Copy the NUL terminated UTF-8 string at `source` into the kernel**, without the NUL. Strings without
+/// a NUL in the first `max_length` bytes are rejected.
+pub fn copy_string_from_user(source: u64, max_length: usize) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut address = source;
+    loop {
+        // Up to the end of the page, the next one may not be mapped if the string ends before it.
+        let chunk = (FRAME_SIZE - address % FRAME_SIZE).min((max_length + 1 - bytes.len()) as u64);
+        let start = bytes.len();
+        bytes.resize(start + chunk as usize, 0);
+        copy_from_user(&mut bytes[start..], address)?;
+        if let Some(end) = bytes[start..].iter().position(|&byte| byte == 0) {
+            bytes.truncate(start + end);
+            return String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument);
+        }
+        if bytes.len() > max_length {
+            return Err(SyscallError::InvalidArgument);
+        }
+        address += chunk;
+    }
+}
+
+#[test_case]
+fn test_kernel_memory_is_rejected() {
+    let kernel = memory::KERNEL_STACKS_START;
+    assert_eq!(check(kernel, 8, Access::Read), Err(SyscallError::BadAddress));
+    assert_eq!(check(0x1000, 8, Access::Read), Err(SyscallError::BadAddress));
+    assert_eq!(check(USER_END - 8, 16, Access::Read), Err(SyscallError::BadAddress));
+    assert_eq!(check(u64::MAX - 4, 8, Access::Read), Err(SyscallError::BadAddress));
+    // Nothing is mapped at the start of user space in the test kernel.
+    assert_eq!(check(USER_START, 1, Access::Read), Err(SyscallError::BadAddress));
+    assert_eq!(check(USER_START, 0, Access::Read), Ok(()));
+}
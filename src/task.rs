@@ -0,0 +1,44 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+pub mod executor;
+pub mod keyboard;
+pub mod serial;
+pub mod simple_executor;
+
+// ------------------------------------------------------------------------------------------------┐
+// Cooperative multitasking with async/await. A `Task` is a pinned, heap allocated future, an       |
+// executor polls tasks until they are done. Unlike the threads in `scheduler`, tasks share one     |
+// stack and only give up the CPU at `.await` points, so they are very cheap.                       |
+// ------------------------------------------------------------------------------------------------┙
+
+/// Unique identifier of a [`Task`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A future that runs to completion inside an executor.
+pub struct Task {
+    id: TaskId,
+    // Futures may not be moved once polled (they can reference themselves), hence `Pin`.
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task { id: TaskId::new(), future: Box::pin(future) }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
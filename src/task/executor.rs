@@ -0,0 +1,160 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+use super::{Task, TaskId};
+
+/// Maximum number of tasks that can be woken up at the same time.
+const TASK_QUEUE_SIZE: usize = 100;
+
+// ---------------------------------------------------------------------------------------------------┐
+// Tasks are only polled after their waker was called, e.g. by an interrupt handler that produced     |
+// new data. The wakers push the task id into `task_queue`, a fixed size lock free queue, so waking    |
+// from interrupt context never allocates or blocks. When nothing is queued the CPU sleeps with `hlt`. |
+// ---------------------------------------------------------------------------------------------------┙
+
+/// Executor that only polls tasks whose waker was called, and halts the CPU while there is no work.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// Add `task` to the executor, it gets polled for the first time on the next run.
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    /// Run the tasks forever, sleeping whenever no task is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Poll every task that was woken up, returns once the queue is empty.
+    pub fn run_ready_tasks(&mut self) {
+        // Destructure `self` to avoid borrow checker errors (closure below borrows the cache only).
+        let Self { tasks, task_queue, waker_cache } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // Task no longer exists, woken after it completed.
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    /// Number of tasks that did not complete yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // An interrupt between the emptiness check and `hlt` could wake a task, and we would
+        // sleep until the next interrupt anyway. So check with interrupts disabled, then
+        // `sti; hlt` atomically (sti only takes effect after the next instruction).
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { task_id, task_queue }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.task_id).expect("task_queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_executor_polls_woken_tasks() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use core::future::Future;
+    use core::pin::Pin;
+
+    // Pending on the first poll, wakes itself so it gets polled again.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    let flag = done.clone();
+    executor.spawn(Task::new(async move {
+        YieldOnce(false).await;
+        flag.set(true);
+    }));
+
+    executor.run_ready_tasks();
+    assert!(done.get());
+    assert_eq!(executor.task_count(), 0);
+}
@@ -0,0 +1,124 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+
+use crate::sync::Once;
+use crate::vga_buffer::{BUFFER_HEIGHT, WRITER};
+use crate::{print, println};
+
+/// Maximum number of scancodes buffered before the consumer falls behind and keys get dropped.
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+/// Lines Shift+PageUp/PageDown scroll the screen, half of it like the Linux console.
+const SCROLL_LINES: usize = BUFFER_HEIGHT / 2;
+
+// ----------------------------------------------------------------------------------------------------┐
+// The keyboard interrupt handler only reads the scancode from port 0x60 and pushes it into            |
+// SCANCODE_QUEUE, a lock free queue, then wakes whoever waits on the ScancodeStream. Decoding and     |
+// printing happen in a normal task, outside of interrupt context and without holding any lock that    |
+// the interrupt handler could need.                                                                   |
+//                                                                                                     |
+//   IRQ 1 -> keyboard_interrupt_handler -> add_scancode -> SCANCODE_QUEUE -> ScancodeStream -> task   |
+//                                                      \-> WAKER.wake() ----------------------/       |
+// ----------------------------------------------------------------------------------------------------┙
+static SCANCODE_QUEUE: Once<ArrayQueue<u8>> = Once::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+// Scancodes dropped because the queue was full or didn't exist yet, the task reports them.
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Called by the keyboard interrupt handler, must not block, allocate or print.
+pub(crate) fn add_scancode(scancode: u8) {
+    match SCANCODE_QUEUE.get() {
+        Some(queue) if queue.push(scancode).is_ok() => WAKER.wake(),
+        _ => {
+            DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stream of raw scancodes from the keyboard.
+///
+/// There is a single queue behind it, so only one `ScancodeStream` may exist.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        assert!(!SCANCODE_QUEUE.is_completed(), "ScancodeStream::new should only be called once");
+        SCANCODE_QUEUE.call_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE));
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.get().expect("not initialized");
+
+        // Fast path, no need to register the waker.
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // Register before checking again, a scancode pushed in between would otherwise be missed.
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Task that decodes the scancodes, echoes the keys to the screen and queues them as [`syscall`](crate::syscall) input.
+///
+/// Shift+PageUp and Shift+PageDown scroll the screen through the scrollback instead.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    // pc_keyboard keeps its modifiers to itself.
+    let (mut left_shift, mut right_shift) = (false, false);
+
+    while let Some(scancode) = scancodes.next().await {
+        let dropped = DROPPED_SCANCODES.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("WARNING: dropped {} scancodes of keyboard input", dropped);
+        }
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            match key_event.code {
+                KeyCode::ShiftLeft => left_shift = key_event.state == KeyState::Down,
+                KeyCode::ShiftRight => right_shift = key_event.state == KeyState::Down,
+                _ => {}
+            }
+            let shift = left_shift || right_shift;
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => {
+                        print!("{}", character);
+                        // User programs read the keyboard through `read` on stdin.
+                        if character.is_ascii() {
+                            crate::syscall::push_input(character as u8);
+                        }
+                    }
+                    DecodedKey::RawKey(KeyCode::PageUp) if shift => WRITER.lock().scroll_up(SCROLL_LINES),
+                    DecodedKey::RawKey(KeyCode::PageDown) if shift => WRITER.lock().scroll_down(SCROLL_LINES),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
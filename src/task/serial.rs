@@ -0,0 +1,165 @@
+use alloc::string::String;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+
+use crate::sync::Once;
+use crate::{serial_print, serial_println};
+
+/// Maximum number of received bytes buffered before input gets dropped.
+const SERIAL_QUEUE_SIZE: usize = 256;
+/// Longest command line we keep, the rest of a longer line is ignored.
+const MAX_LINE: usize = 80;
+
+// ------------------------------------------------------------------------------------------------┐
+// Serial input works like the keyboard (see task/keyboard.rs): the COM1 interrupt handler pushes   |
+// each received byte into SERIAL_QUEUE and wakes the task reading the SerialStream. The task       |
+// collects the bytes into lines and runs them as debug commands, so the kernel can be poked at     |
+// from the host with `-serial stdio` without touching the VGA console.                            |
+// ------------------------------------------------------------------------------------------------┙
+static SERIAL_QUEUE: Once<ArrayQueue<u8>> = Once::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Debug commands, name and what it does.
+const COMMANDS: [(&str, &str, fn()); 5] = [
+    ("acpi", "dump the ACPI tables", crate::acpi::dump),
+    ("hello", "run the embedded hello program (prints on the VGA console)", hello),
+    ("help", "list the commands", help),
+    ("reboot", "restart the machine", reboot),
+    ("shutdown", "power the machine off", shutdown),
+];
+
+/// Called by the serial interrupt handler, must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    // Nobody listens before the stream exists, dropping the byte is fine then.
+    if let Some(queue) = SERIAL_QUEUE.get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Stream of bytes received on COM1.
+///
+/// There is a single queue behind it, so only one `SerialStream` may exist.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        assert!(!SERIAL_QUEUE.is_completed(), "SerialStream::new should only be called once");
+        SERIAL_QUEUE.call_once(|| ArrayQueue::new(SERIAL_QUEUE_SIZE));
+        SerialStream { _private: () }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SERIAL_QUEUE.get().expect("not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        // Register before checking again, a byte pushed in between would otherwise be missed.
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Task that reads command lines from the serial port and runs them.
+///
+/// Needs the COM1 interrupt, see [`interrupts::enable_irq`](crate::interrupts::enable_irq).
+pub async fn run_commands() {
+    let mut bytes = SerialStream::new();
+    let mut line = String::new();
+
+    serial_print!("> ");
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            b'\r' | b'\n' => {
+                serial_println!();
+                run(line.trim());
+                line.clear();
+                serial_print!("> ");
+            }
+            // Backspace and delete
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    serial_print!("\x08 \x08");
+                }
+            }
+            byte if (byte.is_ascii_graphic() || byte == b' ') && line.len() < MAX_LINE => {
+                line.push(byte as char);
+                serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn run(command: &str) {
+    if command.is_empty() {
+        return;
+    }
+    match COMMANDS.iter().find(|(name, _, _)| *name == command) {
+        Some((_, _, function)) => function(),
+        None => {
+            serial_println!("unknown command `{}`, try `help`", command);
+        }
+    }
+}
+
+fn hello() {
+    use crate::elf::programs;
+    use crate::{process, scheduler};
+
+    let pid = match process::spawn(programs::HELLO, &["hello", "from", "the", "serial", "console"], &["TERM=vga"]) {
+        Ok(pid) => pid,
+        Err(error) => {
+            serial_println!("hello: {}", error);
+            return;
+        }
+    };
+    serial_println!("hello runs as process {}", pid.as_u64());
+    // Nobody else waits for it, a kernel thread reaps it and reports how it ended.
+    let reaper = scheduler::spawn(move || {
+        if let Ok((pid, status)) = process::wait(Some(pid)) {
+            serial_println!("process {} {}", pid.as_u64(), status);
+        }
+    });
+    if let Err(error) = reaper {
+        serial_println!("hello: no thread to wait for it: {:?}", error);
+    }
+}
+
+fn reboot() {
+    crate::power::reboot()
+}
+
+fn shutdown() {
+    crate::power::shutdown()
+}
+
+fn help() {
+    for (name, description, _) in COMMANDS.iter() {
+        serial_println!("  {:8} {}", name, description);
+    }
+}
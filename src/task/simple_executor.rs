@@ -0,0 +1,73 @@
+use alloc::collections::VecDeque;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use super::Task;
+
+/// Executor that polls every task in a loop, busy waiting instead of reacting to wakeups.
+///
+/// Good enough for tests and debugging, [`Executor`](super::executor::Executor) is the real one.
+pub struct SimpleExecutor {
+    task_queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    pub fn new() -> SimpleExecutor {
+        SimpleExecutor { task_queue: VecDeque::new() }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        self.task_queue.push_back(task)
+    }
+
+    /// Poll the tasks round robin until all of them are done.
+    pub fn run(&mut self) {
+        while let Some(mut task) = self.task_queue.pop_front() {
+            let waker = dummy_waker();
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {}
+                Poll::Pending => self.task_queue.push_back(task),
+            }
+        }
+    }
+}
+
+impl Default for SimpleExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Waker that does nothing, the simple executor polls everything again anyway.
+fn dummy_raw_waker() -> RawWaker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+
+    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
+    RawWaker::new(core::ptr::null::<()>(), vtable)
+}
+
+fn dummy_waker() -> Waker {
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
+
+#[test_case]
+fn test_simple_executor_runs_tasks() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    async fn number() -> u32 {
+        42
+    }
+
+    let result = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..2 {
+        let result = result.clone();
+        executor.spawn(Task::new(async move { result.set(result.get() + number().await) }));
+    }
+    executor.run();
+    assert_eq!(result.get(), 84);
+}
@@ -0,0 +1,230 @@
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+
+pub use core::time::Duration;
+pub use rtc::{wall_clock, DateTime};
+
+// ------------------------------------------------------------------------------------------------------┐
+// Monotonic kernel clock, driven by the PIT channel 0 interrupt (IRQ 0).                                 |
+//                                                                                                       |
+// Every timer interrupt calls `tick()`, which advances two counters:                                    |
+//   TICKS - number of timer interrupts since `init`, what the scheduler and the timer wheel count in.   |
+//   NANOS - nanoseconds since `init`. One tick is divisor / 1.193182 MHz, which is not a whole number    |
+//           of nanoseconds, so the leftover fraction is kept in NANOS_REMAINDER (in 1/BASE_FREQUENCY ns) |
+//           and the clock does not drift, even if the frequency is changed later on.                    |
+//                                                                                                       |
+// The clock only moves on ticks, so its resolution is one timer period (10ms at DEFAULT_FREQUENCY).     |
+// Only the timer interrupt writes the counters, everybody else just loads them.                         |
+// ------------------------------------------------------------------------------------------------------┙
+
+/// Timer interrupt frequency set by [`init`](crate::init), in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NANOS: AtomicU64 = AtomicU64::new(0);
+static NANOS_REMAINDER: AtomicU64 = AtomicU64::new(0);
+// Power on default of the PIT, in case somebody looks at the clock before `init`.
+static DIVISOR: AtomicU32 = AtomicU32::new(pit::MAX_DIVISOR);
+
+/// **Initialize the clock**, programs the PIT to interrupt `frequency` times per second.
+pub fn init(frequency: u32) {
+    set_frequency(frequency);
+}
+
+/// Change the timer interrupt rate, the clock keeps counting from where it was.
+///
+/// The PIT can only divide its 1.193182 MHz oscillator, so the real rate is the closest
+/// one it can do (between ~18.2 Hz and 1.193182 MHz), see [`frequency`].
+///
+/// Panics once [`apic::init`](crate::interrupts::apic::init) handed the ticks to the LAPIC timers, they
+/// keep the rate they were calibrated for and the clock would run at the wrong speed.
+pub fn set_frequency(frequency: u32) {
+    assert!(
+        !crate::interrupts::apic::is_enabled(),
+        "the timer frequency can't change after the LAPIC timer was calibrated"
+    );
+    let divisor = pit::divisor_for(frequency);
+    // A tick between the store and reprogramming would be counted with the wrong period.
+    interrupts::without_interrupts(|| {
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        unsafe { pit::set_divisor(divisor) };
+    });
+}
+
+/// Actual timer interrupt frequency in Hz (rounded down).
+pub fn frequency() -> u32 {
+    pit::BASE_FREQUENCY / DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Length of one timer tick.
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(ticks_to_nanos(1))
+}
+
+/// Number of timer interrupts since the clock was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the clock was started.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(NANOS.load(Ordering::Relaxed))
+}
+
+/// Number of ticks that cover at least `duration`, rounded up.
+pub fn ticks_for(duration: Duration) -> u64 {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u128;
+    let scaled = duration.as_nanos() * pit::BASE_FREQUENCY as u128;
+    let per_tick = divisor * NANOS_PER_SEC as u128;
+    scaled.div_ceil(per_tick).min(u64::MAX as u128) as u64
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u128;
+    (ticks as u128 * divisor * NANOS_PER_SEC as u128 / pit::BASE_FREQUENCY as u128) as u64
+}
+
+// Tick at which a wait of `duration` that starts now is over. The current tick already started
+// some time ago, so one more is needed to wait at least `duration`.
+fn deadline_tick(duration: Duration) -> u64 {
+    ticks().saturating_add(ticks_for(duration)).saturating_add(1)
+}
+
+/// **Busy wait** for at least `duration`, for code that must not sleep.
+///
+/// Needs timer interrupts, with interrupts disabled this never returns.
+pub fn spin_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// **Block** the caller for at least `duration`.
+///
+/// Threads sleep in the scheduler so other threads can run, before the scheduler is up the CPU
+/// just halts until the deadline.
+pub fn sleep(duration: Duration) {
+    let deadline = deadline_tick(duration);
+    if crate::scheduler::is_initialized() {
+        crate::scheduler::sleep_until(deadline);
+    } else {
+        while ticks() < deadline {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// Called from the timer interrupt, advances the clock and fires expired timers.
+pub(crate) fn tick() {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u64;
+    let base = pit::BASE_FREQUENCY as u64;
+    // divisor * 10^9 / BASE nanoseconds, with the fraction carried over to the next tick.
+    let scaled = NANOS_REMAINDER.load(Ordering::Relaxed) + divisor * NANOS_PER_SEC;
+    NANOS_REMAINDER.store(scaled % base, Ordering::Relaxed);
+    NANOS.fetch_add(scaled / base, Ordering::Relaxed);
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    timer::expire(now);
+}
+
+/// A point on the monotonic clock, like `std::time::Instant`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    // Nanoseconds since the clock was started.
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { nanos: NANOS.load(Ordering::Relaxed) }
+    }
+
+    /// Time passed since `earlier`, zero if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// Time passed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant { nanos: self.nanos.checked_add(nanos)? })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant { nanos: self.nanos.checked_sub(nanos)? })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", Duration::from_nanos(self.nanos))
+    }
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let start = Instant { nanos: 1_000 };
+    let later = start + Duration::from_micros(2);
+
+    assert_eq!(later.nanos, 3_000);
+    assert_eq!(later - start, Duration::from_micros(2));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(later - Duration::from_micros(2), start);
+    assert!(start.checked_sub(Duration::from_micros(2)).is_none());
+    assert!(start.checked_add(Duration::MAX).is_none());
+}
+
+#[test_case]
+fn test_ticks_for_rounds_up() {
+    let period = tick_period();
+
+    assert_eq!(ticks_for(Duration::ZERO), 0);
+    assert_eq!(ticks_for(period), 1);
+    assert_eq!(ticks_for(period + Duration::from_nanos(1)), 2);
+    assert_eq!(ticks_for(period * 10), 10);
+}
@@ -0,0 +1,60 @@
+use x86_64::instructions::port::Port;
+
+// ----------------------------------------------------------------------------------------------┐
+// 8253/8254 Programmable Interval Timer.                                                        |
+//                                                                                               |
+// The PIT has an oscillator running at ~1.193182 MHz and three 16 bit down counters (channels).  |
+// Channel 0 is wired to IRQ 0, every time its counter reaches zero it raises the interrupt and   |
+// (in rate generator mode) reloads the counter with the divisor we programmed.                   |
+//                                                                                               |
+//   IRQ 0 frequency = BASE_FREQUENCY / divisor          (divisor 0 means 65536, ~18.2 Hz)       |
+//                                                                                               |
+// Ports: 0x40 = channel 0 data, 0x43 = mode/command register (write only).                     |
+// ----------------------------------------------------------------------------------------------┙
+
+/// Frequency of the PIT oscillator in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+/// Largest divisor the 16 bit counter can hold (written as 0).
+pub const MAX_DIVISOR: u32 = 0x1_0000;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+// Command byte: bits 7-6 channel 0 | bits 5-4 access lobyte/hibyte | bits 3-1 mode 2 (rate generator) | bit 0 binary
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// **Divisor closest to `frequency` Hz**, clamped to what the counter can represent.
+pub fn divisor_for(frequency: u32) -> u32 {
+    if frequency == 0 {
+        return MAX_DIVISOR;
+    }
+    let (base, frequency) = (BASE_FREQUENCY as u64, frequency as u64);
+    let divisor = (base + frequency / 2) / frequency;
+    divisor.clamp(1, MAX_DIVISOR as u64) as u32
+}
+
+/// Program channel 0 to fire IRQ 0 every `divisor` oscillator cycles.
+///
+/// # Safety
+/// Changes the timer interrupt rate, whoever counts ticks has to know the new divisor.
+pub(super) unsafe fn set_divisor(divisor: u32) {
+    assert!((1..=MAX_DIVISOR).contains(&divisor), "invalid PIT divisor {}", divisor);
+    // 65536 does not fit in 16 bits, the counter interprets 0 as 65536.
+    let value = (divisor % MAX_DIVISOR) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_0);
+    command.write(CHANNEL_0_RATE_GENERATOR);
+    data.write(value as u8);
+    data.write((value >> 8) as u8);
+}
+
+#[test_case]
+fn test_divisor_for() {
+    assert_eq!(divisor_for(BASE_FREQUENCY), 1);
+    assert_eq!(divisor_for(100), 11932);
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(1), MAX_DIVISOR);
+    assert_eq!(divisor_for(0), MAX_DIVISOR);
+    assert_eq!(divisor_for(u32::MAX), 1);
+}
@@ -0,0 +1,316 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::InterruptIndex;
+use crate::sync::SpinLock;
+
+// -------------------------------------------------------------------------------------------------┐
+// CMOS Real Time Clock (Motorola MC146818 compatible).                                             |
+//                                                                                                  |
+// The RTC lives in the CMOS, a small battery backed RAM that is accessed through two ports:        |
+//   0x70 - register index, bit 7 of the written byte disables NMIs while it is set                 |
+//   0x71 - data of the selected register                                                           |
+//                                                                                                  |
+// Registers we care about:                                                                         |
+//   0x00 seconds | 0x02 minutes | 0x04 hours | 0x07 day | 0x08 month | 0x09 year | 0x32 century     |
+//   0x0A status A - bit 7 = update in progress, bits 3-0 = periodic interrupt rate                 |
+//   0x0B status B - bit 1 = 24 hour mode, bit 2 = binary (instead of BCD), bit 6 = periodic IRQ    |
+//   0x0C status C - which interrupt fired, has to be read or the RTC never raises IRQ 8 again      |
+//                                                                                                  |
+// The values are BCD (0x59 = 59) unless status B says binary, and in 12 hour mode bit 7 of the     |
+// hour register is the PM flag. Once a second the RTC updates its registers, reading in the middle |
+// of that gives torn values, so we wait for the update to finish and read until two reads agree.   |
+// -------------------------------------------------------------------------------------------------┙
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+// Not standardized, but where every PC we care about keeps it (the FADT can say otherwise).
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+static CMOS: SpinLock<Cmos> = SpinLock::new(Cmos::new());
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+// Unix time at uptime zero, set by `init`.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Cmos { address: Port::new(CMOS_ADDRESS), data: Port::new(CMOS_DATA) }
+    }
+
+    // NMIs stay disabled while a register is selected, an NMI in between could leave the RTC in
+    // an undefined state.
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(NMI_DISABLE | register);
+            let value = self.data.read();
+            self.address.write(0);
+            value
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address.write(NMI_DISABLE | register);
+            self.data.write(value);
+            self.address.write(0);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+        RawTime {
+            second: self.read(REG_SECONDS),
+            minute: self.read(REG_MINUTES),
+            hour: self.read(REG_HOURS),
+            day: self.read(REG_DAY),
+            month: self.read(REG_MONTH),
+            year: self.read(REG_YEAR),
+            century: self.read(REG_CENTURY),
+        }
+    }
+}
+
+// Register values exactly as the RTC reports them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl RawTime {
+    fn decode(self, status_b: u8) -> DateTime {
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let value = |raw: u8| if binary { raw } else { from_bcd(raw) };
+
+        let pm = status_b & STATUS_B_24_HOUR == 0 && self.hour & HOUR_PM != 0;
+        let mut hour = value(self.hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is midnight (0), 12 PM is noon (12).
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
+
+        let century = match value(self.century) {
+            century @ 19..=99 => century as u16,
+            _ => 20, // No century register, assume we are not running in the past.
+        };
+
+        DateTime {
+            year: century * 100 + value(self.year) as u16,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Calendar date and time of day, as kept by the RTC (usually UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, the date must not be before that.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86_400 + self.hour as u64 * 3_600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+        let (year, month, day) = civil_from_days(days as i64);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3_600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+// ISO 8601, e.g. 2023-01-31 23:59:59
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March, so the leap day is the last day of the "year".
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// **Read the date and time from the RTC**, takes up to a few milliseconds.
+///
+/// Prefer [`wall_clock`], which does not touch the hardware.
+pub fn read() -> DateTime {
+    let mut cmos = CMOS.lock();
+    // Two identical reads in a row can not have been torn by an update.
+    let mut last = cmos.read_raw();
+    loop {
+        let current = cmos.read_raw();
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+    last.decode(cmos.read(REG_STATUS_B))
+}
+
+/// **Initialize the wall clock** from the RTC, needs the monotonic clock to be running.
+pub fn init() {
+    let now = read().to_unix_timestamp();
+    BOOT_TIME.store(now.saturating_sub(super::uptime().as_secs()), Ordering::Relaxed);
+}
+
+/// **Current date and time**, the RTC time read at boot advanced by [`uptime`](super::uptime).
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix_timestamp(unix_timestamp())
+}
+
+/// Seconds since 1970-01-01 00:00:00 UTC (if the RTC is set to UTC).
+pub fn unix_timestamp() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + super::uptime().as_secs()
+}
+
+/// **Enable the periodic RTC interrupt** (IRQ 8) at `32768 >> (rate - 1)` Hz.
+///
+/// `rate` goes from 3 (8192 Hz) to 15 (2 Hz), the interrupts are counted in [`periodic_ticks`].
+pub fn enable_periodic_interrupt(rate: u8) {
+    assert!((3..=15).contains(&rate), "invalid RTC interrupt rate {}", rate);
+    {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(REG_STATUS_A);
+        cmos.write(REG_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        // Acknowledge anything pending, otherwise IRQ 8 never fires.
+        cmos.read(REG_STATUS_C);
+    }
+    crate::interrupts::enable_irq(InterruptIndex::RealTimeClock);
+}
+
+/// Stop the periodic RTC interrupt.
+pub fn disable_periodic_interrupt() {
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+}
+
+/// Number of periodic RTC interrupts since boot.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the IRQ 8 handler.
+pub(crate) fn on_interrupt() {
+    // Reading status C acknowledges the interrupt on the RTC side.
+    let status_c = CMOS.lock().read(REG_STATUS_C);
+    if status_c & STATUS_C_PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    // 2023-07-04 12:30:59 AM in BCD, 12 hour mode.
+    let raw = RawTime { second: 0x59, minute: 0x30, hour: 0x12, day: 0x04, month: 0x07, year: 0x23, century: 0x20 };
+    let time = raw.decode(0);
+    assert_eq!((time.year, time.month, time.day), (2023, 7, 4));
+    assert_eq!((time.hour, time.minute, time.second), (0, 30, 59));
+
+    // 11 PM
+    let time = RawTime { hour: HOUR_PM | 0x11, ..raw }.decode(0);
+    assert_eq!(time.hour, 23);
+}
+
+#[test_case]
+fn test_decode_binary_24_hour() {
+    let raw = RawTime { second: 7, minute: 8, hour: 21, day: 31, month: 12, year: 99, century: 0 };
+    let time = raw.decode(STATUS_B_BINARY | STATUS_B_24_HOUR);
+    assert_eq!(time, DateTime { year: 2099, month: 12, day: 31, hour: 21, minute: 8, second: 7 });
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 0 };
+    assert_eq!(leap_day.to_unix_timestamp(), 1_709_213_820);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_213_820), leap_day);
+    assert_eq!(DateTime::from_unix_timestamp(0).to_unix_timestamp(), 0);
+    assert_eq!(
+        DateTime::from_unix_timestamp(0),
+        DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 }
+    );
+}
@@ -0,0 +1,144 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::Duration;
+use crate::sync::SpinLock;
+
+// ---------------------------------------------------------------------------------------------------┐
+// One-shot timers, kept in a hashed timing wheel.                                                     |
+//                                                                                                    |
+// The wheel has WHEEL_SLOTS buckets, a timer due at tick `t` goes into bucket `t % WHEEL_SLOTS`.       |
+// On every tick only the bucket of that tick has to be looked at, timers that are due in a later     |
+// round of the wheel simply stay where they are. Adding and cancelling never walk the other buckets.  |
+//                                                                                                    |
+//   tick:   ... 254  255 | 256  257 ...                                                               |
+//   slot:   ... 254  255 |   0    1 ...   <- slot 0 holds the timers for ticks 0, 256, 512, ...        |
+//                                                                                                    |
+// `expire` runs in the timer interrupt and only try_locks the wheel. If it is busy the tick is not    |
+// lost, the next tick catches up on every slot since `processed`.                                    |
+// ---------------------------------------------------------------------------------------------------┙
+
+const WHEEL_SLOTS: usize = 256;
+
+static WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel::new());
+
+/// Handle of a timer registered with [`after`], used to [`cancel`] it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct Entry {
+    id: TimerId,
+    deadline: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+struct Wheel {
+    slots: [Vec<Entry>; WHEEL_SLOTS],
+    // Last tick whose slot was processed.
+    processed: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Entry> = Vec::new();
+        Wheel { slots: [EMPTY; WHEEL_SLOTS], processed: 0 }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        // Never put a timer into a slot that was already processed for its round.
+        let deadline = entry.deadline.max(self.processed + 1);
+        self.slots[deadline as usize % WHEEL_SLOTS].push(entry);
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|entry| entry.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Move every timer due at or before `now` into `expired`.
+    fn collect_expired(&mut self, now: u64, expired: &mut Vec<Entry>) {
+        // After a full round every slot was looked at once, no need to go around again.
+        let first = self.processed.saturating_add(1).max(now.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for tick in first..=now {
+            let slot = &mut self.slots[tick as usize % WHEEL_SLOTS];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    expired.push(slot.swap_remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.processed = self.processed.max(now);
+    }
+}
+
+/// **Call `callback` once, after at least `duration`.**
+///
+/// The callback runs inside the timer interrupt with interrupts disabled, so it has to be short and
+/// must not block or take a lock that normal code holds with interrupts enabled. Waking a thread or
+/// a task is fine, doing the actual work there is better.
+pub fn after<F>(duration: Duration, callback: F) -> TimerId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let id = TimerId::new();
+    let entry = Entry { id, deadline: super::deadline_tick(duration), callback: Box::new(callback) };
+    WHEEL.lock().insert(entry);
+    id
+}
+
+/// Cancel the timer `id`, returns `false` if it already fired (or never existed).
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock().remove(id)
+}
+
+/// Called by `time::tick` from the timer interrupt.
+pub(super) fn expire(now: u64) {
+    let mut expired = Vec::new();
+    match WHEEL.try_lock() {
+        Some(mut wheel) => wheel.collect_expired(now, &mut expired),
+        // Somebody is adding or cancelling a timer, catch up on the next tick.
+        None => return,
+    }
+    // Run the callbacks without holding the lock, so they can register new timers.
+    for entry in expired {
+        (entry.callback)();
+    }
+}
+
+#[test_case]
+fn test_wheel_expires_in_order_of_rounds() {
+    let mut wheel = Wheel::new();
+    let entry = |id, deadline| Entry { id: TimerId(id), deadline, callback: Box::new(|| {}) };
+    wheel.insert(entry(1, 3));
+    // Same slot as the first one, but a round later.
+    wheel.insert(entry(2, 3 + WHEEL_SLOTS as u64));
+    wheel.insert(entry(3, 10));
+
+    let mut expired = Vec::new();
+    wheel.collect_expired(5, &mut expired);
+    assert_eq!(expired.iter().map(|entry| entry.id).collect::<Vec<_>>(), [TimerId(1)]);
+
+    assert!(wheel.remove(TimerId(3)));
+    assert!(!wheel.remove(TimerId(3)));
+
+    expired.clear();
+    // Skipping a lot of ticks at once must still find the timer.
+    wheel.collect_expired(5 + 3 * WHEEL_SLOTS as u64, &mut expired);
+    assert_eq!(expired.iter().map(|entry| entry.id).collect::<Vec<_>>(), [TimerId(2)]);
+}
@@ -0,0 +1,118 @@
+use core::arch::asm;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::{USER_END, USER_START};
+use crate::{gdt, scheduler};
+
+// ---------------------------------------------------------------------------------------------------┐
+// Getting into user mode (ring 3) works by "returning" there, there is no instruction to call it.     |
+// Both ways load CS and SS with the ring 3 selectors of the GDT (see gdt.rs):                        |
+//                                                                                                    |
+//   iretq    pops rip, cs, rflags, rsp, ss from the stack, we push a frame that looks like user code  |
+//            got interrupted right before `entry`.                                                   |
+//   sysretq  rip = rcx, rflags = r11, CS and SS come from the STAR MSR. rsp is not touched, so it     |
+//            has to be switched to the user stack by hand right before.                              |
+//                                                                                                    |
+// The thread keeps its kernel stack: interrupts and exceptions in ring 3 switch to the stack in the  |
+// TSS, which the scheduler sets to the top of the kernel stack of the running thread. Whatever the   |
+// thread had on that stack before entering user mode is overwritten, so there is no way back, the     |
+// thread ends when the user code exits or faults (see process.rs).                                  |
+//                                                                                                    |
+// GS base is swapped with `swapgs` on the way out, the trap stubs swap it back on every entry from    |
+// ring 3 (see interrupts/trap.rs). Every other register is cleared so no kernel data leaks.          |
+// ---------------------------------------------------------------------------------------------------┙
+
+/// **Enter user mode via `iretq`**, executing `entry` on the user stack `stack` with interrupts enabled.
+///
+/// # Safety
+/// `entry` and `stack` must be mapped `USER_ACCESSIBLE`, `stack` writable. The caller has to be a thread spawned by the
+/// [`scheduler`], it never returns: its kernel stack is used for interrupts from user mode from now on.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> ! {
+    prepare(entry, stack);
+    let selectors = gdt::selectors();
+    asm!(
+        "swapgs",
+        "push {ss}",
+        "push {stack}",
+        "push {rflags}",
+        "push {cs}",
+        "push {entry}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) u64::from(selectors.user_data.0),
+        stack = in(reg) stack.as_u64(),
+        rflags = in(reg) user_flags(),
+        cs = in(reg) u64::from(selectors.user_code.0),
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    )
+}
+
+/// **Enter user mode via `sysretq`**, same as [`enter`] but without going through memory.
+///
+/// # Safety
+/// See [`enter`].
+pub unsafe fn enter_sysret(entry: VirtAddr, stack: VirtAddr) -> ! {
+    prepare(entry, stack);
+    asm!(
+        "swapgs",
+        // From here on an interrupt would push onto the user stack in ring 0, they are disabled
+        // until sysretq loads rflags from r11.
+        "mov rsp, rdi",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "sysretq",
+        in("rcx") entry.as_u64(),
+        in("r11") user_flags(),
+        in("rdi") stack.as_u64(),
+        options(noreturn)
+    )
+}
+
+/// `true` if `addr` is in the user half of the address space, see [`crate::memory`].
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_START..USER_END).contains(&addr.as_u64())
+}
+
+// Checks shared by both ways in, returns with interrupts disabled.
+fn prepare(entry: VirtAddr, stack: VirtAddr) {
+    // sysretq with a non canonical rip faults in ring 0 on the user stack, so this is not just a sanity check.
+    assert!(is_user_address(entry), "user entry point {:?} is not in user space", entry);
+    assert!(is_user_address(stack - 1u64), "user stack {:?} is not in user space", stack);
+    let kernel_stack = scheduler::current_kernel_stack().expect("only scheduler threads can enter user mode");
+
+    x86_64::instructions::interrupts::disable();
+    // The scheduler sets it on every switch, this only fails before `gdt::init_cpu`.
+    assert_eq!(gdt::kernel_stack(), Some(kernel_stack), "TSS of this CPU has no kernel stack for user mode");
+}
+
+fn user_flags() -> u64 {
+    // Bit 1 is reserved and always set.
+    RFlags::INTERRUPT_FLAG.bits() | 0x2
+}
@@ -1,26 +1,44 @@
 use volatile::Volatile;
-use spin::Mutex;
-
-lazy_static::lazy_static! {
-    /// This is a VGA buffer.
-    /// 
-    /// `Writer` wrapped inside `Mutex`, this makes *WRITER* `Sync` (thread safe) 
-    /// preventing data races and at the same time providing interior mutability.
-    /// 
-    /// #### WRITER is a global mutable thread-safe variable.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) }
-    });
-}
-
-#[allow(dead_code)]
+
+use crate::sync::{Lazy, SpinLock};
+
+mod ansi;
+mod attribute;
+pub mod cp437;
+pub mod cursor;
+mod scrollback;
+
+pub use cursor::CursorShape;
+pub use scrollback::SCROLLBACK_LINES;
+use ansi::Parser;
+use attribute::AttributeController;
+use cursor::Crtc;
+use scrollback::Scrollback;
+
+/// This is a VGA buffer.
+/// 
+/// `Writer` wrapped inside `SpinLock`, this makes *WRITER* `Sync` (thread safe) 
+/// preventing data races and at the same time providing interior mutability.
+/// The lock disables interrupts while held, so interrupt handlers can print too.
+/// 
+/// #### WRITER is a global mutable thread-safe variable.
+/// 
+/// Output continues at the bottom row, below whatever the bootloader printed. Rows scrolled off the
+/// screen are kept in a static scrollback until [`Writer::set_scrollback_capacity`] moves it to the heap.
+pub static WRITER: Lazy<SpinLock<Writer>> = Lazy::new(|| {
+    let mut writer = Writer::new(ColorCode::default(), unsafe { &mut *(0xb8000 as *mut Buffer) });
+    // Lazy runs this once.
+    writer.scrollback = unsafe { Scrollback::boot() };
+    writer.set_cursor_position(BUFFER_HEIGHT - 1, 0);
+    SpinLock::new(writer)
+});
+
+/// **The 16 colors of VGA text mode.**
+///
+/// Variant determinants go from 0 to 15, the last 8 are the bright variants of the first 8.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
-// Color representation enum.
-// Variant determinants go from 0 to 15.
-enum Color {
+pub enum Color {
     Black = 0,
     Blue,
     Green,
@@ -39,14 +57,38 @@ enum Color {
     White,
 }
 
+impl Color {
+    const ALL: [Color; 16] = [
+        Color::Black, Color::Blue, Color::Green, Color::Cyan,
+        Color::Red, Color::Magenta, Color::Brown, Color::LightGray,
+        Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan,
+        Color::LightRed, Color::Pink, Color::Yellow, Color::White,
+    ];
+
+    // the color with determinant `index`, only the lowest four bits count.
+    fn from_index(index: u8) -> Color {
+        Color::ALL[usize::from(index & 0xF)]
+    }
+
+    /// The bright variant, e.g. `LightRed` for `Red`. Bright colors stay the same.
+    pub fn bright(self) -> Color {
+        Color::from_index(self as u8 | 0x8)
+    }
+}
+
+/// **Foreground and background color of a character**, the attribute byte next to it in the buffer.
+///
+/// Bit 7 is either the bright bit of the background or makes the character blink, depending on
+/// [`Writer::set_bright_backgrounds`]. By default it blinks, and bright backgrounds show as their
+/// normal variant, blinking.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 // Newtype wrapper for our second byte that represents background/foreground
 // color for our ascii character (first byte)
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> Self {
+    pub const fn new(foreground: Color, background: Color) -> Self {
         // First four bits define the foreground color, 
         // the next three bits define the background color.
         //
@@ -62,6 +104,62 @@ impl ColorCode {
         // next three bits contain 2 (010) = Green [foreground]
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    pub fn foreground(self) -> Color {
+        Color::from_index(self.0)
+    }
+
+    /// The background color, bit 7 counts as the bright bit.
+    pub fn background(self) -> Color {
+        Color::from_index(self.0 >> 4)
+    }
+
+    pub fn with_foreground(self, foreground: Color) -> Self {
+        ColorCode::new(foreground, self.background())
+    }
+
+    pub fn with_background(self, background: Color) -> Self {
+        ColorCode::new(self.foreground(), background)
+    }
+
+    /// **Set or clear bit 7**, which makes the character blink unless bright backgrounds are enabled.
+    pub const fn with_blink(self, blink: bool) -> Self {
+        if blink { ColorCode(self.0 | BLINK) } else { ColorCode(self.0 & !BLINK) }
+    }
+
+    /// `true` if bit 7 is set, whether it blinks or brightens the background.
+    pub fn is_blinking(self) -> bool {
+        self.0 & BLINK != 0
+    }
+}
+
+impl Default for ColorCode {
+    /// White on black, what [`WRITER`] starts with.
+    fn default() -> Self {
+        ColorCode::new(Color::White, Color::Black)
+    }
+}
+
+// Bit 7 of the attribute byte.
+const BLINK: u8 = 1 << 7;
+
+/// **Something [`Writer::with_color`] and [`cprint!`](crate::cprint) can color text with**: a
+/// [`Color`] only changes the foreground, a [`ColorCode`] both colors.
+pub trait TextColor {
+    /// The colors to write with, instead of `current`.
+    fn apply(self, current: ColorCode) -> ColorCode;
+}
+
+impl TextColor for Color {
+    fn apply(self, current: ColorCode) -> ColorCode {
+        current.with_foreground(self)
+    }
+}
+
+impl TextColor for ColorCode {
+    fn apply(self, _current: ColorCode) -> ColorCode {
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -75,8 +173,8 @@ struct ScreenChar {
 }
 
 // vga_buffer size
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
 // using repr(transparent) to indicate Buffer is only a Newtype
@@ -86,77 +184,321 @@ struct Buffer {
 }
 
 /// [`Writer`] that can directly write to vga_buffer.
+///
+/// It keeps a cursor, the row and column the next character goes to, and moves the blinking
+/// hardware cursor along with it. Rows that scroll off the top go into a scrollback the view can
+/// be moved into, see [`Writer::scroll_up`].
 pub struct Writer {
-    column_position: usize,
+    row: usize,
+    // Goes up to BUFFER_WIDTH, the next character then wraps to a new line first.
+    column: usize,
     color_code: ColorCode,
+    // Bold text (SGR 1) is drawn with the bright variant of the foreground color.
+    bold: bool,
+    // What SGR 0 (reset) goes back to.
+    default_color: ColorCode,
     buffer: &'static mut Buffer,
+    crtc: Crtc,
+    attributes: AttributeController,
+    // Bit 7 of the attribute is the bright bit of the background instead of blink.
+    bright_backgrounds: bool,
+    cursor_shape: CursorShape,
+    cursor_visible: bool,
+    // Escape sequences can be split over several `write_string` calls.
+    parser: Parser,
+    saved_cursor: SavedCursor,
+    scrollback: Scrollback,
 }
 
+// Cursor saved by an escape sequence, see ansi.rs.
+#[derive(Clone, Copy)]
+struct SavedCursor {
+    row: usize,
+    column: usize,
+    color_code: ColorCode,
+    bold: bool,
+}
+
+// Tab stops are every TAB_WIDTH columns.
+const TAB_WIDTH: usize = 8;
+// Written for the backspace key, ASCII BS.
+const BACKSPACE: u8 = 0x08;
+// What the escape sequence parser gets for characters outside ASCII.
+const NON_ASCII: u8 = 0xFF;
+
 impl Writer {
-    // if our column_position is greater or equal to vga_buffer width
-    // or byte equals to new_line, we copy and write the values from the
-    // current row to the preceding row. (check new_line method)
+    fn new(color_code: ColorCode, buffer: &'static mut Buffer) -> Self {
+        Writer {
+            row: 0,
+            column: 0,
+            color_code,
+            bold: false,
+            default_color: color_code,
+            buffer,
+            crtc: Crtc::new(),
+            attributes: AttributeController::new(),
+            bright_backgrounds: false,
+            cursor_shape: CursorShape::default(),
+            cursor_visible: true,
+            parser: Parser::new(),
+            saved_cursor: SavedCursor { row: 0, column: 0, color_code, bold: false },
+            scrollback: Scrollback::none(),
+        }
+    }
+
+    // Control characters move the cursor, everything else is written at the cursor.
+    // The cursor wraps to a new line when a character is written past the end of the row.
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
-            b => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line()
+            b'\r' => self.column = 0,
+            b'\t' => {
+                if self.column >= BUFFER_WIDTH {
+                    self.new_line();
                 }
+                let stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < stop.min(BUFFER_WIDTH) {
+                    self.write_byte(b' ');
+                }
+            }
+            BACKSPACE => self.backspace(),
+            b => self.write_glyph(b),
+        }
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+    // Writes the code page 437 glyph at the cursor, any byte is a glyph here, control characters too.
+    fn write_glyph(&mut self, glyph: u8) {
+        if self.column >= BUFFER_WIDTH {
+            self.new_line()
+        }
 
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: b,
-                    color_code: self.color_code
-                });
+        self.buffer.chars[self.row][self.column].write(ScreenChar {
+            ascii_character: glyph,
+            color_code: self.text_color()
+        });
+
+        self.column += 1;
+    }
 
-                self.column_position += 1;
+    // moves the cursor to the start of the next row, once it is on the last row
+    // each row gets copied and rewritten to the preceding row and the last row is cleared.
+    // The top row goes into the scrollback.
+    fn new_line(&mut self) { 
+        if self.row < BUFFER_HEIGHT - 1 {
+            self.row += 1;
+        } else {
+            self.save_top_row();
+            for row in 1..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    let char = self.buffer.chars[row][col].read();
+                    self.buffer.chars[row-1][col].write(char);
+                }            
             }
+            self.clear_row(BUFFER_HEIGHT - 1);
         }
+        self.column = 0;
     }
 
-    // each row gets copied and rewritten to the preceding row
-    // after that we clear the last row in vga_buffer and reset position
-    fn new_line(&mut self) { 
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let char = self.buffer.chars[row][col].read();
-                self.buffer.chars[row-1][col].write(char);
-            }            
+    // moves the cursor back one cell and blanks it, from the start of a row
+    // that is the last cell of the row above. Nothing happens at the top left corner.
+    fn backspace(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.column = BUFFER_WIDTH - 1;
+        } else {
+            return;
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+        self.buffer.chars[self.row][self.column].write(self.blank());
     }
 
     // clears the row by filling it with blank bytes -> b' '
     // blank bytes will still have same color formatting defined in `Writer`,
     // meaning only background color will be visible.
     fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_character: b' ',
-            color_code: self.color_code,
-        };
+        let blank = self.blank();
 
         for column in 0..BUFFER_WIDTH {
             self.buffer.chars[row][column].write(blank);
         }
     }
+
+    // the color characters are written with.
+    fn text_color(&self) -> ColorCode {
+        if self.bold {
+            self.color_code.with_foreground(self.color_code.foreground().bright())
+        } else {
+            self.color_code
+        }
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        }
+    }
+
+    // the hardware cursor sits on the cell the next character goes to,
+    // on the last cell while a wrap is pending.
+    fn update_cursor(&mut self) {
+        let location = self.row * BUFFER_WIDTH + self.column.min(BUFFER_WIDTH - 1);
+        self.crtc.set_location(location as u16);
+    }
 }
 
 // Public API of our Writer, this is one of core functions that allows us to write
-// bytes into our vga_buffer.
-// Only ASCII bytes from 0x20 (hexadecimal) through 0x7e including new_line (\n) are valid.
-// Invalid ASCII is printed as 0xfe. (check README.md for valid ASCII table)
+// strings into our vga_buffer.
+// Characters are printed as their code page 437 glyph, new_line (\n),
+// carriage return (\r), tab (\t) and backspace (0x08) move the cursor.
+// ANSI escape sequences (colors, cursor movement, erasing) are interpreted, see ansi.rs.
+// Characters without a glyph are printed as cp437::FALLBACK (0xfe), see cp437.rs.
+// Whatever changes the screen scrolls the view back to the live screen first.
 impl Writer {
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        self.scroll_to_bottom();
+        for char in s.chars() {
+            // Escape sequences are ASCII, the parser gets any other character as one byte it doesn't know.
+            let byte = if char.is_ascii() { char as u8 } else { NON_ASCII };
+            match self.parser.advance(byte) {
+                Some(ansi::Action::Print(byte)) => match byte {
+                    b'\n' | b'\r' | b'\t' | BACKSPACE => self.write_byte(byte),
+                    _ => self.write_glyph(cp437::encode(char).unwrap_or(cp437::FALLBACK)),
+                },
+                Some(ansi::Action::Csi(csi)) => self.execute_csi(&csi),
+                Some(ansi::Action::Esc(byte)) => self.execute_esc(byte),
+                None => {}
             }
         }
+        self.update_cursor();
+    }
+
+    /// **Write `s` starting at `row` and `column`**, without moving the cursor. Nothing wraps, what
+    /// doesn't fit into the row is cut off, and control characters are printed as [`cp437::FALLBACK`].
+    ///
+    /// For things that stay at one place on the screen, like a status line.
+    pub fn write_at(&mut self, row: usize, column: usize, s: &str) {
+        assert!(row < BUFFER_HEIGHT && column < BUFFER_WIDTH, "({}, {}) is outside the screen", row, column);
+        self.scroll_to_bottom();
+        for (column, char) in (column..BUFFER_WIDTH).zip(s.chars()) {
+            let ascii_character = cp437::encode(char).unwrap_or(cp437::FALLBACK);
+            self.buffer.chars[row][column].write(ScreenChar { ascii_character, color_code: self.text_color() });
+        }
+    }
+
+    /// **Blank the whole screen** and move the cursor to the top left corner.
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_cursor_position(0, 0);
+    }
+
+    /// The row and column the next character goes to.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// **Move the cursor to `row` and `column`**, output continues there.
+    pub fn set_cursor_position(&mut self, row: usize, column: usize) {
+        assert!(row < BUFFER_HEIGHT && column < BUFFER_WIDTH, "({}, {}) is outside the screen", row, column);
+        self.row = row;
+        self.column = column;
+        self.update_cursor();
+    }
+
+    /// Show the blinking hardware cursor, with the last shape set. It stays hidden while the view
+    /// is scrolled back.
+    pub fn show_cursor(&mut self) {
+        self.cursor_visible = true;
+        if self.scroll_offset() == 0 {
+            self.crtc.set_shape(self.cursor_shape);
+        }
+    }
+
+    /// Hide the hardware cursor, the cursor position is still tracked.
+    pub fn hide_cursor(&mut self) {
+        self.cursor_visible = false;
+        self.crtc.hide();
+    }
+
+    /// **Change the scanlines the hardware cursor covers**, a hidden cursor stays hidden.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        assert!(shape.start <= shape.end && shape.end < 16, "invalid cursor shape {:?}", shape);
+        self.cursor_shape = shape;
+        if self.cursor_visible && self.scroll_offset() == 0 {
+            self.crtc.set_shape(shape);
+        }
+    }
+
+    pub fn is_cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// The colors text is written with.
+    pub fn color(&self) -> ColorCode {
+        self.color_code
+    }
+
+    /// **Write the following text with `color_code`**, until it is changed again (also by escape
+    /// sequences). A reset (`ESC [0m`) goes back to white on black.
+    pub fn set_color(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+        self.bold = false;
+    }
+
+    /// **Write with `color` until the returned guard is dropped**, then the colors before are back.
+    /// The guard derefs to the writer:
+    ///
+    /// ```ignore
+    /// let mut writer = WRITER.lock();
+    /// writeln!(writer.with_color(Color::LightRed), "error: {}", error).unwrap();
+    /// ```
+    pub fn with_color(&mut self, color: impl TextColor) -> ColorGuard<'_> {
+        let previous = (self.color_code, self.bold);
+        self.set_color(color.apply(self.color_code));
+        ColorGuard { writer: self, previous }
+    }
+
+    /// **Use bit 7 of [`ColorCode`] as the bright bit of the background** instead of blink (`false`,
+    /// the default). Changes what the whole screen looks like right away, it is a mode of the VGA card.
+    pub fn set_bright_backgrounds(&mut self, enabled: bool) {
+        self.bright_backgrounds = enabled;
+        self.attributes.set_blink(!enabled);
+    }
+
+    pub fn bright_backgrounds(&self) -> bool {
+        self.bright_backgrounds
+    }
+}
+
+/// Colors of a [`Writer`] changed by [`Writer::with_color`], restores the previous ones when dropped.
+pub struct ColorGuard<'a> {
+    writer: &'a mut Writer,
+    previous: (ColorCode, bool),
+}
+
+impl core::ops::Deref for ColorGuard<'_> {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        self.writer
+    }
+}
+
+impl core::ops::DerefMut for ColorGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Writer {
+        self.writer
+    }
+}
+
+impl Drop for ColorGuard<'_> {
+    fn drop(&mut self) {
+        let (color_code, bold) = self.previous;
+        self.writer.color_code = color_code;
+        self.writer.bold = bold;
     }
 }
 
@@ -182,29 +524,172 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// **Like [`print!`], in a color**: a [`Color`] for the text or a [`ColorCode`] for text and background.
+///
+/// ```ignore
+/// cprintln!(Color::Yellow, "warning: {} frames left", free);
+/// ```
+#[macro_export]
+macro_rules! cprint {
+    ($color:expr, $($arg:tt)*) => ($crate::vga_buffer::_cprint($color, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! cprintln {
+    ($color:expr) => ($crate::cprint!($color, "\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::cprint!($color, "{}\n", format_args!($($arg)*)));
+}
+
 // Writes directly to vga_buffer aka WRITER (global buff)
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+#[doc(hidden)]
+pub fn _cprint(color: impl TextColor, args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    WRITER.lock().with_color(color).write_fmt(args).unwrap();
 }
 
 #[test_case]
 fn test_println_output() {
-    use x86_64::instructions::interrupts;
     use core::fmt::Write;
 
     let output = "Check if this string is in the vga_buffer.";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", output).expect("writeln! failed");
-        for (i, char) in output.chars().enumerate() {
-            let buffer_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(buffer_char.ascii_character as char, char);
-        }
-    });
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", output).expect("writeln! failed");
+    let row = writer.cursor_position().0 - 1;
+    for (i, char) in output.chars().enumerate() {
+        let buffer_char = writer.buffer.chars[row][i].read();
+        assert_eq!(buffer_char.ascii_character as char, char);
+    }
+}
+
+#[test_case]
+fn test_cprintln_colors_text() {
+    cprintln!(Color::LightRed, "Colored {}.", "output");
+    let writer = WRITER.lock();
+    let row = writer.cursor_position().0 - 1;
+    let char = writer.buffer.chars[row][0].read();
+    assert_eq!(char.ascii_character, b'C');
+    assert_eq!(char.color_code, ColorCode::new(Color::LightRed, Color::Black));
+    assert_eq!(writer.color(), ColorCode::default());
+}
+
+// A writer on a buffer of its own in the heap, with ports that do nothing, so tests don't mess up the screen.
+#[cfg(test)]
+fn test_writer() -> Writer {
+    let layout = core::alloc::Layout::new::<Buffer>();
+    // All zeroes is a screen of black NUL characters.
+    let buffer = unsafe { &mut *(alloc::alloc::alloc_zeroed(layout) as *mut Buffer) };
+    let mut writer = Writer::new(ColorCode::new(Color::White, Color::Black), buffer);
+    writer.crtc = Crtc::inert();
+    writer.attributes = AttributeController::inert();
+    writer
+}
+
+#[cfg(test)]
+fn test_row(writer: &Writer, row: usize) -> alloc::string::String {
+    writer.buffer.chars[row].iter().map(|char| char.read().ascii_character as char).collect()
+}
+
+#[test_case]
+fn test_control_characters_move_cursor() {
+    let mut writer = test_writer();
+    writer.write_string("abc\rx\ty\n");
+    // The tab blanks the cells it moves over.
+    assert!(test_row(&writer, 0).starts_with("x       y\0"));
+    assert_eq!(writer.cursor_position(), (1, 0));
+
+    writer.write_string("12345678\t!");
+    assert_eq!(writer.cursor_position(), (1, 17));
+    assert!(test_row(&writer, 1).starts_with("12345678        !"));
+}
+
+#[test_case]
+fn test_backspace_erases_previous_cell() {
+    let mut writer = test_writer();
+    writer.write_string("ab\x08\x08c");
+    assert!(test_row(&writer, 0).starts_with("c \0"));
+
+    // At the start of a row it goes back to the end of the row above.
+    writer.set_cursor_position(1, 0);
+    writer.write_string("\x08");
+    assert_eq!(writer.cursor_position(), (0, BUFFER_WIDTH - 1));
+}
+
+#[test_case]
+fn test_write_at_and_clear_screen() {
+    let mut writer = test_writer();
+    writer.write_string("line");
+    writer.write_at(10, BUFFER_WIDTH - 3, "status");
+    assert!(test_row(&writer, 10).ends_with("sta"));
+    assert_eq!(writer.cursor_position(), (0, 4));
+
+    writer.clear_screen();
+    assert_eq!(writer.cursor_position(), (0, 0));
+    assert!(test_row(&writer, 10).chars().all(|char| char == ' '));
+}
+
+#[test_case]
+fn test_long_lines_wrap_and_scroll() {
+    let mut writer = test_writer();
+    writer.set_cursor_position(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1);
+    writer.write_string("ab");
+    assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 1));
+    assert!(test_row(&writer, BUFFER_HEIGHT - 2).ends_with('a'));
+    assert!(test_row(&writer, BUFFER_HEIGHT - 1).starts_with('b'));
+}
+
+#[test_case]
+fn test_with_color_restores_colors() {
+    use core::fmt::Write;
+
+    let mut writer = test_writer();
+    {
+        let mut yellow = writer.with_color(ColorCode::new(Color::Yellow, Color::Blue));
+        write!(yellow, "a").unwrap();
+        let mut red = yellow.with_color(Color::Red);
+        write!(red, "b").unwrap();
+    }
+    write!(writer, "c").unwrap();
+
+    let colors: [ColorCode; 3] = core::array::from_fn(|column| writer.buffer.chars[0][column].read().color_code);
+    assert_eq!(colors, [
+        ColorCode::new(Color::Yellow, Color::Blue),
+        ColorCode::new(Color::Red, Color::Blue),
+        ColorCode::default(),
+    ]);
+}
+
+#[test_case]
+fn test_blink_bit() {
+    let code = ColorCode::new(Color::White, Color::Blue).with_blink(true);
+    assert!(code.is_blinking());
+    assert_eq!(code.background(), Color::LightBlue);
+    assert!(!code.with_blink(false).is_blinking());
+
+    let mut writer = test_writer();
+    writer.write_string("\x1B[5;44ma\x1B[25mb");
+    assert!(writer.buffer.chars[0][0].read().color_code.is_blinking());
+    assert!(!writer.buffer.chars[0][1].read().color_code.is_blinking());
+}
+
+#[test_case]
+fn test_utf8_is_written_as_cp437() {
+    let mut writer = test_writer();
+    writer.write_string("┌─┐ 25°C µs ☃\n◙");
+    let glyphs: [u8; 12] = core::array::from_fn(|column| writer.buffer.chars[0][column].read().ascii_character);
+    assert_eq!(&glyphs, b"\xDA\xC4\xBF 25\xF8C \xE6s ");
+    assert_eq!(writer.buffer.chars[0][12].read().ascii_character, cp437::FALLBACK);
+    // A glyph with the byte value of a control character is still only a glyph.
+    assert_eq!(writer.buffer.chars[1][0].read().ascii_character, 0x0A);
+    assert_eq!(writer.cursor_position(), (1, 1));
+
+    writer.write_at(5, 0, "╔═╗\x07");
+    assert!(test_row(&writer, 5).starts_with("\u{C9}\u{CD}\u{BB}\u{FE}"));
 }
\ No newline at end of file
@@ -0,0 +1,421 @@
+use super::{Color, SavedCursor, Writer, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+// ----------------------------------------------------------------------------------------------┐
+// ANSI escape sequences, the part of ECMA-48 terminals agree on. A control sequence (CSI) is    |
+//                                                                                               |
+//   ESC [ <private marker> <parameters> <intermediates> <final byte>                            |
+//                                                                                               |
+// parameters are decimal numbers separated by ';', a missing one is 0 and most commands read 0  |
+// as 1. The final byte (0x40 to 0x7E) picks the command. Supported on the screen:               |
+//                                                                                               |
+//   CSI n A / B / C / D    cursor up / down / forward / back n cells                            |
+//   CSI n E / F            cursor to the start of the n-th next / previous line                 |
+//   CSI n G                cursor to column n              (rows and columns count from 1)      |
+//   CSI r ; c H (or f)     cursor to row r, column c                                            |
+//   CSI n J                erase from the cursor to the end (0), from the start (1), all (2, 3) |
+//   CSI n K                same for the line of the cursor (0, 1, 2)                            |
+//   CSI s / u, ESC 7 / 8   save / restore the cursor, ESC 7 / 8 save the colors too             |
+//   CSI ? 25 h / l         show / hide the cursor                                               |
+//   CSI ... m              select graphic rendition (SGR):                                      |
+//                            0 reset, 1 bold, 22 not bold, 5 blink, 25 not blinking             |
+//                            30-37 / 90-97 foreground, 39 default foreground                    |
+//                            40-47 / 100-107 background, 49 default background                  |
+//                            38;5;n / 48;5;n 256 colors, 38;2;r;g;b / 48;2;r;g;b RGB            |
+//                                                                                               |
+// The VGA has 16 colors: bold is drawn as the bright foreground color, 256 and RGB colors get   |
+// the closest of the 16. nostd_color (our serial colors) writes 38;31 instead of 31 and 48;31   |
+// instead of 41, that is understood too. Bit 7 of the attribute byte is either blink or the     |
+// bright bit of the background (see attribute.rs), the one that is off is ignored: bright       |
+// backgrounds get the normal color while blinking is on, blink does nothing otherwise.          |
+// Anything else is swallowed, so it never shows up as garbage on the screen.                    |
+// ----------------------------------------------------------------------------------------------┙
+
+const ESC: u8 = 0x1B;
+// CAN and SUB abort a sequence.
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+// Parameters beyond this are dropped.
+const MAX_PARAMS: usize = 16;
+
+// ANSI color numbers are red = 1, green = 2, blue = 4, the VGA ones blue = 1, green = 2, red = 4.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC followed by intermediate bytes, ignored up to the final byte.
+    EscapeIgnore,
+    Csi,
+    // A control sequence we don't support, ignored up to the final byte.
+    CsiIgnore,
+}
+
+/// What the [`Writer`] has to do for a byte fed to the [`Parser`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
+    /// Not part of an escape sequence, written (or executed, for control characters) as usual.
+    Print(u8),
+    /// A complete control sequence.
+    Csi(Csi),
+    /// ESC followed by `byte`.
+    Esc(u8),
+}
+
+/// A control sequence, `ESC [` up to the final byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    private: bool,
+    final_byte: u8,
+}
+
+impl Csi {
+    fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    // Parameter `index`, `default` if it is missing or 0.
+    fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+/// **Splits the bytes written to the screen into text and escape sequences**, one byte at a time.
+pub(super) struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub(super) const fn new() -> Self {
+        Parser { state: State::Ground, csi: Csi { params: [0; MAX_PARAMS], len: 0, private: false, final_byte: 0 } }
+    }
+
+    /// **Feed the next byte**, `None` while it belongs to an unfinished (or ignored) sequence.
+    pub(super) fn advance(&mut self, byte: u8) -> Option<Action> {
+        match (self.state, byte) {
+            (_, ESC) => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, _) => Some(Action::Print(byte)),
+            (_, CAN) | (_, SUB) => {
+                self.state = State::Ground;
+                None
+            }
+            // Control characters in the middle of a sequence still do their thing.
+            (_, 0x00..=0x1F) => Some(Action::Print(byte)),
+            (State::Escape, b'[') => {
+                self.state = State::Csi;
+                self.csi = Csi { params: [0; MAX_PARAMS], len: 0, private: false, final_byte: 0 };
+                None
+            }
+            (State::Escape, 0x20..=0x2F) => {
+                self.state = State::EscapeIgnore;
+                None
+            }
+            (State::Escape, _) | (State::EscapeIgnore, 0x30..=0x7E) => {
+                let ignored = self.state == State::EscapeIgnore;
+                self.state = State::Ground;
+                if ignored { None } else { Some(Action::Esc(byte)) }
+            }
+            (State::Csi, b'0'..=b'9') => {
+                if self.csi.len == 0 {
+                    self.csi.len = 1;
+                }
+                let param = &mut self.csi.params[self.csi.len - 1];
+                *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                None
+            }
+            (State::Csi, b';') | (State::Csi, b':') => {
+                // The separator ends one parameter and starts the next, "1;" has two.
+                if self.csi.len == 0 {
+                    self.csi.len = 1;
+                }
+                if self.csi.len < MAX_PARAMS {
+                    self.csi.len += 1;
+                }
+                None
+            }
+            (State::Csi, b'<'..=b'?') if self.csi.len == 0 && !self.csi.private => {
+                self.csi.private = true;
+                None
+            }
+            (State::Csi, 0x40..=0x7E) => {
+                self.state = State::Ground;
+                self.csi.final_byte = byte;
+                Some(Action::Csi(self.csi))
+            }
+            (State::CsiIgnore, 0x40..=0x7E) => {
+                self.state = State::Ground;
+                None
+            }
+            // Intermediate bytes, misplaced private markers, and DEL.
+            (State::Csi, _) => {
+                self.state = State::CsiIgnore;
+                None
+            }
+            (State::EscapeIgnore, _) | (State::CsiIgnore, _) => None,
+        }
+    }
+}
+
+// ANSI color `index` (0 to 7), `bright` for the 90-97 and 100-107 ones.
+fn ansi_color(index: u16, bright: bool) -> Color {
+    let color = ANSI_COLORS[usize::from(index & 0x7)];
+    if bright { color.bright() } else { color }
+}
+
+// Closest of the 16 colors: the channels at least half as bright as the brightest one are on,
+// the brightest one picks the dark or bright variant.
+fn nearest_color(red: u16, green: u16, blue: u16) -> Color {
+    let max = red.max(green).max(blue);
+    if max < 48 {
+        return Color::Black;
+    }
+    let on = |channel: u16| channel * 2 >= max;
+    let index = u16::from(on(red)) | u16::from(on(green)) << 1 | u16::from(on(blue)) << 2;
+    if index == 7 && max < 160 {
+        return if max < 96 { Color::DarkGray } else { Color::LightGray };
+    }
+    ansi_color(index, max >= 192)
+}
+
+// Color number `index` of the xterm 256 color palette: the 16 ANSI colors, a 6x6x6 cube and 24 grays.
+fn palette_color(index: u16) -> Color {
+    match index {
+        0..=15 => ansi_color(index, index >= 8),
+        16..=231 => {
+            let level = |value: u16| if value == 0 { 0 } else { 55 + value * 40 };
+            let cube = index - 16;
+            nearest_color(level(cube / 36), level(cube / 6 % 6), level(cube % 6))
+        }
+        _ => {
+            let gray = 8 + (index.min(255) - 232) * 10;
+            nearest_color(gray, gray, gray)
+        }
+    }
+}
+
+// The color after SGR 38 or 48, and how many parameters it took.
+fn extended_color(params: &[u16]) -> (Option<Color>, usize) {
+    match *params {
+        [5, index, ..] => (Some(palette_color(index)), 2),
+        [2, red, green, blue, ..] => (Some(nearest_color(red.min(255), green.min(255), blue.min(255))), 4),
+        // nostd_color's way.
+        [code @ 30..=37, ..] => (Some(ansi_color(code - 30, false)), 1),
+        [code @ 90..=97, ..] => (Some(ansi_color(code - 90, true)), 1),
+        // Can't tell where it ends, the rest is skipped.
+        _ => (None, params.len()),
+    }
+}
+
+impl Writer {
+    pub(super) fn execute_csi(&mut self, csi: &Csi) {
+        if csi.private {
+            match (csi.params(), csi.final_byte) {
+                ([25], b'h') => self.show_cursor(),
+                ([25], b'l') => self.hide_cursor(),
+                _ => {}
+            }
+            return;
+        }
+
+        let count = usize::from(csi.param(0, 1));
+        // A pending wrap (column == BUFFER_WIDTH) is undone by moving the cursor.
+        let column = self.column.min(BUFFER_WIDTH - 1);
+        match csi.final_byte {
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = (self.row + count).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column = (column + count).min(BUFFER_WIDTH - 1),
+            b'D' => self.column = column.saturating_sub(count),
+            b'E' => {
+                self.row = (self.row + count).min(BUFFER_HEIGHT - 1);
+                self.column = 0;
+            }
+            b'F' => {
+                self.row = self.row.saturating_sub(count);
+                self.column = 0;
+            }
+            b'G' => self.column = (count - 1).min(BUFFER_WIDTH - 1),
+            b'H' | b'f' => {
+                self.row = usize::from(csi.param(0, 1) - 1).min(BUFFER_HEIGHT - 1);
+                self.column = usize::from(csi.param(1, 1) - 1).min(BUFFER_WIDTH - 1);
+            }
+            b'J' => match csi.param(0, 0) {
+                0 => self.erase(self.row, column, BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1),
+                1 => self.erase(0, 0, self.row, column),
+                2 | 3 => self.erase(0, 0, BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1),
+                _ => {}
+            },
+            b'K' => match csi.param(0, 0) {
+                0 => self.erase(self.row, column, self.row, BUFFER_WIDTH - 1),
+                1 => self.erase(self.row, 0, self.row, column),
+                2 => self.erase(self.row, 0, self.row, BUFFER_WIDTH - 1),
+                _ => {}
+            },
+            b'm' => self.select_graphic_rendition(csi.params()),
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(false),
+            _ => {}
+        }
+    }
+
+    pub(super) fn execute_esc(&mut self, byte: u8) {
+        match byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(true),
+            _ => {}
+        }
+    }
+
+    // Blank the cells from (first_row, first_column) through (last_row, last_column), in reading order.
+    fn erase(&mut self, first_row: usize, first_column: usize, last_row: usize, last_column: usize) {
+        let blank = self.blank();
+        let first = first_row * BUFFER_WIDTH + first_column;
+        let last = last_row * BUFFER_WIDTH + last_column;
+        for cell in first..=last {
+            self.buffer.chars[cell / BUFFER_WIDTH][cell % BUFFER_WIDTH].write(blank);
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // No parameters at all is a reset.
+        if params.is_empty() {
+            self.color_code = self.default_color;
+            self.bold = false;
+        }
+        let mut index = 0;
+        while index < params.len() {
+            match params[index] {
+                0 => {
+                    self.color_code = self.default_color;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                5 | 6 if !self.bright_backgrounds => self.color_code = self.color_code.with_blink(true),
+                25 if !self.bright_backgrounds => self.color_code = self.color_code.with_blink(false),
+                code @ 30..=37 => self.set_foreground(ansi_color(code - 30, false)),
+                code @ 90..=97 => self.set_foreground(ansi_color(code - 90, true)),
+                39 => self.set_foreground(self.default_color.foreground()),
+                code @ 40..=47 => self.set_background(ansi_color(code - 40, false)),
+                code @ 100..=107 => self.set_background(ansi_color(code - 100, true)),
+                49 => self.set_background(self.default_color.background()),
+                code @ 38 | code @ 48 => {
+                    let (color, used) = extended_color(&params[index + 1..]);
+                    index += used;
+                    match color {
+                        Some(color) if code == 38 => self.set_foreground(color),
+                        Some(color) => self.set_background(color),
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+            index += 1;
+        }
+    }
+
+    fn set_foreground(&mut self, color: Color) {
+        self.color_code = self.color_code.with_foreground(color);
+    }
+
+    fn set_background(&mut self, color: Color) {
+        if self.bright_backgrounds {
+            self.color_code = self.color_code.with_background(color);
+        } else {
+            // The bright bit is the blink bit, which stays as it is.
+            let blink = self.color_code.is_blinking();
+            self.color_code = self.color_code.with_background(Color::from_index(color as u8 & 0x7)).with_blink(blink);
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = SavedCursor {
+            row: self.row,
+            column: self.column,
+            color_code: self.color_code,
+            bold: self.bold,
+        };
+    }
+
+    fn restore_cursor(&mut self, with_colors: bool) {
+        let saved = self.saved_cursor;
+        self.row = saved.row;
+        self.column = saved.column;
+        if with_colors {
+            self.color_code = saved.color_code;
+            self.bold = saved.bold;
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{test_row, test_writer, ColorCode};
+
+#[test_case]
+fn test_sequences_are_not_printed() {
+    use nostd_color::colorize::Colored;
+    use nostd_color::colors::{BRIGHT_GREEN, RED};
+
+    let mut writer = test_writer();
+    writer.write_string(&alloc::format!("[{}] {}", "ok".fg(BRIGHT_GREEN), "error".bg(RED)));
+    assert!(test_row(&writer, 0).starts_with("[ok] error\0"));
+
+    let green = writer.buffer.chars[0][1].read().color_code;
+    assert_eq!(green.foreground(), Color::LightGreen);
+    let red = writer.buffer.chars[0][5].read().color_code;
+    assert_eq!((red.foreground(), red.background()), (Color::White, Color::Red));
+    // Both were reset at the end.
+    assert_eq!(writer.color_code, writer.default_color);
+}
+
+#[test_case]
+fn test_sequences_split_over_writes() {
+    let mut writer = test_writer();
+    writer.write_string("\x1B[3");
+    writer.write_string("4;1mx\x1B[0m");
+    let color_code = writer.buffer.chars[0][0].read().color_code;
+    assert_eq!(color_code, ColorCode::new(Color::LightBlue, Color::Black));
+    assert!(!writer.bold);
+}
+
+#[test_case]
+fn test_cursor_movement_and_erase() {
+    let mut writer = test_writer();
+    writer.write_string("hello\x1B[3;10Hx\x1B[2Ay");
+    assert_eq!(test_row(&writer, 2).find('x'), Some(9));
+    assert_eq!(test_row(&writer, 0).find('y'), Some(10));
+
+    writer.write_string("\x1B[1;2H\x1B[K");
+    assert_eq!(writer.cursor_position(), (0, 1));
+    assert!(test_row(&writer, 0).starts_with('h'));
+    assert!(test_row(&writer, 0)[1..].chars().all(|char| char == ' '));
+
+    writer.write_string("\x1B[s\x1B[20;1Hz\x1B[u!");
+    assert!(test_row(&writer, 19).starts_with('z'));
+    assert!(test_row(&writer, 0).starts_with("h!"));
+
+    writer.write_string("\x1B[2J");
+    assert!(test_row(&writer, 2).chars().all(|char| char == ' '));
+}
+
+#[test_case]
+fn test_extended_colors() {
+    assert_eq!(palette_color(9), Color::LightRed);
+    assert_eq!(palette_color(18), Color::Blue);
+    assert_eq!(palette_color(231), Color::White);
+    assert_eq!(palette_color(244), Color::LightGray);
+    assert_eq!(nearest_color(255, 255, 0), Color::Yellow);
+    assert_eq!(nearest_color(128, 0, 0), Color::Red);
+    assert_eq!(nearest_color(10, 10, 10), Color::Black);
+}
@@ -0,0 +1,62 @@
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+// ---------------------------------------------------------------------------------------------┐
+// Bit 7 of the attribute byte of a character is either "blink" or the bright bit of the        |
+// background color (4 bit backgrounds instead of 3 bit ones). Which one is up to the mode      |
+// control register of the VGA attribute controller, blink is what the BIOS sets up.            |
+//                                                                                              |
+// The attribute controller has a single port 0x3C0 for both the register index and the data:   |
+// a flip-flop alternates between them with every write. Reading 0x3DA (input status 1) resets  |
+// it to "index". The current value of a register is read from 0x3C1.                           |
+//   index byte - bits 4-0 = register, bit 5 = palette address source, must stay set or the     |
+//                screen goes blank                                                             |
+//   0x10 mode control - bit 3 = blink enable                                                   |
+// ---------------------------------------------------------------------------------------------┙
+
+const INPUT_STATUS_1: u16 = 0x3DA;
+const ATTRIBUTE_ADDRESS_DATA: u16 = 0x3C0;
+const ATTRIBUTE_DATA_READ: u16 = 0x3C1;
+
+const REG_MODE_CONTROL: u8 = 0x10;
+const PALETTE_ADDRESS_SOURCE: u8 = 1 << 5;
+const MODE_BLINK_ENABLE: u8 = 1 << 3;
+
+// The attribute controller registers, only touched by the `Writer` that owns them.
+pub(super) struct AttributeController {
+    input_status: PortReadOnly<u8>,
+    address_data: Port<u8>,
+    data_read: PortReadOnly<u8>,
+    // Never touches the ports, for writers that don't own the screen.
+    inert: bool,
+}
+
+impl AttributeController {
+    pub(super) const fn new() -> Self {
+        AttributeController {
+            input_status: PortReadOnly::new(INPUT_STATUS_1),
+            address_data: Port::new(ATTRIBUTE_ADDRESS_DATA),
+            data_read: PortReadOnly::new(ATTRIBUTE_DATA_READ),
+            inert: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) const fn inert() -> Self {
+        AttributeController { inert: true, ..AttributeController::new() }
+    }
+
+    // `true` makes bit 7 of the attribute blink, `false` makes it the bright bit of the background.
+    pub(super) fn set_blink(&mut self, blink: bool) {
+        if self.inert {
+            return;
+        }
+        unsafe {
+            self.input_status.read();
+            self.address_data.write(REG_MODE_CONTROL | PALETTE_ADDRESS_SOURCE);
+            let mode = self.data_read.read();
+            let mode = if blink { mode | MODE_BLINK_ENABLE } else { mode & !MODE_BLINK_ENABLE };
+            // Reading 0x3C1 doesn't move the flip-flop, the next write is still the data.
+            self.address_data.write(mode);
+        }
+    }
+}
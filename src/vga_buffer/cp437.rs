@@ -0,0 +1,101 @@
+// ---------------------------------------------------------------------------------------------┐
+// The font of VGA text mode is code page 437, the character set of the original IBM PC. Bytes  |
+// 0x20 to 0x7E are ASCII, the others are glyphs too:                                           |
+//   0x01 - 0x1F  faces, card suits, arrows and more, at the byte values of control characters  |
+//   0x7F         a house                                                                       |
+//   0x80 - 0xAF  accented Latin letters, currency signs, fractions, quotes                     |
+//   0xB0 - 0xDF  shades, box drawing with single and double lines, block elements              |
+//   0xE0 - 0xFF  Greek letters, math symbols, degree sign, a square, no-break space            |
+//                                                                                              |
+// Strings are UTF-8, each character is looked up here and written as its glyph. Characters     |
+// without a glyph of their own but one that looks the same (heavy and rounded box drawing, the |
+// Greek letters the Latin-1 ones stand in for) use that one. Everything else is FALLBACK.      |
+// ---------------------------------------------------------------------------------------------┙
+
+/// **Glyph for characters code page 437 doesn't have**, `■`. Control characters that don't move the
+/// cursor are written as it too.
+pub const FALLBACK: u8 = 0xFE;
+
+// Characters of the glyphs 0x00 to 0x1F, 0x00 is an empty cell.
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+const HOUSE: char = '⌂';
+
+// Characters of the glyphs 0x80 to 0xFF.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+// Characters drawn with the glyph of another one.
+const ALIASES: [(char, u8); 52] = [
+    // Heavy, mixed and rounded box drawing as the single line one.
+    ('━', 0xC4), ('┃', 0xB3),
+    ('┄', 0xC4), ('┅', 0xC4), ('┈', 0xC4), ('┉', 0xC4), ('╌', 0xC4), ('╍', 0xC4),
+    ('┆', 0xB3), ('┇', 0xB3), ('┊', 0xB3), ('┋', 0xB3), ('╎', 0xB3), ('╏', 0xB3),
+    ('┍', 0xDA), ('┎', 0xDA), ('┏', 0xDA), ('╭', 0xDA),
+    ('┑', 0xBF), ('┒', 0xBF), ('┓', 0xBF), ('╮', 0xBF),
+    ('┕', 0xC0), ('┖', 0xC0), ('┗', 0xC0), ('╰', 0xC0),
+    ('┙', 0xD9), ('┚', 0xD9), ('┛', 0xD9), ('╯', 0xD9),
+    ('┝', 0xC3), ('┠', 0xC3), ('┣', 0xC3),
+    ('┥', 0xB4), ('┨', 0xB4), ('┫', 0xB4),
+    ('┯', 0xC2), ('┰', 0xC2), ('┳', 0xC2),
+    ('┷', 0xC1), ('┸', 0xC1), ('┻', 0xC1),
+    ('┿', 0xC5), ('╂', 0xC5), ('╋', 0xC5),
+    // Greek letters and symbols that share a glyph.
+    ('β', 0xE1), ('μ', 0xE6), ('\u{2126}', 0xEA), ('∅', 0xED), ('∈', 0xEE), ('∑', 0xE4),
+    ('∎', 0xFE),
+];
+
+/// **The code page 437 glyph of `char`**, `None` for characters without one and for the control
+/// characters.
+pub fn encode(char: char) -> Option<u8> {
+    match char {
+        ' '..='~' => Some(char as u8),
+        '\0'..='\x7F' => None,
+        HOUSE => Some(0x7F),
+        _ => LOW.iter().position(|&low| low == char)
+            .or_else(|| HIGH.iter().position(|&high| high == char).map(|index| index + 0x80))
+            .map(|glyph| glyph as u8)
+            .or_else(|| ALIASES.iter().find(|&&(alias, _)| alias == char).map(|&(_, glyph)| glyph)),
+    }
+}
+
+/// **The character glyph `byte` shows**, the way around of [`encode`]. 0x00 is `'\0'`.
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0x00..=0x1F => LOW[usize::from(byte)],
+        0x7F => HOUSE,
+        0x80..=0xFF => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+#[test_case]
+fn test_glyphs_round_trip() {
+    for byte in 0x01..=0xFF {
+        assert_eq!(encode(decode(byte)), Some(byte), "glyph {:#04x}", byte);
+    }
+    assert_eq!(encode('\n'), None);
+    assert_eq!(encode('\x7F'), None);
+    assert_eq!(encode('☃'), None);
+}
+
+#[test_case]
+fn test_aliases() {
+    assert_eq!(encode('┙'), encode('┘'));
+    assert_eq!(encode('╭'), encode('┌'));
+    assert_eq!(encode('μ'), encode('µ'));
+    for (alias, glyph) in ALIASES {
+        assert_ne!(decode(glyph), alias);
+    }
+}
@@ -0,0 +1,108 @@
+use x86_64::instructions::port::Port;
+
+// ---------------------------------------------------------------------------------------------┐
+// The blinking hardware cursor of text mode belongs to the CRT controller of the VGA card,     |
+// which is programmed through two ports:                                                       |
+//   0x3D4 - register index                                                                     |
+//   0x3D5 - data of the selected register                                                      |
+//                                                                                              |
+// Registers we care about:                                                                     |
+//   0x0A cursor start - bits 4-0 = first scanline of the cursor, bit 5 = cursor disabled       |
+//   0x0B cursor end   - bits 4-0 = last scanline of the cursor                                 |
+//   0x0E/0x0F         - high/low byte of the cursor location, row * BUFFER_WIDTH + column      |
+//                                                                                              |
+// A character cell is 16 scanlines high, the usual underline cursor covers the last two. The   |
+// top bits of the start/end registers mean other things, they are kept on shape changes.       |
+// ---------------------------------------------------------------------------------------------┙
+
+const CRTC_ADDRESS: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+
+const REG_CURSOR_START: u8 = 0x0A;
+const REG_CURSOR_END: u8 = 0x0B;
+const REG_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const REG_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+const CURSOR_DISABLE: u8 = 1 << 5;
+const SCANLINE_MASK: u8 = 0x1F;
+
+/// **Scanlines the hardware cursor covers** in its character cell, from `start` to `end` (0 to 15).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorShape {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl CursorShape {
+    /// The thin line at the bottom of the cell, what the BIOS sets up.
+    pub const UNDERLINE: CursorShape = CursorShape { start: 14, end: 15 };
+    /// The lower half of the cell.
+    pub const HALF_BLOCK: CursorShape = CursorShape { start: 8, end: 15 };
+    /// The whole cell.
+    pub const BLOCK: CursorShape = CursorShape { start: 0, end: 15 };
+}
+
+impl Default for CursorShape {
+    fn default() -> Self {
+        Self::UNDERLINE
+    }
+}
+
+// The CRT controller registers, only touched by the `Writer` that owns them.
+pub(super) struct Crtc {
+    address: Port<u8>,
+    data: Port<u8>,
+    // Never touches the ports, for writers that don't own the screen.
+    inert: bool,
+}
+
+impl Crtc {
+    pub(super) const fn new() -> Self {
+        Crtc { address: Port::new(CRTC_ADDRESS), data: Port::new(CRTC_DATA), inert: false }
+    }
+
+    #[cfg(test)]
+    pub(super) const fn inert() -> Self {
+        Crtc { inert: true, ..Crtc::new() }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        if self.inert {
+            return 0;
+        }
+        unsafe {
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        if self.inert {
+            return;
+        }
+        unsafe {
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+
+    // `location` is the index of the cell, row * BUFFER_WIDTH + column.
+    pub(super) fn set_location(&mut self, location: u16) {
+        let [low, high] = location.to_le_bytes();
+        self.write(REG_CURSOR_LOCATION_HIGH, high);
+        self.write(REG_CURSOR_LOCATION_LOW, low);
+    }
+
+    // Shows the cursor too, the disable bit lives in the start register.
+    pub(super) fn set_shape(&mut self, shape: CursorShape) {
+        let start = self.read(REG_CURSOR_START) & !(CURSOR_DISABLE | SCANLINE_MASK);
+        self.write(REG_CURSOR_START, start | (shape.start & SCANLINE_MASK));
+        let end = self.read(REG_CURSOR_END) & !SCANLINE_MASK;
+        self.write(REG_CURSOR_END, end | (shape.end & SCANLINE_MASK));
+    }
+
+    pub(super) fn hide(&mut self) {
+        let start = self.read(REG_CURSOR_START);
+        self.write(REG_CURSOR_START, start | CURSOR_DISABLE);
+    }
+}
@@ -0,0 +1,257 @@
+use alloc::collections::TryReserveError;
+use alloc::vec::Vec;
+
+use super::{ColorCode, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+// ---------------------------------------------------------------------------------------------┐
+// Rows that scroll off the top of the screen go into a ring of lines instead of being lost.    |
+// Until there is a heap the ring is a static one, BOOT_SCROLLBACK, afterwards                  |
+// `Writer::set_scrollback_capacity` moves it into a bigger one on the heap (SCROLLBACK_LINES). |
+//                                                                                              |
+// Scrolling back copies the live screen aside and draws older lines over it:                   |
+//                                                                                              |
+//   ring: oldest ... newest | live screen rows 0 .. BUFFER_HEIGHT                              |
+//                  [ view, `offset` lines above the live screen ]                              |
+//                                                                                              |
+// Anything that changes the screen first snaps the view back to the live screen, so output     |
+// always lands where the cursor is. The hardware cursor is hidden while the view is scrolled.  |
+// ---------------------------------------------------------------------------------------------┙
+
+/// **Lines of scrollback kept once there is a heap**, what the kernel sets up after `init_heap`.
+pub const SCROLLBACK_LINES: usize = 2000;
+// Lines kept before that, in a static ring.
+const BOOT_SCROLLBACK_LINES: usize = 200;
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+// All zeroes, NUL characters black on black, so the static ring goes to .bss.
+const EMPTY_LINE: Line = [ScreenChar { ascii_character: 0, color_code: ColorCode(0) }; BUFFER_WIDTH];
+
+static mut BOOT_SCROLLBACK: [Line; BOOT_SCROLLBACK_LINES] = [EMPTY_LINE; BOOT_SCROLLBACK_LINES];
+
+enum Storage {
+    Static(&'static mut [Line]),
+    Heap(Vec<Line>),
+}
+
+pub(super) struct Scrollback {
+    storage: Storage,
+    // Index of the oldest line in the ring.
+    start: usize,
+    len: usize,
+    // Lines the view is scrolled back, 0 is the live screen.
+    offset: usize,
+    // The live screen, while the view is scrolled back.
+    screen: [Line; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    // A scrollback that keeps nothing.
+    pub(super) const fn none() -> Self {
+        Scrollback::with_storage(Storage::Static(&mut []))
+    }
+
+    // The static ring, there is only one, for WRITER.
+    //
+    // SAFETY: must be called at most once.
+    pub(super) unsafe fn boot() -> Self {
+        let lines = &mut *core::ptr::addr_of_mut!(BOOT_SCROLLBACK);
+        Scrollback::with_storage(Storage::Static(lines))
+    }
+
+    const fn with_storage(storage: Storage) -> Self {
+        Scrollback { storage, start: 0, len: 0, offset: 0, screen: [EMPTY_LINE; BUFFER_HEIGHT] }
+    }
+
+    fn lines(&self) -> &[Line] {
+        match &self.storage {
+            Storage::Static(lines) => lines,
+            Storage::Heap(lines) => lines,
+        }
+    }
+
+    fn lines_mut(&mut self) -> &mut [Line] {
+        match &mut self.storage {
+            Storage::Static(lines) => lines,
+            Storage::Heap(lines) => lines,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.lines().len()
+    }
+
+    // The `index`th line, 0 is the oldest one.
+    fn line(&self, index: usize) -> &Line {
+        let lines = self.lines();
+        &lines[(self.start + index) % lines.len()]
+    }
+
+    // Adds `line` as the newest line, the oldest one goes once the ring is full.
+    fn push(&mut self, line: Line) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+        let end = (self.start + self.len) % capacity;
+        self.lines_mut()[end] = line;
+        if self.len < capacity {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % capacity;
+        }
+    }
+}
+
+impl Writer {
+    // Called before the top row scrolls off the screen.
+    pub(super) fn save_top_row(&mut self) {
+        let line = core::array::from_fn(|column| self.buffer.chars[0][column].read());
+        self.scrollback.push(line);
+    }
+
+    /// **Scroll the view up by `lines`** into the scrollback, as far as it goes. Shift+PageUp.
+    pub fn scroll_up(&mut self, lines: usize) {
+        if self.scrollback.len == 0 || lines == 0 {
+            return;
+        }
+        if self.scrollback.offset == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                for column in 0..BUFFER_WIDTH {
+                    self.scrollback.screen[row][column] = self.buffer.chars[row][column].read();
+                }
+            }
+            self.crtc.hide();
+        }
+        self.scrollback.offset = (self.scrollback.offset + lines).min(self.scrollback.len);
+        self.draw_view();
+    }
+
+    /// **Scroll the view down by `lines`**, back towards the live screen. Shift+PageDown.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if lines >= self.scrollback.offset {
+            self.scroll_to_bottom();
+        } else {
+            self.scrollback.offset -= lines;
+            self.draw_view();
+        }
+    }
+
+    /// **Show the live screen again**, output does this by itself.
+    pub fn scroll_to_bottom(&mut self) {
+        if self.scrollback.offset == 0 {
+            return;
+        }
+        self.scrollback.offset = 0;
+        for row in 0..BUFFER_HEIGHT {
+            for column in 0..BUFFER_WIDTH {
+                self.buffer.chars[row][column].write(self.scrollback.screen[row][column]);
+            }
+        }
+        if self.cursor_visible {
+            self.crtc.set_shape(self.cursor_shape);
+        }
+        self.update_cursor();
+    }
+
+    /// Lines the view is scrolled back, 0 while it shows the live screen.
+    pub fn scroll_offset(&self) -> usize {
+        self.scrollback.offset
+    }
+
+    /// Lines in the scrollback, the view can go back this far.
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len
+    }
+
+    /// **Keep up to `lines` lines of scrollback**, in a ring on the heap. The newest lines in the
+    /// scrollback stay, the view goes back to the live screen.
+    ///
+    /// Needs the heap, until this is called there are a few hundred lines in a static ring.
+    pub fn set_scrollback_capacity(&mut self, lines: usize) -> Result<(), TryReserveError> {
+        self.scroll_to_bottom();
+
+        let mut ring = Vec::new();
+        ring.try_reserve_exact(lines)?;
+        let kept = self.scrollback.len.min(lines);
+        for index in self.scrollback.len - kept..self.scrollback.len {
+            ring.push(*self.scrollback.line(index));
+        }
+        ring.resize(lines, EMPTY_LINE);
+
+        self.scrollback.storage = Storage::Heap(ring);
+        self.scrollback.start = 0;
+        self.scrollback.len = kept;
+        Ok(())
+    }
+
+    // Draws the screen for the current offset, from the ring and the saved live screen.
+    fn draw_view(&mut self) {
+        let first = self.scrollback.len - self.scrollback.offset;
+        for row in 0..BUFFER_HEIGHT {
+            let index = first + row;
+            let line = if index < self.scrollback.len {
+                self.scrollback.line(index)
+            } else {
+                &self.scrollback.screen[index - self.scrollback.len]
+            };
+            for (column, char) in line.iter().enumerate() {
+                self.buffer.chars[row][column].write(*char);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{test_row, test_writer};
+
+// Writes lines "0" to "29" with a newline each, the first 6 of them scroll off the screen.
+#[cfg(test)]
+fn write_numbered_lines(writer: &mut Writer) {
+    use core::fmt::Write;
+
+    for line in 0..30 {
+        writeln!(writer, "{}", line).unwrap();
+    }
+}
+
+#[test_case]
+fn test_scroll_view_and_snap_back() {
+    let mut writer = test_writer();
+    writer.set_scrollback_capacity(4).unwrap();
+    write_numbered_lines(&mut writer);
+    assert_eq!(writer.scrollback_len(), 4);
+
+    // Only the newest 4 lines are kept, "2" to "5".
+    writer.scroll_up(BUFFER_HEIGHT);
+    assert_eq!(writer.scroll_offset(), 4);
+    assert!(test_row(&writer, 0).starts_with("2\0"));
+    assert!(test_row(&writer, 4).starts_with("6\0"));
+
+    writer.scroll_down(1);
+    assert!(test_row(&writer, 0).starts_with("3\0"));
+
+    // Output brings the live screen back first.
+    writer.write_string("x");
+    assert_eq!(writer.scroll_offset(), 0);
+    assert!(test_row(&writer, BUFFER_HEIGHT - 2).starts_with("29\0"));
+    assert!(test_row(&writer, BUFFER_HEIGHT - 1).starts_with('x'));
+}
+
+#[test_case]
+fn test_scrollback_capacity_keeps_newest_lines() {
+    let mut writer = test_writer();
+    // Without a scrollback the view doesn't move.
+    write_numbered_lines(&mut writer);
+    writer.scroll_up(1);
+    assert_eq!(writer.scroll_offset(), 0);
+
+    // Now all 30 lines scroll off, "6" to "29" from before and then "0" to "5".
+    writer.set_scrollback_capacity(8).unwrap();
+    write_numbered_lines(&mut writer);
+    writer.set_scrollback_capacity(2).unwrap();
+    assert_eq!(writer.scrollback_len(), 2);
+    writer.scroll_up(2);
+    assert!(test_row(&writer, 0).starts_with("4\0"));
+    assert!(test_row(&writer, 1).starts_with("5\0"));
+}
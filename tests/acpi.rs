@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::acpi::{self, Fadt, Madt};
+use rust_os::{allocator, htl_loop, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn tables_are_found_and_valid() {
+    let tables = acpi::tables().expect("no ACPI tables");
+    assert!(tables.root.checksum_ok);
+    assert!(!tables.entries.is_empty());
+    // QEMU always provides these two.
+    assert!(acpi::find_table(b"APIC").is_some());
+    assert!(acpi::find_table(b"FACP").is_some());
+    assert!(acpi::find_table(b"NONE").is_none());
+}
+
+#[test_case]
+fn madt_lists_boot_cpu_and_io_apic() {
+    let madt = Madt::get().unwrap();
+    assert!(madt.processors.iter().any(|p| p.usable));
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_ne!(madt.local_apic_address.as_u64(), 0);
+}
+
+#[test_case]
+fn fadt_has_power_management_blocks() {
+    let fadt = Fadt::get().unwrap();
+    assert!(fadt.pm1a_control_block.is_present());
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+    assert_ne!(fadt.sci_interrupt, 0);
+}
+
+#[test_case]
+fn dump_does_not_panic() {
+    acpi::dump();
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::interrupts::apic;
+use rust_os::time::{self, Duration, Instant};
+use rust_os::{allocator, htl_loop, memory, scheduler};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_the_machine() {
+    let madt = apic::madt().expect("MADT was not kept");
+    assert!(!madt.processors.is_empty());
+    assert!(!madt.io_apics.is_empty());
+    let local_apic = apic::local_apic().unwrap();
+    assert!(madt.processors.iter().any(|p| p.apic_id == local_apic.id()));
+}
+
+#[test_case]
+fn second_init_is_rejected() {
+    assert!(matches!(apic::init(), Err(apic::ApicError::AlreadyInitialized)));
+}
+
+#[test_case]
+fn lapic_timer_drives_the_clock() {
+    assert!(apic::is_enabled());
+    // The PIT is masked now, so ticks can only come from the LAPIC timer.
+    let start = time::ticks();
+    let instant = Instant::now();
+    time::sleep(Duration::from_millis(100));
+    assert!(time::ticks() >= start + time::ticks_for(Duration::from_millis(100)));
+    assert!(instant.elapsed() >= Duration::from_millis(100));
+}
+
+#[test_case]
+fn rtc_irq_routed_through_io_apic() {
+    use rust_os::time::rtc;
+
+    rtc::enable_periodic_interrupt(6);
+    let start = rtc::periodic_ticks();
+    time::sleep(Duration::from_millis(100));
+    rtc::disable_periodic_interrupt();
+    assert!(rtc::periodic_ticks() > start + 10);
+}
+
+#[test_case]
+fn scheduler_preempts_on_lapic_timer() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    scheduler::init().expect("scheduler initialization failed");
+    scheduler::spawn(|| RAN.store(true, Ordering::SeqCst)).unwrap();
+    // Spin without yielding, only a timer interrupt can run the other thread.
+    while !RAN.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+}
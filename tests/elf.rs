@@ -0,0 +1,196 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::loader::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, MAX_ARGUMENTS_SIZE};
+use rust_os::elf::{self, programs, Elf, ElfError, LoadedProgram, PF_X};
+use rust_os::memory::{self, AddressSpace, FRAME_ALLOCATOR};
+use rust_os::scheduler::{self, ThreadId, ThreadState};
+use rust_os::time::{Duration, Instant};
+use rust_os::{allocator, gdt, htl_loop, usermode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn hello() -> Elf<'static> {
+    Elf::parse(programs::HELLO).unwrap()
+}
+
+fn load(argv: &[&str], envp: &[&str]) -> (Arc<AddressSpace>, LoadedProgram) {
+    let address_space = Arc::new(AddressSpace::new().unwrap());
+    let program = elf::load(&address_space, &hello(), argv, envp).unwrap();
+    (address_space, program)
+}
+
+fn read_u64(address_space: &AddressSpace, addr: u64) -> u64 {
+    let mut bytes = [0; 8];
+    address_space.read(VirtAddr::new(addr), &mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+fn read_string(address_space: &AddressSpace, addr: u64) -> String {
+    let mut string = String::new();
+    for offset in 0.. {
+        let mut byte = [0];
+        address_space.read(VirtAddr::new(addr + offset), &mut byte).unwrap();
+        if byte[0] == 0 {
+            break;
+        }
+        string.push(byte[0] as char);
+    }
+    string
+}
+
+fn flags(address_space: &AddressSpace, addr: u64) -> Option<PageTableFlags> {
+    let page = Page::containing_address(VirtAddr::new(addr));
+    address_space.memory().lock().translate_page(page).map(|(_, flags)| flags)
+}
+
+fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
+
+fn wait_for_exit(id: ThreadId) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !matches!(scheduler::state(id), None | Some(ThreadState::Exited)) {
+        assert!(Instant::now() < deadline, "user thread did not terminate");
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn segments_are_mapped_with_their_permissions() {
+    let (address_space, _) = load(&["hello"], &[]);
+
+    for segment in hello().segments() {
+        let start = segment.virtual_address;
+        let end = start + segment.memory_size - 1;
+        for addr in [start, end] {
+            let flags = flags(&address_space, addr).expect("segment is not mapped");
+            assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+            assert_eq!(flags.contains(PageTableFlags::WRITABLE), segment.page_flags().contains(PageTableFlags::WRITABLE));
+            assert_eq!(flags.contains(PageTableFlags::NO_EXECUTE), segment.flags & PF_X == 0);
+        }
+
+        let mut contents = alloc::vec![0; segment.file_size as usize];
+        address_space.read(VirtAddr::new(start), &mut contents).unwrap();
+        assert_eq!(&contents[..], hello().segment_data(&segment));
+        // Past the file contents everything is zero.
+        if segment.memory_size >= segment.file_size + 8 {
+            assert_eq!(read_u64(&address_space, (end - 7) & !7), 0);
+        }
+    }
+
+    // Nothing of it shows up in the kernel page table.
+    let page = Page::containing_address(hello().entry());
+    assert_eq!(memory::kernel_memory().lock().translate_page(page), None);
+}
+
+#[test_case]
+fn stack_holds_arguments_environment_and_auxv() {
+    let argv = ["hello", "first", ""];
+    let envp = ["PATH=/bin", "HOME=/"];
+    let (address_space, program) = load(&argv, &envp);
+    let sp = program.stack_pointer.as_u64();
+    assert_eq!(sp % 16, 0, "stack pointer is not 16 byte aligned");
+    assert!(usermode::is_user_address(program.stack_pointer));
+
+    assert_eq!(read_u64(&address_space, sp), argv.len() as u64);
+    let mut slot = sp + 8;
+    for list in [&argv[..], &envp[..]] {
+        for expected in list {
+            let pointer = read_u64(&address_space, slot);
+            assert_eq!(read_string(&address_space, pointer), *expected);
+            slot += 8;
+        }
+        assert_eq!(read_u64(&address_space, slot), 0);
+        slot += 8;
+    }
+
+    let mut auxv = alloc::vec::Vec::new();
+    loop {
+        let (kind, value) = (read_u64(&address_space, slot), read_u64(&address_space, slot + 8));
+        auxv.push((kind, value));
+        slot += 16;
+        if kind == AT_NULL {
+            break;
+        }
+    }
+    let elf = hello();
+    assert!(auxv.contains(&(AT_ENTRY, elf.entry().as_u64())));
+    assert!(auxv.contains(&(AT_PAGESZ, 4096)));
+    assert!(auxv.contains(&(AT_PHDR, elf.program_header_address().unwrap().as_u64())));
+}
+
+#[test_case]
+fn hello_runs_in_its_own_address_space() {
+    let (address_space, program) = load(&["hello", "from", "ring 3"], &["TERM=vga"]);
+    let (entry, stack) = (program.entry, program.stack_pointer);
+    let id = scheduler::spawn_in(address_space.clone(), move || unsafe { usermode::enter(entry, stack) }).unwrap();
+    wait_for_exit(id);
+
+    // `results` at the start of the data segment, see user/hello.S
+    let data = hello().segments().find(|segment| segment.flags & PF_X == 0).unwrap().virtual_address;
+    assert_eq!(read_u64(&address_space, data), u64::from_le_bytes(*b"hello\0\0\0"));
+    assert_eq!(read_u64(&address_space, data + 8), 3, "argc");
+    assert_eq!(read_u64(&address_space, data + 16), 1, "number of environment variables");
+    assert_eq!(read_u64(&address_space, data + 24), 0, ".bss was not zeroed");
+    assert_eq!(read_u64(&address_space, data + 32), stack.as_u64());
+}
+
+#[test_case]
+fn programs_at_the_same_address_run_side_by_side() {
+    let ids = [
+        elf::spawn(programs::HELLO, &["hello", "one"], &[]).unwrap(),
+        elf::spawn(programs::HELLO, &["hello", "two"], &[]).unwrap(),
+    ];
+    for id in ids {
+        wait_for_exit(id);
+    }
+}
+
+#[test_case]
+fn dropping_the_address_space_frees_its_frames() {
+    // Threads of the earlier tests give their stacks back on one of the next switches, let that happen first.
+    rust_os::time::sleep(Duration::from_millis(50));
+    // Warm up, the first load allocates heap structures that stay around.
+    drop(load(&["hello"], &[]));
+
+    let free = free_frames();
+    let (address_space, _) = load(&["hello"], &[]);
+    assert!(free_frames() < free);
+    drop(address_space);
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn oversized_arguments_are_rejected() {
+    let address_space = AddressSpace::new().unwrap();
+    let huge = "x".repeat(MAX_ARGUMENTS_SIZE as usize);
+    assert_eq!(elf::load(&address_space, &hello(), &[&huge], &[]), Err(ElfError::ArgumentsTooLarge));
+}
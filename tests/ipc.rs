@@ -0,0 +1,178 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::elf::programs;
+use rust_os::ipc::{self, pipe::PIPE_CAPACITY, IpcError};
+use rust_os::process::{self, ExitStatus};
+use rust_os::scheduler;
+use rust_os::task::executor::Executor;
+use rust_os::task::Task;
+use rust_os::time::{Duration, Instant};
+use rust_os::{allocator, gdt, htl_loop, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Several times what a pipe buffers, so the writer has to wait for the reader.
+const STREAM_LENGTH: usize = 3 * PIPE_CAPACITY + 123;
+const MESSAGES: usize = 50;
+
+fn stream() -> impl Iterator<Item = u8> {
+    (0..STREAM_LENGTH).map(|index| (index % 251) as u8)
+}
+
+// Poll the tasks of `executor` until all of them are done.
+fn run_to_completion(executor: &mut Executor) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while executor.task_count() > 0 {
+        assert!(Instant::now() < deadline, "tasks did not complete");
+        executor.run_ready_tasks();
+        scheduler::yield_now();
+    }
+}
+
+// Run user/ipctest.S with `test` as argv[1].
+fn ipctest(test: &str) -> ExitStatus {
+    let pid = process::spawn(programs::IPCTEST, &["ipctest", test], &[]).unwrap();
+    process::wait(Some(pid)).unwrap().1
+}
+
+#[test_case]
+fn pipe_between_threads() {
+    let (reader, writer) = ipc::pipe();
+    scheduler::spawn(move || {
+        let data: Vec<u8> = stream().collect();
+        for chunk in data.chunks(1000) {
+            assert_eq!(writer.write(chunk), Ok(chunk.len()));
+        }
+    })
+    .unwrap();
+
+    let mut received = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        match reader.read(&mut buffer).unwrap() {
+            0 => break,
+            count => received.extend_from_slice(&buffer[..count]),
+        }
+    }
+    assert!(received.iter().copied().eq(stream()));
+}
+
+#[test_case]
+fn channel_between_threads() {
+    let (sender, receiver) = ipc::channel::<(usize, String)>(2);
+    for producer in 0..2 {
+        let sender = sender.clone();
+        scheduler::spawn(move || {
+            for index in 0..MESSAGES {
+                sender.send((producer, format!("message {}", index))).unwrap();
+            }
+        })
+        .unwrap();
+    }
+    drop(sender);
+
+    // Each producer's messages arrive in order, the channel closes after the last one.
+    let mut next = [0; 2];
+    loop {
+        match receiver.recv() {
+            Ok((producer, text)) => {
+                assert_eq!(text, format!("message {}", next[producer]));
+                next[producer] += 1;
+            }
+            Err(error) => {
+                assert_eq!(error, IpcError::Closed);
+                break;
+            }
+        }
+    }
+    assert_eq!(next, [MESSAGES; 2]);
+}
+
+#[test_case]
+fn async_receiver_is_woken_by_a_thread() {
+    let (sender, receiver) = ipc::channel::<usize>(1);
+    let received = Arc::new(AtomicUsize::new(0));
+    let count = received.clone();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        while let Ok(message) = receiver.recv_async().await {
+            assert_eq!(message, count.load(Ordering::Relaxed));
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+    scheduler::spawn(move || {
+        for message in 0..MESSAGES {
+            sender.send(message).unwrap();
+        }
+    })
+    .unwrap();
+
+    run_to_completion(&mut executor);
+    assert_eq!(received.load(Ordering::Relaxed), MESSAGES);
+}
+
+#[test_case]
+fn async_writer_waits_for_a_thread() {
+    let (reader, writer) = ipc::pipe();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let data: Vec<u8> = stream().collect();
+        assert_eq!(writer.write_async(&data).await, Ok(STREAM_LENGTH));
+    }));
+    let consumer = scheduler::spawn(move || {
+        let mut received = Vec::new();
+        let mut buffer = [0; 512];
+        while let Ok(count @ 1..) = reader.read(&mut buffer) {
+            received.extend_from_slice(&buffer[..count]);
+        }
+        assert!(received.iter().copied().eq(stream()));
+    })
+    .unwrap();
+
+    run_to_completion(&mut executor);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while scheduler::state(consumer).is_some() {
+        assert!(Instant::now() < deadline, "consumer did not finish");
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn processes_talk_through_a_pipe() {
+    assert_eq!(ipctest("pipe"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn processes_talk_through_a_channel() {
+    assert_eq!(ipctest("channel"), ExitStatus::Exited(0));
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use nostd_color::{colorize::Colored, colors::{BRIGHT_GREEN, BRIGHT_RED, RED, YELLOW}};
+use rust_os::sync::SpinLock;
+use rust_os::{exit_qemu, QemuExitCode, serial_print, serial_println, htl_loop};
+
+// Lock classes go by the type of the protected value, so each lock gets its own.
+struct First(u8);
+struct Second(u8);
+
+static FIRST: SpinLock<First> = SpinLock::new(First(0));
+static SECOND: SpinLock<Second> = SpinLock::new(Second(0));
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("{}...\t", "lock_order::inverted_order_panics".fg(YELLOW));
+    // Lock checking is only in debug builds.
+    if !cfg!(debug_assertions) {
+        serial_println!("[{}]", "ok".fg(BRIGHT_GREEN));
+        exit_qemu(QemuExitCode::Success);
+    }
+    take_inverted();
+    serial_println!("[{}]", "failed".fg(BRIGHT_RED));
+    exit_qemu(QemuExitCode::Failed);
+    htl_loop()
+}
+
+// If lockdep panics with the inversion report, then test passes. Any other panic is a failure.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { bytes: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    if message.bytes[..message.len].starts_with(b"lockdep: lock order inversion: ") {
+        serial_println!("[{}]", "ok".fg(BRIGHT_GREEN));
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[{}]", "failed".fg(BRIGHT_RED));
+        serial_println!("{}: {}\n", "Error".fg(RED), info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    htl_loop()
+}
+
+// The start of the panic message, there is no heap to format it into.
+struct Message {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// Never deadlocks on one CPU, but two CPUs doing one order each could. Lockdep reports the second order.
+fn take_inverted() {
+    {
+        let first = FIRST.lock();
+        let second = SECOND.lock();
+        assert_eq!(first.0 + second.0, 0);
+    }
+    let second = SECOND.lock();
+    let first = FIRST.lock();
+    assert_eq!(first.0 + second.0, 0);
+}
@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::fault::{self, COPY_ON_WRITE};
+use rust_os::{allocator, htl_loop, memory};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// Unused part of the lower half, see the address space layout in src/memory.rs
+const LAZY_START: u64 = 0x0000_1000_0000_0000;
+const COW_START: u64 = 0x0000_1000_1000_0000;
+const STACK_TOP: u64 = 0x0000_1000_2000_0000;
+const SMALL_REGIONS_START: u64 = 0x0000_1000_3000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn writable() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+#[test_case]
+fn demand_zero_region() {
+    fault::register_lazy_region(VirtAddr::new(LAZY_START), 4 * 4096, writable()).unwrap();
+
+    let ptr = LAZY_START as *mut u64;
+    unsafe {
+        // First touch maps a zeroed frame.
+        assert_eq!(ptr.add(512 * 3).read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+}
+
+#[test_case]
+fn overlapping_and_too_many_regions() {
+    use memory::MemoryError;
+
+    let page = |index: u64| VirtAddr::new(SMALL_REGIONS_START + index * 4096);
+    fault::register_lazy_region(page(0), 2 * 4096, writable()).unwrap();
+    assert_eq!(fault::register_lazy_region(page(1), 4096, writable()), Err(MemoryError::RegionOverlaps));
+
+    // The regions of the other tests count too, fill up whatever is left.
+    let mut index = 2;
+    while fault::register_lazy_region(page(index), 4096, writable()).is_ok() {
+        index += 1;
+        assert!(index <= fault::MAX_REGIONS as u64 + 2, "no region limit");
+    }
+    assert_eq!(fault::register_lazy_region(page(index), 4096, writable()), Err(MemoryError::TooManyRegions));
+    for index in 0..index {
+        fault::unregister_region(page(index));
+    }
+    fault::register_lazy_region(page(0), 4096, writable()).unwrap();
+    fault::unregister_region(page(0));
+}
+
+#[test_case]
+fn copy_on_write_page() {
+    let original = Page::containing_address(VirtAddr::new(COW_START));
+    let alias = original + 1;
+
+    {
+        let mut memory = memory::kernel_memory().lock();
+        memory.map_range(Page::range_inclusive(original, original), writable() | PageTableFlags::PRESENT).unwrap();
+        unsafe { (COW_START as *mut u64).write_volatile(7) };
+
+        let frame = fault::share_copy_on_write(&mut memory, original).unwrap();
+        let (_, flags) = memory.translate_page(original).unwrap();
+        unsafe { memory.map(alias, frame, flags).unwrap() };
+        assert!(flags.contains(COPY_ON_WRITE));
+    }
+
+    let original_ptr = COW_START as *mut u64;
+    let alias_ptr = alias.start_address().as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(alias_ptr.read_volatile(), 7);
+        // Writing through the alias gives it a private copy, the original keeps its value.
+        alias_ptr.write_volatile(8);
+        assert_eq!(alias_ptr.read_volatile(), 8);
+        assert_eq!(original_ptr.read_volatile(), 7);
+        // The original is now the only user and just becomes writable again.
+        original_ptr.write_volatile(9);
+        assert_eq!(original_ptr.read_volatile(), 9);
+    }
+
+    {
+        let memory = memory::kernel_memory().lock();
+        let (original_frame, original_flags) = memory.translate_page(original).unwrap();
+        let (alias_frame, _) = memory.translate_page(alias).unwrap();
+        assert_ne!(original_frame, alias_frame);
+        assert!(!original_flags.contains(COPY_ON_WRITE));
+        assert!(original_flags.contains(PageTableFlags::WRITABLE));
+    }
+}
+
+#[test_case]
+fn stack_region_grows_down() {
+    fault::register_stack_region(VirtAddr::new(STACK_TOP), 64 * 4096, writable()).unwrap();
+
+    let top = STACK_TOP as *mut u8;
+    unsafe {
+        top.sub(1).write_volatile(1);
+        // Skipping a few pages is still growth.
+        top.sub(4 * 4096).write_volatile(2);
+        assert_eq!(top.sub(2 * 4096).read_volatile(), 0);
+    }
+
+    let memory = memory::kernel_memory().lock();
+    for page in 1..=4u64 {
+        assert!(memory.translate(VirtAddr::new(STACK_TOP - page * 4096)).is_some());
+    }
+    assert!(memory.translate(VirtAddr::new(STACK_TOP - 5 * 4096)).is_none());
+}
@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::{programs, Elf};
+use rust_os::memory::{self, FRAME_ALLOCATOR, USER_START};
+use rust_os::process::{self, ExitStatus, Pid, ProcessError, SIGKILL, SIGSEGV};
+use rust_os::scheduler::{self, ThreadId};
+use rust_os::time::{self, Duration, Instant};
+use rust_os::{allocator, gdt, htl_loop};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Run user/proctest.S with `test` as argv[1], see there for what each test does.
+fn proctest(test: &str) -> Pid {
+    process::spawn(programs::PROCTEST, &["proctest", test], &[]).unwrap()
+}
+
+fn run(test: &str) -> ExitStatus {
+    let pid = proctest(test);
+    let (reaped, status) = process::wait(Some(pid)).unwrap();
+    assert_eq!(reaped, pid);
+    assert!(process::with_process(pid, |_| ()).is_none(), "process was not reaped");
+    status
+}
+
+fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
+
+fn wait_until_gone(thread: ThreadId) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while scheduler::state(thread).is_some() {
+        assert!(Instant::now() < deadline, "thread {} did not terminate", thread.as_u64());
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn fork_copies_memory_and_registers() {
+    assert_eq!(run("fork"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn exec_replaces_the_program() {
+    assert_eq!(run("exec"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn a_fault_kills_the_process() {
+    assert_eq!(run("crash"), ExitStatus::Killed(SIGSEGV));
+}
+
+#[test_case]
+fn kill_ends_every_thread() {
+    let pid = proctest("loop");
+    let address_space = process::with_process(pid, |process| process.address_space().cloned()).flatten().unwrap();
+
+    // A second thread runs the same code, on a stack of its own with the same arguments.
+    let page = Page::containing_address(VirtAddr::new(USER_START + 0x10_0000_0000));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    address_space.memory().lock().map_zeroed(page, flags).unwrap();
+    let stack = page.start_address();
+    let strings = stack.as_u64() + 64;
+    let mut arguments = [0u8; 32];
+    for (word, value) in arguments.chunks_mut(8).zip([2, strings, strings + 9, 0]) {
+        word.copy_from_slice(&value.to_le_bytes());
+    }
+    address_space.write(stack, &arguments).unwrap();
+    address_space.write(VirtAddr::new(strings), b"proctest\0loop\0").unwrap();
+    drop(address_space);
+
+    let entry = Elf::parse(programs::PROCTEST).unwrap().entry();
+    let second = process::spawn_thread(pid, entry, stack).unwrap();
+    let threads = process::with_process(pid, |process| process.threads().to_vec()).unwrap();
+    assert_eq!(threads.len(), 2);
+    assert!(threads.contains(&second));
+
+    // Both are busy in user mode, only the timer interrupt gets them out.
+    time::sleep(Duration::from_millis(20));
+    process::kill(pid, ExitStatus::Killed(SIGKILL)).unwrap();
+    assert_eq!(process::wait(Some(pid)).unwrap(), (pid, ExitStatus::Killed(SIGKILL)));
+    for thread in threads {
+        wait_until_gone(thread);
+    }
+}
+
+#[test_case]
+fn orphans_are_reaped_when_they_exit() {
+    let before = process::count();
+    // The parent exits right away, its child 50 ms later with nobody left to wait for it.
+    assert_eq!(run("orphan"), ExitStatus::Exited(5));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while process::count() != before {
+        assert!(Instant::now() < deadline, "orphan was not reaped");
+        time::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test_case]
+fn exited_processes_free_their_memory() {
+    // Warm up, the first run allocates heap structures that stay around.
+    run("fork");
+    // Kernel stacks of exited threads are given back on one of the next switches.
+    time::sleep(Duration::from_millis(50));
+
+    let free = free_frames();
+    assert_eq!(run("fork"), ExitStatus::Exited(0));
+    time::sleep(Duration::from_millis(50));
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn wait_needs_a_child() {
+    assert_eq!(process::wait(None), Err(ProcessError::NoChildren));
+    assert_eq!(process::wait(Some(Pid::from_u64(u64::MAX))), Err(ProcessError::NoSuchProcess));
+    assert_eq!(process::kill(Pid::from_u64(u64::MAX), ExitStatus::Killed(SIGKILL)), Err(ProcessError::NoSuchProcess));
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rust_os::scheduler::{self, ThreadState};
+use rust_os::{allocator, htl_loop, memory, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn wait_until(condition: impl Fn() -> bool) {
+    while !condition() {
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn threads_run_and_exit() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut ids = [None; 3];
+    for id in ids.iter_mut() {
+        *id = Some(scheduler::spawn(|| {
+            for _ in 0..100 {
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+                scheduler::yield_now();
+            }
+        }).unwrap());
+    }
+
+    wait_until(|| COUNTER.load(Ordering::SeqCst) == 300);
+    for id in ids.iter().flatten() {
+        wait_until(|| scheduler::state(*id).is_none());
+    }
+}
+
+#[test_case]
+fn timer_preempts_busy_thread() {
+    static SPINNING: AtomicBool = AtomicBool::new(true);
+    static OTHER_RAN: AtomicBool = AtomicBool::new(false);
+
+    // Never yields, only the timer interrupt can take the CPU away from it.
+    scheduler::spawn(|| {
+        while SPINNING.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }).unwrap();
+    scheduler::spawn(|| OTHER_RAN.store(true, Ordering::SeqCst)).unwrap();
+
+    wait_until(|| OTHER_RAN.load(Ordering::SeqCst));
+    SPINNING.store(false, Ordering::SeqCst);
+}
+
+#[test_case]
+fn sleep_blocks_for_ticks() {
+    static WOKE_AT: AtomicUsize = AtomicUsize::new(0);
+
+    let start = time::ticks();
+    let id = scheduler::spawn(|| {
+        scheduler::sleep(3);
+        WOKE_AT.store(time::ticks() as usize, Ordering::SeqCst);
+    }).unwrap();
+
+    wait_until(|| matches!(scheduler::state(id), Some(ThreadState::Sleeping { .. }) | None));
+    wait_until(|| WOKE_AT.load(Ordering::SeqCst) != 0);
+    assert!(WOKE_AT.load(Ordering::SeqCst) as u64 >= start + 3);
+}
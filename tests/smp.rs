@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use rust_os::interrupts::apic;
+use rust_os::time::{Duration, Instant};
+use rust_os::{allocator, htl_loop, memory, scheduler, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    apic::init().expect("APIC initialization failed");
+    scheduler::init().expect("scheduler initialization failed");
+    smp::init().expect("starting the application processors failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn every_usable_core_is_online() {
+    let usable = apic::madt().unwrap().processors.iter().filter(|p| p.usable).count();
+    // QEMU runs with `-smp 4`, see Cargo.toml.
+    assert!(usable > 1);
+    assert_eq!(smp::cpu_count(), usable);
+}
+
+#[test_case]
+fn second_init_is_rejected() {
+    assert!(matches!(smp::init(), Err(smp::SmpError::AlreadyStarted)));
+}
+
+#[test_case]
+fn per_cpu_data_belongs_to_the_executing_cpu() {
+    for _ in 0..100 {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let per_cpu = smp::percpu::current();
+            assert_eq!(per_cpu.apic_id(), apic::local_apic().unwrap().id());
+            assert!(per_cpu.index() < smp::cpu_count());
+            assert!(!per_cpu.tss().is_null());
+        });
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn threads_run_on_several_cpus() {
+    static SEEN_ON: AtomicU64 = AtomicU64::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let threads = smp::cpu_count();
+    for _ in 0..threads {
+        // Busy threads that never yield, only more CPUs let them make progress at the same time.
+        scheduler::spawn(|| {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(200) {
+                SEEN_ON.fetch_or(1 << smp::cpu_index(), Ordering::SeqCst);
+                core::hint::spin_loop();
+            }
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+    }
+
+    while FINISHED.load(Ordering::SeqCst) < threads {
+        scheduler::yield_now();
+    }
+    assert!(SEEN_ON.load(Ordering::SeqCst).count_ones() > 1);
+}
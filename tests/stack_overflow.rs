@@ -2,55 +2,253 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
-use rust_os::{serial_print, QemuExitCode, exit_qemu, htl_loop};
-use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::gdt::{self, Ist};
+use rust_os::sync::Lazy;
+use rust_os::{allocator, memory, serial_print, serial_println, QemuExitCode, exit_qemu, htl_loop};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
 use nostd_color::colorize::Colored;
 use nostd_color::colors::{GREEN, YELLOW, RED};
 
-lazy_static! {
-    static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(rust_os::gdt::DOUBLE_FAULT_IST_INDEX);
+// ---------------------------------------------------------------------------------------------------┐
+// Every case overflows one stack on purpose and checks that the kernel can tell which one it was.   |
+// The handler that catches the overflow does not return to the broken code, it "returns" into the   |
+// next case on a fresh stack instead, so a single boot runs the whole matrix:                        |
+//                                                                                                    |
+//   case            overflows                   caught by                                            |
+//   kernel stack    boot stack (recursion)      double fault (or page fault with `page-fault-ist`)   |
+//   double fault    double fault IST            double fault again, on the fresh IST top             |
+//   NMI             NMI IST (int 2)             double fault (or page fault)                         |
+//   machine check   machine check IST (int 18)  double fault (or page fault)                         |
+//   page fault      page fault IST              page fault again, only with `page-fault-ist`         |
+//   kernel report   machine check IST (call)    the kernel's own handlers, after `init_idt`          |
+//                                                                                                    |
+// The last case checks the report of the real handlers (see interrupts/exceptions.rs) instead, it    |
+// ends in their panic, so nothing can run after it.                                                  |
+// ---------------------------------------------------------------------------------------------------┙
+
+#[derive(Clone, Copy)]
+enum Expect {
+    // Fault outside of every interrupt stack.
+    KernelStack,
+    Guard(Ist),
+    // Overflow reported by the kernel's own handlers, with their panic message.
+    Report(Ist),
+}
+
+struct Case {
+    name: &'static str,
+    expect: Expect,
+    trigger: fn(),
+}
+
+const CASES: [Case; 6] = [
+    Case { name: "kernel_stack", expect: Expect::KernelStack, trigger: overflow },
+    Case { name: "double_fault_ist", expect: Expect::Guard(Ist::DoubleFault), trigger: double_fault },
+    Case { name: "nmi_ist", expect: Expect::Guard(Ist::Nmi), trigger: nmi },
+    Case { name: "machine_check_ist", expect: Expect::Guard(Ist::MachineCheck), trigger: machine_check },
+    Case { name: "page_fault_ist", expect: Expect::Guard(Ist::PageFault), trigger: page_fault },
+    Case { name: "kernel_report", expect: Expect::Report(Ist::MachineCheck), trigger: machine_check_stack },
+];
+
+const RESUME_STACK_SIZE: usize = 4096 * 4;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static mut RESUME_STACK: [u8; RESUME_STACK_SIZE] = [0; RESUME_STACK_SIZE];
+
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        // Not `set_handler_fn`, that one wants a diverging handler and this one returns into the next case.
+        idt.double_fault
+            .set_handler_addr(VirtAddr::new(test_double_fault_handler as *const () as u64))
+            .set_stack_index(rust_os::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(test_overflow_handler)
+            .set_stack_index(Ist::Nmi.index());
+        idt.machine_check
+            .set_handler_fn(test_machine_check_handler)
+            .set_stack_index(Ist::MachineCheck.index());
+        let page_fault = idt.page_fault.set_handler_fn(test_page_fault_handler);
+        if cfg!(feature = "page-fault-ist") {
+            page_fault.set_stack_index(Ist::PageFault.index());
         }
-        
-        idt
-    };
+    }
+
+    idt
+});
+
+extern "x86-interrupt" fn test_double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    caught(stack_frame);
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    caught(stack_frame);
 }
 
-extern "x86-interrupt" fn test_double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    serial_print!("[{}]\n", "ok".fg(GREEN));
-    exit_qemu(QemuExitCode::Success);
+// NMI and machine check only run for their own case, they use up their IST stack.
+extern "x86-interrupt" fn test_overflow_handler(_stack_frame: InterruptStackFrame) {
+    overflow();
+}
+
+extern "x86-interrupt" fn test_machine_check_handler(_stack_frame: InterruptStackFrame) -> ! {
+    overflow();
     htl_loop()
 }
 
-fn idt_init_test() {
-    TEST_IDT.load();
+// Decide whether the fault is the one the current case waits for, and if so continue with the next case.
+fn caught(mut stack_frame: InterruptStackFrame) {
+    // Read first, the checks below must not be able to overwrite it.
+    let address = Cr2::read();
+    let case = &CASES[CURRENT.load(Ordering::SeqCst)];
+
+    match (case.expect, gdt::overflowed_ist(address)) {
+        (Expect::KernelStack, None) => {}
+        (Expect::Guard(expected), Some(ist)) if ist == expected => {}
+        (Expect::Guard(_), None) => {
+            // The fault that starts the case, now overflow the interrupt stack we are on.
+            overflow();
+        }
+        (_, hit) => {
+            serial_println!("[{}]", "failed".fg(RED));
+            serial_println!("expected {} overflow, hit {:?} at {:?}", case.name, hit, address);
+            exit_qemu(QemuExitCode::Failed);
+            htl_loop();
+        }
+    }
+
+    serial_println!("[{}]", "ok".fg(GREEN));
+    CURRENT.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        // Looks like `next_case` was called: rsp + 8 is 16 byte aligned.
+        let top = core::ptr::addr_of!(RESUME_STACK) as u64 + RESUME_STACK_SIZE as u64;
+        let stack_pointer = VirtAddr::new((top & !0xF) - 8);
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(next_case as *const () as u64);
+            frame.stack_pointer = stack_pointer;
+        });
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    serial_print!("{}...\t", "stack_overflow::stack_oveflow".fg(YELLOW));
+entry_point!(main);
 
+fn main(boot_info: &'static BootInfo) -> ! {
     rust_os::gdt::init_gdt();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    // Interrupt stacks with guard pages.
+    rust_os::gdt::init_cpu().expect("allocating the interrupt stacks failed");
     idt_init_test();
 
-    stack_overflow();
+    next_case()
+}
+
+fn idt_init_test() {
+    TEST_IDT.load();
+}
+
+extern "C" fn next_case() -> ! {
+    let index = CURRENT.load(Ordering::SeqCst);
+    if index == CASES.len() {
+        exit_qemu(QemuExitCode::Success);
+        htl_loop();
+    }
+
+    let case = &CASES[index];
+    serial_print!("{}", "stack_overflow::".fg(YELLOW));
+    serial_print!("{}...\t", case.name.fg(YELLOW));
+    if matches!(case.expect, Expect::Guard(Ist::PageFault)) && !cfg!(feature = "page-fault-ist") {
+        serial_println!("[{}]", "skipped, needs the page-fault-ist feature".fg(YELLOW));
+        CURRENT.fetch_add(1, Ordering::SeqCst);
+        next_case();
+    }
+
+    if let Expect::Report(_) = case.expect {
+        // The test handlers are gone from here on.
+        rust_os::interrupts::init_idt();
+    }
+    (case.trigger)();
 
     panic!("Execution continued after overflow!");
 }
 
+// The kernel reports an overflow with a panic, for the last case that is a pass if it names the right stack.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    htl_loop()
+    if let Some(Expect::Report(ist)) = CASES.get(CURRENT.load(Ordering::SeqCst)).map(|case| case.expect) {
+        let mut expected = Message { bytes: [0; 128], len: 0 };
+        let _ = write!(expected, "EXCEPTION: STACK OVERFLOW\n{} interrupt stack of CPU ", ist.name());
+        let mut message = Message { bytes: [0; 128], len: 0 };
+        let _ = write!(message, "{}", info.message());
+        if message.bytes[..message.len].starts_with(&expected.bytes[..expected.len]) {
+            serial_println!("[{}]", "ok".fg(GREEN));
+            exit_qemu(QemuExitCode::Success);
+            htl_loop();
+        }
+    }
+    rust_os::test_panic_handler(info)
+}
+
+// The start of a panic message, formatted without the heap.
+struct Message {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
 }
 
 #[allow(unconditional_recursion)]
-fn stack_overflow() {
-    stack_overflow(); // Each recursion return address is pushed eventually overflowing the stack
+fn overflow() {
+    overflow(); // Each recursion return address is pushed eventually overflowing the stack
     volatile::Volatile::new(0).read(); // Prevent tail recursion optimization
+}
+
+// Vector 0x90 has no handler, neither does #NP (segment not present) that this raises: a double fault.
+fn double_fault() {
+    unsafe { core::arch::asm!("int 0x90", options(nomem, nostack)) };
+}
+
+fn nmi() {
+    unsafe { core::arch::asm!("int 2", options(nomem, nostack)) };
+}
+
+fn machine_check() {
+    unsafe { core::arch::asm!("int 18", options(nomem, nostack)) };
+}
+
+fn page_fault() {
+    // Lower half address nothing is mapped at.
+    unsafe { core::ptr::read_volatile(0x0000_4444_0000_0000 as *const u64) };
+}
+
+// Recursion on the machine check stack without a machine check, only its guard page stops it.
+fn machine_check_stack() {
+    let tss = rust_os::smp::percpu::current().tss();
+    let top = unsafe { (*tss).interrupt_stack_table[Ist::MachineCheck.index() as usize] };
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) top.as_u64(),
+            overflow = sym overflow_from_call,
+            options(noreturn)
+        )
+    };
+}
+
+extern "C" fn overflow_from_call() -> ! {
+    overflow();
+    htl_loop()
 }
\ No newline at end of file
@@ -0,0 +1,205 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::scheduler;
+use rust_os::sync::{Condvar, Lazy, Mutex, RwLock, Semaphore, SpinLock};
+use rust_os::{allocator, htl_loop, memory};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const THREADS: usize = 4;
+
+fn wait_until(condition: impl Fn() -> bool) {
+    while !condition() {
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn spin_lock_disables_interrupts_while_held() {
+    let lock = SpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    // Nested locks restore what was there before them, interrupts come back with the outer one.
+    let other = SpinLock::new(0);
+    let outer = lock.lock();
+    drop(other.lock());
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn mutex_serializes_threads() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let counter = Arc::new(Mutex::new(0));
+    for _ in 0..THREADS {
+        let counter = counter.clone();
+        scheduler::spawn(move || {
+            for _ in 0..50 {
+                let mut guard = counter.lock();
+                let value = *guard;
+                // Others run meanwhile and have to wait for the mutex, an update in between would get lost.
+                scheduler::yield_now();
+                *guard = value + 1;
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+
+    wait_until(|| DONE.load(Ordering::SeqCst) == THREADS);
+    assert_eq!(*counter.lock(), THREADS * 50);
+    assert!(!counter.is_locked());
+}
+
+#[test_case]
+fn semaphore_limits_holders() {
+    static SEMAPHORE: Semaphore = Semaphore::new(2);
+    static HOLDERS: AtomicUsize = AtomicUsize::new(0);
+    static MAX_HOLDERS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..THREADS {
+        scheduler::spawn(|| {
+            for _ in 0..10 {
+                SEMAPHORE.acquire();
+                let holders = HOLDERS.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_HOLDERS.fetch_max(holders, Ordering::SeqCst);
+                scheduler::yield_now();
+                HOLDERS.fetch_sub(1, Ordering::SeqCst);
+                SEMAPHORE.release();
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+
+    wait_until(|| DONE.load(Ordering::SeqCst) == THREADS);
+    assert_eq!(MAX_HOLDERS.load(Ordering::SeqCst), 2);
+    assert_eq!(SEMAPHORE.available(), 2);
+}
+
+#[test_case]
+fn rwlock_readers_share_and_writer_excludes() {
+    static LOCK: RwLock<(u64, u64)> = RwLock::new((0, 0));
+    static READERS: AtomicUsize = AtomicUsize::new(0);
+    static MAX_READERS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..THREADS {
+        scheduler::spawn(|| {
+            for _ in 0..20 {
+                let pair = LOCK.read();
+                let readers = READERS.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_READERS.fetch_max(readers, Ordering::SeqCst);
+                scheduler::yield_now();
+                // The writer keeps both halves equal, a reader never sees it halfway.
+                assert_eq!(pair.0, pair.1);
+                READERS.fetch_sub(1, Ordering::SeqCst);
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    scheduler::spawn(|| {
+        for _ in 0..20 {
+            let mut pair = LOCK.write();
+            assert_eq!(READERS.load(Ordering::SeqCst), 0);
+            pair.0 += 1;
+            scheduler::yield_now();
+            pair.1 += 1;
+        }
+        DONE.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+
+    wait_until(|| DONE.load(Ordering::SeqCst) == THREADS + 1);
+    assert_eq!(*LOCK.read(), (20, 20));
+    assert!(MAX_READERS.load(Ordering::SeqCst) > 1);
+}
+
+#[test_case]
+fn condvar_hands_items_to_consumer() {
+    static QUEUE: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+    static NOT_EMPTY: Condvar = Condvar::new();
+    static CONSUMED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    const ITEMS: usize = 100;
+
+    scheduler::spawn(|| {
+        while CONSUMED.lock().len() < ITEMS {
+            let mut queue = NOT_EMPTY.wait_while(QUEUE.lock(), |queue| queue.is_empty());
+            let item = queue.pop_front().unwrap();
+            drop(queue);
+            CONSUMED.lock().push(item);
+        }
+    })
+    .unwrap();
+
+    for item in 0..ITEMS {
+        QUEUE.lock().push_back(item);
+        NOT_EMPTY.notify_one();
+        if item % 7 == 0 {
+            scheduler::yield_now();
+        }
+    }
+
+    wait_until(|| CONSUMED.lock().len() == ITEMS);
+    assert!(CONSUMED.lock().iter().copied().eq(0..ITEMS));
+}
+
+#[test_case]
+fn lazy_initializes_once_across_threads() {
+    static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
+    static VALUE: Lazy<usize> = Lazy::new(|| {
+        INITIALIZED.fetch_add(1, Ordering::SeqCst);
+        scheduler::yield_now();
+        42
+    });
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..THREADS {
+        scheduler::spawn(|| {
+            assert_eq!(*VALUE, 42);
+            DONE.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+
+    wait_until(|| DONE.load(Ordering::SeqCst) == THREADS);
+    assert_eq!(INITIALIZED.load(Ordering::SeqCst), 1);
+}
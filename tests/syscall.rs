@@ -0,0 +1,346 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use rust_os::scheduler::{self, ThreadId, ThreadState};
+use rust_os::syscall::{self, Syscall, SyscallError, PROT_READ, PROT_WRITE, STDIN, STDOUT};
+use rust_os::time::{Duration, Instant};
+use rust_os::{allocator, gdt, htl_loop, memory, usermode};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// User programs, copied into a user page before they run. Results go right below the initial stack
+// pointer (slot 1 = [rsp - 8], slot 2 = [rsp - 16], ...), the read buffer is at [rsp - 128]. Every program
+// ends with `exit`, the store after it only happens if `exit` returned.
+global_asm!(
+    // Every system call once, through `syscall`.
+    ".global user_calls",
+    ".global user_calls_end",
+    "user_calls:",
+    // Not touched by the kernel, must survive every call.
+    "mov ebx, 0x1234",
+    "mov eax, {getpid}",
+    "syscall",
+    "mov [rsp - 8], rax",
+    "mov eax, {write}",
+    "mov edi, {stdout}",
+    "lea rsi, [rip + 4f]",
+    "mov edx, 18",
+    "syscall",
+    "mov [rsp - 16], rax",
+    "mov eax, {yield_now}",
+    "syscall",
+    "mov [rsp - 24], rax",
+    "mov eax, {sleep}",
+    "mov edi, 20",
+    "syscall",
+    "mov [rsp - 32], rax",
+    "mov eax, {mmap}",
+    "xor edi, edi",
+    "mov esi, 8192",
+    "mov edx, {read_write}",
+    "syscall",
+    "mov [rsp - 40], rax",
+    // Demand zero, the first access maps the page.
+    "mov rcx, [rax + 4096]",
+    "mov [rsp - 48], rcx",
+    "mov qword ptr [rax + 4096], 42",
+    "mov rcx, [rax + 4096]",
+    "mov [rsp - 56], rcx",
+    "mov [rsp - 64], rbx",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "mov qword ptr [rsp - 72], 1",
+    "hlt",
+    "4:",
+    ".ascii \"hello from ring 3\\n\"",
+    "user_calls_end:",
+    // The same through `int 0x80`.
+    ".global user_int80",
+    ".global user_int80_end",
+    "user_int80:",
+    // `int 0x80` preserves rcx and r11, unlike `syscall`.
+    "mov ecx, 0x5678",
+    "mov eax, {getpid}",
+    "int 0x80",
+    "mov [rsp - 8], rax",
+    "mov eax, {write}",
+    "mov edi, {stdout}",
+    "lea rsi, [rip + 4f]",
+    "mov edx, 7",
+    "int 0x80",
+    "mov [rsp - 16], rax",
+    "mov [rsp - 24], rcx",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "int 0x80",
+    "mov qword ptr [rsp - 32], 1",
+    "hlt",
+    "4:",
+    ".ascii \"int 80\\n\"",
+    "user_int80_end:",
+    // Blocks in `read` until the test provides input.
+    ".global user_read",
+    ".global user_read_end",
+    "user_read:",
+    "mov eax, {read}",
+    "mov edi, {stdin}",
+    "lea rsi, [rsp - 128]",
+    "mov edx, 16",
+    "syscall",
+    "mov [rsp - 8], rax",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "hlt",
+    "user_read_end:",
+    // Arguments the kernel has to reject.
+    ".global user_errors",
+    ".global user_errors_end",
+    "user_errors:",
+    "movabs r12, 0xFFFF800000000000",
+    "mov eax, {write}",
+    "mov edi, {stdout}",
+    "mov rsi, r12",
+    "mov edx, 8",
+    "syscall",
+    "mov [rsp - 8], rax",
+    "mov eax, {write}",
+    "mov edi, 7",
+    "lea rsi, [rsp - 128]",
+    "mov edx, 1",
+    "syscall",
+    "mov [rsp - 16], rax",
+    "mov eax, 999",
+    "syscall",
+    "mov [rsp - 24], rax",
+    "mov eax, {mmap}",
+    "mov rdi, r12",
+    "mov esi, 4096",
+    "mov edx, {read_write}",
+    "syscall",
+    "mov [rsp - 32], rax",
+    "mov eax, {read}",
+    "mov edi, {stdin}",
+    "mov rsi, r12",
+    "mov edx, 8",
+    "syscall",
+    "mov [rsp - 40], rax",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "hlt",
+    "user_errors_end:",
+    // `exec` with 255 arguments of 4000 bytes each, and the same again as environment. Together far more
+    // than the kernel heap, the kernel has to give up before copying them all.
+    ".global user_exec_too_long",
+    ".global user_exec_too_long_end",
+    "user_exec_too_long:",
+    // The string fills the stack page up to the result slots.
+    "lea rdi, [rsp - 4096]",
+    "mov al, 0x61",
+    "mov ecx, 4000",
+    "rep stosb",
+    "mov byte ptr [rdi], 0",
+    // The array of pointers to it in the second half of the code page.
+    "lea rdi, [rip + user_exec_too_long]",
+    "add rdi, 2048",
+    "mov r12, rdi",
+    "lea rax, [rsp - 4096]",
+    "mov ecx, 255",
+    "rep stosq",
+    "mov qword ptr [rdi], 0",
+    "mov eax, {exec}",
+    "lea rdi, [rip + 4f]",
+    "mov rsi, r12",
+    "mov rdx, r12",
+    "syscall",
+    "mov [rsp - 8], rax",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "hlt",
+    "4:",
+    ".asciz \"hello\"",
+    "user_exec_too_long_end:",
+    read = const Syscall::Read as u64,
+    write = const Syscall::Write as u64,
+    exit = const Syscall::Exit as u64,
+    yield_now = const Syscall::Yield as u64,
+    sleep = const Syscall::Sleep as u64,
+    getpid = const Syscall::GetPid as u64,
+    mmap = const Syscall::Mmap as u64,
+    exec = const Syscall::Exec as u64,
+    stdin = const STDIN,
+    stdout = const STDOUT,
+    read_write = const PROT_READ | PROT_WRITE,
+);
+
+extern "C" {
+    static user_calls: u8;
+    static user_calls_end: u8;
+    static user_int80: u8;
+    static user_int80_end: u8;
+    static user_read: u8;
+    static user_read_end: u8;
+    static user_errors: u8;
+    static user_errors_end: u8;
+    static user_exec_too_long: u8;
+    static user_exec_too_long_end: u8;
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// A code page followed by a stack page, both user accessible.
+struct UserPages {
+    code: Page,
+}
+
+impl UserPages {
+    fn map(slot: u64, program: (&u8, &u8)) -> Self {
+        let code = Page::containing_address(VirtAddr::new(memory::USER_START + slot * 0x10_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        memory::kernel_memory().lock().map_range(Page::range_inclusive(code, code + 1), flags).unwrap();
+
+        let (start, end) = program;
+        let size = end as *const u8 as usize - start as *const u8 as usize;
+        unsafe {
+            core::ptr::copy_nonoverlapping(start as *const u8, code.start_address().as_mut_ptr::<u8>(), size);
+            (code + 1).start_address().as_mut_ptr::<u8>().write_bytes(0, 4096);
+        }
+        UserPages { code }
+    }
+
+    // Run the program in a new thread, through `iretq`.
+    fn spawn(&self) -> ThreadId {
+        let (entry, stack) = (self.code.start_address(), self.stack_top());
+        scheduler::spawn(move || unsafe { usermode::enter(entry, stack) }).unwrap()
+    }
+
+    fn stack_top(&self) -> VirtAddr {
+        (self.code + 2).start_address()
+    }
+
+    fn result(&self, slot: u64) -> u64 {
+        unsafe { (self.stack_top() - slot * 8).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn read_buffer(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((self.stack_top() - 128u64).as_ptr(), 16) }
+    }
+}
+
+impl Drop for UserPages {
+    fn drop(&mut self) {
+        let mut memory = memory::kernel_memory().lock();
+        unsafe { memory.unmap_range_and_free(Page::range_inclusive(self.code, self.code + 1)).unwrap() };
+    }
+}
+
+fn has_exited(id: ThreadId) -> bool {
+    matches!(scheduler::state(id), None | Some(ThreadState::Exited))
+}
+
+fn wait_for_exit(id: ThreadId) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !has_exited(id) {
+        assert!(Instant::now() < deadline, "user thread did not terminate");
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn every_syscall_through_the_syscall_instruction() {
+    let user = UserPages::map(0, unsafe { (&user_calls, &user_calls_end) });
+    let start = Instant::now();
+    let id = user.spawn();
+    wait_for_exit(id);
+
+    assert_eq!(user.result(1), id.as_u64(), "getpid");
+    assert_eq!(user.result(2), 18, "write");
+    assert_eq!(user.result(3), 0, "yield");
+    assert_eq!(user.result(4), 0, "sleep");
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    let mapped = user.result(5);
+    assert!(usermode::is_user_address(VirtAddr::new(mapped)), "mmap returned {:#x}", mapped);
+    assert_eq!(user.result(6), 0, "mmap memory is zeroed");
+    assert_eq!(user.result(7), 42);
+    assert_eq!(user.result(8), 0x1234, "rbx was not preserved");
+    assert_eq!(user.result(9), 0, "exit returned");
+}
+
+#[test_case]
+fn every_syscall_through_int_0x80() {
+    let user = UserPages::map(1, unsafe { (&user_int80, &user_int80_end) });
+    let id = user.spawn();
+    wait_for_exit(id);
+
+    assert_eq!(user.result(1), id.as_u64());
+    assert_eq!(user.result(2), 7);
+    assert_eq!(user.result(3), 0x5678, "rcx was not preserved");
+    assert_eq!(user.result(4), 0, "exit returned");
+}
+
+#[test_case]
+fn read_blocks_until_there_is_input() {
+    let user = UserPages::map(2, unsafe { (&user_read, &user_read_end) });
+    let id = user.spawn();
+
+    rust_os::time::sleep(Duration::from_millis(50));
+    assert!(!has_exited(id), "read returned without input");
+    // All at once, `read` returns whatever is there when it wakes up.
+    interrupts::without_interrupts(|| {
+        for byte in b"ring 3" {
+            syscall::push_input(*byte);
+        }
+    });
+    wait_for_exit(id);
+
+    assert_eq!(user.result(1), 6);
+    assert_eq!(&user.read_buffer()[..6], b"ring 3");
+}
+
+#[test_case]
+fn bad_arguments_are_rejected() {
+    let user = UserPages::map(3, unsafe { (&user_errors, &user_errors_end) });
+    let id = user.spawn();
+    wait_for_exit(id);
+
+    assert_eq!(SyscallError::from_return(user.result(1)), Err(SyscallError::BadAddress));
+    assert_eq!(SyscallError::from_return(user.result(2)), Err(SyscallError::BadFileDescriptor));
+    assert_eq!(SyscallError::from_return(user.result(3)), Err(SyscallError::NoSuchSyscall));
+    assert_eq!(SyscallError::from_return(user.result(4)), Err(SyscallError::InvalidArgument));
+    assert_eq!(SyscallError::from_return(user.result(5)), Err(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn exec_rejects_huge_argument_lists() {
+    let user = UserPages::map(4, unsafe { (&user_exec_too_long, &user_exec_too_long_end) });
+    let id = user.spawn();
+    wait_for_exit(id);
+
+    assert_eq!(SyscallError::from_return(user.result(1)), Err(SyscallError::ArgumentListTooLong));
+}
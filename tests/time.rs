@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rust_os::time::{self, timer, Duration, Instant};
+use rust_os::{allocator, htl_loop, memory, scheduler};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn pit_runs_at_configured_frequency() {
+    // The PIT can not hit 100 Hz exactly, but it gets within a fraction of a percent.
+    assert!((99..=100).contains(&time::frequency()));
+    let period = time::tick_period();
+    assert!(period > Duration::from_micros(9_990) && period < Duration::from_micros(10_010));
+}
+
+#[test_case]
+fn uptime_is_monotonic() {
+    let start = Instant::now();
+    let ticks = time::ticks();
+    let mut last = start;
+    while time::ticks() < ticks + 5 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+    assert!(start.elapsed() >= time::tick_period() * 4);
+    assert!(time::uptime() >= start.elapsed());
+}
+
+#[test_case]
+fn spin_wait_waits_long_enough() {
+    let start = Instant::now();
+    time::spin_wait(Duration::from_millis(30));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+}
+
+#[test_case]
+fn sleep_without_scheduler_halts() {
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(50));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test_case]
+fn timer_callbacks_fire_once() {
+    static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+    static CANCELLED_FIRED: AtomicBool = AtomicBool::new(false);
+
+    let start = time::ticks();
+    timer::after(Duration::from_millis(20), || {
+        FIRED_AT.store(time::ticks(), Ordering::SeqCst);
+    });
+    let cancelled = timer::after(Duration::from_millis(20), || {
+        CANCELLED_FIRED.store(true, Ordering::SeqCst);
+    });
+    assert!(timer::cancel(cancelled));
+
+    time::sleep(Duration::from_millis(100));
+    let fired_at = FIRED_AT.load(Ordering::SeqCst);
+    assert!(fired_at >= start + time::ticks_for(Duration::from_millis(20)));
+    assert!(!CANCELLED_FIRED.load(Ordering::SeqCst));
+    assert!(!timer::cancel(cancelled));
+}
+
+#[test_case]
+fn wall_clock_follows_uptime() {
+    use time::rtc;
+
+    let rtc_now = rtc::read();
+    // Whatever QEMU was started with, it is not before this code was written.
+    assert!(rtc_now.year >= 2023);
+    let drift = rtc::wall_clock().to_unix_timestamp() as i64 - rtc_now.to_unix_timestamp() as i64;
+    assert!(drift.abs() <= 1);
+}
+
+#[test_case]
+fn rtc_periodic_interrupt_fires() {
+    use time::rtc;
+
+    // 1024 Hz
+    rtc::enable_periodic_interrupt(6);
+    let start = rtc::periodic_ticks();
+    time::sleep(Duration::from_millis(100));
+    rtc::disable_periodic_interrupt();
+    assert!(rtc::periodic_ticks() > start + 10);
+}
+
+// Runs last, everything above must also work without threads.
+#[test_case]
+fn sleep_blocks_thread() {
+    static WOKE: AtomicBool = AtomicBool::new(false);
+
+    scheduler::init().expect("scheduler initialization failed");
+    let start = Instant::now();
+    scheduler::spawn(|| {
+        time::sleep(Duration::from_millis(50));
+        WOKE.store(true, Ordering::SeqCst);
+    }).unwrap();
+
+    while !WOKE.load(Ordering::SeqCst) {
+        scheduler::yield_now();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
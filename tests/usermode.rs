@@ -0,0 +1,190 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use rust_os::scheduler::{self, ThreadId, ThreadState};
+use rust_os::time::{Duration, Instant};
+use rust_os::{allocator, gdt, htl_loop, memory, usermode};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// User programs, copied into a user page before they run. They only use rsp relative addresses, the
+// results go right below the initial stack pointer, and they end with `hlt`: a #GP in ring 3 that
+// terminates the thread (in ring 0 it would just halt).
+global_asm!(
+    ".global user_program",
+    ".global user_program_end",
+    "user_program:",
+    "mov rax, cs",
+    "mov [rsp - 8], rax",
+    // Long enough to be preempted a few times.
+    "mov ecx, 50000000",
+    "3:",
+    "dec rcx",
+    "jnz 3b",
+    "mov [rsp - 16], rsp",
+    "hlt",
+    "user_program_end:",
+    ".global user_kernel_read",
+    ".global user_kernel_read_end",
+    "user_kernel_read:",
+    // Start of the physical memory mapping, present but not user accessible.
+    "movabs rax, 0xFFFF800000000000",
+    "mov rax, [rax]",
+    "mov qword ptr [rsp - 8], 1",
+    "hlt",
+    "user_kernel_read_end:",
+);
+
+extern "C" {
+    static user_program: u8;
+    static user_program_end: u8;
+    static user_kernel_read: u8;
+    static user_kernel_read_end: u8;
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    gdt::init_cpu().expect("allocating the interrupt stacks failed");
+    scheduler::init().expect("scheduler initialization failed");
+
+    test_main();
+    htl_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// A code page followed by a stack page, both user accessible.
+struct UserPages {
+    code: Page,
+}
+
+impl UserPages {
+    // Every test uses its own slot, so nothing depends on unmapping being done right.
+    fn map(slot: u64, program: (&u8, &u8)) -> Self {
+        let code = Page::containing_address(VirtAddr::new(memory::USER_START + slot * 0x10_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        memory::kernel_memory().lock().map_range(Page::range_inclusive(code, code + 1), flags).unwrap();
+
+        let (start, end) = program;
+        let size = end as *const u8 as usize - start as *const u8 as usize;
+        unsafe {
+            core::ptr::copy_nonoverlapping(start as *const u8, code.start_address().as_mut_ptr::<u8>(), size);
+            // Result slots.
+            (code + 1).start_address().as_mut_ptr::<u8>().write_bytes(0, 4096);
+        }
+        UserPages { code }
+    }
+
+    fn entry(&self) -> VirtAddr {
+        self.code.start_address()
+    }
+
+    fn stack_top(&self) -> VirtAddr {
+        (self.code + 2).start_address()
+    }
+
+    // `index` 1 is right below the initial stack pointer.
+    fn result(&self, index: u64) -> u64 {
+        unsafe { (self.stack_top() - index * 8).as_ptr::<u64>().read_volatile() }
+    }
+}
+
+impl Drop for UserPages {
+    fn drop(&mut self) {
+        let mut memory = memory::kernel_memory().lock();
+        // The user thread is gone, nothing references the pages anymore.
+        unsafe { memory.unmap_range_and_free(Page::range_inclusive(self.code, self.code + 1)).unwrap() };
+    }
+}
+
+fn program() -> (&'static u8, &'static u8) {
+    unsafe { (&user_program, &user_program_end) }
+}
+
+fn wait_for_exit(id: ThreadId) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !matches!(scheduler::state(id), None | Some(ThreadState::Exited)) {
+        assert!(Instant::now() < deadline, "user thread did not terminate");
+        scheduler::yield_now();
+    }
+}
+
+#[test_case]
+fn iretq_enters_ring_3() {
+    let user = UserPages::map(0, program());
+    let (entry, stack) = (user.entry(), user.stack_top());
+    let id = scheduler::spawn(move || unsafe { usermode::enter(entry, stack) }).unwrap();
+
+    wait_for_exit(id);
+    assert_eq!(user.result(1) & 3, 3, "user code did not run in ring 3");
+    assert_eq!(user.result(1), u64::from(gdt::selectors().user_code.0));
+    assert_eq!(user.result(2), stack.as_u64());
+}
+
+#[test_case]
+fn sysretq_enters_ring_3() {
+    let user = UserPages::map(1, program());
+    let (entry, stack) = (user.entry(), user.stack_top());
+    let id = scheduler::spawn(move || unsafe { usermode::enter_sysret(entry, stack) }).unwrap();
+
+    wait_for_exit(id);
+    assert_eq!(user.result(1), u64::from(gdt::selectors().user_code.0));
+    assert_eq!(user.result(2), stack.as_u64());
+}
+
+#[test_case]
+fn user_threads_are_preempted_and_resumed() {
+    // Both spin long enough to be switched out in ring 3, each has to come back on its own kernel stack.
+    let users = [UserPages::map(2, program()), UserPages::map(3, program())];
+    let mut ids = [None; 2];
+    for (user, id) in users.iter().zip(ids.iter_mut()) {
+        let (entry, stack) = (user.entry(), user.stack_top());
+        *id = Some(scheduler::spawn(move || unsafe { usermode::enter(entry, stack) }).unwrap());
+    }
+
+    for (user, id) in users.iter().zip(ids.iter()) {
+        wait_for_exit(id.unwrap());
+        assert_eq!(user.result(1) & 3, 3);
+        assert_eq!(user.result(2), user.stack_top().as_u64());
+    }
+}
+
+#[test_case]
+fn user_code_cannot_read_kernel_memory() {
+    let user = UserPages::map(4, unsafe { (&user_kernel_read, &user_kernel_read_end) });
+    let (entry, stack) = (user.entry(), user.stack_top());
+    let id = scheduler::spawn(move || unsafe { usermode::enter(entry, stack) }).unwrap();
+
+    wait_for_exit(id);
+    // Killed by the page fault, before the store.
+    assert_eq!(user.result(1), 0);
+}
+
+#[test_case]
+fn kernel_stack_follows_the_thread() {
+    let id = scheduler::spawn(|| {
+        for _ in 0..10 {
+            interrupts::without_interrupts(|| {
+                assert_eq!(gdt::kernel_stack(), scheduler::current_kernel_stack());
+            });
+            scheduler::yield_now();
+        }
+    })
+    .unwrap();
+
+    wait_for_exit(id);
+}